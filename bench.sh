@@ -0,0 +1,21 @@
+#!/bin/bash
+# 関数の数を倍々に増やしたソースをコンパイルし、かかった時間を表示する。
+# 字句解析と構文解析が線形なら、時間もほぼ倍々になる
+cargo build --release -q || exit 1
+
+generate() {
+  n="$1"
+  for i in $(seq 1 "$n"); do
+    echo "int f$i(int a, int b) { int x; x = a * $i + b; if (x > 100) x = x - 100; while (x > 10) x = x / 2; return x; }"
+  done
+  echo "int main() { return f1(1, 2); }"
+}
+
+for n in 2500 5000 10000 20000; do
+  generate "$n" > tmp_bench.c
+  start=$(date +%s%N)
+  ./target/release/cygcc tmp_bench.c > tmp_bench.s || exit 1
+  end=$(date +%s%N)
+  echo "$n functions: $(( (end - start) / 1000000 )) ms"
+done
+rm -f tmp_bench.c tmp_bench.s
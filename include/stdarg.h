@@ -0,0 +1,16 @@
+// cygccに同梱する最小限のヘッダ。
+// 可変長引数の関数はまだ定義できないので、va_startやva_argはない。
+// va_listはSystem V ABIの__va_list_tagと同じ並びの構造体へのポインタで、
+// vprintfのようにva_listを受け取る関数を宣言するためだけに使う
+#ifndef __CYGCC_STDARG_H
+#define __CYGCC_STDARG_H
+
+struct valisttag {
+    unsigned int gpoffset;
+    unsigned int fpoffset;
+    long *overflowargarea;
+    long *regsavearea;
+};
+#define va_list struct valisttag *
+
+#endif
@@ -0,0 +1,11 @@
+// cygccに同梱する最小限のヘッダ。
+// _Boolがないのでboolはintにする
+#ifndef __CYGCC_STDBOOL_H
+#define __CYGCC_STDBOOL_H
+
+#define bool int
+#define true 1
+#define false 0
+#define __bool_true_false_are_defined 1
+
+#endif
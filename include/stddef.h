@@ -0,0 +1,10 @@
+// cygccに同梱する最小限のヘッダ。
+// typedefがないので型の名前はマクロで定義する
+#ifndef __CYGCC_STDDEF_H
+#define __CYGCC_STDDEF_H
+
+#define size_t unsigned long
+#define ptrdiff_t long
+#define NULL 0
+
+#endif
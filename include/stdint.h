@@ -0,0 +1,26 @@
+// cygccに同梱する最小限のヘッダ。
+// charとshortがないので8ビットと16ビットの型はない
+#ifndef __CYGCC_STDINT_H
+#define __CYGCC_STDINT_H
+
+#define int32_t int
+#define uint32_t unsigned int
+#define int64_t long
+#define uint64_t unsigned long
+#define intptr_t long
+#define uintptr_t unsigned long
+#define intmax_t long
+#define uintmax_t unsigned long
+
+#define INT32_MAX 2147483647
+#define INT32_MIN (-2147483647 - 1)
+#define UINT32_MAX 4294967295U
+#define INT64_MAX 9223372036854775807L
+#define INT64_MIN (-9223372036854775807L - 1)
+#define UINT64_MAX 18446744073709551615UL
+#define INTPTR_MAX INT64_MAX
+#define INTPTR_MIN INT64_MIN
+#define UINTPTR_MAX UINT64_MAX
+#define SIZE_MAX UINT64_MAX
+
+#endif
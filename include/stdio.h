@@ -0,0 +1,25 @@
+// cygccに同梱する最小限のヘッダ。
+// charと文字列がないので、文字はintで扱い、書式を取る関数は引数を書かずに宣言する
+#ifndef __CYGCC_STDIO_H
+#define __CYGCC_STDIO_H
+
+#include <stddef.h>
+
+// 中身は使わないので、FILEはメンバを宣言しない構造体にする
+#define FILE struct file
+#define EOF (-1)
+
+extern FILE *stdin;
+extern FILE *stdout;
+extern FILE *stderr;
+
+int printf();
+int fprintf();
+int puts();
+int putchar(int c);
+int getchar();
+int fputc(int c, FILE *stream);
+int fgetc(FILE *stream);
+int fflush(FILE *stream);
+
+#endif
@@ -0,0 +1,26 @@
+// cygccに同梱する最小限のヘッダ。
+// voidがないので、void *はint *で表し、値を返さない関数はintを返すものとして宣言する
+#ifndef __CYGCC_STDLIB_H
+#define __CYGCC_STDLIB_H
+
+#include <stddef.h>
+
+#define EXIT_SUCCESS 0
+#define EXIT_FAILURE 1
+#define RAND_MAX 2147483647
+
+int *malloc(size_t size);
+int *calloc(size_t n, size_t size);
+int *realloc(int *p, size_t size);
+int free(int *p);
+int exit(int status);
+int abort();
+int abs(int x);
+long labs(long x);
+int atoi();
+long atol();
+long strtol();
+int rand();
+int srand(unsigned int seed);
+
+#endif
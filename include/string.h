@@ -0,0 +1,15 @@
+// cygccに同梱する最小限のヘッダ。
+// charとvoidがないので、メモリはint *で表し、文字列を取る関数は引数を書かずに宣言する
+#ifndef __CYGCC_STRING_H
+#define __CYGCC_STRING_H
+
+#include <stddef.h>
+
+int *memcpy(int *dest, int *src, size_t n);
+int *memmove(int *dest, int *src, size_t n);
+int *memset(int *s, int c, size_t n);
+int memcmp(int *a, int *b, size_t n);
+size_t strlen();
+int strcmp();
+
+#endif
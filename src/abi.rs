@@ -0,0 +1,127 @@
+// System V AMD64 ABIで引数と戻り値をどこで渡すかの分類。
+// 16バイト以下の値は8バイト (eightbyte) ごとに分類し、それより大きい構造体はMEMORYになる。
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum RegClass {
+    // 汎用レジスタ (引数はrdi..r9、戻り値はrax, rdx)
+    Integer,
+    // xmmレジスタ (引数はxmm0..xmm7、戻り値はxmm0, xmm1)
+    Sse,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum ArgClass {
+    // eightbyteごとの分類の並び
+    Regs(Vec<RegClass>),
+    // 引数はスタックに積み、戻り値は呼び出し側が用意した領域に書く (アドレスは隠し引数でrdiに渡す)
+    Memory,
+}
+
+// レジスタで渡す値のeightbyteの数の上限。これより大きい構造体はMEMORYになるので、
+// Regsの並びはこの長さを超えない
+pub const MAX_REG_EIGHTBYTES: usize = 2;
+const MAX_REG_STRUCT_SIZE: i32 = MAX_REG_EIGHTBYTES as i32 * 8;
+
+pub fn classify_scalar(is_floating: bool) -> ArgClass {
+    if is_floating {
+        ArgClass::Regs(vec![RegClass::Sse])
+    } else {
+        ArgClass::Regs(vec![RegClass::Integer])
+    }
+}
+
+// fieldsは構造体を平らにしたときのスカラーのメンバの (オフセット, 浮動小数点数か)。
+// eightbyteに含まれるメンバがすべて浮動小数点数ならSSE、そうでなければINTEGERになる。
+pub fn classify_struct(size: i32, fields: &[(i32, bool)]) -> ArgClass {
+    if size > MAX_REG_STRUCT_SIZE {
+        return ArgClass::Memory;
+    }
+    let classes = (0..eightbytes(size).len() as i32)
+        .map(|i| {
+            let all_floating = fields
+                .iter()
+                .filter(|(offset, _)| offset / 8 == i)
+                .all(|(_, is_floating)| *is_floating);
+            if all_floating {
+                RegClass::Sse
+            } else {
+                RegClass::Integer
+            }
+        })
+        .collect();
+    ArgClass::Regs(classes)
+}
+
+// 構造体を8バイトずつに区切ったときの各部分の大きさ。
+// メンバは4バイト単位で並ぶので、最後の部分は4か8になる。
+pub fn eightbytes(size: i32) -> Vec<i32> {
+    let mut sizes = vec![];
+    let mut rest = size;
+    while rest > 0 {
+        sizes.push(rest.min(8));
+        rest -= 8;
+    }
+    sizes
+}
+
+// Round up `n` to the nearest multiple of `align`. For instance,
+// align_to(5, 8) returns 8 and align_to(11, 8) returns 16.
+pub fn align_to(n: i32, align: i32) -> i32 {
+    (n + align - 1) / align * align
+}
+
+#[test]
+fn classify_struct_test() {
+    use RegClass::*;
+    let ints = |offsets: &[i32]| -> Vec<(i32, bool)> {
+        offsets.iter().map(|offset| (*offset, false)).collect()
+    };
+    assert_eq!(
+        classify_struct(4, &ints(&[0])),
+        ArgClass::Regs(vec![Integer])
+    );
+    assert_eq!(
+        classify_struct(8, &ints(&[0, 4])),
+        ArgClass::Regs(vec![Integer])
+    );
+    assert_eq!(
+        classify_struct(12, &ints(&[0, 4, 8])),
+        ArgClass::Regs(vec![Integer, Integer])
+    );
+    assert_eq!(
+        classify_struct(16, &ints(&[0, 8])),
+        ArgClass::Regs(vec![Integer, Integer])
+    );
+    assert_eq!(
+        classify_struct(20, &ints(&[0, 4, 8, 12, 16])),
+        ArgClass::Memory
+    );
+    assert_eq!(classify_struct(24, &ints(&[0, 8, 16])), ArgClass::Memory);
+
+    // struct { double d; int i; } と struct { float x; float y; double z; }
+    assert_eq!(
+        classify_struct(16, &[(0, true), (8, false)]),
+        ArgClass::Regs(vec![Sse, Integer])
+    );
+    assert_eq!(
+        classify_struct(16, &[(0, true), (4, true), (8, true)]),
+        ArgClass::Regs(vec![Sse, Sse])
+    );
+    // struct { float f; int i; } は同じeightbyteに整数があるのでINTEGER
+    assert_eq!(
+        classify_struct(8, &[(0, true), (4, false)]),
+        ArgClass::Regs(vec![Integer])
+    );
+    assert_eq!(classify_scalar(true), ArgClass::Regs(vec![Sse]));
+
+    // レジスタで渡すときはMAX_REG_EIGHTBYTES個までのeightbyteになる
+    for size in (4..=32).step_by(4) {
+        if let ArgClass::Regs(classes) = classify_struct(size, &ints(&[0])) {
+            assert!(classes.len() <= MAX_REG_EIGHTBYTES, "{}", size);
+        }
+    }
+
+    assert_eq!(eightbytes(4), vec![4]);
+    assert_eq!(eightbytes(12), vec![8, 4]);
+    assert_eq!(eightbytes(16), vec![8, 8]);
+}
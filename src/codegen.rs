@@ -1,584 +1,1334 @@
-use crate::parser::Exp;
-use crate::parser::Exp::*;
-use crate::parser::Op::*;
+// 中間表現 (ir) からx86-64のアセンブリを出力する。
+// 整数の仮想レジスタはregallocが割り当てた汎用レジスタに置き、残りはスタックの領域に置く。
+// レジスタにない値は命令ごとにrax, rdi, xmm0, xmm1へ読んで計算する
+use crate::abi::{align_to, eightbytes, ArgClass, RegClass, MAX_REG_EIGHTBYTES};
+use crate::diagnostic::{Diagnostic, Message};
+use crate::ir::{
+    Abi, BinOp, Block, BlockId, Callee, ConvOp, Data, Function, Inst, Module, Operand, Slot,
+    Terminator, Ty, VReg,
+};
+use crate::lower::{lower, without_location};
 use crate::parser::Program;
-use crate::parser::Stmt;
-use crate::parser::TypeAndExp;
-use crate::parser::TypeDec;
-use crate::parser::UOp::*;
-use std::collections::HashMap;
+use crate::peephole::{self, inst, reg, sym, Arg, Line, Mem, Size};
+use crate::regalloc::{allocate, Address};
+use std::io::{self, Write};
 
 static ARG_REG: [&str; 6] = ["rdi", "rsi", "rdx", "rcx", "r8", "r9"];
+static ARG_REG32: [&str; 6] = ["edi", "esi", "edx", "ecx", "r8d", "r9d"];
+// 戻り値の汎用レジスタ。xmmレジスタと同じく、レジスタで返す値のeightbyteの数だけある
+static RET_REG: [&str; MAX_REG_EIGHTBYTES] = ["rax", "rdx"];
+static RET_REG32: [&str; MAX_REG_EIGHTBYTES] = ["eax", "edx"];
+const ARG_SSE_REG_COUNT: usize = 8;
 
-const LOCAL_VAR_OFFSET: i32 = 8;
-const LOCAL_POINTER_OFFSET: i32 = 4;
+const VREG_SIZE: i32 = 8;
 const RSP_CONST: i32 = 16;
 
-fn push(val: String, state_holder: &mut StateHolder) {
-    println!("  push {}", val);
-    state_holder.push_depth();
+// 生成したアセンブリの書き出し先。io::Writeなら何でもよく、Vec<u8>に書けばメモリに溜まる。
+// 行は命令とオペランドに分けた値で溜めておき、関数を書き終えるごとに (peepholeなら覗き穴最適化をしてから) 書き出す。
+// 書き込みのエラーは最初のものだけ覚えておき、finishで返す
+pub struct Emitter<'a> {
+    out: &'a mut dyn Write,
+    error: Option<io::Error>,
+    lines: Vec<Line>,
+    peephole: bool,
 }
 
-fn pop(val: String, state_holder: &mut StateHolder) {
-    println!("  pop {}", val);
-    state_holder.pop_depth();
+impl<'a> Emitter<'a> {
+    pub fn new(out: &'a mut dyn Write) -> Emitter<'a> {
+        Emitter {
+            out,
+            error: None,
+            lines: vec![],
+            peephole: false,
+        }
+    }
+    fn push(&mut self, line: Line) {
+        self.lines.push(line);
+    }
+    fn label(&mut self, name: String) {
+        self.push(Line::Label(name));
+    }
+    fn directive(&mut self, text: String) {
+        self.push(Line::Directive(text));
+    }
+    fn flush_lines(&mut self) {
+        if self.peephole {
+            peephole::optimize(&mut self.lines);
+        }
+        for line in self.lines.drain(..) {
+            if self.error.is_none() {
+                if let Err(err) = writeln!(self.out, "{}", line) {
+                    self.error = Some(err);
+                }
+            }
+        }
+    }
+    fn finish(mut self) -> io::Result<()> {
+        self.flush_lines();
+        match self.error {
+            Some(err) => Err(err),
+            None => self.out.flush(),
+        }
+    }
 }
 
-pub fn start(p: Program) {
-    println!(".intel_syntax noprefix");
-    start_to_code_gen(p);
+// state_holderのEmitterへ命令を一つ書く。オペランドはpeephole::Argで渡す
+macro_rules! emit {
+    ($state_holder:expr, $op:expr $(, $arg:expr)* $(,)?) => {
+        $state_holder.emitter.push(inst($op, vec![$($arg),*]))
+    };
 }
 
-fn code_gen_option_exp(exp: Option<Exp>, state_holder: &mut StateHolder) {
-    match exp {
-        None => {}
-        Some(ref exp) => {
-            code_gen_exp(exp, state_holder);
+// 構文木をIRに下ろしてから、アセンブリをoutに書き、警告を返す。
+// エラーがあればそこで止め、それまでの警告とエラーを返す。書き込みの失敗もエラーにする
+pub fn start(p: Program, out: &mut dyn Write) -> Result<Vec<Diagnostic>, Vec<Diagnostic>> {
+    // ASTの位置を元のソースに戻す表がないので、診断は位置なしにする
+    let (module, mut diagnostics) = lower(p, &without_location)?;
+    match emit_module(&module, 0, true, out) {
+        Ok(()) => Ok(diagnostics),
+        Err(err) => {
+            diagnostics.push(Diagnostic::new(Message::WriteFailed(err.to_string())));
+            Err(diagnostics)
         }
     }
 }
 
-fn start_to_code_gen(p: Program) {
-    let mut state_holder = new_state_holder();
-    code_gen(p, &mut state_holder);
+// IRのモジュールをアセンブリにしてoutに書く。opt_levelが1以上なら覗き穴最適化をする。
+// sibling_callsなら、末尾の呼び出しをフレームを畳んでからのjmpにする
+pub fn emit_module(
+    module: &Module,
+    opt_level: u8,
+    sibling_calls: bool,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    let mut emitter = Emitter::new(out);
+    emitter.peephole = opt_level >= 1;
+    let mut state_holder = new_state_holder(emitter);
+    state_holder.sibling_calls = sibling_calls;
+    state_holder
+        .emitter
+        .directive(".intel_syntax noprefix".to_string());
+    for data in &module.data {
+        code_gen_data(data, &mut state_holder);
+    }
+    for function in &module.functions {
+        code_gen_func(function, &mut state_holder);
+    }
+    state_holder.emitter.finish()
+}
+
+fn code_gen_data(data: &Data, state_holder: &mut StateHolder) {
+    let emitter = &mut state_holder.emitter;
+    emitter.directive("  .data".to_string());
+    if data.global {
+        emitter.directive(format!(".global {}", data.label));
+    }
+    emitter.directive(format!("  .align {}", data.align));
+    emitter.label(data.label.clone());
+    emitter.directive(match (data.init, data.size) {
+        (Some(i), 4) => format!("  .long {}", i as i32),
+        (Some(i), _) => format!("  .quad {}", i),
+        (None, size) => format!("  .zero {}", size),
+    });
+    emitter.directive("  .text".to_string());
 }
 
-fn unbox<T>(value: Box<T>) -> T {
-    *value
+// 64ビットのレジスタ名を32ビットの名前にする
+fn reg32(reg: &str) -> String {
+    match reg {
+        "rax" => "eax".to_string(),
+        "rbx" => "ebx".to_string(),
+        "rdi" => "edi".to_string(),
+        "rsi" => "esi".to_string(),
+        "rdx" => "edx".to_string(),
+        "rcx" => "ecx".to_string(),
+        reg => format!("{}d", reg),
+    }
 }
 
-fn infix_pointer_exp_converter(exp: Exp, state_holder: &mut StateHolder) -> Exp {
-    fn pointer_type_size(t: TypeDec) -> i32 {
-        match t {
-            TypeDec::Pointer(t) => 8,
-            TypeDec::Int => 4,
-        }
+// 型tyの値を入れるレジスタ。浮動小数点数はxmmの名前をそのまま使う
+fn sized_reg(name: &str, ty: Ty) -> Arg {
+    match ty {
+        Ty::I32 => reg(&reg32(name)),
+        _ => reg(name),
     }
-    match exp.clone() {
-        InfixExp { left, op, right } => match op {
-            Plus | Minus => match (*left.clone(), *right.clone()) {
-                (Exp::Var(v), right) => {
-                    let left_type = state_holder.get_local_var_type(v);
-                    match &left_type {
-                        TypeDec::Pointer(p) => InfixExp {
-                            left,
-                            op,
-                            right: Box::new(InfixExp {
-                                left: Box::new(right),
-                                op: Asterisk,
-                                right: Box::new(Exp::Int(pointer_type_size(left_type))),
-                            }),
-                        },
-                        _ => exp,
-                    }
-                }
-                (left, Exp::Var(v)) => {
-                    let right_type = state_holder.get_local_var_type(v);
-                    match &right_type {
-                        TypeDec::Pointer(p) => InfixExp {
-                            left: Box::new(InfixExp {
-                                left: Box::new(left),
-                                op: Asterisk,
-                                right: Box::new(Exp::Int(pointer_type_size(right_type))),
-                            }),
-                            op: op,
-                            right: right,
-                        },
-                        _ => exp,
-                    }
-                }
-                _ => exp,
-            },
-            _ => exp,
-        },
-        _ => exp,
+}
+
+// SSE命令の接尾辞。f32は単精度 (ss)、f64は倍精度 (sd)
+fn sse_suffix(ty: Ty) -> &'static str {
+    match ty {
+        Ty::F32 => "ss",
+        _ => "sd",
     }
 }
 
-pub fn code_gen(p: Program, state_holder: &mut StateHolder) {
-    for stmt in p {
-        match stmt {
-            Stmt::Exp(ref exp) => {
-                code_gen_exp(exp, state_holder);
-            }
-            Stmt::Return(ref exp) => {
-                code_gen_exp(exp, state_holder);
-                println!("  jmp .L.return.{}", state_holder.get_fun_name());
-            }
-            Stmt::Block(stmts) => code_gen(stmts, state_holder),
-            Stmt::If { cond, stmt1, stmt2 } => {
-                code_gen_if(*cond, *stmt1, *stmt2, state_holder);
-            }
-            Stmt::While { cond, stmt } => {
-                code_gen_while(*cond, *stmt, state_holder);
+// 型tyの値をaddrが指す場所から読む命令の、オペランドを除いた部分
+fn mov_from_memory(ty: Ty) -> &'static str {
+    match ty {
+        Ty::I32 => "mov",
+        Ty::I64 => "mov",
+        Ty::F32 => "movss",
+        Ty::F64 => "movsd",
+    }
+}
+
+// memが指す型tyの値。大きさをdword ptrなどで書く
+fn sized_mem(mem: Mem, ty: Ty) -> Arg {
+    let size = match ty {
+        Ty::I32 | Ty::F32 => Size::Dword,
+        Ty::I64 | Ty::F64 => Size::Qword,
+    };
+    Arg::Mem(mem.sized(size))
+}
+
+// rbpからのオフセットのアドレス
+fn frame(offset: i32) -> Mem {
+    Mem::new("rbp", offset as i64)
+}
+
+// operandの値を型tyとしてレジスタtoに読む。toは汎用レジスタなら64ビットの名前で渡す
+fn load_operand(operand: &Operand, ty: Ty, to: &str, state_holder: &mut StateHolder) {
+    match operand {
+        Operand::Reg(vreg) => {
+            if let Some(address) = state_holder.address(*vreg) {
+                emit!(state_holder, "lea", reg(to), Arg::Mem(address));
+                return;
             }
-            Stmt::For {
-                exp1,
-                exp2,
-                exp3,
-                stmt,
-            } => {
-                code_gen_for(*exp1, *exp2, *exp3, *stmt, state_holder);
+            if let Some(from) = state_holder.vreg_reg(*vreg) {
+                if from != to {
+                    emit!(state_holder, "mov", sized_reg(to, ty), sized_reg(from, ty));
+                }
+                return;
             }
-            Stmt::Func {
-                t,
-                fun,
-                params,
-                body,
-            } => {
-                code_gen_func(*fun, params, body, state_holder);
+            let offset = state_holder.vreg_offset(*vreg);
+            emit!(
+                state_holder,
+                mov_from_memory(ty),
+                sized_reg(to, ty),
+                sized_mem(frame(offset), ty)
+            );
+        }
+        // 浮動小数点数の即値はビット列を汎用レジスタ経由で移す
+        Operand::Imm(i) => match ty {
+            Ty::I32 => emit!(
+                state_holder,
+                "mov",
+                sized_reg(to, ty),
+                Arg::Imm(*i as i32 as i64)
+            ),
+            Ty::I64 if *i < i32::MIN as i64 || *i > i32::MAX as i64 => {
+                emit!(state_holder, "movabs", reg(to), Arg::Imm(*i))
             }
-            Stmt::VarDec { t, var } => {
-                let var = match unbox(var) {
-                    Exp::Var(var) => {
-                        state_holder.set_local_var_env(t.clone(), var.clone());
-                        var
-                    }
-                    _ => panic!("error"),
-                };
-                state_holder.set_local_var_env(t, var);
+            Ty::I64 => emit!(state_holder, "mov", reg(to), Arg::Imm(*i)),
+            Ty::F32 => {
+                emit!(state_holder, "mov", reg("r11d"), Arg::Imm(*i as i32 as i64));
+                emit!(state_holder, "movd", reg(to), reg("r11d"));
             }
-            _ => {
-                panic!("未対応");
+            Ty::F64 => {
+                emit!(state_holder, "movabs", reg("r11"), Arg::Imm(*i));
+                emit!(state_holder, "movq", reg(to), reg("r11"));
             }
+        },
+    }
+}
+
+// レジスタfromの値を仮想レジスタdstの場所に書く
+fn store_vreg(dst: VReg, from: &str, state_holder: &mut StateHolder) {
+    let ty = state_holder.vreg_type(dst);
+    if let Some(to) = state_holder.vreg_reg(dst) {
+        if to != from {
+            emit!(state_holder, "mov", sized_reg(to, ty), sized_reg(from, ty));
         }
+        return;
     }
+    let offset = state_holder.vreg_offset(dst);
+    emit!(
+        state_holder,
+        mov_from_memory(ty),
+        sized_mem(frame(offset), ty),
+        sized_reg(from, ty)
+    );
 }
 
-fn code_gen_func_call(f: &Exp, args: &Vec<Exp>, state_holder: &mut StateHolder) {
-    let name = match f {
-        Exp::Var(v) => v,
-        _ => panic!("error in code_gen_func_call, func nameがVarでない"),
-    };
-    let len = (&args).len();
-    for arg in args {
-        code_gen_exp(arg, state_holder);
-        push("rax".to_string(), state_holder);
+// 整数のoperandを、読み出す命令のオペランドにそのまま書ける形にする。
+// レジスタ、スタックの領域、32ビットに収まる即値のどれかで、収まらない即値ならNone
+fn source(operand: &Operand, ty: Ty, state_holder: &StateHolder) -> Option<Arg> {
+    match operand {
+        Operand::Reg(vreg) if state_holder.address(*vreg).is_some() => None,
+        Operand::Reg(vreg) => Some(match state_holder.vreg_reg(*vreg) {
+            Some(reg) => sized_reg(reg, ty),
+            None => sized_mem(frame(state_holder.vreg_offset(*vreg)), ty),
+        }),
+        Operand::Imm(i) if ty == Ty::I32 => Some(Arg::Imm(*i as i32 as i64)),
+        Operand::Imm(i) if *i >= i32::MIN as i64 && *i <= i32::MAX as i64 => Some(Arg::Imm(*i)),
+        Operand::Imm(_) => None,
     }
-    for i in (0..len).rev() {
-        pop(ARG_REG[i].to_string(), state_holder);
+}
+
+// 整数の仮想レジスタdstに結果を作るレジスタ。割り当てがあればそのレジスタ、なければrax
+fn dst_reg(dst: VReg, state_holder: &StateHolder) -> &'static str {
+    state_holder.vreg_reg(dst).unwrap_or("rax")
+}
+
+// addrが指すメモリのオペランド。アドレスの分かっている仮想レジスタならそのまま書き、
+// そうでなければレジスタ (なければregに読む) を通す
+fn memory(addr: &Operand, reg: &'static str, state_holder: &mut StateHolder) -> Mem {
+    if let Operand::Reg(vreg) = addr {
+        if let Some(address) = state_holder.address(*vreg) {
+            return address;
+        }
     }
-    println!("  mov rax, 0");
+    Mem::new(operand_reg(addr, Ty::I64, reg, state_holder), 0)
+}
 
-    if state_holder.depth % 2 == 0 {
-        println!("  call {}", name)
+// operandがレジスタにあればその名前、なければregに読んでregを返す
+fn operand_reg(
+    operand: &Operand,
+    ty: Ty,
+    reg: &'static str,
+    state_holder: &mut StateHolder,
+) -> &'static str {
+    match operand {
+        Operand::Reg(vreg) => match state_holder.vreg_reg(*vreg) {
+            Some(from) => from,
+            None => {
+                load_operand(operand, ty, reg, state_holder);
+                reg
+            }
+        },
+        Operand::Imm(_) => {
+            load_operand(operand, ty, reg, state_holder);
+            reg
+        }
+    }
+}
+
+// 仮想レジスタの値を入れる作業用のレジスタ
+fn work_reg(ty: Ty) -> &'static str {
+    if ty.is_floating() {
+        "xmm0"
     } else {
-        println!("  sub rsp, 8");
-        println!("  call {}", name);
-        println!("  add rsp, 8");
+        "rax"
     }
 }
 
-// Round up `n` to the nearest multiple of `align`. For instance,
-// align_to(5, 8) returns 8 and align_to(11, 8) returns 16.
-fn align_to(n: i32, align: i32) -> i32 {
-    return (n + align - 1) / align * align;
+// eightbyteを受け渡すレジスタ。汎用レジスタは64ビットと32ビットの名前、xmmレジスタは番号で表す
+#[derive(Clone, Copy)]
+enum Reg {
+    Gp(&'static str, &'static str),
+    Sse(usize),
 }
 
-fn get_stack_size_from_stmts(body: &Vec<Stmt>) -> i32 {
-    let mut c = 0;
-    for s in body {
-        c += get_locals_stmt(s);
-    }
-    c
+fn xmm(n: usize) -> Arg {
+    reg(&format!("xmm{}", n))
 }
 
-fn get_locals_stmt(stmt: &Stmt) -> i32 {
-    match stmt {
-        Stmt::Block(ref stmts) => get_stack_size_from_stmts(stmts),
-        Stmt::If { cond, stmt1, stmt2 } => {
-            let c2 = match **stmt2 {
-                Some(ref stmt) => get_locals_stmt(stmt),
-                None => 0,
-            };
-            get_locals_stmt(&stmt1) + c2
-        }
-        Stmt::While { cond, stmt } => get_locals_stmt(&stmt),
-        Stmt::For {
-            exp1,
-            exp2,
-            exp3,
-            stmt,
-        } => get_locals_stmt(&stmt),
-        Stmt::Func {
-            t,
-            fun,
-            params,
-            body,
-        } => params.len() as i32 + get_stack_size_from_stmts(&body),
-        Stmt::VarDec { t, var } => match t {
-            TypeDec::Int => LOCAL_VAR_OFFSET,
-            TypeDec::Pointer(t) => LOCAL_POINTER_OFFSET,
-        },
-        _ => 0,
+// eightbyteの分類に従ってレジスタを割り当てる。
+// 足りなければ値全体をメモリで渡すので、一つも使わずにNoneを返す。
+fn assign_regs(
+    classes: &[RegClass],
+    gp: &mut usize,
+    sse: &mut usize,
+    gp_regs: (&[&'static str], &[&'static str]),
+    sse_count: usize,
+) -> Option<Vec<Reg>> {
+    let gp_needed = classes.iter().filter(|c| **c == RegClass::Integer).count();
+    let sse_needed = classes.len() - gp_needed;
+    if *gp + gp_needed > gp_regs.0.len() || *sse + sse_needed > sse_count {
+        return None;
     }
+    let regs = classes
+        .iter()
+        .map(|c| match c {
+            RegClass::Integer => {
+                *gp += 1;
+                Reg::Gp(gp_regs.0[*gp - 1], gp_regs.1[*gp - 1])
+            }
+            RegClass::Sse => {
+                *sse += 1;
+                Reg::Sse(*sse - 1)
+            }
+        })
+        .collect();
+    Some(regs)
 }
 
-fn get_stack_size_from_params(params: &Vec<TypeAndExp>) -> i32 {
-    let mut sum = 0;
-    for (t, _) in params {
-        match t {
-            TypeDec::Pointer(_) => {
-                sum += LOCAL_POINTER_OFFSET;
+// 戻り値はINTEGERならrax, rdx、SSEならxmm0, xmm1の順に使う。
+// classesはMAX_REG_EIGHTBYTES個までなので、どちらのレジスタも足りなくなることはない
+fn ret_regs(classes: &[RegClass]) -> Vec<Reg> {
+    let (mut gp, mut sse) = (0, 0);
+    classes
+        .iter()
+        .map(|c| match c {
+            RegClass::Integer => {
+                gp += 1;
+                Reg::Gp(RET_REG[gp - 1], RET_REG32[gp - 1])
             }
-            TypeDec::Int => {
-                sum += LOCAL_VAR_OFFSET;
+            RegClass::Sse => {
+                sse += 1;
+                Reg::Sse(sse - 1)
             }
-        }
-    }
-    sum
+        })
+        .collect()
 }
 
-fn get_stack_size(params: &Vec<TypeAndExp>, body: &Vec<Stmt>) -> i32 {
-    let stack_size = get_stack_size_from_params(params) as i32 + get_stack_size_from_stmts(&body);
-    align_to(stack_size, RSP_CONST)
+// ABIでの分類。スカラーは整数か浮動小数点数かで決まる
+fn arg_class(abi: &Abi) -> ArgClass {
+    match abi {
+        Abi::Scalar(ty) if ty.is_floating() => ArgClass::Regs(vec![RegClass::Sse]),
+        Abi::Scalar(_) => ArgClass::Regs(vec![RegClass::Integer]),
+        Abi::Struct { class, .. } => class.clone(),
+    }
 }
 
-fn code_gen_func(f: Exp, params: Vec<TypeAndExp>, body: Vec<Stmt>, state_holder: &mut StateHolder) {
-    state_holder.reset_local_var_env();
-    let name = match f {
-        Exp::Var(v) => v,
-        _ => panic!(format!("error, func nameがVarでない: {:?}", f)),
-    };
-    state_holder.set_fun_name(name.clone());
-    state_holder.reset_offset();
-    let stack_size = get_stack_size(&params, &body);
-    println!(".global {}", name);
-    println!("{}:", name);
+fn abi_size(abi: &Abi) -> i32 {
+    match abi {
+        Abi::Scalar(Ty::I32 | Ty::F32) => 4,
+        Abi::Scalar(_) => 8,
+        Abi::Struct { size, .. } => *size,
+    }
+}
 
-    // Prologue
-    println!("  push rbp");
-    println!("  mov rbp, rsp");
-    println!("  sub rsp, {}", stack_size);
+enum ArgLocation {
+    Reg(Vec<Reg>),
+    // スタックの引数領域の先頭からのオフセット
+    Stack(i32),
+}
 
-    let mut i = 0;
-    for v in params {
-        let v = match v {
-            (t, Exp::Var(v)) => {
-                state_holder.set_local_var_env(t, v.clone());
-                v
-            }
-            _ => panic!(format!("error in code_gen_func paramsがVarでない")),
+// 引数をレジスタとスタックのどちらで渡すか決め、スタックで渡す分の大きさと使ったxmmレジスタの数を返す。
+// 戻り値がMEMORYのときはrdiが隠し引数になる。
+fn arg_locations(args: &[&Abi], ret: &Abi) -> (Vec<ArgLocation>, i32, usize) {
+    let mut gp = if returns_in_memory(ret) { 1 } else { 0 };
+    let mut sse = 0;
+    let mut stack_size = 0;
+    let mut locations = vec![];
+    for abi in args {
+        let regs = match arg_class(abi) {
+            ArgClass::Regs(classes) => assign_regs(
+                &classes,
+                &mut gp,
+                &mut sse,
+                (&ARG_REG, &ARG_REG32),
+                ARG_SSE_REG_COUNT,
+            ),
+            ArgClass::Memory => None,
         };
-        println!(
-            "  mov [{} + rbp], {}",
-            state_holder.get_local_var_offset(&v),
-            ARG_REG[i]
-        );
-        i += 1;
+        match regs {
+            Some(regs) => locations.push(ArgLocation::Reg(regs)),
+            None => {
+                locations.push(ArgLocation::Stack(stack_size));
+                stack_size += align_to(abi_size(abi), 8);
+            }
+        }
     }
-    code_gen(body, state_holder);
-    state_holder.assert_depth();
+    (locations, stack_size, sse)
+}
 
-    println!(".L.return.{}:", name);
-    println!("  mov rsp, rbp");
-    println!("  pop rbp");
-    println!("  ret");
+fn returns_in_memory(ret: &Abi) -> bool {
+    matches!(
+        ret,
+        Abi::Struct {
+            class: ArgClass::Memory,
+            ..
+        }
+    )
 }
 
-fn code_gen_for(
-    exp1: Option<Exp>,
-    exp2: Option<Exp>,
-    exp3: Option<Exp>,
-    stmt: Stmt,
-    state_holder: &mut StateHolder,
-) {
-    let (begin_label, jbegin_label) = state_holder.get_label("beginFor".to_string());
-    let (end_label, jend_label) = state_holder.get_label("endFor".to_string());
-    code_gen_option_exp(exp1, state_holder);
-    println!("{}", begin_label);
-    match exp2 {
-        None => {}
-        _ => {
-            code_gen_option_exp(exp2, state_holder);
-            println!("  cmp rax, 0");
-            println!("  je {}", jend_label);
-        }
-    }
-    code_gen(vec![stmt], state_holder);
-    code_gen_option_exp(exp3, state_holder);
-    println!("  jmp {}", jbegin_label);
-    println!("{}", end_label);
-}
-
-fn code_gen_while(cond: Exp, stmt: Stmt, state_holder: &mut StateHolder) {
-    let (begin_label, jbegin_label) = state_holder.get_label("beginWhile".to_string());
-    let (end_label, jend_label) = state_holder.get_label("endWhile".to_string());
-    println!("{}", begin_label);
-    code_gen_exp(&cond, state_holder);
-    println!("  cmp rax, 0");
-    println!("  je {}", jend_label);
-    code_gen(vec![stmt], state_holder);
-    println!("  jmp {}", jbegin_label);
-    println!("{}", end_label);
-}
-
-fn code_gen_if(cond: Exp, stmt1: Stmt, stmt2: Option<Stmt>, state_holder: &mut StateHolder) {
-    code_gen_exp(&cond, state_holder);
-    match stmt2 {
-        Some(stmt2) => {
-            let (else_label, jelse_label) = state_holder.get_label("if".to_string());
-            let (if_label, jif_label) = state_holder.get_label("else".to_string());
-            println!("  cmp rax, 0");
-            println!("  je {}", jelse_label);
-            code_gen(vec![stmt1], state_holder);
-            println!("  jmp {}", jif_label);
-            println!("{}", else_label);
-            code_gen(vec![stmt2], state_holder);
-            println!("{}", if_label);
-        }
-        None => {
-            let (label, jlabel) = state_holder.get_label("if".to_string());
-            println!("  cmp rax, 0");
-            println!("  je {}", jlabel);
-            code_gen(vec![stmt1], state_holder);
-            println!("{}", label);
-        }
-    }
-}
-
-fn gen_addr(exp: &Exp, state_holder: &mut StateHolder) {
-    let v = match exp {
-        Exp::Var(v) => {
-            if !state_holder.check_local_var_from_env(v.clone()) {
-                panic!(format!("未定義変数 {}", v))
-            }
-            println!(
-                "  lea rax, [{} + rbp]",
-                state_holder.get_local_var_offset(v)
-            );
+// srcが指す値のeightbyteを順にレジスタへ読む。4バイトの部分は32ビットで読む。
+fn load_eightbytes(src: &str, regs: &[Reg], size: i32, state_holder: &mut StateHolder) {
+    for (i, (to, part)) in regs.iter().zip(eightbytes(size)).enumerate() {
+        let from = Mem::new(src, i as i64 * 8);
+        match (to, part) {
+            (Reg::Gp(to, _), 8) => emit!(state_holder, "mov", reg(to), Arg::Mem(from)),
+            (Reg::Gp(_, to), _) => emit!(state_holder, "mov", reg(to), sized_mem(from, Ty::I32)),
+            (Reg::Sse(n), 8) => emit!(state_holder, "movsd", xmm(*n), sized_mem(from, Ty::F64)),
+            (Reg::Sse(n), _) => emit!(state_holder, "movss", xmm(*n), sized_mem(from, Ty::F32)),
         }
-        Exp::UnaryExp { op: Deref, exp } => {
-            code_gen_exp(exp, state_holder);
+    }
+}
+
+// レジスタに入った値のeightbyteをdstが指す場所へ書く
+fn store_eightbytes(dst: &str, regs: &[Reg], size: i32, state_holder: &mut StateHolder) {
+    for (i, (from, part)) in regs.iter().zip(eightbytes(size)).enumerate() {
+        let to = Mem::new(dst, i as i64 * 8);
+        match (from, part) {
+            (Reg::Gp(from, _), 8) => emit!(state_holder, "mov", Arg::Mem(to), reg(from)),
+            (Reg::Gp(_, from), _) => emit!(state_holder, "mov", sized_mem(to, Ty::I32), reg(from)),
+            (Reg::Sse(n), 8) => emit!(state_holder, "movsd", sized_mem(to, Ty::F64), xmm(*n)),
+            (Reg::Sse(n), _) => emit!(state_holder, "movss", sized_mem(to, Ty::F32), xmm(*n)),
         }
-        _ => panic!("error"),
-    };
+    }
 }
 
-fn code_gen_assign(left: &Exp, right: &Exp, state_holder: &mut StateHolder) {
-    gen_addr(left, state_holder);
+// srcが指す領域をdstが指す場所へsizeバイトコピーする (r11を使う)
+fn copy_memory(src: &str, dst: &str, size: i32, state_holder: &mut StateHolder) {
+    let mut i = 0;
+    while i < size {
+        let (from, to) = (Mem::new(src, i as i64), Mem::new(dst, i as i64));
+        if size - i >= 8 {
+            emit!(state_holder, "mov", reg("r11"), Arg::Mem(from));
+            emit!(state_holder, "mov", Arg::Mem(to), reg("r11"));
+            i += 8;
+        } else {
+            emit!(state_holder, "mov", reg("r11d"), sized_mem(from, Ty::I32));
+            emit!(state_holder, "mov", sized_mem(to, Ty::I32), reg("r11d"));
+            i += 4;
+        }
+    }
+}
 
-    push("rax".to_string(), state_holder);
+fn code_gen_func(f: &Function, state_holder: &mut StateHolder) {
+    let allocation = allocate(f);
+    // 保存するcallee-savedレジスタはrbpの直下に積む。その下に、スロット、隠し引数のアドレス、
+    // レジスタで受け取った構造体、レジスタに割り当てられなかった仮想レジスタの順に並べる
+    let saved_size = allocation.callee_saved.len() as i32 * 8;
+    let mut size = saved_size;
+    let mut allocate = |n: i32| {
+        size += align_to(n, 8);
+        -size
+    };
+    let slot_offsets = f.slots.iter().map(|n| allocate(*n)).collect();
+    let ret_buffer_offset = if returns_in_memory(&f.ret) {
+        allocate(8)
+    } else {
+        0
+    };
+    let abis: Vec<&Abi> = f.params.iter().map(|(_, abi)| abi).collect();
+    let (locations, _, _) = arg_locations(&abis, &f.ret);
+    let struct_offsets: Vec<i32> = f
+        .params
+        .iter()
+        .map(|(_, abi)| match abi {
+            Abi::Struct { size, .. } => allocate(*size),
+            Abi::Scalar(_) => 0,
+        })
+        .collect();
+    let used = used_vregs(f);
+    let vreg_offsets = (0..f.vregs.len())
+        .map(|i| {
+            if used[i] && allocation.regs[i].is_none() && allocation.addresses[i].is_none() {
+                allocate(VREG_SIZE)
+            } else {
+                0
+            }
+        })
+        .collect();
+    state_holder.begin_function(f, slot_offsets, vreg_offsets, ret_buffer_offset);
+    state_holder.vreg_regs = allocation.regs;
+    state_holder.vreg_addresses = allocation.addresses;
+    state_holder.callee_saved = allocation.callee_saved.clone();
+    state_holder.tail_calls = state_holder.sibling_calls && allows_sibling_calls(f);
+
+    // static関数はファイル内だけのシンボルにする
+    if f.global {
+        state_holder
+            .emitter
+            .directive(format!(".global {}", f.name));
+    }
+    state_holder.emitter.label(f.name.clone());
 
-    code_gen_exp(right, state_holder);
+    // Prologue
+    emit!(state_holder, "push", reg("rbp"));
+    emit!(state_holder, "mov", reg("rbp"), reg("rsp"));
+    for saved in &allocation.callee_saved {
+        emit!(state_holder, "push", reg(saved));
+    }
+    // pushした分を除いても、rspが16の倍数になるように取る
+    let frame_size = align_to(size, RSP_CONST) - saved_size;
+    if frame_size > 0 {
+        emit!(state_holder, "sub", reg("rsp"), Arg::Imm(frame_size as i64));
+    }
+    if returns_in_memory(&f.ret) {
+        emit!(
+            state_holder,
+            "mov",
+            Arg::Mem(frame(ret_buffer_offset)),
+            reg("rdi")
+        );
+    }
+    // レジスタに載らなかった引数は呼び出し元のスタック (リターンアドレスと古いrbpの上) にある。
+    // 構造体の引数は置き場所のアドレスを仮想レジスタの値にする
+    let mut stack_offset = 16;
+    for (((param, abi), location), struct_offset) in
+        f.params.iter().zip(locations).zip(struct_offsets)
+    {
+        match (abi, location) {
+            (Abi::Struct { size, .. }, ArgLocation::Reg(regs)) => {
+                emit!(
+                    state_holder,
+                    "lea",
+                    reg("rax"),
+                    Arg::Mem(frame(struct_offset))
+                );
+                store_eightbytes("rax", &regs, *size, state_holder);
+                store_vreg(*param, "rax", state_holder);
+            }
+            (Abi::Struct { size, .. }, ArgLocation::Stack(_)) => {
+                emit!(
+                    state_holder,
+                    "lea",
+                    reg("rax"),
+                    Arg::Mem(frame(stack_offset))
+                );
+                store_vreg(*param, "rax", state_holder);
+                stack_offset += align_to(*size, 8);
+            }
+            (Abi::Scalar(_), ArgLocation::Reg(regs)) => {
+                let from = match regs[0] {
+                    Reg::Gp(from, _) => from.to_string(),
+                    Reg::Sse(n) => format!("xmm{}", n),
+                };
+                store_vreg(*param, &from, state_holder);
+            }
+            (Abi::Scalar(ty), ArgLocation::Stack(_)) => {
+                let reg = work_reg(*ty);
+                emit!(
+                    state_holder,
+                    mov_from_memory(*ty),
+                    sized_reg(reg, *ty),
+                    sized_mem(frame(stack_offset), *ty)
+                );
+                store_vreg(*param, reg, state_holder);
+                stack_offset += 8;
+            }
+        }
+    }
 
-    pop("rdi".to_string(), state_holder);
-    println!("  mov [rdi], rax");
+    // どこからも飛ばれないブロックにはラベルを付けない
+    let mut targeted = vec![false; f.blocks.len()];
+    for block in &f.blocks {
+        for to in block.term.successors() {
+            targeted[to.0] = true;
+        }
+    }
+    for (i, block) in f.blocks.iter().enumerate() {
+        code_gen_block(BlockId(i), block, targeted[i], state_holder);
+    }
+
+    state_holder.emitter.label(format!(".L.return.{}", f.name));
+    code_gen_epilogue(state_holder);
+    emit!(state_holder, "ret");
+    state_holder.emitter.flush_lines();
 }
-pub fn code_gen_exp(exp: &Exp, state_holder: &mut StateHolder) {
-    let exp = &infix_pointer_exp_converter(exp.clone(), state_holder);
-    match exp {
-        FuncCall { fun, args } => {
-            code_gen_func_call(fun, args, state_holder);
+
+// 保存したレジスタを戻し、フレームを畳む。rspは関数に入ったときの値に戻る
+fn code_gen_epilogue(state_holder: &mut StateHolder) {
+    let callee_saved = state_holder.callee_saved.clone();
+    if callee_saved.is_empty() {
+        emit!(state_holder, "mov", reg("rsp"), reg("rbp"));
+    } else {
+        let saved_size = callee_saved.len() as i32 * 8;
+        emit!(
+            state_holder,
+            "lea",
+            reg("rsp"),
+            Arg::Mem(frame(-saved_size))
+        );
+        for saved in callee_saved.iter().rev() {
+            emit!(state_holder, "pop", reg(saved));
         }
-        InfixExp { left, op, right } => {
-            match op {
-                Assign => {
-                    code_gen_assign(&left, &right, state_holder);
-                    return;
-                }
-                _ => {}
+    }
+    emit!(state_holder, "pop", reg("rbp"));
+}
+
+// 呼び出し元のフレームを畳んでも呼び出し先から見えるものが変わらない関数か。
+// スロットのアドレスがload/store以外に使われていると、呼び出し先がフレームを指すかもしれない。
+// 構造体の仮引数も、フレームに置いたものをアドレスで持つので同じように扱う
+fn allows_sibling_calls(f: &Function) -> bool {
+    if f.params
+        .iter()
+        .any(|(_, abi)| matches!(abi, Abi::Struct { .. }))
+    {
+        return false;
+    }
+    let addrs: Vec<VReg> = f
+        .blocks
+        .iter()
+        .flat_map(|block| block.insts.iter())
+        .filter_map(|inst| match inst {
+            Inst::SlotAddr { dst, .. } => Some(*dst),
+            _ => None,
+        })
+        .collect();
+    let escapes = |operand: &Operand| matches!(operand, Operand::Reg(reg) if addrs.contains(reg));
+    f.blocks.iter().all(|block| {
+        block.insts.iter().all(|inst| match inst {
+            Inst::Load { .. } | Inst::MemCopy { .. } => true,
+            Inst::Store { value, .. } => !escapes(value),
+            inst => !inst.operands().into_iter().any(escapes),
+        }) && !block.term.operands().into_iter().any(escapes)
+    })
+}
+
+// ブロックの最後の呼び出しの値をそのまま返すなら、その呼び出し。
+// 引数がすべてレジスタで渡せて、戻り値の渡し方が同じときだけ末尾呼び出しにできる
+fn sibling_call<'b>(block: &'b Block, state_holder: &StateHolder) -> Option<&'b Inst> {
+    let call = block.insts.last()?;
+    match (call, &block.term) {
+        (
+            Inst::Call {
+                dst,
+                args,
+                ret: ret @ Abi::Scalar(_),
+                ..
+            },
+            Terminator::Ret(Some(Operand::Reg(value))),
+        ) if state_holder.tail_calls && dst == value && *ret == state_holder.ret => {
+            let abis: Vec<&Abi> = args.iter().map(|(_, abi)| abi).collect();
+            let (_, stack_size, _) = arg_locations(&abis, ret);
+            if stack_size == 0 {
+                Some(call)
+            } else {
+                None
             }
-            // ちょっと無駄が多いコードになったが、
-            // こうした方が左辺→右辺という計算順序が遵守されるから
-            // いいかな、という判断。
-            code_gen_exp(&left, state_holder);
-            push("rax".to_string(), state_holder);
-            code_gen_exp(&right, state_holder);
-            push("rax".to_string(), state_holder);
-            pop("rdi".to_string(), state_holder);
-            pop("rax".to_string(), state_holder);
-            match op {
-                Plus => {
-                    println!("  add rax, rdi");
-                }
-                Minus => {
-                    println!("  sub rax, rdi");
-                }
-                Asterisk => {
-                    println!("  imul rax, rdi");
-                }
-                Slash => {
-                    println!("  cqo");
-                    println!("  idiv rdi");
-                }
-                Eq => {
-                    println!("  cmp rax, rdi");
-                    println!("  sete al");
-                    println!("  movzb rax, al");
-                }
-                NotEq => {
-                    println!("  cmp rax, rdi");
-                    println!("  setne al");
-                    println!("  movzb rax, al");
-                }
-                Ls => {
-                    println!("  cmp rax, rdi");
-                    println!("  setl al");
-                    println!("  movzb rax, al");
-                }
-                LsEq => {
-                    println!("  cmp rax, rdi");
-                    println!("  setle al");
-                    println!("  movzb rax, al");
-                }
-                Gr => {
-                    println!("  cmp rdi, rax");
-                    println!("  setl al");
-                    println!("  movzb rax, al");
-                }
-                GrEq => {
-                    println!("  cmp rdi, rax");
-                    println!("  setle al");
-                    println!("  movzb rax, al");
+        }
+        _ => None,
+    }
+}
+
+// 関数に現れる仮想レジスタ
+fn used_vregs(f: &Function) -> Vec<bool> {
+    let mut used = vec![false; f.vregs.len()];
+    for (param, _) in &f.params {
+        used[param.0] = true;
+    }
+    for block in &f.blocks {
+        for inst in &block.insts {
+            if let Some(dst) = inst.dst() {
+                used[dst.0] = true;
+            }
+            for operand in inst.operands() {
+                if let Operand::Reg(reg) = operand {
+                    used[reg.0] = true;
                 }
+            }
+        }
+        for operand in block.term.operands() {
+            if let Operand::Reg(reg) = operand {
+                used[reg.0] = true;
+            }
+        }
+    }
+    used
+}
+
+fn code_gen_block(id: BlockId, block: &Block, labeled: bool, state_holder: &mut StateHolder) {
+    if labeled {
+        let label = state_holder.block_label(id);
+        state_holder.emitter.label(label);
+    }
+    if let Some(Inst::Call { callee, args, .. }) = sibling_call(block, state_holder) {
+        for inst in &block.insts[..block.insts.len() - 1] {
+            code_gen_inst(inst, state_holder);
+        }
+        code_gen_sibling_call(callee, args, state_holder);
+        return;
+    }
+    for inst in &block.insts {
+        code_gen_inst(inst, state_holder);
+    }
+    match &block.term {
+        Terminator::Jump(to) => {
+            emit!(state_holder, "jmp", sym(&state_holder.block_label(*to)));
+        }
+        Terminator::Branch { cond, then, els } => {
+            let ty = state_holder.operand_type(cond, Ty::I32);
+            let cond = match (cond, source(cond, ty, state_holder)) {
+                (Operand::Reg(_), Some(cond)) => cond,
                 _ => {
-                    panic!("error");
+                    load_operand(cond, ty, "rax", state_holder);
+                    sized_reg("rax", ty)
                 }
+            };
+            emit!(state_holder, "cmp", cond, Arg::Imm(0));
+            emit!(state_holder, "je", sym(&state_holder.block_label(*els)));
+            emit!(state_holder, "jmp", sym(&state_holder.block_label(*then)));
+        }
+        Terminator::Ret(value) => {
+            if let Some(value) = value {
+                code_gen_return(value, state_holder);
             }
+            emit!(
+                state_holder,
+                "jmp",
+                sym(&format!(".L.return.{}", state_holder.fun_name))
+            );
         }
-        Int(i) => {
-            println!("  mov rax, {}", i);
+    }
+}
+
+// 戻り値をABIに従って置く。構造体はレジスタに入れるか、隠し引数の領域にコピーする
+fn code_gen_return(value: &Operand, state_holder: &mut StateHolder) {
+    match state_holder.ret.clone() {
+        Abi::Scalar(ty) => load_operand(value, ty, work_reg(ty), state_holder),
+        Abi::Struct {
+            size,
+            class: ArgClass::Regs(classes),
+        } => {
+            load_operand(value, Ty::I64, "r11", state_holder);
+            load_eightbytes("r11", &ret_regs(&classes), size, state_holder);
         }
-        Var(_) => {
-            gen_addr(exp, state_holder);
-            println!("  mov rax, [rax]");
+        Abi::Struct {
+            size,
+            class: ArgClass::Memory,
+        } => {
+            load_operand(value, Ty::I64, "rax", state_holder);
+            emit!(
+                state_holder,
+                "mov",
+                reg("rdi"),
+                Arg::Mem(frame(state_holder.ret_buffer_offset))
+            );
+            copy_memory("rax", "rdi", size, state_holder);
+            emit!(state_holder, "mov", reg("rax"), reg("rdi"));
         }
-        UnaryExp { op, exp } => match op {
-            Address => gen_addr(exp, state_holder),
-            Deref => {
-                code_gen_exp(exp, state_holder);
-                println!("  mov rax, [rax]");
-            }
-        },
     }
 }
 
-struct Varinfo {
-    name: String,
-    t: TypeDec,
+fn code_gen_inst(inst: &Inst, state_holder: &mut StateHolder) {
+    match inst {
+        Inst::Const { dst, value } => code_gen_copy(*dst, &Operand::Imm(*value), state_holder),
+        Inst::Copy { dst, src } => code_gen_copy(*dst, src, state_holder),
+        Inst::Binary {
+            dst,
+            op,
+            ty,
+            lhs,
+            rhs,
+        } if ty.is_floating() => code_gen_float_binary(*dst, *op, *ty, lhs, rhs, state_holder),
+        Inst::Binary {
+            dst,
+            op,
+            ty,
+            lhs,
+            rhs,
+        } => code_gen_int_binary(*dst, *op, *ty, lhs, rhs, state_holder),
+        Inst::Convert { dst, op, from, src } => {
+            code_gen_convert(*dst, *op, *from, src, state_holder)
+        }
+        // アドレスは使う場所で書く
+        Inst::SlotAddr { dst, .. } | Inst::SymbolAddr { dst, .. }
+            if state_holder.address(*dst).is_some() => {}
+        Inst::SlotAddr { dst, slot } => {
+            let offset = state_holder.slot_offset(*slot);
+            let to = dst_reg(*dst, state_holder);
+            emit!(state_holder, "lea", reg(to), Arg::Mem(frame(offset)));
+            store_vreg(*dst, to, state_holder);
+        }
+        Inst::SymbolAddr { dst, symbol } => {
+            let to = dst_reg(*dst, state_holder);
+            emit!(state_holder, "lea", reg(to), Arg::Mem(Mem::symbol(symbol)));
+            store_vreg(*dst, to, state_holder);
+        }
+        Inst::Load { dst, ty, addr, .. } => {
+            let addr = memory(addr, "rax", state_holder);
+            let to = if ty.is_floating() {
+                "xmm0"
+            } else {
+                dst_reg(*dst, state_holder)
+            };
+            emit!(
+                state_holder,
+                mov_from_memory(*ty),
+                sized_reg(to, *ty),
+                sized_mem(addr, *ty)
+            );
+            store_vreg(*dst, to, state_holder);
+        }
+        Inst::Store {
+            ty, addr, value, ..
+        } => {
+            let addr = memory(addr, "rdi", state_holder);
+            let value = match source(value, *ty, state_holder) {
+                // レジスタか即値ならそのまま書ける
+                Some(value) if !ty.is_floating() && !matches!(value, Arg::Mem(_)) => value,
+                _ => {
+                    let reg = work_reg(*ty);
+                    load_operand(value, *ty, reg, state_holder);
+                    sized_reg(reg, *ty)
+                }
+            };
+            emit!(
+                state_holder,
+                mov_from_memory(*ty),
+                sized_mem(addr, *ty),
+                value
+            );
+        }
+        Inst::MemCopy { dst, src, size } => {
+            load_operand(src, Ty::I64, "rax", state_holder);
+            load_operand(dst, Ty::I64, "rdi", state_holder);
+            copy_memory("rax", "rdi", *size, state_holder);
+        }
+        Inst::Call {
+            dst,
+            callee,
+            args,
+            ret,
+            ret_slot,
+        } => code_gen_call(*dst, callee, args, ret, *ret_slot, state_holder),
+        Inst::Phi { .. } => panic!("phiはSSA形式から戻してからコード生成する"),
+    }
 }
 
-pub struct StateHolder {
-    offset_map: HashMap<String, i32>,
-    max_offset: i32,
-    label_counter: i32,
-    current_fun_name: String,
-    depth: i32,
-    local_vars_env: Vec<Varinfo>,
+// 仮想レジスタにsrcの値を入れる。浮動小数点数でなく、どちらかがレジスタか即値なら一命令で移す
+fn code_gen_copy(dst: VReg, src: &Operand, state_holder: &mut StateHolder) {
+    let ty = state_holder.vreg_type(dst);
+    if !ty.is_floating() {
+        if let Some(reg) = state_holder.vreg_reg(dst) {
+            load_operand(src, ty, reg, state_holder);
+            return;
+        }
+        match source(src, ty, state_holder) {
+            Some(value) if !matches!(value, Arg::Mem(_)) => {
+                let offset = state_holder.vreg_offset(dst);
+                emit!(state_holder, "mov", sized_mem(frame(offset), ty), value);
+                return;
+            }
+            _ => (),
+        }
+    }
+    load_operand(src, ty, work_reg(ty), state_holder);
+    store_vreg(dst, work_reg(ty), state_holder);
 }
 
-fn new_state_holder() -> StateHolder {
-    return StateHolder {
-        offset_map: HashMap::new(),
-        max_offset: LOCAL_VAR_OFFSET,
-        label_counter: 0,
-        current_fun_name: "".to_string(),
-        depth: 0,
-        local_vars_env: vec![],
+// 整数の演算。結果を作るレジスタ (dstのレジスタかrax) に左辺を読み、右辺はレジスタ、メモリ、即値のまま使う。
+// 除算はrax, rdxを使い、右辺の即値はrdiに読む
+fn code_gen_int_binary(
+    dst: VReg,
+    op: BinOp,
+    ty: Ty,
+    lhs: &Operand,
+    rhs: &Operand,
+    state_holder: &mut StateHolder,
+) {
+    if op.is_comparison() {
+        let lhs = operand_reg(lhs, ty, "rax", state_holder);
+        let rhs = match source(rhs, ty, state_holder) {
+            Some(rhs) => rhs,
+            None => sized_reg(operand_reg(rhs, ty, "rdi", state_holder), ty),
+        };
+        let set = match op {
+            BinOp::Eq => "sete",
+            BinOp::Ne => "setne",
+            BinOp::Lt => "setl",
+            BinOp::Le => "setle",
+            BinOp::Gt => "setg",
+            BinOp::Ge => "setge",
+            BinOp::ULt => "setb",
+            BinOp::ULe => "setbe",
+            BinOp::UGt => "seta",
+            _ => "setae",
+        };
+        let to = dst_reg(dst, state_holder);
+        emit!(state_holder, "cmp", sized_reg(lhs, ty), rhs);
+        emit!(state_holder, set, reg("al"));
+        emit!(state_holder, "movzx", sized_reg(to, Ty::I32), reg("al"));
+        store_vreg(dst, to, state_holder);
+        return;
+    }
+    let divide = matches!(op, BinOp::Div | BinOp::UDiv);
+    let to = if divide {
+        "rax"
+    } else {
+        dst_reg(dst, state_holder)
+    };
+    load_operand(lhs, ty, to, state_holder);
+    let rhs = match source(rhs, ty, state_holder) {
+        Some(value) if !(divide && matches!(rhs, Operand::Imm(_))) => value,
+        _ => {
+            load_operand(rhs, ty, "rdi", state_holder);
+            sized_reg("rdi", ty)
+        }
     };
+    let work = sized_reg(to, ty);
+    match op {
+        BinOp::Add => emit!(state_holder, "add", work, rhs),
+        BinOp::Sub => emit!(state_holder, "sub", work, rhs),
+        BinOp::Mul => emit!(state_holder, "imul", work, rhs),
+        BinOp::Div => {
+            emit!(state_holder, if ty == Ty::I32 { "cdq" } else { "cqo" });
+            emit!(state_holder, "idiv", rhs);
+        }
+        _ => {
+            emit!(state_holder, "mov", reg("edx"), Arg::Imm(0));
+            emit!(state_holder, "div", rhs);
+        }
+    }
+    store_vreg(dst, to, state_holder);
 }
 
-impl StateHolder {
-    fn push_depth(&mut self) {
-        self.depth += 1;
-    }
-    fn pop_depth(&mut self) {
-        self.depth -= 1;
+// 浮動小数点数の演算。左辺をxmm0、右辺をxmm1に置いて計算する
+fn code_gen_float_binary(
+    dst: VReg,
+    op: BinOp,
+    ty: Ty,
+    lhs: &Operand,
+    rhs: &Operand,
+    state_holder: &mut StateHolder,
+) {
+    load_operand(lhs, ty, "xmm0", state_holder);
+    load_operand(rhs, ty, "xmm1", state_holder);
+    let suffix = sse_suffix(ty);
+    let sse_op = |name: &str| format!("{}{}", name, suffix);
+    // 比較はucomiで行う。NaNとの比較は!=だけが真になる
+    match op {
+        BinOp::Add => emit!(state_holder, &sse_op("add"), xmm(0), xmm(1)),
+        BinOp::Sub => emit!(state_holder, &sse_op("sub"), xmm(0), xmm(1)),
+        BinOp::Mul => emit!(state_holder, &sse_op("mul"), xmm(0), xmm(1)),
+        BinOp::Div => emit!(state_holder, &sse_op("div"), xmm(0), xmm(1)),
+        BinOp::Eq => {
+            emit!(state_holder, &sse_op("ucomi"), xmm(0), xmm(1));
+            emit!(state_holder, "sete", reg("al"));
+            emit!(state_holder, "setnp", reg("dl"));
+            emit!(state_holder, "and", reg("al"), reg("dl"));
+            emit!(state_holder, "movzx", reg("eax"), reg("al"));
+        }
+        BinOp::Ne => {
+            emit!(state_holder, &sse_op("ucomi"), xmm(0), xmm(1));
+            emit!(state_holder, "setne", reg("al"));
+            emit!(state_holder, "setp", reg("dl"));
+            emit!(state_holder, "or", reg("al"), reg("dl"));
+            emit!(state_holder, "movzx", reg("eax"), reg("al"));
+        }
+        BinOp::Lt | BinOp::Le => {
+            emit!(state_holder, &sse_op("ucomi"), xmm(1), xmm(0));
+            emit!(
+                state_holder,
+                if op == BinOp::Lt { "seta" } else { "setae" },
+                reg("al")
+            );
+            emit!(state_holder, "movzx", reg("eax"), reg("al"));
+        }
+        BinOp::Gt | BinOp::Ge => {
+            emit!(state_holder, &sse_op("ucomi"), xmm(0), xmm(1));
+            emit!(
+                state_holder,
+                if op == BinOp::Gt { "seta" } else { "setae" },
+                reg("al")
+            );
+            emit!(state_holder, "movzx", reg("eax"), reg("al"));
+        }
+        op => panic!("浮動小数点数にない演算: {:?}", op),
     }
-    fn assert_depth(&mut self) {
-        assert_eq!(self.depth, 0);
+    let from = if op.is_comparison() { "rax" } else { "xmm0" };
+    store_vreg(dst, from, state_holder);
+}
+
+fn code_gen_convert(
+    dst: VReg,
+    op: ConvOp,
+    from: Ty,
+    src: &Operand,
+    state_holder: &mut StateHolder,
+) {
+    let to = state_holder.vreg_type(dst);
+    load_operand(src, from, work_reg(from), state_holder);
+    let cvtsi2 = format!("cvtsi2{}", sse_suffix(to));
+    match op {
+        ConvOp::SExt => emit!(state_holder, "movsxd", reg("rax"), reg("eax")),
+        // 32ビットのレジスタに読んだ時点で上位はゼロになっている
+        ConvOp::ZExt | ConvOp::Trunc => {}
+        ConvOp::IntToFloat => emit!(state_holder, &cvtsi2, xmm(0), reg("rax")),
+        ConvOp::ULongToFloat => {
+            // 2^63以上の値は符号付きとしては負になるので、半分にして変換してから2倍する。
+            // 捨てる最下位ビットは丸めのために残しておく
+            emit!(state_holder, "test", reg("rax"), reg("rax"));
+            emit!(state_holder, "js", sym("1f"));
+            emit!(state_holder, &cvtsi2, xmm(0), reg("rax"));
+            emit!(state_holder, "jmp", sym("2f"));
+            state_holder.emitter.label("1".to_string());
+            emit!(state_holder, "mov", reg("rdi"), reg("rax"));
+            emit!(state_holder, "shr", reg("rdi"), Arg::Imm(1));
+            emit!(state_holder, "and", reg("eax"), Arg::Imm(1));
+            emit!(state_holder, "or", reg("rdi"), reg("rax"));
+            emit!(state_holder, &cvtsi2, xmm(0), reg("rdi"));
+            emit!(
+                state_holder,
+                &format!("add{}", sse_suffix(to)),
+                xmm(0),
+                xmm(0)
+            );
+            state_holder.emitter.label("2".to_string());
+        }
+        ConvOp::FloatToInt => emit!(
+            state_holder,
+            &format!("cvtt{}2si", sse_suffix(from)),
+            reg("rax"),
+            xmm(0)
+        ),
+        ConvOp::FloatExt => emit!(state_holder, "cvtss2sd", xmm(0), xmm(0)),
+        ConvOp::FloatTrunc => emit!(state_holder, "cvtsd2ss", xmm(0), xmm(0)),
     }
-    fn set_fun_name(&mut self, name: String) {
-        self.current_fun_name = name;
+    store_vreg(dst, work_reg(to), state_holder);
+}
+
+fn code_gen_call(
+    dst: VReg,
+    callee: &Callee,
+    args: &[(Operand, Abi)],
+    ret: &Abi,
+    ret_slot: Option<Slot>,
+    state_holder: &mut StateHolder,
+) {
+    let (reserve, sse) = code_gen_args(args, ret, ret_slot, state_holder);
+    let target = call_target(callee, state_holder);
+    // 可変長引数の関数のために、alに使ったxmmレジスタの数を入れておく
+    emit!(state_holder, "mov", reg("eax"), Arg::Imm(sse as i64));
+    emit!(state_holder, "call", target);
+    if reserve > 0 {
+        emit!(state_holder, "add", reg("rsp"), Arg::Imm(reserve as i64));
     }
-    fn get_fun_name(&mut self) -> String {
-        self.current_fun_name.clone()
+
+    // レジスタで返ってきた構造体は一時領域に書き、そのアドレスを値にする
+    match ret {
+        Abi::Scalar(ty) => store_vreg(dst, work_reg(*ty), state_holder),
+        Abi::Struct { size, class } => {
+            let offset = state_holder.slot_offset(ret_slot.expect("構造体の戻り値の領域がない"));
+            emit!(state_holder, "lea", reg("rdi"), Arg::Mem(frame(offset)));
+            if let ArgClass::Regs(classes) = class {
+                store_eightbytes("rdi", &ret_regs(classes), *size, state_holder);
+            }
+            store_vreg(dst, "rdi", state_holder);
+        }
     }
-    fn get_label(&mut self, prefix: String) -> (String, String) {
-        let jump_label = format!(".L.{}{}", prefix, self.get_label_counter());
-        let label = format!("{}:", jump_label);
-        (label, jump_label)
+}
+
+// 末尾の呼び出し。引数を置いてからフレームを畳み、戻り先のアドレスを残したまま呼び出し先へ飛ぶ。
+// 呼び出し先はこの関数の呼び出し元へ直接戻る
+fn code_gen_sibling_call(callee: &Callee, args: &[(Operand, Abi)], state_holder: &mut StateHolder) {
+    let ret = state_holder.ret.clone();
+    let (_, sse) = code_gen_args(args, &ret, None, state_holder);
+    let target = call_target(callee, state_holder);
+    emit!(state_holder, "mov", reg("eax"), Arg::Imm(sse as i64));
+    code_gen_epilogue(state_holder);
+    emit!(state_holder, "jmp", target);
+}
+
+// 関数ポインタはr10に読む。r10は引数にもcallee-savedにも使わない
+fn call_target(callee: &Callee, state_holder: &mut StateHolder) -> Arg {
+    match callee {
+        Callee::Symbol(name) => sym(name),
+        Callee::Reg(operand) => {
+            load_operand(operand, Ty::I64, "r10", state_holder);
+            reg("r10")
+        }
     }
-    fn get_label_counter(&mut self) -> i32 {
-        let value = self.label_counter;
-        self.label_counter += 1;
-        value
+}
+
+// 引数をABIに従ってレジスタとスタックに置く。スタックに取った大きさと、使ったxmmレジスタの数を返す
+fn code_gen_args(
+    args: &[(Operand, Abi)],
+    ret: &Abi,
+    ret_slot: Option<Slot>,
+    state_holder: &mut StateHolder,
+) -> (i32, usize) {
+    let abis: Vec<&Abi> = args.iter().map(|(_, abi)| abi).collect();
+    let (locations, stack_size, sse) = arg_locations(&abis, ret);
+    // rspはプロローグの後で16の倍数なので、スタック引数の領域も16の倍数で取る
+    let reserve = align_to(stack_size, RSP_CONST);
+    if reserve > 0 {
+        emit!(state_holder, "sub", reg("rsp"), Arg::Imm(reserve as i64));
     }
-    fn get_pointer_offset(&mut self, str: &String) -> i32 {
-        if let Some(offset) = self.offset_map.get(str) {
-            return -1 * *offset;
+    for ((value, abi), location) in args.iter().zip(&locations) {
+        if let ArgLocation::Stack(offset) = location {
+            match abi {
+                Abi::Struct { size, .. } => {
+                    load_operand(value, Ty::I64, "rax", state_holder);
+                    emit!(
+                        state_holder,
+                        "lea",
+                        reg("rdi"),
+                        Arg::Mem(Mem::new("rsp", *offset as i64))
+                    );
+                    copy_memory("rax", "rdi", *size, state_holder);
+                }
+                Abi::Scalar(ty) => {
+                    let from = work_reg(*ty);
+                    load_operand(value, *ty, from, state_holder);
+                    emit!(
+                        state_holder,
+                        mov_from_memory(*ty),
+                        sized_mem(Mem::new("rsp", *offset as i64), *ty),
+                        sized_reg(from, *ty)
+                    );
+                }
+            }
         }
-        let offset = self.max_offset;
-        self.max_offset += LOCAL_POINTER_OFFSET;
-        self.offset_map.insert(str.clone(), offset);
-        let offset = -1 * offset;
-        offset
     }
-    fn get_local_var_offset(&mut self, str: &String) -> i32 {
-        if let Some(offset) = self.offset_map.get(str) {
-            return -1 * *offset;
+    for ((value, abi), location) in args.iter().zip(&locations) {
+        if let ArgLocation::Reg(regs) = location {
+            match abi {
+                Abi::Struct { size, .. } => {
+                    load_operand(value, Ty::I64, "r11", state_holder);
+                    load_eightbytes("r11", regs, *size, state_holder);
+                }
+                Abi::Scalar(ty) => {
+                    let to = match regs[0] {
+                        Reg::Gp(to, _) => to.to_string(),
+                        Reg::Sse(n) => format!("xmm{}", n),
+                    };
+                    load_operand(value, *ty, &to, state_holder);
+                }
+            }
         }
-        let offset = self.max_offset;
-        self.max_offset += LOCAL_VAR_OFFSET;
-        self.offset_map.insert(str.clone(), offset);
-        let offset = -1 * offset;
-        offset
     }
-    fn reset_offset(&mut self) {
-        self.offset_map = HashMap::new();
-        self.max_offset = LOCAL_VAR_OFFSET;
+    if returns_in_memory(ret) {
+        let offset = state_holder.slot_offset(ret_slot.expect("構造体の戻り値の領域がない"));
+        emit!(state_holder, "lea", reg("rdi"), Arg::Mem(frame(offset)));
     }
-    fn set_local_var_env(&mut self, t: TypeDec, var: String) {
-        self.local_vars_env.push(Varinfo { name: var, t: t });
+    (reserve, sse)
+}
+
+struct StateHolder<'a> {
+    // 出力中の関数の名前、戻り値の渡し方、仮想レジスタの型
+    fun_name: String,
+    ret: Abi,
+    vreg_types: Vec<Ty>,
+    // スロットと仮想レジスタの、rbpからのオフセット
+    slot_offsets: Vec<i32>,
+    vreg_offsets: Vec<i32>,
+    // 仮想レジスタに割り当てた汎用レジスタと、値として持つアドレス
+    vreg_regs: Vec<Option<&'static str>>,
+    vreg_addresses: Vec<Option<Address>>,
+    // MEMORYに分類される構造体を返す関数で、隠し引数のアドレスを保存した場所
+    ret_buffer_offset: i32,
+    // プロローグで保存したcallee-savedレジスタ
+    callee_saved: Vec<&'static str>,
+    // 末尾呼び出しをjmpにするか (-fno-optimize-sibling-callsでしない)。
+    // tail_callsは出力中の関数でそうできるか
+    sibling_calls: bool,
+    tail_calls: bool,
+    emitter: Emitter<'a>,
+}
+
+fn new_state_holder(emitter: Emitter) -> StateHolder {
+    StateHolder {
+        fun_name: "".to_string(),
+        ret: Abi::Scalar(Ty::I32),
+        vreg_types: vec![],
+        slot_offsets: vec![],
+        vreg_offsets: vec![],
+        vreg_regs: vec![],
+        vreg_addresses: vec![],
+        ret_buffer_offset: 0,
+        callee_saved: vec![],
+        sibling_calls: false,
+        tail_calls: false,
+        emitter,
     }
-    fn get_local_var_type(&mut self, var: String) -> TypeDec {
-        for v in &self.local_vars_env {
-            if v.name == var {
-                return v.t.clone();
-            }
+}
+
+impl StateHolder<'_> {
+    fn begin_function(
+        &mut self,
+        f: &Function,
+        slot_offsets: Vec<i32>,
+        vreg_offsets: Vec<i32>,
+        ret_buffer_offset: i32,
+    ) {
+        self.fun_name = f.name.clone();
+        self.ret = f.ret.clone();
+        self.vreg_types = f.vregs.clone();
+        self.slot_offsets = slot_offsets;
+        self.vreg_offsets = vreg_offsets;
+        self.ret_buffer_offset = ret_buffer_offset;
+    }
+    fn block_label(&self, id: BlockId) -> String {
+        format!(".L.{}.{}", self.fun_name, id)
+    }
+    fn vreg_type(&self, vreg: VReg) -> Ty {
+        self.vreg_types[vreg.0]
+    }
+    // 即値の型は使われる場所で決まるので、なければdefaultにする
+    fn operand_type(&self, operand: &Operand, default: Ty) -> Ty {
+        match operand {
+            Operand::Reg(vreg) => self.vreg_type(*vreg),
+            Operand::Imm(_) => default,
         }
-        panic!("envにvarがない")
     }
-    fn reset_local_var_env(&mut self) {
-        self.local_vars_env = vec![];
+    fn vreg_reg(&self, vreg: VReg) -> Option<&'static str> {
+        self.vreg_regs[vreg.0]
     }
-    fn check_local_var_from_env(&mut self, var: String) -> bool {
-        for v in &self.local_vars_env {
-            if v.name == var {
-                return true;
-            }
+    // アドレスを値とする仮想レジスタなら、メモリのオペランドとして書いたアドレス
+    fn address(&self, vreg: VReg) -> Option<Mem> {
+        match &self.vreg_addresses[vreg.0] {
+            Some(Address::Slot(slot)) => Some(frame(self.slot_offset(*slot))),
+            Some(Address::Symbol(symbol)) => Some(Mem::symbol(symbol)),
+            None => None,
         }
-        return false;
+    }
+    fn vreg_offset(&self, vreg: VReg) -> i32 {
+        self.vreg_offsets[vreg.0]
+    }
+    fn slot_offset(&self, slot: Slot) -> i32 {
+        self.slot_offsets[slot.0]
     }
 }
 
 #[test]
-fn test_map() {
-    let mut state_holder = new_state_holder();
-    let offset = state_holder.get_local_var_offset(&"a".to_string());
-    assert_eq!(offset, -LOCAL_VAR_OFFSET);
-    let offset = state_holder.get_local_var_offset(&"a".to_string());
-    assert_eq!(offset, -LOCAL_VAR_OFFSET);
-    let offset = state_holder.get_local_var_offset(&"b".to_string());
-    assert_eq!(offset, -2 * LOCAL_VAR_OFFSET);
-    let offset = state_holder.get_local_var_offset(&"c".to_string());
-    assert_eq!(offset, LOCAL_VAR_OFFSET * -3);
-    let offset = state_holder.get_local_var_offset(&"d".to_string());
-    assert_eq!(offset, LOCAL_VAR_OFFSET * -4);
-    let offset = state_holder.get_local_var_offset(&"d".to_string());
-    assert_eq!(offset, LOCAL_VAR_OFFSET * -4);
-    state_holder.reset_offset();
-    let offset = state_holder.get_local_var_offset(&"d".to_string());
-    assert_eq!(offset, -LOCAL_VAR_OFFSET);
-    let offset = state_holder.get_local_var_offset(&"d".to_string());
-    assert_eq!(offset, -LOCAL_VAR_OFFSET);
-    let offset = state_holder.get_local_var_offset(&"a".to_string());
-    assert_eq!(offset, LOCAL_VAR_OFFSET * -2);
-    let offset = state_holder.get_local_var_offset(&"a".to_string());
-    assert_eq!(offset, LOCAL_VAR_OFFSET * -2);
-
-    let (label, jlabel) = state_holder.get_label("if".to_string());
-    assert_eq!(label, ".L.if0:");
-    assert_eq!(jlabel, ".L.if0");
+fn emit_to_buffer_test() {
+    let compile = |src: &str| {
+        let mut out: Vec<u8> = vec![];
+        start(crate::parser::program_of(src), &mut out).map(|_| String::from_utf8(out).unwrap())
+    };
+    // 一回の実行で二つのプログラムをそれぞれメモリに出力する
+    let f = compile("int f() { return 42; }").unwrap();
+    let g = compile("int g(int a) { return a + 1; }").unwrap();
+    assert!(f.starts_with(".intel_syntax noprefix\n"));
+    assert!(f.contains("f:\n") && !f.contains("g:\n"));
+    assert!(g.contains("g:\n") && !g.contains("f:\n"));
+    assert!(f.contains("  mov eax, 42\n"));
+    // エラーはプロセスを終わらせずに返ってくる
+    let errors = compile("int h() { return y; }").unwrap_err();
+    assert_eq!(
+        errors,
+        vec![Diagnostic::new(Message::UndefinedVariable("y".to_string()))]
+    );
+}
+
+#[test]
+fn register_allocation_test() {
+    let program = crate::parser::program_of("int g(int x); int f(int a) { return g(a) + a; }");
+    let mut out: Vec<u8> = vec![];
+    start(program, &mut out).unwrap();
+    let asm = String::from_utf8(out).unwrap();
+    // 呼び出しをまたいで生きる引数はcallee-savedレジスタに置き、プロローグとエピローグで保存する
+    assert!(asm.contains("  push rbx\n  sub rsp, 8\n  mov ebx, edi\n"));
+    assert!(asm.contains("  lea rsp, [rbp - 8]\n  pop rbx\n  pop rbp\n  ret\n"));
+    // スロットのアドレスはレジスタを通さずに書く
+    assert!(asm.contains("  mov dword ptr [rbp - 16], ebx\n"));
+}
+
+#[test]
+fn sibling_call_test() {
+    let compile = |src: &str, sibling_calls: bool| {
+        let module = crate::lower::module_of(src).ok().unwrap();
+        let mut out: Vec<u8> = vec![];
+        emit_module(&module, 0, sibling_calls, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    };
+    // 末尾の呼び出しはフレームを畳んでから飛ぶ
+    let src = "int g(int x); int f(int a) { return g(a + 1); }";
+    assert!(compile(src, true)
+        .contains("  mov eax, 0\n  lea rsp, [rbp - 8]\n  pop rbx\n  pop rbp\n  jmp g\n"));
+    assert!(compile(src, false).contains("  call g\n"));
+    // 関数ポインタはr10から飛ぶ
+    let asm = compile("int f(int (*fp)(int), int a) { return fp(a); }", true);
+    assert!(asm.contains("  pop rbp\n  jmp r10\n"));
+    // ローカル変数のアドレスを渡すときや、スタックで渡す引数があるときはcallのまま
+    let asm = compile(
+        "int g(int *p); int f() { int x; x = 1; return g(&x); }",
+        true,
+    );
+    assert!(asm.contains("  call g\n") && !asm.contains("jmp g"));
+    let asm = compile(
+        "int g(int a, int b, int c, int d, int e, int f, int h); \
+         int f(int a) { return g(a, a, a, a, a, a, a); }",
+        true,
+    );
+    assert!(asm.contains("  call g\n"));
+    // 戻り値の型が違えば変換が要るので末尾の呼び出しではない
+    let asm = compile("long g(int x); int f(int a) { return g(a); }", true);
+    assert!(asm.contains("  call g\n"));
 }
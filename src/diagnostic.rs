@@ -0,0 +1,839 @@
+use std::env;
+use std::fmt;
+use std::rc::Rc;
+
+// 診断メッセージの言語
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Lang {
+    En,
+    Ja,
+}
+
+impl Lang {
+    // --langの値かLANGの値 (ja_JP.UTF-8 など) から言語を決める
+    pub fn from_name(name: &str) -> Option<Lang> {
+        if name.starts_with("ja") {
+            Some(Lang::Ja)
+        } else if name.starts_with("en") || name == "C" || name == "POSIX" {
+            Some(Lang::En)
+        } else {
+            None
+        }
+    }
+
+    // --langがないとき。LANGが日本語でなければ英語にする
+    pub fn from_env() -> Lang {
+        env::var("LANG")
+            .ok()
+            .and_then(|name| Lang::from_name(&name))
+            .unwrap_or(Lang::En)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Severity {
+    Error,
+    Warning,
+    Note,
+}
+
+// コードと一緒に出すので、言語によらず英語のまま表示する
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Severity::Error => write!(f, "error"),
+            Severity::Warning => write!(f, "warning"),
+            Severity::Note => write!(f, "note"),
+        }
+    }
+}
+
+// 「expected `;` after expression」のafter expressionの部分
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Context {
+    AfterReturnValue,
+    AfterExpression,
+    AfterIf,
+    AfterWhile,
+    AfterFor,
+    AfterIfCondition,
+    AfterWhileCondition,
+    AfterForInitializer,
+    AfterForCondition,
+    AfterForClauses,
+    AfterStructDefinition,
+    AfterStructMember,
+    AfterDeclaration,
+    AfterArguments,
+    AfterParameters,
+    CloseDeclarator,
+    CloseParen,
+    CloseBlock,
+}
+
+impl Context {
+    fn english(self) -> &'static str {
+        match self {
+            Context::AfterReturnValue => "after return value",
+            Context::AfterExpression => "after expression",
+            Context::AfterIf => "after `if`",
+            Context::AfterWhile => "after `while`",
+            Context::AfterFor => "after `for`",
+            Context::AfterIfCondition => "after `if` condition",
+            Context::AfterWhileCondition => "after `while` condition",
+            Context::AfterForInitializer => "after `for` initializer",
+            Context::AfterForCondition => "after `for` condition",
+            Context::AfterForClauses => "after `for` clauses",
+            Context::AfterStructDefinition => "after struct definition",
+            Context::AfterStructMember => "after struct member",
+            Context::AfterDeclaration => "after declaration",
+            Context::AfterArguments => "after arguments",
+            Context::AfterParameters => "after parameters",
+            Context::CloseDeclarator => "to close declarator",
+            Context::CloseParen => "to close parenthesized expression",
+            Context::CloseBlock => "to close block",
+        }
+    }
+
+    fn japanese(self) -> &'static str {
+        match self {
+            Context::AfterReturnValue => "戻り値の後ろ",
+            Context::AfterExpression => "式の後ろ",
+            Context::AfterIf => "`if` の後ろ",
+            Context::AfterWhile => "`while` の後ろ",
+            Context::AfterFor => "`for` の後ろ",
+            Context::AfterIfCondition => "`if` の条件式の後ろ",
+            Context::AfterWhileCondition => "`while` の条件式の後ろ",
+            Context::AfterForInitializer => "`for` の初期化式の後ろ",
+            Context::AfterForCondition => "`for` の条件式の後ろ",
+            Context::AfterForClauses => "`for` の三つの式の後ろ",
+            Context::AfterStructDefinition => "構造体の定義の後ろ",
+            Context::AfterStructMember => "構造体のメンバの後ろ",
+            Context::AfterDeclaration => "宣言の後ろ",
+            Context::AfterArguments => "引数の後ろ",
+            Context::AfterParameters => "仮引数の後ろ",
+            Context::CloseDeclarator => "宣言子のかっこを閉じる位置",
+            Context::CloseParen => "かっこを閉じる位置",
+            Context::CloseBlock => "ブロックを閉じる位置",
+        }
+    }
+}
+
+// ポインタの変換が起きる場所
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Conversion {
+    Return,
+    Initialization,
+    Argument,
+    Assignment,
+}
+
+impl Conversion {
+    fn english(self) -> &'static str {
+        match self {
+            Conversion::Return => "return",
+            Conversion::Initialization => "initialization",
+            Conversion::Argument => "argument passing",
+            Conversion::Assignment => "assignment",
+        }
+    }
+
+    fn japanese(self) -> &'static str {
+        match self {
+            Conversion::Return => "return",
+            Conversion::Initialization => "初期化",
+            Conversion::Argument => "引数",
+            Conversion::Assignment => "代入",
+        }
+    }
+}
+
+// 利用者に見せる診断メッセージ。文はここの英語と日本語のカタログにだけ書き、
+// 各モジュールはメッセージIDとその引数を作る。foundのNoneは入力の終わり
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Message {
+    // 字句解析
+    InvalidNumber(String),
+    InvalidNumberSuffix(String),
+    MissingExponentDigits(String),
+    IntegerTooLarge(String),
+    UnknownCharacter(char),
+    // プリプロセッサ
+    UnterminatedConditional,
+    // 対応する#ifのない#elif, #else, #endif。ディレクティブの名前を持つ
+    UnmatchedDirective(String),
+    MalformedDirective(String),
+    ErrorDirective(String),
+    WarningDirective(String),
+    UnknownDirective(String),
+    MissingMacroName,
+    DefinedAsMacroName,
+    PasteAtEdge,
+    InvalidPaste(String, String),
+    StringifyNonParameter,
+    DuplicateMacroParameter(String),
+    MalformedMacroParameters,
+    UnterminatedMacroCall(String),
+    MacroArgumentCount {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    IncludeNotFound(String),
+    IncludeTooDeep,
+    IncludeReadFailed(String, String),
+    MalformedDefined,
+    ExtraTokenInIf(String),
+    MissingColonInIf,
+    DivisionByZeroInIf,
+    DivisionOverflowInIf,
+    UnclosedParenInIf,
+    InvalidTokenInIf(String),
+    MissingIfExpression,
+    InvalidNumberInIf(String),
+    InvalidCharInIf(String),
+    UnterminatedComment,
+    UnterminatedQuote,
+    // 構文解析
+    Expected {
+        expected: String,
+        context: Context,
+        found: Option<String>,
+    },
+    ExpectedExpression(Option<String>),
+    ExpectedTypeSpecifier(Option<String>),
+    ExpectedMemberName(Option<String>),
+    ExpectedVariableName(Option<String>),
+    // 閉じかっこがないときに、対応する開きかっこを指す注記
+    ToMatch(String),
+    InvalidTypeCombination(String, String),
+    StorageClassOnMember,
+    StorageClassOnParameter,
+    MissingParameterName(String),
+    InlineOnNonFunction,
+    // コード生成
+    NonConstantInitializer(String),
+    ExternInitialized(String),
+    InvalidConversion {
+        from: String,
+        to: String,
+    },
+    UndefinedVariable(String),
+    AssignToConst(String),
+    DuplicateMember {
+        tag: String,
+        member: String,
+    },
+    IncompleteStruct(String),
+    NotAStruct(String),
+    NoSuchMember {
+        t: String,
+        member: String,
+    },
+    DiscardedQualifiers {
+        context: Conversion,
+        from: String,
+        to: String,
+    },
+    // 関数の中の到達しない文 (-Wunreachable-code)
+    UnreachableCode(String),
+    // 入出力
+    WriteFailed(String),
+    ReadFailed(String, String),
+    // コマンドライン
+    MissingOptionArgument(String),
+    UnknownLanguage(String),
+    UnknownDiagnosticsFormat(String),
+    InvalidOptLevel(String),
+    MultipleInputs,
+    NoInput,
+}
+
+// 元のファイルでの位置。行と列は1始まり
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Location {
+    pub file: Rc<str>,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:{}", self.file, self.line, self.column)
+    }
+}
+
+// 位置の付いた診断。endは問題のトークンの終わり (含まない)。
+// コード生成のエラーのように位置がわからないものはlocationがNone
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Diagnostic {
+    pub message: Message,
+    pub location: Option<Location>,
+    pub end: Option<Location>,
+    pub notes: Vec<Diagnostic>,
+}
+
+impl Diagnostic {
+    pub fn new(message: Message) -> Diagnostic {
+        Diagnostic {
+            message,
+            location: None,
+            end: None,
+            notes: vec![],
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Format {
+    Human,
+    Json,
+}
+
+impl Format {
+    // --diagnostics-formatの値
+    pub fn from_name(name: &str) -> Option<Format> {
+        match name {
+            "human" => Some(Format::Human),
+            "json" => Some(Format::Json),
+            _ => None,
+        }
+    }
+}
+
+// 診断の出し方。言語と形式を選ぶ
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Reporter {
+    pub lang: Lang,
+    pub format: Format,
+}
+
+impl Reporter {
+    pub fn render(&self, diagnostic: &Diagnostic) -> String {
+        match self.format {
+            Format::Human => self.render_human(diagnostic),
+            Format::Json => self.render_json(diagnostic),
+        }
+    }
+
+    // 標準エラー出力に出す。JSONなら一つの診断が一行になる
+    pub fn emit(&self, diagnostic: &Diagnostic) {
+        eprintln!("{}", self.render(diagnostic));
+    }
+
+    // gccと同じ「file:line:column: error[E0101]: ...」の形。注記は続く行に出す
+    fn render_human(&self, diagnostic: &Diagnostic) -> String {
+        let mut out = match &diagnostic.location {
+            Some(location) => format!("{}: {}", location, diagnostic.message.render(self.lang)),
+            None => diagnostic.message.render(self.lang),
+        };
+        for note in &diagnostic.notes {
+            out.push('\n');
+            out.push_str(&self.render_human(note));
+        }
+        out
+    }
+
+    fn render_json(&self, diagnostic: &Diagnostic) -> String {
+        let mut fields = vec![];
+        if diagnostic.message.severity() != Severity::Note {
+            fields.push(format!(
+                "\"code\":{}",
+                json_string(diagnostic.message.code())
+            ));
+        }
+        fields.push(format!(
+            "\"severity\":{}",
+            json_string(&diagnostic.message.severity().to_string())
+        ));
+        fields.push(format!(
+            "\"message\":{}",
+            json_string(&diagnostic.message.text(self.lang))
+        ));
+        match &diagnostic.location {
+            Some(location) => {
+                fields.push(format!("\"file\":{}", json_string(&location.file)));
+                fields.push(format!("\"line\":{}", location.line));
+                fields.push(format!("\"column\":{}", location.column));
+            }
+            None => fields.push("\"file\":null,\"line\":null,\"column\":null".to_string()),
+        }
+        match &diagnostic.end {
+            Some(end) => {
+                fields.push(format!("\"end_line\":{}", end.line));
+                fields.push(format!("\"end_column\":{}", end.column));
+            }
+            None => fields.push("\"end_line\":null,\"end_column\":null".to_string()),
+        }
+        if diagnostic.message.severity() != Severity::Note {
+            let notes: Vec<String> = diagnostic
+                .notes
+                .iter()
+                .map(|note| self.render_json(note))
+                .collect();
+            fields.push(format!("\"notes\":[{}]", notes.join(",")));
+        }
+        format!("{{{}}}", fields.join(","))
+    }
+}
+
+// JSONの文字列リテラル。制御文字は\uでエスケープする
+fn json_string(s: &str) -> String {
+    let mut out = String::from("\"");
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            '\t' => out.push_str("\\t"),
+            c if (c as u32) < 0x20 => out.push_str(&format!("\\u{:04x}", c as u32)),
+            c => out.push(c),
+        }
+    }
+    out.push('"');
+    out
+}
+
+fn found_english(found: &Option<String>) -> String {
+    match found {
+        Some(token) => format!("`{}`", token),
+        None => "end of input".to_string(),
+    }
+}
+
+fn found_japanese(found: &Option<String>) -> String {
+    match found {
+        Some(token) => format!("`{}` がある", token),
+        None => "入力が終わっている".to_string(),
+    }
+}
+
+impl Message {
+    // 検索できるように表示するコード。一度決めたら変えない
+    pub fn code(&self) -> &'static str {
+        match self {
+            Message::InvalidNumber(_) => "E0001",
+            Message::InvalidNumberSuffix(_) => "E0002",
+            Message::MissingExponentDigits(_) => "E0003",
+            Message::IntegerTooLarge(_) => "E0004",
+            Message::UnknownCharacter(_) => "E0005",
+            Message::UnterminatedConditional => "E0301",
+            Message::UnmatchedDirective(_) => "E0302",
+            Message::MalformedDirective(_) => "E0303",
+            Message::ErrorDirective(_) => "E0304",
+            Message::WarningDirective(_) => "W0301",
+            Message::UnknownDirective(_) => "E0305",
+            Message::MissingMacroName => "E0306",
+            Message::DefinedAsMacroName => "E0307",
+            Message::PasteAtEdge => "E0308",
+            Message::InvalidPaste(_, _) => "E0309",
+            Message::StringifyNonParameter => "E0310",
+            Message::DuplicateMacroParameter(_) => "E0311",
+            Message::MalformedMacroParameters => "E0312",
+            Message::UnterminatedMacroCall(_) => "E0313",
+            Message::MacroArgumentCount { .. } => "E0314",
+            Message::IncludeNotFound(_) => "E0315",
+            Message::IncludeTooDeep => "E0316",
+            Message::IncludeReadFailed(_, _) => "E0317",
+            Message::MalformedDefined => "E0318",
+            Message::ExtraTokenInIf(_) => "E0319",
+            Message::MissingColonInIf => "E0320",
+            Message::DivisionByZeroInIf => "E0321",
+            Message::DivisionOverflowInIf => "E0322",
+            Message::UnclosedParenInIf => "E0323",
+            Message::InvalidTokenInIf(_) => "E0324",
+            Message::MissingIfExpression => "E0325",
+            Message::InvalidNumberInIf(_) => "E0326",
+            Message::InvalidCharInIf(_) => "E0327",
+            Message::UnterminatedComment => "E0328",
+            Message::UnterminatedQuote => "E0329",
+            Message::Expected { .. } => "E0101",
+            Message::ExpectedExpression(_) => "E0102",
+            Message::ExpectedTypeSpecifier(_) => "E0103",
+            Message::ExpectedMemberName(_) => "E0104",
+            Message::ExpectedVariableName(_) => "E0105",
+            Message::ToMatch(_) => "N0101",
+            Message::InvalidTypeCombination(_, _) => "E0106",
+            Message::StorageClassOnMember => "E0107",
+            Message::StorageClassOnParameter => "E0108",
+            Message::MissingParameterName(_) => "E0109",
+            Message::InlineOnNonFunction => "E0110",
+            Message::NonConstantInitializer(_) => "E0201",
+            Message::ExternInitialized(_) => "E0202",
+            Message::InvalidConversion { .. } => "E0203",
+            Message::UndefinedVariable(_) => "E0204",
+            Message::AssignToConst(_) => "E0205",
+            Message::DuplicateMember { .. } => "E0206",
+            Message::IncompleteStruct(_) => "E0207",
+            Message::NotAStruct(_) => "E0208",
+            Message::NoSuchMember { .. } => "E0209",
+            Message::DiscardedQualifiers { .. } => "W0201",
+            Message::UnreachableCode(_) => "W0202",
+            Message::WriteFailed(_) => "E0401",
+            Message::ReadFailed(_, _) => "E0402",
+            Message::MissingOptionArgument(_) => "E0501",
+            Message::UnknownLanguage(_) => "E0502",
+            Message::UnknownDiagnosticsFormat(_) => "E0503",
+            Message::InvalidOptLevel(_) => "E0504",
+            Message::MultipleInputs => "E0505",
+            Message::NoInput => "E0506",
+        }
+    }
+
+    pub fn severity(&self) -> Severity {
+        match self {
+            Message::DiscardedQualifiers { .. }
+            | Message::UnreachableCode(_)
+            | Message::WarningDirective(_) => Severity::Warning,
+            Message::ToMatch(_) => Severity::Note,
+            _ => Severity::Error,
+        }
+    }
+
+    pub fn text(&self, lang: Lang) -> String {
+        match lang {
+            Lang::En => self.english(),
+            Lang::Ja => self.japanese(),
+        }
+    }
+
+    // 「error[E0101]: expected `;` after expression, found `}`」の形。注記にはコードを付けない
+    pub fn render(&self, lang: Lang) -> String {
+        match self.severity() {
+            Severity::Note => format!("{}: {}", self.severity(), self.text(lang)),
+            severity => format!("{}[{}]: {}", severity, self.code(), self.text(lang)),
+        }
+    }
+
+    fn english(&self) -> String {
+        match self {
+            Message::InvalidNumber(s) => format!("invalid number `{}`", s),
+            Message::InvalidNumberSuffix(s) => format!("invalid suffix on number `{}`", s),
+            Message::MissingExponentDigits(s) => format!("exponent has no digits in `{}`", s),
+            Message::IntegerTooLarge(s) => format!("integer constant `{}` is too large", s),
+            Message::UnknownCharacter(c) => format!("unknown character `{}`", c),
+            Message::UnterminatedConditional => "unterminated conditional directive".to_string(),
+            Message::UnmatchedDirective(name) => format!("`#{}` without `#if`", name),
+            Message::MalformedDirective(name) => format!("malformed `#{}` directive", name),
+            Message::ErrorDirective(text) => format!("#error {}", text),
+            Message::WarningDirective(text) => format!("#warning {}", text),
+            Message::UnknownDirective(name) => format!("unknown directive `#{}`", name),
+            Message::MissingMacroName => "macro name missing".to_string(),
+            Message::DefinedAsMacroName => "`defined` cannot be used as a macro name".to_string(),
+            Message::PasteAtEdge => {
+                "`##` cannot appear at either end of a macro expansion".to_string()
+            }
+            Message::InvalidPaste(lhs, rhs) => format!(
+                "pasting `{}` and `{}` does not give a valid preprocessing token",
+                lhs, rhs
+            ),
+            Message::StringifyNonParameter => {
+                "`#` is not followed by a macro parameter".to_string()
+            }
+            Message::DuplicateMacroParameter(param) => {
+                format!("duplicate macro parameter `{}`", param)
+            }
+            Message::MalformedMacroParameters => "malformed macro parameter list".to_string(),
+            Message::UnterminatedMacroCall(name) => {
+                format!("unterminated call to macro `{}`", name)
+            }
+            Message::MacroArgumentCount {
+                name,
+                expected,
+                found,
+            } => format!(
+                "macro `{}` takes {} arguments, but {} given",
+                name, expected, found
+            ),
+            Message::IncludeNotFound(name) => format!("`{}` file not found", name),
+            Message::IncludeTooDeep => "#include nested too deeply".to_string(),
+            Message::IncludeReadFailed(path, err) => format!("cannot read `{}`: {}", path, err),
+            Message::MalformedDefined => "malformed `defined`".to_string(),
+            Message::ExtraTokenInIf(token) => {
+                format!("extra token `{}` at end of #if expression", token)
+            }
+            Message::MissingColonInIf => "expected `:` for `?` in #if expression".to_string(),
+            Message::DivisionByZeroInIf => "division by zero in #if expression".to_string(),
+            Message::DivisionOverflowInIf => "division overflows in #if expression".to_string(),
+            Message::UnclosedParenInIf => "missing `)` in #if expression".to_string(),
+            Message::InvalidTokenInIf(token) => {
+                format!("token `{}` is not valid in #if expression", token)
+            }
+            Message::MissingIfExpression => "#if with no expression".to_string(),
+            Message::InvalidNumberInIf(number) => {
+                format!("invalid number `{}` in #if expression", number)
+            }
+            Message::InvalidCharInIf(c) => {
+                format!("invalid character constant `{}` in #if expression", c)
+            }
+            Message::UnterminatedComment => "unterminated comment".to_string(),
+            Message::UnterminatedQuote => "missing terminating quote".to_string(),
+            Message::Expected {
+                expected,
+                context,
+                found,
+            } => format!(
+                "expected `{}` {}, found {}",
+                expected,
+                context.english(),
+                found_english(found)
+            ),
+            Message::ExpectedExpression(found) => {
+                format!("expected expression, found {}", found_english(found))
+            }
+            Message::ExpectedTypeSpecifier(found) => {
+                format!("expected type specifier, found {}", found_english(found))
+            }
+            Message::ExpectedMemberName(found) => {
+                format!("expected member name, found {}", found_english(found))
+            }
+            Message::ExpectedVariableName(found) => {
+                format!("expected variable name, found {}", found_english(found))
+            }
+            Message::ToMatch(open) => format!("to match this `{}`", open),
+            Message::InvalidTypeCombination(t, ty) => {
+                format!("cannot combine `{}` with `{}`", t, ty)
+            }
+            Message::StorageClassOnMember => {
+                "storage class is not allowed for a struct member".to_string()
+            }
+            Message::StorageClassOnParameter => {
+                "storage class is not allowed for a parameter".to_string()
+            }
+            Message::MissingParameterName(fun) => {
+                format!("parameter name omitted in definition of `{}`", fun)
+            }
+            Message::InlineOnNonFunction => "`inline` is only allowed for a function".to_string(),
+            Message::NonConstantInitializer(var) => {
+                format!("initializer of static variable `{}` is not a constant", var)
+            }
+            Message::ExternInitialized(var) => {
+                format!("extern variable `{}` cannot be initialized", var)
+            }
+            Message::InvalidConversion { from, to } => {
+                format!("cannot convert `{}` to `{}`", from, to)
+            }
+            Message::UndefinedVariable(var) => format!("undefined variable `{}`", var),
+            Message::AssignToConst(t) => format!("cannot assign to const lvalue of type `{}`", t),
+            Message::DuplicateMember { tag, member } => {
+                format!("duplicate member `{}` in struct `{}`", member, tag)
+            }
+            Message::IncompleteStruct(tag) => format!("incomplete type `struct {}`", tag),
+            Message::NotAStruct(t) => format!("member reference to non-struct type `{}`", t),
+            Message::NoSuchMember { t, member } => format!("`{}` has no member `{}`", t, member),
+            Message::DiscardedQualifiers { context, from, to } => format!(
+                "{} from `{}` to `{}` discards qualifiers of the pointed-to type",
+                context.english(),
+                from,
+                to
+            ),
+            Message::UnreachableCode(fun) => {
+                format!("code in function `{}` will never be executed", fun)
+            }
+            Message::WriteFailed(err) => format!("cannot write assembly: {}", err),
+            Message::ReadFailed(path, err) => format!("cannot read `{}`: {}", path, err),
+            Message::MissingOptionArgument(opt) => format!("missing argument to `{}`", opt),
+            Message::UnknownLanguage(opt) => format!("unknown language in `{}`", opt),
+            Message::UnknownDiagnosticsFormat(opt) => {
+                format!("unknown diagnostics format in `{}`", opt)
+            }
+            Message::InvalidOptLevel(opt) => format!("invalid optimization level `{}`", opt),
+            Message::MultipleInputs => "more than one input given".to_string(),
+            Message::NoInput => "no input".to_string(),
+        }
+    }
+
+    fn japanese(&self) -> String {
+        match self {
+            Message::InvalidNumber(s) => format!("数値の形式がおかしい: `{}`", s),
+            Message::InvalidNumberSuffix(s) => format!("数値の接尾辞がおかしい: `{}`", s),
+            Message::MissingExponentDigits(s) => format!("指数部に数字がない: `{}`", s),
+            Message::IntegerTooLarge(s) => format!("整数定数が大きすぎる: `{}`", s),
+            Message::UnknownCharacter(c) => format!("不明な文字 `{}`", c),
+            Message::UnterminatedConditional => "`#if` に対応する `#endif` がない".to_string(),
+            Message::UnmatchedDirective(name) => format!("対応する `#if` のない `#{}`", name),
+            Message::MalformedDirective(name) => format!("`#{}` の形式がおかしい", name),
+            Message::ErrorDirective(text) => format!("#error {}", text),
+            Message::WarningDirective(text) => format!("#warning {}", text),
+            Message::UnknownDirective(name) => format!("不明なディレクティブ `#{}`", name),
+            Message::MissingMacroName => "マクロの名前がない".to_string(),
+            Message::DefinedAsMacroName => "`defined` はマクロの名前にできない".to_string(),
+            Message::PasteAtEdge => "`##` がマクロの端にある".to_string(),
+            Message::InvalidPaste(lhs, rhs) => format!(
+                "`##` で `{}` と `{}` をつなげても一つのトークンにならない",
+                lhs, rhs
+            ),
+            Message::StringifyNonParameter => "`#` の後ろが仮引数でない".to_string(),
+            Message::DuplicateMacroParameter(param) => {
+                format!("マクロの仮引数 `{}` が重複している", param)
+            }
+            Message::MalformedMacroParameters => "マクロの仮引数の形式がおかしい".to_string(),
+            Message::UnterminatedMacroCall(name) => {
+                format!("マクロ `{}` の呼び出しが閉じていない", name)
+            }
+            Message::MacroArgumentCount {
+                name,
+                expected,
+                found,
+            } => format!(
+                "マクロ `{}` の引数の数が違う: {}個必要なところに{}個",
+                name, expected, found
+            ),
+            Message::IncludeNotFound(name) => format!("`{}` が見つからない", name),
+            Message::IncludeTooDeep => "#include の入れ子が深すぎる".to_string(),
+            Message::IncludeReadFailed(path, err) => format!("`{}` を読めない: {}", path, err),
+            Message::MalformedDefined => "`defined` の形式がおかしい".to_string(),
+            Message::ExtraTokenInIf(token) => {
+                format!("#if の式の後ろに余分なトークン `{}` がある", token)
+            }
+            Message::MissingColonInIf => "#if の式の `?` に対応する `:` がない".to_string(),
+            Message::DivisionByZeroInIf => "#if の式で0による除算".to_string(),
+            Message::DivisionOverflowInIf => "#if の式の除算がオーバーフローする".to_string(),
+            Message::UnclosedParenInIf => "#if の式のかっこが閉じていない".to_string(),
+            Message::InvalidTokenInIf(token) => {
+                format!("#if の式に使えないトークン `{}`", token)
+            }
+            Message::MissingIfExpression => "#if の式がない".to_string(),
+            Message::InvalidNumberInIf(number) => {
+                format!("#if の式の数値の形式がおかしい: `{}`", number)
+            }
+            Message::InvalidCharInIf(c) => {
+                format!("#if の式の文字定数の形式がおかしい: `{}`", c)
+            }
+            Message::UnterminatedComment => "コメントが閉じていない".to_string(),
+            Message::UnterminatedQuote => "引用符が閉じていない".to_string(),
+            Message::Expected {
+                expected,
+                context,
+                found,
+            } => format!(
+                "{}に `{}` が必要だが、{}",
+                context.japanese(),
+                expected,
+                found_japanese(found)
+            ),
+            Message::ExpectedExpression(found) => {
+                format!("式が必要だが、{}", found_japanese(found))
+            }
+            Message::ExpectedTypeSpecifier(found) => {
+                format!("型指定子が必要だが、{}", found_japanese(found))
+            }
+            Message::ExpectedMemberName(found) => {
+                format!("メンバ名が必要だが、{}", found_japanese(found))
+            }
+            Message::ExpectedVariableName(found) => {
+                format!("変数名が必要だが、{}", found_japanese(found))
+            }
+            Message::ToMatch(open) => format!("この `{}` に対応する", open),
+            Message::InvalidTypeCombination(t, ty) => {
+                format!("型指定子の組み合わせがおかしい: `{}` と `{}`", t, ty)
+            }
+            Message::StorageClassOnMember => "メンバに記憶域クラスは指定できない".to_string(),
+            Message::StorageClassOnParameter => "仮引数に記憶域クラスは指定できない".to_string(),
+            Message::MissingParameterName(fun) => {
+                format!("関数 `{}` の定義に仮引数名がない", fun)
+            }
+            Message::InlineOnNonFunction => "`inline` は関数にだけ指定できる".to_string(),
+            Message::NonConstantInitializer(var) => {
+                format!("静的な変数 `{}` の初期化子が定数でない", var)
+            }
+            Message::ExternInitialized(var) => {
+                format!("externな変数 `{}` は初期化できない", var)
+            }
+            Message::InvalidConversion { from, to } => {
+                format!("`{}` から `{}` には変換できない", from, to)
+            }
+            Message::UndefinedVariable(var) => format!("未定義変数 `{}`", var),
+            Message::AssignToConst(t) => format!("constな左辺値 (`{}`) には代入できない", t),
+            Message::DuplicateMember { tag, member } => {
+                format!("構造体 `{}` のメンバ `{}` が重複している", tag, member)
+            }
+            Message::IncompleteStruct(tag) => format!("不完全な構造体型 `struct {}`", tag),
+            Message::NotAStruct(t) => format!("構造体でない型 `{}` のメンバを参照している", t),
+            Message::NoSuchMember { t, member } => {
+                format!("`{}` にメンバ `{}` はない", t, member)
+            }
+            Message::DiscardedQualifiers { context, from, to } => format!(
+                "{}で `{}` から `{}` への変換によりポインタの指す先の修飾子が捨てられる",
+                context.japanese(),
+                from,
+                to
+            ),
+            Message::UnreachableCode(fun) => format!("関数 `{}` に実行されないコードがある", fun),
+            Message::WriteFailed(err) => format!("アセンブリを書き出せない: {}", err),
+            Message::ReadFailed(path, err) => format!("`{}` を読めない: {}", path, err),
+            Message::MissingOptionArgument(opt) => format!("`{}` の後ろに引数がない", opt),
+            Message::UnknownLanguage(opt) => format!("不明な言語: `{}`", opt),
+            Message::UnknownDiagnosticsFormat(opt) => format!("不明な診断の形式: `{}`", opt),
+            Message::InvalidOptLevel(opt) => format!("不明な最適化のレベル: `{}`", opt),
+            Message::MultipleInputs => "入力が二つ以上ある".to_string(),
+            Message::NoInput => "入力がない".to_string(),
+        }
+    }
+}
+
+#[test]
+fn message_catalogue_test() {
+    let message = Message::Expected {
+        expected: ";".to_string(),
+        context: Context::AfterExpression,
+        found: Some("}".to_string()),
+    };
+    assert_eq!(
+        message.render(Lang::En),
+        "error[E0101]: expected `;` after expression, found `}`"
+    );
+    assert_eq!(
+        message.render(Lang::Ja),
+        "error[E0101]: 式の後ろに `;` が必要だが、`}` がある"
+    );
+    assert_eq!(Lang::from_name("ja_JP.UTF-8"), Some(Lang::Ja));
+    assert_eq!(Lang::from_name("en_US.UTF-8"), Some(Lang::En));
+    assert_eq!(Lang::from_name("fr"), None);
+}
+
+#[test]
+fn json_diagnostic_test() {
+    let location = |line, column| Location {
+        file: Rc::from("a \"b\".c"),
+        line,
+        column,
+    };
+    let mut diagnostic = Diagnostic::new(Message::Expected {
+        expected: ")".to_string(),
+        context: Context::CloseParen,
+        found: Some(";".to_string()),
+    });
+    diagnostic.location = Some(location(2, 10));
+    diagnostic.end = Some(location(2, 11));
+    let mut note = Diagnostic::new(Message::ToMatch("(".to_string()));
+    note.location = Some(location(1, 3));
+    diagnostic.notes.push(note);
+    let reporter = Reporter {
+        lang: Lang::En,
+        format: Format::Json,
+    };
+    assert_eq!(
+        reporter.render(&diagnostic),
+        "{\"code\":\"E0101\",\"severity\":\"error\",\
+         \"message\":\"expected `)` to close parenthesized expression, found `;`\",\
+         \"file\":\"a \\\"b\\\".c\",\"line\":2,\"column\":10,\"end_line\":2,\"end_column\":11,\
+         \"notes\":[{\"severity\":\"note\",\"message\":\"to match this `(`\",\
+         \"file\":\"a \\\"b\\\".c\",\"line\":1,\"column\":3,\"end_line\":null,\"end_column\":null}]}"
+    );
+    let reporter = Reporter {
+        lang: Lang::En,
+        format: Format::Human,
+    };
+    assert_eq!(
+        reporter.render(&diagnostic),
+        "a \"b\".c:2:10: error[E0101]: expected `)` to close parenthesized expression, found `;`\n\
+         a \"b\".c:1:3: note: to match this `(`"
+    );
+    assert_eq!(
+        reporter.render(&Diagnostic::new(Message::UndefinedVariable(
+            "x".to_string()
+        ))),
+        "error[E0204]: undefined variable `x`"
+    );
+}
@@ -1,57 +1,179 @@
+// 整数定数式の計算と、構文木の定数畳み込み。
+// 値は実行時と同じになるように型の幅で計算する。符号付きのオーバーフローは2の補数で折り返し、
+// 0での除算とオーバーフローする除算 (INT_MIN / -1) は実行時に例外になるので畳み込まない
+#[cfg(test)]
 use crate::lexer::tokenize;
-use crate::parser::{parse_exp, Exp, Op};
+use crate::lower::{arith_type, int_bin_op, is_integer, ty_of};
+use crate::opt::fold_binary;
+#[cfg(test)]
+use crate::parser::parse_exp;
+use crate::parser::{Exp, Op, Program, Stmt, TypeDec};
 
-enum Val {
-    Int(i32),
-    B(bool),
-    Var(String),
+// 整数定数式なら、その値と型。値は型の幅で符号拡張かゼロ拡張した64ビットで持つ
+pub fn eval_const(exp: &Exp) -> Option<(i64, TypeDec)> {
+    match exp {
+        Exp::Int(i, t) if is_integer(t) => Some((normalize(*i, t), t.unqualified().clone())),
+        Exp::InfixExp {
+            left, op, right, ..
+        } if *op != Op::Assign => {
+            let (left, left_type) = eval_const(left)?;
+            let (right, right_type) = eval_const(right)?;
+            let t = arith_type(&left_type, &right_type);
+            let value = eval_binary(op, &t, left, right)?;
+            match op {
+                Op::Plus | Op::Minus | Op::Asterisk | Op::Slash => Some((value, t)),
+                _ => Some((value, TypeDec::Int)),
+            }
+        }
+        _ => None,
+    }
+}
+
+// 型tにそろえた二つの整数の演算。比較の結果は0か1になる。
+// 畳み込めない除算ならNone
+pub fn eval_binary(op: &Op, t: &TypeDec, left: i64, right: i64) -> Option<i64> {
+    let op = int_bin_op(op, t.is_unsigned());
+    let value = fold_binary(op, ty_of(t), left, right)?;
+    if op.is_comparison() {
+        Some(value)
+    } else {
+        Some(normalize(value, t))
+    }
+}
+
+// 整数の型tの値として64ビットに広げる。intは符号拡張、unsigned intはゼロ拡張する
+fn normalize(value: i64, t: &TypeDec) -> i64 {
+    match t.unqualified() {
+        TypeDec::Int => value as i32 as i64,
+        TypeDec::UInt => value as u32 as i64,
+        _ => value,
+    }
+}
+
+// 定数だけでできた部分式をExp::Intに置き換える
+pub fn fold_program(program: Program) -> Program {
+    program.into_iter().map(fold_stmt).collect()
+}
+
+fn fold_stmt(stmt: Stmt) -> Stmt {
+    match stmt {
+        Stmt::Exp(exp, pos) => Stmt::Exp(fold_exp(exp), pos),
+        Stmt::Return(exp, pos) => Stmt::Return(fold_exp(exp), pos),
+        Stmt::Block(stmts, pos) => Stmt::Block(fold_program(stmts), pos),
+        Stmt::If {
+            cond,
+            stmt1,
+            stmt2,
+            pos,
+        } => Stmt::If {
+            cond: Box::new(fold_exp(*cond)),
+            stmt1: Box::new(fold_stmt(*stmt1)),
+            stmt2: Box::new(stmt2.map(fold_stmt)),
+            pos,
+        },
+        Stmt::While { cond, stmt, pos } => Stmt::While {
+            cond: Box::new(fold_exp(*cond)),
+            stmt: Box::new(fold_stmt(*stmt)),
+            pos,
+        },
+        Stmt::For {
+            exp1,
+            exp2,
+            exp3,
+            stmt,
+            pos,
+        } => Stmt::For {
+            exp1: Box::new(exp1.map(fold_exp)),
+            exp2: Box::new(exp2.map(fold_exp)),
+            exp3: Box::new(exp3.map(fold_exp)),
+            stmt: Box::new(fold_stmt(*stmt)),
+            pos,
+        },
+        Stmt::Func {
+            t,
+            fun,
+            params,
+            body,
+            storage,
+            inline,
+            pos,
+        } => Stmt::Func {
+            t,
+            fun,
+            params,
+            body: fold_program(body),
+            storage,
+            inline,
+            pos,
+        },
+        Stmt::VarDec {
+            t,
+            var,
+            storage,
+            init,
+            pos,
+        } => Stmt::VarDec {
+            t,
+            var,
+            storage,
+            init: Box::new(init.map(fold_exp)),
+            pos,
+        },
+        stmt @ Stmt::StructDec { .. } => stmt,
+    }
 }
 
-fn eval_exp(exp: Exp) -> Val {
+fn fold_exp(exp: Exp) -> Exp {
     match exp {
-        Exp::InfixExp { left, op, right } => {
-            let left = eval_exp(*left);
-            let right = eval_exp(*right);
-            let (left, right) = match (left, right) {
-                (Val::Int(left), Val::Int(right)) => (left, right),
-                _ => panic!("error"),
+        Exp::InfixExp {
+            left,
+            op,
+            right,
+            pos,
+        } => {
+            let exp = Exp::InfixExp {
+                left: Box::new(fold_exp(*left)),
+                op,
+                right: Box::new(fold_exp(*right)),
+                pos,
             };
-            match op {
-                Op::Plus => Val::Int(left + right),
-                Op::Minus => Val::Int(left - right),
-                Op::Asterisk => Val::Int(left * right),
-                Op::Slash => Val::Int(left / right),
-                Op::Eq => Val::B(left == right),
-                Op::NotEq => Val::B(left != right),
-                Op::Ls => Val::B(left < right),
-                Op::Gr => Val::B(left > right),
-                Op::LsEq => Val::B(left <= right),
-                Op::GrEq => Val::B(left >= right),
-                _ => panic!("未対応"),
+            match eval_const(&exp) {
+                Some((value, t)) => Exp::Int(value, t),
+                None => exp,
             }
         }
-        Exp::Int(i) => Val::Int(i),
-        Exp::Var(v) => Val::Var(v),
-        _ => panic!("未対応"),
+        Exp::UnaryExp { op, exp, pos } => Exp::UnaryExp {
+            op,
+            exp: Box::new(fold_exp(*exp)),
+            pos,
+        },
+        Exp::FuncCall { fun, args, pos } => Exp::FuncCall {
+            fun: Box::new(fold_exp(*fun)),
+            args: args.into_iter().map(fold_exp).collect(),
+            pos,
+        },
+        Exp::Member { exp, member, pos } => Exp::Member {
+            exp: Box::new(fold_exp(*exp)),
+            member,
+            pos,
+        },
+        exp => exp,
     }
 }
 
+#[cfg(test)]
 fn get_int_result_from_string(str: &str) -> i32 {
     let tokens = tokenize(str).ok().unwrap();
     let (exp, _) = parse_exp(tokens.as_slice()).ok().unwrap();
-    match eval_exp(exp) {
-        Val::Int(i) => i,
-        _ => panic!("error"),
+    match fold_exp(exp) {
+        Exp::Int(i, TypeDec::Int) => i as i32,
+        exp => panic!("not folded: {:?}", exp),
     }
 }
 
+#[cfg(test)]
 fn get_bool_result_from_string(str: &str) -> bool {
-    let tokens = tokenize(str).ok().unwrap();
-    let (exp, _) = parse_exp(tokens.as_slice()).ok().unwrap();
-    match eval_exp(exp) {
-        Val::B(b) => b,
-        _ => panic!("error"),
-    }
+    get_int_result_from_string(str) != 0
 }
 
 #[test]
@@ -83,6 +205,7 @@ fn parse_exp_test() {
 }
 
 #[test]
+#[allow(clippy::bool_assert_comparison)]
 fn eval_exp_bool_test() {
     assert_eq!(get_bool_result_from_string("1 == 1"), true);
     assert_eq!(get_bool_result_from_string("1 != 1"), false);
@@ -99,3 +222,48 @@ fn eval_exp_bool_test() {
     assert_eq!(get_bool_result_from_string("1 < 1"), false);
     assert_eq!(get_bool_result_from_string("1 <= 1"), true);
 }
+
+#[test]
+fn fold_types_test() {
+    let fold_str = |src: &str| {
+        let tokens = tokenize(src).ok().unwrap();
+        fold_exp(parse_exp(tokens.as_slice()).ok().unwrap().0)
+    };
+    // intのオーバーフローは32ビットで折り返す
+    assert_eq!(
+        fold_str("2147483647 + 1"),
+        Exp::Int(-2147483648, TypeDec::Int)
+    );
+    assert_eq!(fold_str("0 - 1u"), Exp::Int(4294967295, TypeDec::UInt));
+    assert_eq!(
+        fold_str("4294967295u / 2"),
+        Exp::Int(2147483647, TypeDec::UInt)
+    );
+    assert_eq!(fold_str("-1 < 0u"), Exp::Int(0, TypeDec::Int));
+    assert_eq!(fold_str("-1 < 0l"), Exp::Int(1, TypeDec::Int));
+    assert_eq!(fold_str("-1 + 0ul"), Exp::Int(-1, TypeDec::ULong));
+    assert_eq!(
+        fold_str("2147483647l + 1"),
+        Exp::Int(2147483648, TypeDec::Long)
+    );
+    // 0での除算とINT_MIN / -1は実行時まで残す。ほかの定数の部分は畳み込む
+    match fold_str("(1 + 1) / (2 - 2)") {
+        Exp::InfixExp {
+            left, op, right, ..
+        } => {
+            assert_eq!(op, Op::Slash);
+            assert_eq!(*left, Exp::Int(2, TypeDec::Int));
+            assert_eq!(*right, Exp::Int(0, TypeDec::Int));
+        }
+        exp => panic!("folded: {:?}", exp),
+    }
+    assert!(matches!(
+        fold_str("(0 - 2147483647 - 1) / -1"),
+        Exp::InfixExp { op: Op::Slash, .. }
+    ));
+    // 変数を含む部分は残す
+    match fold_str("x + 2 * 3") {
+        Exp::InfixExp { right, .. } => assert_eq!(*right, Exp::Int(6, TypeDec::Int)),
+        exp => panic!("{:?}", exp),
+    }
+}
@@ -0,0 +1,259 @@
+// インライン展開。-O2で、小さな葉関数 (ほかの関数を呼ばない関数) の呼び出しを本体の複製で置き換える。
+// 葉関数だけを展開するので、再帰する関数が展開されることはない。
+// 呼び出し先の仮想レジスタ・スロット・ブロックは呼び出し元に新しい番号で足し、仮引数には引数をコピーする。
+// retは戻り値をスロットに書いて呼び出しの後ろのブロックへ飛ぶjmpにするので、
+// 展開したコードは呼び出し先の.L.return.<name>を使わない。戻り値のスロットはSSA形式にするときに昇格される
+use crate::ir::{
+    Abi, Block, BlockId, Callee, Function, Inst, Module, Operand, Slot, Terminator, Ty, VReg,
+};
+use crate::ssa::{new_vreg, ty_size};
+use std::collections::{HashMap, HashSet};
+
+// この命令数までの関数を展開する。inline指定があればINLINE_HINT_SIZEまで
+const INLINE_SIZE: usize = 16;
+const INLINE_HINT_SIZE: usize = 64;
+
+pub fn inline_functions(module: &mut Module) {
+    let callees: HashMap<String, Function> = module
+        .functions
+        .iter()
+        .filter(|f| is_inlinable(f))
+        .map(|f| (f.name.clone(), f.clone()))
+        .collect();
+    if callees.is_empty() {
+        return;
+    }
+    for f in module.functions.iter_mut() {
+        while let Some((block, index)) = find_call(f, &callees) {
+            inline_call(f, block, index, &callees);
+        }
+    }
+    remove_unused_static_functions(module);
+}
+
+// 引数と戻り値がスカラーの、小さな葉関数
+fn is_inlinable(f: &Function) -> bool {
+    let insts = || f.blocks.iter().flat_map(|block| block.insts.iter());
+    let limit = if f.inline {
+        INLINE_HINT_SIZE
+    } else {
+        INLINE_SIZE
+    };
+    !insts().any(|inst| matches!(inst, Inst::Call { .. }))
+        && insts().count() <= limit
+        && matches!(f.ret, Abi::Scalar(_))
+        && f.params
+            .iter()
+            .all(|(_, abi)| matches!(abi, Abi::Scalar(_)))
+}
+
+// 展開できる呼び出しの位置。引数の数や渡し方が定義と合わない呼び出しは展開しない
+fn find_call(f: &Function, callees: &HashMap<String, Function>) -> Option<(usize, usize)> {
+    for (i, block) in f.blocks.iter().enumerate() {
+        for (j, inst) in block.insts.iter().enumerate() {
+            if let Inst::Call {
+                callee: Callee::Symbol(name),
+                args,
+                ret,
+                ..
+            } = inst
+            {
+                let matches = callees.get(name).is_some_and(|callee| {
+                    callee.ret == *ret
+                        && callee.params.len() == args.len()
+                        && callee
+                            .params
+                            .iter()
+                            .zip(args)
+                            .all(|((_, param), (_, arg))| param == arg)
+                });
+                if matches {
+                    return Some((i, j));
+                }
+            }
+        }
+    }
+    None
+}
+
+// blockのindex番目の呼び出しを展開する。呼び出しの後ろの命令は新しいブロックに移す
+fn inline_call(f: &mut Function, block: usize, index: usize, callees: &HashMap<String, Function>) {
+    let mut rest = f.blocks[block].insts.split_off(index);
+    let (dst, callee, args) = match rest.remove(0) {
+        Inst::Call {
+            dst,
+            callee: Callee::Symbol(name),
+            args,
+            ..
+        } => (dst, &callees[&name], args),
+        inst => panic!("展開できる呼び出しではない: {:?}", inst),
+    };
+    let ty = match callee.ret {
+        Abi::Scalar(ty) => ty,
+        Abi::Struct { .. } => panic!("構造体を返す関数は展開しない"),
+    };
+    let vreg_base = f.vregs.len();
+    let slot_base = f.slots.len();
+    let block_base = f.blocks.len();
+    f.vregs.extend(callee.vregs.iter().copied());
+    f.slots.extend(callee.slots.iter().copied());
+    let result = Slot(f.slots.len());
+    f.slots.push(ty_size(ty));
+    let next = BlockId(block_base + callee.blocks.len());
+
+    let vreg = |reg: VReg| VReg(reg.0 + vreg_base);
+    let operand = |operand: &Operand| match operand {
+        Operand::Reg(reg) => Operand::Reg(vreg(*reg)),
+        imm => *imm,
+    };
+    let to = |id: BlockId| BlockId(id.0 + block_base);
+    for ((param, _), (arg, _)) in callee.params.iter().zip(args) {
+        f.blocks[block].insts.push(Inst::Copy {
+            dst: vreg(*param),
+            src: arg,
+        });
+    }
+    let term = std::mem::replace(&mut f.blocks[block].term, Terminator::Jump(to(BlockId(0))));
+
+    for callee_block in &callee.blocks {
+        let mut insts = callee_block.insts.clone();
+        for inst in insts.iter_mut() {
+            if let Some(dst) = inst.dst_mut() {
+                *dst = vreg(*dst);
+            }
+            for operand in inst.operands_mut() {
+                if let Operand::Reg(reg) = operand {
+                    *reg = vreg(*reg);
+                }
+            }
+            if let Inst::SlotAddr { slot, .. } = inst {
+                *slot = Slot(slot.0 + slot_base);
+            }
+        }
+        let term = match &callee_block.term {
+            Terminator::Jump(target) => Terminator::Jump(to(*target)),
+            Terminator::Branch { cond, then, els } => Terminator::Branch {
+                cond: operand(cond),
+                then: to(*then),
+                els: to(*els),
+            },
+            Terminator::Ret(value) => {
+                if let Some(value) = value {
+                    let addr = new_vreg(f, Ty::I64);
+                    insts.push(Inst::SlotAddr {
+                        dst: addr,
+                        slot: result,
+                    });
+                    insts.push(Inst::Store {
+                        ty,
+                        addr: Operand::Reg(addr),
+                        value: operand(value),
+                        volatile: false,
+                    });
+                }
+                Terminator::Jump(next)
+            }
+        };
+        f.blocks.push(Block { insts, term });
+    }
+
+    // 最後まで実行してretに値がなかったときの戻り値は不定なので、書く前のスロットを読んでよい
+    let addr = new_vreg(f, Ty::I64);
+    let mut insts = vec![
+        Inst::SlotAddr {
+            dst: addr,
+            slot: result,
+        },
+        Inst::Load {
+            dst,
+            ty,
+            addr: Operand::Reg(addr),
+            volatile: false,
+        },
+    ];
+    insts.append(&mut rest);
+    f.blocks.push(Block { insts, term });
+}
+
+// 展開してどこからも呼ばれなくなったstaticな関数を消す
+fn remove_unused_static_functions(module: &mut Module) {
+    let mut used = HashSet::new();
+    for inst in module
+        .functions
+        .iter()
+        .flat_map(|f| f.blocks.iter())
+        .flat_map(|block| block.insts.iter())
+    {
+        match inst {
+            Inst::Call {
+                callee: Callee::Symbol(name),
+                ..
+            }
+            | Inst::SymbolAddr { symbol: name, .. } => {
+                used.insert(name.clone());
+            }
+            _ => (),
+        }
+    }
+    module
+        .functions
+        .retain(|f| f.global || used.contains(&f.name));
+}
+
+#[test]
+fn inline_functions_test() {
+    let inline_str = |src: &str| {
+        let mut module = crate::lower::module_of(src).ok().unwrap();
+        inline_functions(&mut module);
+        module
+    };
+    let calls = |f: &Function| -> Vec<String> {
+        f.blocks
+            .iter()
+            .flat_map(|block| block.insts.iter())
+            .filter_map(|inst| match inst {
+                Inst::Call {
+                    callee: Callee::Symbol(name),
+                    ..
+                } => Some(name.clone()),
+                _ => None,
+            })
+            .collect()
+    };
+
+    // 葉関数は展開する。どこからも呼ばれなくなったstaticな関数は消し、外から見える関数は残す
+    let module = inline_str(
+        "int add2(int a, int b) { return a + b; } static int twice(int x) { return x * 2; } \
+         int main() { return add2(1, twice(3)); }",
+    );
+    let names: Vec<&str> = module.functions.iter().map(|f| f.name.as_str()).collect();
+    assert_eq!(names, vec!["add2", "main"]);
+    assert!(calls(&module.functions[1]).is_empty());
+    // 戻り値はスロットを通して呼び出しの後ろのブロックに渡す
+    let main = &module.functions[1];
+    assert!(matches!(main.blocks[0].term, Terminator::Jump(BlockId(1))));
+    let last = main.blocks.last().unwrap();
+    assert!(matches!(last.insts[1], Inst::Load { .. }));
+    assert!(matches!(last.term, Terminator::Ret(Some(_))));
+
+    // 再帰する関数やほかの関数を呼ぶ関数は展開しない
+    let module = inline_str(
+        "int fact(int n) { if (n < 2) return 1; return n * fact(n - 1); } \
+         int g(int x) { return fact(x); } int main() { return g(3); }",
+    );
+    assert_eq!(calls(&module.functions[2]), vec!["g"]);
+
+    // 大きな関数はinline指定があるときだけ展開する
+    let body = "{ x = x + 1; x = x + 2; x = x + 3; x = x + 4; return x; }";
+    let module = inline_str(&format!(
+        "int big(int x) {} int main() {{ return big(1); }}",
+        body
+    ));
+    assert_eq!(calls(&module.functions[1]), vec!["big"]);
+    let module = inline_str(&format!(
+        "inline int big(int x) {} int main() {{ return big(1); }}",
+        body
+    ));
+    assert!(module.functions[0].inline);
+    assert!(calls(&module.functions[1]).is_empty());
+}
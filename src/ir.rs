@@ -0,0 +1,556 @@
+// 構文木とアセンブリの間に置く中間表現。
+// 仮想レジスタに値を置く三番地コードで、関数は基本ブロックの並びからなる。
+// 変数はスロット (スタックの領域) かシンボルのアドレスを通して明示的にload/storeする。
+// lowerが構文木から作り、codegenがx86-64のアセンブリにする。Displayでテキストに書き出せる
+use crate::abi::ArgClass;
+use std::fmt;
+
+// 仮想レジスタの型。intとunsigned intはi32、long・ポインタ・構造体のアドレスはi64になる。
+// 符号の有無は型ではなく命令 (除算、比較、拡張) で区別する
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Ty {
+    I32,
+    I64,
+    F32,
+    F64,
+}
+
+impl Ty {
+    pub fn is_floating(self) -> bool {
+        matches!(self, Ty::F32 | Ty::F64)
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct VReg(pub usize);
+
+// 関数のスタックに取る名前のない領域。ローカル変数や構造体の一時領域に使う
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub struct Slot(pub usize);
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash, Ord, PartialOrd)]
+pub struct BlockId(pub usize);
+
+// 命令の引数。即値の型は使われる命令の型で決まり、浮動小数点数ならビット列を表す
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum Operand {
+    Reg(VReg),
+    Imm(i64),
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum BinOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+    UDiv,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    ULt,
+    ULe,
+    UGt,
+    UGe,
+}
+
+impl BinOp {
+    // 比較の結果はi32の0か1になる
+    pub fn is_comparison(self) -> bool {
+        !matches!(
+            self,
+            BinOp::Add | BinOp::Sub | BinOp::Mul | BinOp::Div | BinOp::UDiv
+        )
+    }
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum ConvOp {
+    // i32からi64への符号拡張とゼロ拡張
+    SExt,
+    ZExt,
+    // i64の下位32ビットを取る
+    Trunc,
+    // 符号付き整数 (i64) から浮動小数点数へ
+    IntToFloat,
+    // unsigned long (i64) から浮動小数点数へ
+    ULongToFloat,
+    // 浮動小数点数から符号付き整数 (i64) へ。小数部は切り捨てる
+    FloatToInt,
+    FloatExt,
+    FloatTrunc,
+}
+
+// 関数の引数と戻り値の渡し方。構造体はアドレスを値とし、ABIでの分類を持つ
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Abi {
+    Scalar(Ty),
+    Struct { size: i32, class: ArgClass },
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Callee {
+    Symbol(String),
+    Reg(Operand),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Inst {
+    // 浮動小数点数の定数はビット列で持つ
+    Const {
+        dst: VReg,
+        value: i64,
+    },
+    Copy {
+        dst: VReg,
+        src: Operand,
+    },
+    // tyは両辺の型。結果は比較ならi32、それ以外はty
+    Binary {
+        dst: VReg,
+        op: BinOp,
+        ty: Ty,
+        lhs: Operand,
+        rhs: Operand,
+    },
+    Convert {
+        dst: VReg,
+        op: ConvOp,
+        from: Ty,
+        src: Operand,
+    },
+    SlotAddr {
+        dst: VReg,
+        slot: Slot,
+    },
+    SymbolAddr {
+        dst: VReg,
+        symbol: String,
+    },
+    // volatileな読み書きは最適化で消したり並べ替えたりしない
+    Load {
+        dst: VReg,
+        ty: Ty,
+        addr: Operand,
+        volatile: bool,
+    },
+    Store {
+        ty: Ty,
+        addr: Operand,
+        value: Operand,
+        volatile: bool,
+    },
+    // srcが指すsizeバイトをdstが指す場所へコピーする
+    MemCopy {
+        dst: Operand,
+        src: Operand,
+        size: i32,
+    },
+    // 構造体を返す関数では、戻り値をret_slotに置き、そのアドレスがdstになる
+    Call {
+        dst: VReg,
+        callee: Callee,
+        args: Vec<(Operand, Abi)>,
+        ret: Abi,
+        ret_slot: Option<Slot>,
+    },
+    // SSA形式でだけ現れる。直前のブロックごとに値を選ぶ
+    Phi {
+        dst: VReg,
+        args: Vec<(BlockId, Operand)>,
+    },
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Terminator {
+    Jump(BlockId),
+    // condが0でなければthen、0ならelseへ
+    Branch {
+        cond: Operand,
+        then: BlockId,
+        els: BlockId,
+    },
+    // 値のないretは関数の終わりまで実行したとき
+    Ret(Option<Operand>),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Block {
+    pub insts: Vec<Inst>,
+    pub term: Terminator,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Function {
+    pub name: String,
+    pub global: bool,
+    // inline指定があった。-O2でのインライン展開の目安にする
+    pub inline: bool,
+    pub params: Vec<(VReg, Abi)>,
+    pub ret: Abi,
+    // スロットごとの大きさ
+    pub slots: Vec<i32>,
+    // 仮想レジスタごとの型
+    pub vregs: Vec<Ty>,
+    // 先頭が入口のブロック
+    pub blocks: Vec<Block>,
+}
+
+// .dataに置く静的な変数。初期値は変数の型でのビット列
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Data {
+    pub label: String,
+    pub global: bool,
+    pub size: i32,
+    pub align: i32,
+    pub init: Option<i64>,
+}
+
+#[derive(Debug, Clone, Default, Eq, PartialEq)]
+pub struct Module {
+    pub data: Vec<Data>,
+    pub functions: Vec<Function>,
+}
+
+impl Inst {
+    // 命令が値を書く仮想レジスタ
+    pub fn dst(&self) -> Option<VReg> {
+        match self {
+            Inst::Const { dst, .. }
+            | Inst::Copy { dst, .. }
+            | Inst::Binary { dst, .. }
+            | Inst::Convert { dst, .. }
+            | Inst::SlotAddr { dst, .. }
+            | Inst::SymbolAddr { dst, .. }
+            | Inst::Load { dst, .. }
+            | Inst::Call { dst, .. }
+            | Inst::Phi { dst, .. } => Some(*dst),
+            Inst::Store { .. } | Inst::MemCopy { .. } => None,
+        }
+    }
+
+    pub fn dst_mut(&mut self) -> Option<&mut VReg> {
+        match self {
+            Inst::Const { dst, .. }
+            | Inst::Copy { dst, .. }
+            | Inst::Binary { dst, .. }
+            | Inst::Convert { dst, .. }
+            | Inst::SlotAddr { dst, .. }
+            | Inst::SymbolAddr { dst, .. }
+            | Inst::Load { dst, .. }
+            | Inst::Call { dst, .. }
+            | Inst::Phi { dst, .. } => Some(dst),
+            Inst::Store { .. } | Inst::MemCopy { .. } => None,
+        }
+    }
+
+    // 命令が読むオペランド
+    pub fn operands(&self) -> Vec<&Operand> {
+        match self {
+            Inst::Const { .. } | Inst::SlotAddr { .. } | Inst::SymbolAddr { .. } => vec![],
+            Inst::Copy { src, .. } | Inst::Convert { src, .. } => vec![src],
+            Inst::Binary { lhs, rhs, .. } => vec![lhs, rhs],
+            Inst::Load { addr, .. } => vec![addr],
+            Inst::Store { addr, value, .. } => vec![addr, value],
+            Inst::MemCopy { dst, src, .. } => vec![dst, src],
+            Inst::Call { callee, args, .. } => {
+                let mut operands = vec![];
+                if let Callee::Reg(callee) = callee {
+                    operands.push(callee);
+                }
+                operands.extend(args.iter().map(|(arg, _)| arg));
+                operands
+            }
+            Inst::Phi { args, .. } => args.iter().map(|(_, arg)| arg).collect(),
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        match self {
+            Inst::Const { .. } | Inst::SlotAddr { .. } | Inst::SymbolAddr { .. } => vec![],
+            Inst::Copy { src, .. } | Inst::Convert { src, .. } => vec![src],
+            Inst::Binary { lhs, rhs, .. } => vec![lhs, rhs],
+            Inst::Load { addr, .. } => vec![addr],
+            Inst::Store { addr, value, .. } => vec![addr, value],
+            Inst::MemCopy { dst, src, .. } => vec![dst, src],
+            Inst::Call { callee, args, .. } => {
+                let mut operands = vec![];
+                if let Callee::Reg(callee) = callee {
+                    operands.push(callee);
+                }
+                operands.extend(args.iter_mut().map(|(arg, _)| arg));
+                operands
+            }
+            Inst::Phi { args, .. } => args.iter_mut().map(|(_, arg)| arg).collect(),
+        }
+    }
+
+    // 結果が使われなければ消してよい命令
+    pub fn is_pure(&self) -> bool {
+        match self {
+            Inst::Store { .. } | Inst::MemCopy { .. } | Inst::Call { .. } => false,
+            Inst::Load { volatile, .. } => !volatile,
+            _ => true,
+        }
+    }
+}
+
+impl Terminator {
+    pub fn successors(&self) -> Vec<BlockId> {
+        match self {
+            Terminator::Jump(block) => vec![*block],
+            Terminator::Branch { then, els, .. } => vec![*then, *els],
+            Terminator::Ret(_) => vec![],
+        }
+    }
+
+    pub fn operands_mut(&mut self) -> Vec<&mut Operand> {
+        match self {
+            Terminator::Jump(_) | Terminator::Ret(None) => vec![],
+            Terminator::Branch { cond, .. } => vec![cond],
+            Terminator::Ret(Some(value)) => vec![value],
+        }
+    }
+
+    pub fn operands(&self) -> Vec<&Operand> {
+        match self {
+            Terminator::Jump(_) | Terminator::Ret(None) => vec![],
+            Terminator::Branch { cond, .. } => vec![cond],
+            Terminator::Ret(Some(value)) => vec![value],
+        }
+    }
+}
+
+impl fmt::Display for Ty {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Ty::I32 => write!(f, "i32"),
+            Ty::I64 => write!(f, "i64"),
+            Ty::F32 => write!(f, "f32"),
+            Ty::F64 => write!(f, "f64"),
+        }
+    }
+}
+
+impl fmt::Display for VReg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "%{}", self.0)
+    }
+}
+
+impl fmt::Display for Slot {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "${}", self.0)
+    }
+}
+
+impl fmt::Display for BlockId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "bb{}", self.0)
+    }
+}
+
+impl fmt::Display for Operand {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Operand::Reg(reg) => write!(f, "{}", reg),
+            Operand::Imm(i) => write!(f, "{}", i),
+        }
+    }
+}
+
+impl fmt::Display for BinOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            BinOp::Add => "add",
+            BinOp::Sub => "sub",
+            BinOp::Mul => "mul",
+            BinOp::Div => "div",
+            BinOp::UDiv => "udiv",
+            BinOp::Eq => "eq",
+            BinOp::Ne => "ne",
+            BinOp::Lt => "lt",
+            BinOp::Le => "le",
+            BinOp::Gt => "gt",
+            BinOp::Ge => "ge",
+            BinOp::ULt => "ult",
+            BinOp::ULe => "ule",
+            BinOp::UGt => "ugt",
+            BinOp::UGe => "uge",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for ConvOp {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ConvOp::SExt => "sext",
+            ConvOp::ZExt => "zext",
+            ConvOp::Trunc => "trunc",
+            ConvOp::IntToFloat => "itof",
+            ConvOp::ULongToFloat => "ultof",
+            ConvOp::FloatToInt => "ftoi",
+            ConvOp::FloatExt => "fext",
+            ConvOp::FloatTrunc => "ftrunc",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl fmt::Display for Abi {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Abi::Scalar(ty) => write!(f, "{}", ty),
+            Abi::Struct { size, .. } => write!(f, "struct({})", size),
+        }
+    }
+}
+
+impl fmt::Display for Callee {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Callee::Symbol(symbol) => write!(f, "@{}", symbol),
+            Callee::Reg(operand) => write!(f, "{}", operand),
+        }
+    }
+}
+
+impl fmt::Display for Inst {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Inst::Const { dst, value } => write!(f, "{} = const {}", dst, value),
+            Inst::Copy { dst, src } => write!(f, "{} = copy {}", dst, src),
+            Inst::Binary {
+                dst,
+                op,
+                ty,
+                lhs,
+                rhs,
+            } => write!(f, "{} = {} {} {}, {}", dst, op, ty, lhs, rhs),
+            Inst::Convert { dst, op, from, src } => write!(f, "{} = {} {} {}", dst, op, from, src),
+            Inst::SlotAddr { dst, slot } => write!(f, "{} = addr {}", dst, slot),
+            Inst::SymbolAddr { dst, symbol } => write!(f, "{} = addr @{}", dst, symbol),
+            Inst::Load {
+                dst,
+                ty,
+                addr,
+                volatile,
+            } => {
+                let volatile = if *volatile { "volatile " } else { "" };
+                write!(f, "{} = load {}{} {}", dst, volatile, ty, addr)
+            }
+            Inst::Store {
+                ty,
+                addr,
+                value,
+                volatile,
+            } => {
+                let volatile = if *volatile { "volatile " } else { "" };
+                write!(f, "store {}{} {}, {}", volatile, ty, addr, value)
+            }
+            Inst::MemCopy { dst, src, size } => write!(f, "memcpy {}, {}, {}", dst, src, size),
+            Inst::Call {
+                dst,
+                callee,
+                args,
+                ret,
+                ret_slot,
+            } => {
+                let args: Vec<String> = args
+                    .iter()
+                    .map(|(arg, abi)| format!("{} {}", abi, arg))
+                    .collect();
+                write!(f, "{} = call {} {}({})", dst, ret, callee, args.join(", "))?;
+                match ret_slot {
+                    Some(slot) => write!(f, " -> {}", slot),
+                    None => Ok(()),
+                }
+            }
+            Inst::Phi { dst, args } => {
+                let args: Vec<String> = args
+                    .iter()
+                    .map(|(block, arg)| format!("[{}, {}]", block, arg))
+                    .collect();
+                write!(f, "{} = phi {}", dst, args.join(", "))
+            }
+        }
+    }
+}
+
+impl fmt::Display for Terminator {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Terminator::Jump(block) => write!(f, "jmp {}", block),
+            Terminator::Branch { cond, then, els } => write!(f, "br {}, {}, {}", cond, then, els),
+            Terminator::Ret(Some(value)) => write!(f, "ret {}", value),
+            Terminator::Ret(None) => write!(f, "ret"),
+        }
+    }
+}
+
+// 仮想レジスタの型は値を定義する命令と仮引数に書く
+impl fmt::Display for Function {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let params: Vec<String> = self
+            .params
+            .iter()
+            .map(|(reg, abi)| format!("{}: {}", reg, abi))
+            .collect();
+        write!(f, "function ")?;
+        if !self.global {
+            write!(f, "static ")?;
+        }
+        if self.inline {
+            write!(f, "inline ")?;
+        }
+        writeln!(f, "{}({}) -> {} {{", self.name, params.join(", "), self.ret)?;
+        for (i, size) in self.slots.iter().enumerate() {
+            writeln!(f, "  slot {}: {}", Slot(i), size)?;
+        }
+        for (i, block) in self.blocks.iter().enumerate() {
+            writeln!(f, "{}:", BlockId(i))?;
+            for inst in &block.insts {
+                match inst.dst() {
+                    Some(dst) => writeln!(f, "  {}  ; {}", inst, self.vregs[dst.0])?,
+                    None => writeln!(f, "  {}", inst)?,
+                }
+            }
+            writeln!(f, "  {}", block.term)?;
+        }
+        writeln!(f, "}}")
+    }
+}
+
+impl fmt::Display for Data {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "data ")?;
+        if !self.global {
+            write!(f, "static ")?;
+        }
+        write!(
+            f,
+            "{}: size {}, align {}",
+            self.label, self.size, self.align
+        )?;
+        match self.init {
+            Some(init) => writeln!(f, " = {}", init),
+            None => writeln!(f),
+        }
+    }
+}
+
+impl fmt::Display for Module {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for data in &self.data {
+            write!(f, "{}", data)?;
+        }
+        for function in &self.functions {
+            write!(f, "{}", function)?;
+        }
+        Ok(())
+    }
+}
@@ -1,3 +1,12 @@
+use crate::diagnostic::Message;
+use std::collections::HashMap;
+use std::fmt;
+use std::num::IntErrorKind;
+use std::rc::Rc;
+
+// 識別子の名前。同じ名前は字句解析中に一つの文字列を共有する (intern)
+pub type Symbol = Rc<str>;
+
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum Token {
     Plus,
@@ -14,8 +23,10 @@ pub enum Token {
     LParen,
     RParen,
     Semicolon,
-    Int(i32),
-    Var(String),
+    Int(i64, IntType),
+    // 浮動小数点数のリテラル。EqとHashを保つため、値はtのビット列 (floatならf32) で持つ
+    Float { bits: u64, t: Type },
+    Var(Symbol),
     Return,
     If,
     Else,
@@ -26,106 +37,227 @@ pub enum Token {
     Type(Type),
     Comma,
     Address,
+    Static,
+    Extern,
+    Inline,
+    Const,
+    Volatile,
+    Struct,
+    Dot,
+    Arrow,
 }
+// エラーメッセージ用に、ソースに書かれていた形で表示する
+impl fmt::Display for Token {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let s = match self {
+            Token::Plus => "+",
+            Token::Minus => "-",
+            Token::Asterisk => "*",
+            Token::Slash => "/",
+            Token::Eq => "==",
+            Token::NotEq => "!=",
+            Token::Assign => "=",
+            Token::Gr => ">",
+            Token::Ls => "<",
+            Token::GrEq => ">=",
+            Token::LsEq => "<=",
+            Token::LParen => "(",
+            Token::RParen => ")",
+            Token::Semicolon => ";",
+            Token::Int(i, _) => return write!(f, "{}", i),
+            Token::Float {
+                bits,
+                t: Type::Float,
+            } => return write!(f, "{}", f32::from_bits(*bits as u32)),
+            Token::Float { bits, .. } => return write!(f, "{}", f64::from_bits(*bits)),
+            Token::Var(v) => v,
+            Token::Return => "return",
+            Token::If => "if",
+            Token::Else => "else",
+            Token::While => "while",
+            Token::For => "for",
+            Token::LBrace => "{",
+            Token::RBrace => "}",
+            Token::Type(Type::Int) => "int",
+            Token::Type(Type::Float) => "float",
+            Token::Type(Type::Double) => "double",
+            Token::Type(Type::Long) => "long",
+            Token::Type(Type::Unsigned) => "unsigned",
+            Token::Comma => ",",
+            Token::Address => "&",
+            Token::Static => "static",
+            Token::Extern => "extern",
+            Token::Inline => "inline",
+            Token::Const => "const",
+            Token::Volatile => "volatile",
+            Token::Struct => "struct",
+            Token::Dot => ".",
+            Token::Arrow => "->",
+        };
+        write!(f, "{}", s)
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum Type {
     Int,
+    Float,
+    Double,
+    Long,
+    Unsigned,
 }
 
-fn reserved_words_map(str: &str) -> Token {
+// 整数リテラルの型。Cの規則に従い、値の大きさと接尾辞、10進数かどうかで決まる。
+// 値は64ビットのビット列として持つので、unsigned longの大きな値は負のi64になる。
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum IntType {
+    Int,
+    UInt,
+    Long,
+    ULong,
+}
+
+fn reserved_words_map(str: &str) -> Option<Token> {
     match str {
-        "return" => Token::Return,
-        "if" => Token::If,
-        "else" => Token::Else,
-        "while" => Token::While,
-        "for" => Token::For,
-        "int" => Token::Type(Type::Int),
-        _ => Token::Var(str.to_string()),
+        "return" => Some(Token::Return),
+        "if" => Some(Token::If),
+        "else" => Some(Token::Else),
+        "while" => Some(Token::While),
+        "for" => Some(Token::For),
+        "int" => Some(Token::Type(Type::Int)),
+        "float" => Some(Token::Type(Type::Float)),
+        "double" => Some(Token::Type(Type::Double)),
+        "long" => Some(Token::Type(Type::Long)),
+        "unsigned" => Some(Token::Type(Type::Unsigned)),
+        "static" => Some(Token::Static),
+        "extern" => Some(Token::Extern),
+        "inline" => Some(Token::Inline),
+        "const" => Some(Token::Const),
+        "volatile" => Some(Token::Volatile),
+        "struct" => Some(Token::Struct),
+        _ => None,
     }
 }
 
-type LexerResult = Result<Vec<Token>, String>;
+type LexerResult = Result<Vec<Token>, LexError>;
 
-fn split_str(s: &str) -> Vec<char> {
-    s.chars().collect()
+// 字句解析のエラー。posはエラーになったトークンの先頭のバイト位置
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct LexError {
+    pub message: Message,
+    pub pos: usize,
 }
 
-pub fn tokenize(s: &str) -> LexerResult {
-    let mut acm: Vec<Token> = vec![];
-    let tokens = tokenize_main(split_str(s).as_slice(), &mut acm)?;
-    Ok(tokens)
-}
-
-fn tokenize_main<'a>(s: &'a [char], acm: &mut Vec<Token>) -> LexerResult {
-    match s {
-        [' ', rest @ ..] | ['\n', rest @ ..] => tokenize_main(rest, acm),
-        [first, _rest @ ..] if first.is_numeric() => {
-            let get_num_result = get_num(s, String::new());
-            match get_num_result {
-                Ok((num, rest)) => {
-                    acm.push(num);
-                    tokenize_main(rest, acm)
-                }
-                Err(err) => Err(err),
-            }
-        }
-        [first, _rest @ ..] if first.is_alphabetic() => {
-            let get_var_result = get_var(s, String::new());
-            match get_var_result {
-                Ok((Token::Var(v), rest)) => {
-                    acm.push(reserved_words_map(&v));
-                    tokenize_main(rest, acm)
-                }
-                Err(err) => Err(err),
-                _ => panic!(format!("get_varがVarを返さない")),
-            }
-        }
-        [first, second, rest @ ..] if two_char_is_two_symbol(*first, *second) => {
-            acm.push(two_char_to_token_mapper(*first, *second));
-            tokenize_main(rest, acm)
-        }
-        [first, rest @ ..] => {
-            let token = symbol_to_token_mapper(first.clone())?;
-            acm.push(token);
-            tokenize_main(rest, acm)
-        }
-        _ => Ok(acm.clone()),
-    }
+// トークンがソースのどこにあったか (バイト位置、endは含まない)
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
 }
 
-fn two_char_is_two_symbol(first: char, second: char) -> bool {
-    two_symbol(concat_two_char(first, second))
+// ソースをバイト単位で先頭から読むカーソル。
+// 再帰せずに一度だけ走査し、トークンの文字列はソースの部分文字列から作る
+struct Cursor<'a> {
+    src: &'a str,
+    pos: usize,
 }
 
-fn two_symbol(string: String) -> bool {
-    let two_symbol_list: &[String] = &[
-        "==".to_string(),
-        "!=".to_string(),
-        "<=".to_string(),
-        ">=".to_string(),
-    ];
-    two_symbol_list.contains(&string)
+impl<'a> Cursor<'a> {
+    fn peek(&self, n: usize) -> Option<u8> {
+        self.src.as_bytes().get(self.pos + n).copied()
+    }
+
+    fn rest(&self) -> &'a str {
+        &self.src[self.pos..]
+    }
+
+    // predを満たす間読み進め、読んだ部分を返す
+    fn eat_while(&mut self, pred: impl Fn(u8) -> bool) -> &'a str {
+        let start = self.pos;
+        while self.peek(0).is_some_and(&pred) {
+            self.pos += 1;
+        }
+        &self.src[start..self.pos]
+    }
+}
+
+pub fn tokenize(s: &str) -> LexerResult {
+    tokenize_with_spans(s).map(|(tokens, _)| tokens)
 }
 
-fn concat_two_char(first: char, second: char) -> String {
-    format!("{}{}", first, second)
+// トークンと同じ並びで、それぞれのSpanも返す
+pub fn tokenize_with_spans(s: &str) -> Result<(Vec<Token>, Vec<Span>), LexError> {
+    let mut cursor = Cursor { src: s, pos: 0 };
+    let mut symbols = HashMap::new();
+    let mut tokens = vec![];
+    let mut spans = vec![];
+    while let Some(c) = cursor.peek(0) {
+        let start = cursor.pos;
+        let error_at = |message| LexError {
+            message,
+            pos: start,
+        };
+        let token = match (c, cursor.peek(1)) {
+            (b' ', _) | (b'\n', _) => {
+                cursor.pos += 1;
+                continue;
+            }
+            (c, _) if c.is_ascii_digit() => tokenize_num(&mut cursor).map_err(error_at)?,
+            // .5 のように小数点から始まる数
+            (b'.', Some(second)) if second.is_ascii_digit() => {
+                tokenize_num(&mut cursor).map_err(error_at)?
+            }
+            (c, _) if c.is_ascii_alphabetic() => get_var(&mut cursor, &mut symbols),
+            _ => match two_symbol_to_token_mapper(cursor.rest().get(..2).unwrap_or("")) {
+                Some(token) => {
+                    cursor.pos += 2;
+                    token
+                }
+                None => {
+                    let c = cursor.rest().chars().next().unwrap();
+                    cursor.pos += c.len_utf8();
+                    symbol_to_token_mapper(c).map_err(error_at)?
+                }
+            },
+        };
+        tokens.push(token);
+        spans.push(Span {
+            start,
+            end: cursor.pos,
+        });
+    }
+    Ok((tokens, spans))
 }
 
-fn two_char_to_token_mapper(first: char, second: char) -> Token {
-    two_symbol_to_token_mapper(&concat_two_char(first, second))
+fn tokenize_num(cursor: &mut Cursor) -> Result<Token, Message> {
+    let radix = match (cursor.peek(0), cursor.peek(1)) {
+        (Some(b'0'), Some(b'x')) | (Some(b'0'), Some(b'X')) => 16,
+        (Some(b'0'), Some(b'b')) | (Some(b'0'), Some(b'B')) => 2,
+        _ => return get_num(cursor),
+    };
+    cursor.pos += 2;
+    // 0xや0bの後ろの数字を読む
+    let digits = cursor.eat_while(|c| (c as char).is_digit(radix));
+    if digits.is_empty() {
+        return Err(Message::InvalidNumber(
+            cursor.src[cursor.pos - 2..cursor.pos].to_string(),
+        ));
+    }
+    get_int_suffix(cursor, digits, radix)
 }
 
-fn two_symbol_to_token_mapper(string: &str) -> Token {
+fn two_symbol_to_token_mapper(string: &str) -> Option<Token> {
     match string {
-        "==" => Token::Eq,
-        "!=" => Token::NotEq,
-        "<=" => Token::LsEq,
-        ">=" => Token::GrEq,
-        _ => panic!(format!("unexpected two symbol {}", string)),
+        "==" => Some(Token::Eq),
+        "!=" => Some(Token::NotEq),
+        "<=" => Some(Token::LsEq),
+        ">=" => Some(Token::GrEq),
+        "->" => Some(Token::Arrow),
+        _ => None,
     }
 }
 
-fn symbol_to_token_mapper(c: char) -> Result<Token, String> {
+fn symbol_to_token_mapper(c: char) -> Result<Token, Message> {
     match c {
         '+' => Ok(Token::Plus),
         '-' => Ok(Token::Minus),
@@ -141,40 +273,128 @@ fn symbol_to_token_mapper(c: char) -> Result<Token, String> {
         '}' => Ok(Token::RBrace),
         ',' => Ok(Token::Comma),
         '&' => Ok(Token::Address),
-        _ => Err(format!("symbol_to_token_mapper error: {}", c)),
+        '.' => Ok(Token::Dot),
+        _ => Err(Message::UnknownCharacter(c)),
     }
 }
 
-fn get_num<'a>(s: &'a [char], acm: String) -> Result<(Token, &'a [char]), String> {
-    match s {
-        [first, rest @ ..] if first.is_numeric() => {
-            let acm = format!("{}{}", acm, first);
-            get_num(rest, acm)
-        }
-        _ => {
-            let num = acm.parse();
-            match num {
-                Err(_) => Err(format!("数値の形式がおかしい。{}", acm)),
-                Ok(num) => Ok((Token::Int(num), s)),
+fn get_num(cursor: &mut Cursor) -> Result<Token, Message> {
+    let start = cursor.pos;
+    let digits = cursor.eat_while(|c| c.is_ascii_digit());
+    match cursor.peek(0) {
+        // 小数点か指数部があれば浮動小数点数になる
+        Some(b'.') => {
+            cursor.pos += 1;
+            cursor.eat_while(|c| c.is_ascii_digit());
+            if let Some(b'e') | Some(b'E') = cursor.peek(0) {
+                get_exponent(cursor, start)?;
             }
+            get_float_suffix(cursor, start)
+        }
+        Some(b'e') | Some(b'E') => {
+            get_exponent(cursor, start)?;
+            get_float_suffix(cursor, start)
         }
+        // 0から始まる整数は8進数
+        _ if digits.len() > 1 && digits.starts_with('0') => get_int_suffix(cursor, &digits[1..], 8),
+        _ => get_int_suffix(cursor, digits, 10),
     }
 }
 
-fn get_var<'a>(s: &'a [char], acm: String) -> Result<(Token, &'a [char]), String> {
-    match s {
-        [first, rest @ ..] if *first == '_' || first.is_alphabetic() || first.is_numeric() => {
-            let acm = format!("{}{}", acm, first);
-            get_var(rest, acm)
+// 整数リテラルの接尾辞 (u, l, ll とその組み合わせ) を読み、値と型を決める
+fn get_int_suffix(cursor: &mut Cursor, digits: &str, radix: u32) -> Result<Token, Message> {
+    const SUFFIXES: &[&str] = &[
+        "ull", "ULL", "uLL", "Ull", "llu", "LLU", "llU", "LLu", "ul", "UL", "uL", "Ul", "lu", "LU",
+        "lU", "Lu", "ll", "LL", "u", "U", "l", "L",
+    ];
+    let suffix = SUFFIXES
+        .iter()
+        .find(|suffix| cursor.rest().starts_with(**suffix))
+        .map_or("", |suffix| *suffix);
+    cursor.pos += suffix.len();
+    let unsigned = suffix.contains(['u', 'U']);
+    let long = suffix.contains(['l', 'L']);
+    if let Some(next) = cursor.peek(0) {
+        if next.is_ascii_alphanumeric() || next == b'_' {
+            return Err(Message::InvalidNumberSuffix(format!(
+                "{}{}",
+                digits, next as char
+            )));
         }
-        _ => {
-            let var = acm.parse();
-            match var {
-                Err(_) => Err(format!("変数の形式がおかしい: {}", acm)),
-                Ok(var) => Ok((Token::Var(var), s)),
-            }
+    }
+    let value = match u64::from_str_radix(digits, radix) {
+        Ok(value) => value,
+        // 089のように基数に合わない数字は数値の形式の誤り、それ以外は桁あふれ
+        Err(err) if *err.kind() == IntErrorKind::PosOverflow => {
+            return Err(Message::IntegerTooLarge(digits.to_string()))
         }
+        Err(_) => return Err(Message::InvalidNumber(digits.to_string())),
+    };
+    match int_literal_type(value, radix == 10, unsigned, long) {
+        Some(t) => Ok(Token::Int(value as i64, t)),
+        None => Err(Message::IntegerTooLarge(digits.to_string())),
+    }
+}
+
+// 候補の型のうち値が収まる最初のもの。
+// 10進数は接尾辞にuがなければ符号付きの型だけが候補になる。
+fn int_literal_type(value: u64, decimal: bool, unsigned: bool, long: bool) -> Option<IntType> {
+    let candidates: &[IntType] = match (unsigned, long, decimal) {
+        (false, false, true) => &[IntType::Int, IntType::Long],
+        (false, false, false) => &[IntType::Int, IntType::UInt, IntType::Long, IntType::ULong],
+        (true, false, _) => &[IntType::UInt, IntType::ULong],
+        (false, true, true) => &[IntType::Long],
+        (false, true, false) => &[IntType::Long, IntType::ULong],
+        (true, true, _) => &[IntType::ULong],
+    };
+    candidates.iter().copied().find(|t| match t {
+        IntType::Int => value <= i32::MAX as u64,
+        IntType::UInt => value <= u32::MAX as u64,
+        IntType::Long => value <= i64::MAX as u64,
+        IntType::ULong => true,
+    })
+}
+
+// e/Eとその後ろの符号と数字を読む。startは数の先頭
+fn get_exponent(cursor: &mut Cursor, start: usize) -> Result<(), Message> {
+    cursor.pos += 1;
+    if let Some(b'+') | Some(b'-') = cursor.peek(0) {
+        cursor.pos += 1;
+    }
+    if cursor.eat_while(|c| c.is_ascii_digit()).is_empty() {
+        return Err(Message::MissingExponentDigits(
+            cursor.src[start..cursor.pos].to_string(),
+        ));
     }
+    Ok(())
+}
+
+// f/Fが付けばfloat、l/L (long double) と接尾辞なしはdoubleとして扱う
+fn get_float_suffix(cursor: &mut Cursor, start: usize) -> Result<Token, Message> {
+    let literal = &cursor.src[start..cursor.pos];
+    let t = match cursor.peek(0) {
+        Some(b'f') | Some(b'F') => Type::Float,
+        _ => Type::Double,
+    };
+    if let Some(b'f') | Some(b'F') | Some(b'l') | Some(b'L') = cursor.peek(0) {
+        cursor.pos += 1;
+    }
+    let bits = match t {
+        Type::Float => literal.parse::<f32>().map(|f| f.to_bits() as u64),
+        _ => literal.parse::<f64>().map(|f| f.to_bits()),
+    };
+    match bits {
+        Err(_) => Err(Message::InvalidNumber(literal.to_string())),
+        Ok(bits) => Ok(Token::Float { bits, t }),
+    }
+}
+
+fn get_var<'a>(cursor: &mut Cursor<'a>, symbols: &mut HashMap<&'a str, Symbol>) -> Token {
+    let name = cursor.eat_while(|c| c == b'_' || c.is_ascii_alphanumeric());
+    reserved_words_map(name).unwrap_or_else(|| {
+        let symbol = symbols.entry(name).or_insert_with(|| Rc::from(name));
+        Token::Var(symbol.clone())
+    })
 }
 
 #[test]
@@ -197,11 +417,11 @@ fn tokenize_test() {
     assert_eq!(
         result,
         vec![
-            Token::Int(100),
+            Token::Int(100, IntType::Int),
             Token::Plus,
-            Token::Int(1234),
+            Token::Int(1234, IntType::Int),
             Token::Minus,
-            Token::Int(5555)
+            Token::Int(5555, IntType::Int)
         ]
     );
 
@@ -210,25 +430,92 @@ fn tokenize_test() {
     assert_eq!(
         result,
         vec![
-            Token::Int(10),
+            Token::Int(10, IntType::Int),
             Token::NotEq,
-            Token::Int(2),
+            Token::Int(2, IntType::Int),
             Token::Plus,
-            Token::Int(2),
+            Token::Int(2, IntType::Int),
             Token::Plus,
-            Token::Int(2),
+            Token::Int(2, IntType::Int),
             Token::Eq,
-            Token::Int(6),
+            Token::Int(6, IntType::Int),
             Token::Semicolon,
             Token::Return,
-            Token::Var("a".to_string()),
+            Token::Var("a".into()),
             Token::Semicolon,
         ]
     );
-    let result = tokenize("if(1) true; else false;").ok().unwrap();
-    println!("{:?}", result);
-    let result = tokenize("while(true) i = i * 2;").ok().unwrap();
-    println!("{:?}", result);
-    let result = tokenize("for (i = 0; i < 5; i++) i = i * 2;").ok().unwrap();
-    println!("{:?}", result);
+    let result = tokenize("1.5 .25 3. 1e3 2.5E-1f").ok().unwrap();
+
+    assert_eq!(
+        result,
+        vec![
+            Token::Float {
+                bits: 1.5f64.to_bits(),
+                t: Type::Double
+            },
+            Token::Float {
+                bits: 0.25f64.to_bits(),
+                t: Type::Double
+            },
+            Token::Float {
+                bits: 3.0f64.to_bits(),
+                t: Type::Double
+            },
+            Token::Float {
+                bits: 1000.0f64.to_bits(),
+                t: Type::Double
+            },
+            Token::Float {
+                bits: 0.25f32.to_bits() as u64,
+                t: Type::Float
+            },
+        ]
+    );
+    assert!(tokenize("1e+").is_err());
+
+    let result = tokenize("0x7f 0755 0b101 10UL 4294967295 0xffffffff 3000000000u 7ll")
+        .ok()
+        .unwrap();
+    assert_eq!(
+        result,
+        vec![
+            Token::Int(127, IntType::Int),
+            Token::Int(493, IntType::Int),
+            Token::Int(5, IntType::Int),
+            Token::Int(10, IntType::ULong),
+            Token::Int(4294967295, IntType::Long),
+            Token::Int(4294967295, IntType::UInt),
+            Token::Int(3000000000, IntType::UInt),
+            Token::Int(7, IntType::Long),
+        ]
+    );
+    let result = tokenize("0xffffffffffffffff 9223372036854775807")
+        .ok()
+        .unwrap();
+    assert_eq!(
+        result,
+        vec![
+            Token::Int(-1, IntType::ULong),
+            Token::Int(i64::MAX, IntType::Long),
+        ]
+    );
+    assert!(tokenize("18446744073709551615").is_err());
+    assert!(tokenize("089").is_err());
+    assert!(tokenize("10x").is_err());
+    assert!(tokenize("0x").is_err());
+
+    assert!(tokenize("if(1) true; else false;").is_ok());
+    assert!(tokenize("while(true) i = i * 2;").is_ok());
+    assert!(tokenize("for (i = 0; i < 5; i++) i = i * 2;").is_ok());
+}
+
+#[test]
+fn tokenize_large_input_test() {
+    // 再帰しないので、大きな入力でもスタックが溢れない
+    let src = "int x = 12345 + y;\n".repeat(50000);
+    let result = tokenize(&src).ok().unwrap();
+    assert_eq!(result.len(), 7 * 50000);
+    assert_eq!(result[2], Token::Assign);
+    assert_eq!(result[4], Token::Plus);
 }
@@ -0,0 +1,277 @@
+// cygccをライブラリとして使うための入口。
+// compileで一度に、tokenize・parse・generateで段階ごとにコンパイルできる。
+// どの段階のエラーも位置の付いたDiagnosticで返し、標準出力や標準エラー出力には何も書かない
+pub mod abi;
+pub mod codegen;
+pub mod diagnostic;
+pub mod eval;
+pub mod inline;
+pub mod ir;
+pub mod lexer;
+mod lower;
+pub mod opt;
+pub mod parser;
+pub mod peephole;
+pub mod preprocess;
+pub mod reach;
+pub mod regalloc;
+pub mod ssa;
+
+use crate::diagnostic::{Diagnostic, Location, Message};
+use crate::lexer::{tokenize_with_spans, Span, Token};
+use crate::parser::{parse_program, ParseError, Pos, Program};
+use crate::preprocess::{preprocess, to_source_with_map, SourceMap};
+
+#[derive(Debug, Clone)]
+pub struct CompileOptions {
+    // 診断に出すファイル名。#include "..." はこのファイルのディレクトリから探す
+    pub file_name: String,
+    pub preprocess: preprocess::Options,
+    // 最適化のレベル。0なら最適化しない
+    pub opt_level: u8,
+    // 到達しない文を捨てたときに警告する
+    pub warn_unreachable_code: bool,
+    // 末尾の呼び出しをjmpにする。-fno-optimize-sibling-callsでfalse
+    pub optimize_sibling_calls: bool,
+}
+
+impl Default for CompileOptions {
+    fn default() -> CompileOptions {
+        CompileOptions {
+            file_name: "<input>".to_string(),
+            preprocess: preprocess::Options::default(),
+            opt_level: 0,
+            warn_unreachable_code: false,
+            optimize_sibling_calls: true,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Output {
+    pub assembly: String,
+    // エラーにならなかった警告
+    pub warnings: Vec<Diagnostic>,
+}
+
+// 中間表現までの結果
+#[derive(Debug, Clone)]
+pub struct IrOutput {
+    pub module: ir::Module,
+    pub warnings: Vec<Diagnostic>,
+}
+
+// プリプロセスと字句解析の結果。診断の位置を元のソースに戻すための表も持つ
+#[derive(Debug)]
+pub struct Tokens {
+    pub tokens: Vec<Token>,
+    pub spans: Vec<Span>,
+    pub map: SourceMap,
+    pub warnings: Vec<Diagnostic>,
+}
+
+pub fn compile(source: &str, options: &CompileOptions) -> Result<Output, Vec<Diagnostic>> {
+    let tokens = tokenize(source, options)?;
+    let program = parse(&tokens)?;
+    let mut ir = lower_with(program, options.warn_unreachable_code, Some(&tokens))?;
+    opt::optimize(&mut ir.module, options.opt_level);
+    let mut out = vec![];
+    if let Err(err) = codegen::emit_module(
+        &ir.module,
+        options.opt_level,
+        options.optimize_sibling_calls,
+        &mut out,
+    ) {
+        return Err(vec![Diagnostic::new(Message::WriteFailed(err.to_string()))]);
+    }
+    let mut warnings = tokens.warnings;
+    warnings.append(&mut ir.warnings);
+    Ok(Output {
+        assembly: String::from_utf8(out).expect("アセンブリはASCIIだけで書く"),
+        warnings,
+    })
+}
+
+// アセンブリの代わりに中間表現を返す
+pub fn compile_to_ir(source: &str, options: &CompileOptions) -> Result<IrOutput, Vec<Diagnostic>> {
+    let tokens = tokenize(source, options)?;
+    let program = parse(&tokens)?;
+    let mut output = lower_with(program, options.warn_unreachable_code, Some(&tokens))?;
+    opt::optimize(&mut output.module, options.opt_level);
+    let mut warnings = tokens.warnings;
+    warnings.append(&mut output.warnings);
+    output.warnings = warnings;
+    Ok(output)
+}
+
+pub fn tokenize(source: &str, options: &CompileOptions) -> Result<Tokens, Vec<Diagnostic>> {
+    let preprocessed = match preprocess(source, &options.file_name, &options.preprocess) {
+        Ok(preprocessed) => preprocessed,
+        Err(err) => return Err(vec![err.diagnostic()]),
+    };
+    let warnings: Vec<Diagnostic> = preprocessed
+        .warnings
+        .iter()
+        .map(|warning| warning.diagnostic())
+        .collect();
+    let (code, map) = to_source_with_map(&preprocessed.tokens);
+    match tokenize_with_spans(&code) {
+        Ok((tokens, spans)) => Ok(Tokens {
+            tokens,
+            spans,
+            map,
+            warnings,
+        }),
+        Err(err) => {
+            let mut diagnostic = Diagnostic::new(err.message);
+            diagnostic.location = location(&map, err.pos);
+            let mut diagnostics = warnings;
+            diagnostics.push(diagnostic);
+            Err(diagnostics)
+        }
+    }
+}
+
+// 構文エラーは回復して読み進め、すべてを返す
+pub fn parse(tokens: &Tokens) -> Result<Program, Vec<Diagnostic>> {
+    let (program, errors) = parse_program(&tokens.tokens);
+    if errors.is_empty() {
+        Ok(program)
+    } else {
+        Err(errors
+            .iter()
+            .map(|err| parse_diagnostic(err, tokens))
+            .collect())
+    }
+}
+
+// programを読んだトークン列がわからないので、診断は位置なしで返す
+pub fn lower(program: Program) -> Result<IrOutput, Vec<Diagnostic>> {
+    lower_with(program, false, None)
+}
+
+// 定数だけの部分式を畳み込み、到達しない文を捨ててから下ろす。
+// warn_unreachableなら捨てた文の警告も返す。
+// tokensがprogramを読んだトークン列なら、診断に元のソースの位置を付ける
+fn lower_with(
+    program: Program,
+    warn_unreachable: bool,
+    tokens: Option<&Tokens>,
+) -> Result<IrOutput, Vec<Diagnostic>> {
+    let locate = |message, pos| match tokens {
+        Some(tokens) => pos_diagnostic(message, pos, tokens),
+        None => Diagnostic::new(message),
+    };
+    let (program, unreachable) = reach::remove_unreachable(eval::fold_program(program));
+    let (module, mut warnings) = lower::lower(program, &locate)?;
+    if warn_unreachable {
+        warnings.extend(
+            unreachable
+                .into_iter()
+                .map(|(message, pos)| locate(message, pos)),
+        );
+    }
+    Ok(IrOutput { module, warnings })
+}
+
+pub fn generate(program: Program) -> Result<Output, Vec<Diagnostic>> {
+    let mut out = vec![];
+    let (program, _) = reach::remove_unreachable(eval::fold_program(program));
+    let warnings = codegen::start(program, &mut out)?;
+    Ok(Output {
+        assembly: String::from_utf8(out).expect("アセンブリはASCIIだけで書く"),
+        warnings,
+    })
+}
+
+// 出力したソースの位置posを元のファイルの位置にする
+fn location(map: &SourceMap, pos: usize) -> Option<Location> {
+    map.lookup(pos)
+        .map(|(file, line, column)| Location { file, line, column })
+}
+
+// 出力したソースで終わりがposの範囲の、元のファイルでの終わりの位置。
+// 最後の文字の次の列にする
+fn end_location(map: &SourceMap, pos: usize) -> Option<Location> {
+    location(map, pos - 1).map(|end| Location {
+        column: end.column + 1,
+        ..end
+    })
+}
+
+fn parse_diagnostic(err: &ParseError, tokens: &Tokens) -> Diagnostic {
+    // 入力の終わりでのエラーは最後のトークンの位置で報告する
+    let index = err
+        .index(&tokens.tokens)
+        .min(tokens.tokens.len().saturating_sub(1));
+    let mut diagnostic = Diagnostic::new(err.message.clone());
+    if let Some(span) = tokens.spans.get(index) {
+        diagnostic.location = location(&tokens.map, span.start);
+        diagnostic.end = end_location(&tokens.map, span.end);
+    }
+    diagnostic.notes = err
+        .notes
+        .iter()
+        .map(|note| parse_diagnostic(note, tokens))
+        .collect();
+    diagnostic
+}
+
+// 文や式についての診断。位置はその最初のトークンの先頭から最後のトークンの終わりまで
+fn pos_diagnostic(message: Message, pos: Pos, tokens: &Tokens) -> Diagnostic {
+    let range = pos.range(&tokens.tokens);
+    let mut diagnostic = Diagnostic::new(message);
+    if let (Some(first), Some(last)) = (
+        tokens.spans.get(range.start),
+        range.end.checked_sub(1).and_then(|i| tokens.spans.get(i)),
+    ) {
+        diagnostic.location = location(&tokens.map, first.start);
+        diagnostic.end = end_location(&tokens.map, last.end);
+    }
+    diagnostic
+}
+
+#[test]
+fn compile_test() {
+    let options = CompileOptions::default();
+    let output = compile(
+        "#include <stdbool.h>\nint main() { return true + 1; }",
+        &options,
+    )
+    .unwrap();
+    assert!(output.assembly.contains("main:\n"));
+    assert!(output.warnings.is_empty());
+
+    // 段階ごとにも使える
+    let tokens = tokenize("int f() { return 1; }", &options).unwrap();
+    assert_eq!(tokens.tokens.len(), 9);
+    let program = parse(&tokens).unwrap();
+    assert_eq!(program.len(), 1);
+    assert!(generate(program).unwrap().assembly.contains("f:\n"));
+
+    let errors = compile("int main() {\n  return 1 +;\n}", &options).unwrap_err();
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].message.code(), "E0102");
+    let location = errors[0].location.as_ref().unwrap();
+    assert_eq!((location.line, location.column), (2, 13));
+
+    let output = compile(
+        "#warning check\nint main() { int x; const int *p = &x; int *q = p; return 0; }",
+        &options,
+    )
+    .unwrap();
+    let codes: Vec<&str> = output.warnings.iter().map(|w| w.message.code()).collect();
+    assert_eq!(codes, vec!["W0301", "W0201"]);
+
+    // 下ろすときのエラーにも式の位置が付く
+    let errors = compile("int main() {\n  return y + 1;\n}", &options).unwrap_err();
+    assert_eq!(errors[0].message.code(), "E0204");
+    let location = errors[0].location.as_ref().unwrap();
+    let end = errors[0].end.as_ref().unwrap();
+    assert_eq!((location.line, location.column), (2, 10));
+    assert_eq!((end.line, end.column), (2, 11));
+
+    let errors = compile("int main() { return 0; }\nint f() { return 0x; }", &options).unwrap_err();
+    assert_eq!(errors[0].message.code(), "E0001");
+    assert_eq!(errors[0].location.as_ref().unwrap().line, 2);
+}
@@ -0,0 +1,1458 @@
+// 構文木を中間表現 (ir) に下ろす。
+// 型の検査と変換、構造体の配置、変数の置き場所 (スロットかシンボルか) はここで決め、
+// codegenにはアセンブリにするだけのIRを渡す
+use crate::abi::{align_to, classify_scalar, classify_struct, ArgClass};
+use crate::diagnostic::{Conversion, Diagnostic, Message};
+use crate::ir::{
+    Abi, BinOp, Block, BlockId, Callee, ConvOp, Data, Function, Inst, Module, Operand, Slot,
+    Terminator, Ty, VReg,
+};
+use crate::lexer::Symbol;
+use crate::parser::int_exp;
+use crate::parser::qualify;
+use crate::parser::Exp;
+use crate::parser::Exp::*;
+use crate::parser::Member;
+use crate::parser::Op;
+use crate::parser::Op::*;
+use crate::parser::Pos;
+use crate::parser::Program;
+use crate::parser::Stmt;
+use crate::parser::StorageClass;
+use crate::parser::TypeAndExp;
+use crate::parser::TypeDec;
+use crate::parser::UOp::*;
+use std::cell::Cell;
+use std::collections::HashMap;
+
+const LOCAL_VAR_SIZE: i32 = 8;
+
+// プログラム全体をIRにし、警告と一緒に返す。
+// エラーがあればそこで止め、それまでの警告とエラーを返す。
+// 診断はlocateで、ASTの位置から元のソースの位置の付いたものにする
+pub fn lower(
+    p: Program,
+    locate: &dyn Fn(Message, Pos) -> Diagnostic,
+) -> Result<(Module, Vec<Diagnostic>), Vec<Diagnostic>> {
+    let mut state_holder = new_state_holder();
+    let result = lower_program(p, &mut state_holder);
+    let mut diagnostics: Vec<Diagnostic> = std::mem::take(&mut state_holder.warnings)
+        .into_iter()
+        .map(|(message, pos)| locate(message, pos))
+        .collect();
+    match result {
+        Ok(()) => Ok((state_holder.module, diagnostics)),
+        Err(LowerError(message, pos)) => {
+            diagnostics.push(locate(message, pos));
+            Err(diagnostics)
+        }
+    }
+}
+
+// ASTを読んだトークン列がなく、元のソースの位置がわからないときのlocate
+pub fn without_location(message: Message, _: Pos) -> Diagnostic {
+    Diagnostic::new(message)
+}
+
+// 続行できないエラーとその位置。?でlowerまで戻す
+struct LowerError(Message, Pos);
+
+type LowerResult<T> = Result<T, LowerError>;
+
+fn lower_option_exp(exp: Option<Exp>, state_holder: &mut StateHolder) -> LowerResult<()> {
+    if let Some(ref exp) = exp {
+        lower_exp(exp, state_holder)?;
+    }
+    Ok(())
+}
+
+fn lower_program(p: Program, state_holder: &mut StateHolder) -> LowerResult<()> {
+    // 後ろで定義される関数のアドレスも取れるように、先に関数の型を登録しておく
+    for stmt in &p {
+        register_function(stmt, state_holder);
+    }
+    for stmt in p {
+        match stmt {
+            Stmt::VarDec {
+                t,
+                var,
+                storage,
+                init,
+                pos,
+            } if !matches!(t, TypeDec::Func { .. }) => {
+                state_holder.pos.set(pos);
+                lower_global_var(t, *var, storage, *init, state_holder)?;
+            }
+            stmt => lower_stmts(vec![stmt], state_holder)?,
+        }
+    }
+    Ok(())
+}
+
+// 静的な変数の初期値はアセンブル時に決まる整数定数に限る
+fn const_int_value(exp: &Exp) -> Option<i64> {
+    match exp {
+        Int(i, _) => Some(*i),
+        InfixExp {
+            left,
+            op: Minus,
+            right,
+            ..
+        } if **left == int_exp(0) => const_int_value(right).map(|i| i.wrapping_neg()),
+        _ => None,
+    }
+}
+
+fn const_float_value(exp: &Exp) -> Option<f64> {
+    match exp {
+        Exp::Float {
+            bits,
+            t: TypeDec::Float,
+        } => Some(f32::from_bits(*bits as u32) as f64),
+        Exp::Float { bits, .. } => Some(f64::from_bits(*bits)),
+        Int(i, TypeDec::ULong) => Some(*i as u64 as f64),
+        Int(i, _) => Some(*i as f64),
+        InfixExp {
+            left,
+            op: Minus,
+            right,
+            ..
+        } if **left == int_exp(0) => const_float_value(right).map(|f| -f),
+        _ => None,
+    }
+}
+
+// 静的な変数の初期値を、変数の型でのビット列として求める
+fn const_data_value(t: &TypeDec, exp: &Exp) -> Option<i64> {
+    match t.unqualified() {
+        TypeDec::Float => const_float_value(exp).map(|f| (f as f32).to_bits() as i64),
+        TypeDec::Double => const_float_value(exp).map(|f| f.to_bits() as i64),
+        _ => const_int_value(exp).or_else(|| const_float_value(exp).map(|f| f as i64)),
+    }
+}
+
+fn lower_data(
+    label: &str,
+    t: &TypeDec,
+    global: bool,
+    init: Option<Exp>,
+    state_holder: &mut StateHolder,
+) -> LowerResult<()> {
+    let init = match init.map(|exp| const_data_value(t, &exp)) {
+        Some(None) => {
+            return Err(state_holder.error(Message::NonConstantInitializer(label.to_string())))
+        }
+        Some(Some(i)) => Some(i),
+        None => None,
+    };
+    let data = Data {
+        label: label.to_string(),
+        global,
+        size: state_holder.size_of(t)?,
+        align: state_holder.align_of(t)?,
+        init,
+    };
+    state_holder.module.data.push(data);
+    Ok(())
+}
+
+fn lower_global_var(
+    t: TypeDec,
+    var: Exp,
+    storage: StorageClass,
+    init: Option<Exp>,
+    state_holder: &mut StateHolder,
+) -> LowerResult<()> {
+    let var = match var {
+        Exp::Var(var, _) => var,
+        _ => panic!("error"),
+    };
+    match storage {
+        // extern は他のオブジェクトで定義されるのでリンク時に解決される
+        StorageClass::Extern if init.is_some() => {
+            return Err(state_holder.error(Message::ExternInitialized(var.to_string())))
+        }
+        StorageClass::Extern => {}
+        StorageClass::Static => lower_data(&var, &t, false, init, state_holder)?,
+        StorageClass::Default => lower_data(&var, &t, true, init, state_holder)?,
+    }
+    state_holder.set_global_var_env(t, var.clone(), var.to_string());
+    Ok(())
+}
+
+fn register_function(stmt: &Stmt, state_holder: &mut StateHolder) {
+    match stmt {
+        Stmt::Func { t, fun, params, .. } => {
+            if let Exp::Var(name, _) = &**fun {
+                let t = TypeDec::Func {
+                    ret: Box::new(t.clone()),
+                    params: params.iter().map(|(t, _)| t.clone()).collect(),
+                };
+                state_holder.set_function(name.clone(), t);
+            }
+        }
+        Stmt::VarDec {
+            t: t @ TypeDec::Func { .. },
+            var,
+            ..
+        } => {
+            if let Exp::Var(name, _) = &**var {
+                state_holder.set_function(name.clone(), t.clone());
+            }
+        }
+        _ => {}
+    }
+}
+
+// 式の型を求める。関数名は関数型 (関数指示子) になり、
+// 値として使われるときは関数へのポインタとして扱われる。
+// 型が決まらないときのエラー (ないメンバなど) はその式の位置で報告する
+fn exp_type(exp: &Exp, state_holder: &StateHolder) -> LowerResult<TypeDec> {
+    let outer = state_holder.enter(exp.pos());
+    let t = exp_type_at(exp, state_holder)?;
+    state_holder.pos.set(outer);
+    Ok(t)
+}
+
+fn exp_type_at(exp: &Exp, state_holder: &StateHolder) -> LowerResult<TypeDec> {
+    let t = match exp {
+        Int(_, t) => t.clone(),
+        Exp::Float { t, .. } => t.clone(),
+        Var(v, _) => match state_holder.find_var_type(v) {
+            Some(t) => t,
+            None => state_holder.find_function(v).unwrap_or(TypeDec::Int),
+        },
+        UnaryExp {
+            op: Address, exp, ..
+        } => TypeDec::Pointer(Box::new(exp_type(exp, state_holder)?)),
+        UnaryExp { op: Deref, exp, .. } => match exp_type(exp, state_holder)?.unqualified() {
+            TypeDec::Pointer(t) => *t.clone(),
+            t @ TypeDec::Func { .. } => t.clone(),
+            _ => TypeDec::Int,
+        },
+        FuncCall { fun, .. } => match func_type(&exp_type(fun, state_holder)?) {
+            Some(TypeDec::Func { ret, .. }) => *ret,
+            _ => TypeDec::Int,
+        },
+        Exp::Member { exp, member, .. } => {
+            state_holder
+                .member(&exp_type(exp, state_holder)?, member)?
+                .0
+        }
+        InfixExp {
+            left, op, right, ..
+        } => match op {
+            // 代入式の値は左辺の修飾子を持たない
+            Assign => exp_type(left, state_holder)?.unqualified().clone(),
+            Plus | Minus => {
+                let left = exp_type(left, state_holder)?;
+                let right = exp_type(right, state_holder)?;
+                match (left.unqualified(), right.unqualified()) {
+                    (TypeDec::Pointer(_), TypeDec::Pointer(_)) => TypeDec::Int,
+                    (t @ TypeDec::Pointer(_), _) | (_, t @ TypeDec::Pointer(_)) => t.clone(),
+                    _ => arith_type(&left, &right),
+                }
+            }
+            Asterisk | Slash => arith_type(
+                &exp_type(left, state_holder)?,
+                &exp_type(right, state_holder)?,
+            ),
+            _ => TypeDec::Int,
+        },
+    };
+    Ok(t)
+}
+
+fn infix_pointer_exp_converter(exp: Exp, state_holder: &mut StateHolder) -> Exp {
+    fn pointer_type_size(t: TypeDec) -> i64 {
+        match t {
+            TypeDec::Int => 4,
+            _ => 8,
+        }
+    }
+    match exp.clone() {
+        InfixExp {
+            left,
+            op,
+            right,
+            pos,
+        } => match op {
+            Plus | Minus => match (*left.clone(), *right.clone()) {
+                (Exp::Var(v, _), right) => {
+                    let left_type = state_holder
+                        .find_var_type(&v)
+                        .map(|t| t.unqualified().clone());
+                    match left_type {
+                        Some(left_type @ TypeDec::Pointer(_)) => InfixExp {
+                            left,
+                            op,
+                            right: Box::new(InfixExp {
+                                left: Box::new(right),
+                                op: Asterisk,
+                                right: Box::new(int_exp(pointer_type_size(left_type))),
+                                pos,
+                            }),
+                            pos,
+                        },
+                        _ => exp,
+                    }
+                }
+                (left, Exp::Var(v, _)) => {
+                    let right_type = state_holder
+                        .find_var_type(&v)
+                        .map(|t| t.unqualified().clone());
+                    match right_type {
+                        Some(right_type @ TypeDec::Pointer(_)) => InfixExp {
+                            left: Box::new(InfixExp {
+                                left: Box::new(left),
+                                op: Asterisk,
+                                right: Box::new(int_exp(pointer_type_size(right_type))),
+                                pos,
+                            }),
+                            op,
+                            right,
+                            pos,
+                        },
+                        _ => exp,
+                    }
+                }
+                _ => exp,
+            },
+            _ => exp,
+        },
+        _ => exp,
+    }
+}
+
+fn lower_stmts(p: Program, state_holder: &mut StateHolder) -> LowerResult<()> {
+    for stmt in p {
+        let outer = state_holder.enter(Some(stmt.pos()));
+        lower_stmt(stmt, state_holder)?;
+        state_holder.pos.set(outer);
+    }
+    Ok(())
+}
+
+fn lower_stmt(stmt: Stmt, state_holder: &mut StateHolder) -> LowerResult<()> {
+    match stmt {
+        Stmt::Exp(ref exp, _) => {
+            lower_exp(exp, state_holder)?;
+        }
+        Stmt::Return(ref exp, _) => {
+            let fun_name = state_holder.get_fun_name();
+            let ret = match state_holder.find_function(&fun_name) {
+                Some(TypeDec::Func { ret, .. }) => *ret,
+                _ => TypeDec::Int,
+            };
+            let t = exp_type(exp, state_holder)?;
+            check_pointer_conversion(&ret, &t, Conversion::Return, state_holder);
+            let value = lower_exp(exp, state_holder)?;
+            // 構造体はそのアドレスを返し、ABIに従った受け渡しはcodegenが行う
+            let value = if is_struct(&ret) {
+                value
+            } else {
+                convert(&t, &ret, value, state_holder)?
+            };
+            state_holder.terminate(Terminator::Ret(Some(value)));
+            // returnの後ろの文は到達しないブロックに置く
+            let next = state_holder.new_block();
+            state_holder.switch_to(next);
+        }
+        Stmt::Block(stmts, _) => lower_stmts(stmts, state_holder)?,
+        Stmt::If {
+            cond, stmt1, stmt2, ..
+        } => {
+            lower_if(*cond, *stmt1, *stmt2, state_holder)?;
+        }
+        Stmt::While { cond, stmt, .. } => {
+            lower_while(*cond, *stmt, state_holder)?;
+        }
+        Stmt::For {
+            exp1,
+            exp2,
+            exp3,
+            stmt,
+            ..
+        } => {
+            lower_for(*exp1, *exp2, *exp3, *stmt, state_holder)?;
+        }
+        Stmt::Func {
+            fun,
+            params,
+            body,
+            storage,
+            inline,
+            ..
+        } => {
+            lower_func(*fun, params, body, storage, inline, state_holder)?;
+        }
+        Stmt::StructDec { tag, members, .. } => {
+            state_holder.set_struct(tag, members)?;
+        }
+        Stmt::VarDec {
+            t,
+            var,
+            storage,
+            init,
+            pos,
+        } => {
+            let var = match *var {
+                Exp::Var(var, _) => var,
+                _ => panic!("error"),
+            };
+            match (t, storage) {
+                // 関数の宣言は変数の領域を取らない
+                (t @ TypeDec::Func { .. }, _) => state_holder.set_function(var, t),
+                (_, StorageClass::Extern) if init.is_some() => {
+                    return Err(state_holder.error(Message::ExternInitialized(var.to_string())))
+                }
+                (t, StorageClass::Extern) => {
+                    state_holder.set_static_var_env(t, var.clone(), var.to_string())
+                }
+                (t, StorageClass::Static) => {
+                    // 静的ローカル変数は関数名と連番で一意なラベルを付けて.dataに置く
+                    let label = state_holder
+                        .get_label(format!("static.{}.{}.", state_holder.current_fun_name, var));
+                    lower_data(&label, &t, false, *init, state_holder)?;
+                    state_holder.set_static_var_env(t, var, label);
+                }
+                (t, StorageClass::Default) => {
+                    state_holder.set_local_var_env(t.clone(), var.clone())?;
+                    // 初期化はconstな変数にもできるので、代入のチェックは通さない
+                    if let Some(init) = *init {
+                        let init_t = exp_type(&init, state_holder)?;
+                        check_pointer_conversion(
+                            &t,
+                            &init_t,
+                            Conversion::Initialization,
+                            state_holder,
+                        );
+                        lower_store(&Exp::Var(var, pos), &init, state_holder)?;
+                    }
+                }
+            }
+        }
+    }
+    Ok(())
+}
+
+// 算術演算の結果の型 (通常の算術型変換)。
+// double, float, unsigned long, long, unsigned intの順に、両辺のうち先に現れる型になる
+pub(crate) fn arith_type(left: &TypeDec, right: &TypeDec) -> TypeDec {
+    fn rank(t: &TypeDec) -> i32 {
+        match t.unqualified() {
+            TypeDec::Double => 5,
+            TypeDec::Float => 4,
+            TypeDec::ULong => 3,
+            TypeDec::Long => 2,
+            TypeDec::UInt => 1,
+            _ => 0,
+        }
+    }
+    let t = if rank(left) >= rank(right) {
+        left
+    } else {
+        right
+    };
+    match rank(t) {
+        0 => TypeDec::Int,
+        _ => t.unqualified().clone(),
+    }
+}
+
+pub(crate) fn is_integer(t: &TypeDec) -> bool {
+    matches!(
+        t.unqualified(),
+        TypeDec::Int | TypeDec::UInt | TypeDec::Long | TypeDec::ULong
+    )
+}
+
+// 型の値を置く仮想レジスタの型。関数指示子と構造体はアドレスを値にする
+pub(crate) fn ty_of(t: &TypeDec) -> Ty {
+    match t.unqualified() {
+        TypeDec::Int | TypeDec::UInt => Ty::I32,
+        TypeDec::Float => Ty::F32,
+        TypeDec::Double => Ty::F64,
+        _ => Ty::I64,
+    }
+}
+
+// 型fromの値valueを型toの値に変換する。
+// 整数の拡張は元の型の符号で決まり、intは符号拡張、unsigned intはゼロ拡張する
+fn convert(
+    from: &TypeDec,
+    to: &TypeDec,
+    value: Operand,
+    state_holder: &mut StateHolder,
+) -> LowerResult<Operand> {
+    let value = match (from.unqualified(), to.unqualified()) {
+        (TypeDec::Float, TypeDec::Double) => {
+            conv(ConvOp::FloatExt, Ty::F32, value, Ty::F64, state_holder)
+        }
+        (TypeDec::Double, TypeDec::Float) => {
+            conv(ConvOp::FloatTrunc, Ty::F64, value, Ty::F32, state_holder)
+        }
+        (from, to) if from.is_floating() && to.is_floating() => value,
+        (TypeDec::ULong, to) if to.is_floating() => conv(
+            ConvOp::ULongToFloat,
+            Ty::I64,
+            value,
+            ty_of(to),
+            state_holder,
+        ),
+        (from, to) if to.is_floating() && is_integer(from) => {
+            let value = convert(from, &TypeDec::Long, value, state_holder)?;
+            conv(ConvOp::IntToFloat, Ty::I64, value, ty_of(to), state_holder)
+        }
+        // unsigned longへは2^63未満の値だけを正しく変換する
+        (from, to) if from.is_floating() && is_integer(to) => {
+            let value = conv(
+                ConvOp::FloatToInt,
+                ty_of(from),
+                value,
+                Ty::I64,
+                state_holder,
+            );
+            convert(&TypeDec::Long, to, value, state_holder)?
+        }
+        (from, to) if from.is_floating() || to.is_floating() => {
+            return Err(state_holder.error(Message::InvalidConversion {
+                from: from.to_string(),
+                to: to.to_string(),
+            }))
+        }
+        (from, to) => match (ty_of(from), ty_of(to)) {
+            (Ty::I32, Ty::I64) if from.is_unsigned() => {
+                conv(ConvOp::ZExt, Ty::I32, value, Ty::I64, state_holder)
+            }
+            (Ty::I32, Ty::I64) => conv(ConvOp::SExt, Ty::I32, value, Ty::I64, state_holder),
+            (Ty::I64, Ty::I32) => conv(ConvOp::Trunc, Ty::I64, value, Ty::I32, state_holder),
+            _ => value,
+        },
+    };
+    Ok(value)
+}
+
+fn conv(op: ConvOp, from: Ty, src: Operand, to: Ty, state_holder: &mut StateHolder) -> Operand {
+    let dst = state_holder.new_vreg(to);
+    state_holder.push(Inst::Convert { dst, op, from, src });
+    Operand::Reg(dst)
+}
+
+// 関数または関数ポインタの型から関数の型を取り出す
+fn func_type(t: &TypeDec) -> Option<TypeDec> {
+    match t.unqualified() {
+        t @ TypeDec::Func { .. } => Some(t.clone()),
+        TypeDec::Pointer(t) => match t.unqualified() {
+            t @ TypeDec::Func { .. } => Some(t.clone()),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// const T * から T * のように、ポインタの指す先の修飾子が捨てられる変換を警告する
+fn check_pointer_conversion(
+    to: &TypeDec,
+    from: &TypeDec,
+    context: Conversion,
+    state_holder: &mut StateHolder,
+) {
+    if let (TypeDec::Pointer(to_base), TypeDec::Pointer(from_base)) =
+        (to.unqualified(), from.unqualified())
+    {
+        if (from_base.is_const() && !to_base.is_const())
+            || (from_base.is_volatile() && !to_base.is_volatile())
+        {
+            state_holder.warning(Message::DiscardedQualifiers {
+                context,
+                from: from.to_string(),
+                to: to.to_string(),
+            });
+        }
+    }
+}
+
+// ABIでの分類。構造体はメンバの型から、それ以外は整数か浮動小数点数かで決まる
+fn arg_class(t: &TypeDec, state_holder: &StateHolder) -> LowerResult<ArgClass> {
+    match t.unqualified() {
+        TypeDec::Struct(_) => {
+            let mut fields = vec![];
+            state_holder.scalar_fields(t, 0, &mut fields)?;
+            Ok(classify_struct(state_holder.size_of(t)?, &fields))
+        }
+        t => Ok(classify_scalar(t.is_floating())),
+    }
+}
+
+// 型の値を関数の引数や戻り値として渡すときの渡し方
+fn abi(t: &TypeDec, state_holder: &StateHolder) -> LowerResult<Abi> {
+    if is_struct(t) {
+        Ok(Abi::Struct {
+            size: state_holder.size_of(t)?,
+            class: arg_class(t, state_holder)?,
+        })
+    } else {
+        Ok(Abi::Scalar(ty_of(t)))
+    }
+}
+
+fn is_struct(t: &TypeDec) -> bool {
+    matches!(t.unqualified(), TypeDec::Struct(_))
+}
+
+fn lower_func_call(f: &Exp, args: &[Exp], state_holder: &mut StateHolder) -> LowerResult<Operand> {
+    let (ret_t, params) = match func_type(&exp_type(f, state_holder)?) {
+        Some(TypeDec::Func { ret, params }) => (*ret, Some(params)),
+        _ => (TypeDec::Int, None),
+    };
+    // ローカル変数でない名前はシンボルとして直接呼ぶ。
+    // それ以外 (関数ポインタや任意の式) は値を求めて間接呼び出しする。
+    let callee = match f {
+        Exp::Var(v, _) if !state_holder.check_var_from_env(v) => Callee::Symbol(v.to_string()),
+        _ => Callee::Reg(lower_exp(f, state_holder)?),
+    };
+    // 引数はすべて評価し、仮引数の型に変換しておく。構造体はそのアドレスを渡す。
+    // 対応する仮引数がわからなければ、floatはdoubleに格上げする。
+    let mut ir_args = vec![];
+    for (i, arg) in args.iter().enumerate() {
+        let t = exp_type(arg, state_holder)?;
+        let param_t = match params.as_ref().and_then(|params| params.get(i)) {
+            Some(param) => {
+                check_pointer_conversion(param, &t, Conversion::Argument, state_holder);
+                param.unqualified().clone()
+            }
+            None if *t.unqualified() == TypeDec::Float => TypeDec::Double,
+            None => t.unqualified().clone(),
+        };
+        let value = lower_exp(arg, state_holder)?;
+        let value = convert(&t, &param_t, value, state_holder)?;
+        ir_args.push((value, abi(&param_t, state_holder)?));
+    }
+    // 構造体の戻り値は一時領域に置き、そのアドレスを式の値にする
+    let ret_slot = if is_struct(&ret_t) {
+        Some(state_holder.new_slot(state_holder.size_of(&ret_t)?))
+    } else {
+        None
+    };
+    let dst = state_holder.new_vreg(ty_of(&ret_t));
+    let ret = abi(&ret_t, state_holder)?;
+    state_holder.push(Inst::Call {
+        dst,
+        callee,
+        args: ir_args,
+        ret,
+        ret_slot,
+    });
+    Ok(Operand::Reg(dst))
+}
+
+fn lower_func(
+    f: Exp,
+    params: Vec<TypeAndExp>,
+    body: Vec<Stmt>,
+    storage: StorageClass,
+    inline: bool,
+    state_holder: &mut StateHolder,
+) -> LowerResult<()> {
+    state_holder.reset_local_var_env();
+    let name = match f {
+        Exp::Var(v, _) => v,
+        _ => panic!("error, func nameがVarでない: {:?}", f),
+    };
+    state_holder.set_fun_name(name.to_string());
+    state_holder.begin_function();
+
+    let ret_t = match state_holder.find_function(&name) {
+        Some(TypeDec::Func { ret, .. }) => *ret,
+        _ => TypeDec::Int,
+    };
+    // 引数は仮想レジスタで受け取り、それぞれの変数のスロットに書いておく
+    let mut ir_params = vec![];
+    for v in params {
+        let (t, v) = match v {
+            (t, Exp::Var(v, _)) => {
+                state_holder.set_local_var_env(t.clone(), v.clone())?;
+                (t, v)
+            }
+            _ => panic!("error in lower_func paramsがVarでない"),
+        };
+        let param = state_holder.new_vreg(ty_of(&t));
+        ir_params.push((param, abi(&t, state_holder)?));
+        let addr = state_holder.local_var_addr(&v);
+        store(&t, addr, Operand::Reg(param), state_holder)?;
+    }
+    lower_stmts(body, state_holder)?;
+
+    let ret = abi(&ret_t, state_holder)?;
+    let mut function = state_holder.end_function(
+        name.to_string(),
+        storage != StorageClass::Static,
+        ir_params,
+        ret,
+    );
+    function.inline = inline;
+    state_holder.module.functions.push(function);
+    Ok(())
+}
+
+// 条件式の値。浮動小数点数は0と等しくなければ1にする (NaNも真)
+fn lower_cond(cond: &Exp, state_holder: &mut StateHolder) -> LowerResult<Operand> {
+    let value = lower_exp(cond, state_holder)?;
+    let t = exp_type(cond, state_holder)?;
+    if !t.is_floating() {
+        return Ok(value);
+    }
+    let zero = state_holder.new_vreg(ty_of(&t));
+    state_holder.push(Inst::Const {
+        dst: zero,
+        value: 0,
+    });
+    let dst = state_holder.new_vreg(Ty::I32);
+    state_holder.push(Inst::Binary {
+        dst,
+        op: BinOp::Ne,
+        ty: ty_of(&t),
+        lhs: value,
+        rhs: Operand::Reg(zero),
+    });
+    Ok(Operand::Reg(dst))
+}
+
+// 現在のブロックをcondで分岐させて終える
+fn branch(cond: Operand, then: BlockId, els: BlockId, state_holder: &mut StateHolder) {
+    state_holder.terminate(Terminator::Branch { cond, then, els });
+}
+
+// 現在のブロックをtoへのジャンプで終え、toから続ける
+fn jump_to(to: BlockId, state_holder: &mut StateHolder) {
+    state_holder.terminate(Terminator::Jump(to));
+    state_holder.switch_to(to);
+}
+
+fn lower_for(
+    exp1: Option<Exp>,
+    exp2: Option<Exp>,
+    exp3: Option<Exp>,
+    stmt: Stmt,
+    state_holder: &mut StateHolder,
+) -> LowerResult<()> {
+    lower_option_exp(exp1, state_holder)?;
+    let begin = state_holder.new_block();
+    jump_to(begin, state_holder);
+    let body = state_holder.new_block();
+    let end = state_holder.new_block();
+    match exp2 {
+        None => jump_to(body, state_holder),
+        Some(exp2) => {
+            let cond = lower_cond(&exp2, state_holder)?;
+            branch(cond, body, end, state_holder);
+            state_holder.switch_to(body);
+        }
+    }
+    lower_stmts(vec![stmt], state_holder)?;
+    lower_option_exp(exp3, state_holder)?;
+    state_holder.terminate(Terminator::Jump(begin));
+    state_holder.switch_to(end);
+    Ok(())
+}
+
+fn lower_while(cond: Exp, stmt: Stmt, state_holder: &mut StateHolder) -> LowerResult<()> {
+    let begin = state_holder.new_block();
+    jump_to(begin, state_holder);
+    let body = state_holder.new_block();
+    let end = state_holder.new_block();
+    let cond = lower_cond(&cond, state_holder)?;
+    branch(cond, body, end, state_holder);
+    state_holder.switch_to(body);
+    lower_stmts(vec![stmt], state_holder)?;
+    state_holder.terminate(Terminator::Jump(begin));
+    state_holder.switch_to(end);
+    Ok(())
+}
+
+fn lower_if(
+    cond: Exp,
+    stmt1: Stmt,
+    stmt2: Option<Stmt>,
+    state_holder: &mut StateHolder,
+) -> LowerResult<()> {
+    let cond = lower_cond(&cond, state_holder)?;
+    let then = state_holder.new_block();
+    match stmt2 {
+        Some(stmt2) => {
+            let els = state_holder.new_block();
+            let end = state_holder.new_block();
+            branch(cond, then, els, state_holder);
+            state_holder.switch_to(then);
+            lower_stmts(vec![stmt1], state_holder)?;
+            state_holder.terminate(Terminator::Jump(end));
+            state_holder.switch_to(els);
+            lower_stmts(vec![stmt2], state_holder)?;
+            jump_to(end, state_holder);
+        }
+        None => {
+            let end = state_holder.new_block();
+            branch(cond, then, end, state_holder);
+            state_holder.switch_to(then);
+            lower_stmts(vec![stmt1], state_holder)?;
+            jump_to(end, state_holder);
+        }
+    }
+    Ok(())
+}
+
+// 式の値が置かれた場所のアドレス
+fn lower_addr(exp: &Exp, state_holder: &mut StateHolder) -> LowerResult<Operand> {
+    let outer = state_holder.enter(exp.pos());
+    let addr = lower_addr_at(exp, state_holder)?;
+    state_holder.pos.set(outer);
+    Ok(addr)
+}
+
+fn lower_addr_at(exp: &Exp, state_holder: &mut StateHolder) -> LowerResult<Operand> {
+    let addr = match exp {
+        Exp::Var(v, _) => match state_holder.find_var(v).map(|v| v.symbol.clone()) {
+            Some(None) => state_holder.local_var_addr(v),
+            Some(Some(symbol)) => state_holder.symbol_addr(symbol),
+            None if state_holder.find_function(v).is_some() => {
+                state_holder.symbol_addr(v.to_string())
+            }
+            None => return Err(state_holder.error(Message::UndefinedVariable(v.to_string()))),
+        },
+        Exp::UnaryExp { op: Deref, exp, .. } => lower_exp(exp, state_holder)?,
+        Exp::Member { exp, member, .. } => {
+            let (_, offset) = state_holder.member(&exp_type(exp, state_holder)?, member)?;
+            let base = lower_addr(exp, state_holder)?;
+            let dst = state_holder.new_vreg(Ty::I64);
+            state_holder.push(Inst::Binary {
+                dst,
+                op: BinOp::Add,
+                ty: Ty::I64,
+                lhs: base,
+                rhs: Operand::Imm(offset as i64),
+            });
+            Operand::Reg(dst)
+        }
+        // 構造体を値とする式 (関数呼び出しなど) はその値の置き場所のアドレスになる
+        exp if is_struct(&exp_type(exp, state_holder)?) => lower_exp(exp, state_holder)?,
+        _ => panic!("error"),
+    };
+    Ok(addr)
+}
+
+// 浮動小数点数の演算。両辺を共通の型に変換して計算する
+fn lower_float_infix(
+    left: &Exp,
+    op: &Op,
+    right: &Exp,
+    state_holder: &mut StateHolder,
+) -> LowerResult<Operand> {
+    let left_type = exp_type(left, state_holder)?;
+    let right_type = exp_type(right, state_holder)?;
+    let t = arith_type(&left_type, &right_type);
+    let lhs = lower_exp(left, state_holder)?;
+    let lhs = convert(&left_type, &t, lhs, state_holder)?;
+    let rhs = lower_exp(right, state_holder)?;
+    let rhs = convert(&right_type, &t, rhs, state_holder)?;
+    let op = match op {
+        Plus => BinOp::Add,
+        Minus => BinOp::Sub,
+        Asterisk => BinOp::Mul,
+        Slash => BinOp::Div,
+        Eq => BinOp::Eq,
+        NotEq => BinOp::Ne,
+        Ls => BinOp::Lt,
+        LsEq => BinOp::Le,
+        Gr => BinOp::Gt,
+        GrEq => BinOp::Ge,
+        Assign => panic!("error"),
+    };
+    Ok(binary(op, ty_of(&t), lhs, rhs, state_holder))
+}
+
+fn binary(
+    op: BinOp,
+    ty: Ty,
+    lhs: Operand,
+    rhs: Operand,
+    state_holder: &mut StateHolder,
+) -> Operand {
+    let dst = state_holder.new_vreg(if op.is_comparison() { Ty::I32 } else { ty });
+    state_holder.push(Inst::Binary {
+        dst,
+        op,
+        ty,
+        lhs,
+        rhs,
+    });
+    Operand::Reg(dst)
+}
+
+// addrが指す値を読む。関数指示子と構造体はそのアドレス自体を値として扱うので読まない。
+// 式に現れた読み書きはそのままload/storeにし、volatileな値には印を付けて最適化で消さないようにする。
+fn load(t: &TypeDec, addr: Operand, state_holder: &mut StateHolder) -> Operand {
+    match t.unqualified() {
+        TypeDec::Func { .. } | TypeDec::Struct(_) => addr,
+        _ => {
+            let dst = state_holder.new_vreg(ty_of(t));
+            state_holder.push(Inst::Load {
+                dst,
+                ty: ty_of(t),
+                addr,
+                volatile: t.is_volatile(),
+            });
+            Operand::Reg(dst)
+        }
+    }
+}
+
+// valueをaddrが指す場所に書き、書いた値を返す。構造体はvalueが指す中身をコピーし、コピー先を値にする。
+fn store(
+    t: &TypeDec,
+    addr: Operand,
+    value: Operand,
+    state_holder: &mut StateHolder,
+) -> LowerResult<Operand> {
+    let value = match t.unqualified() {
+        TypeDec::Struct(_) => {
+            let size = state_holder.size_of(t)?;
+            state_holder.push(Inst::MemCopy {
+                dst: addr,
+                src: value,
+                size,
+            });
+            addr
+        }
+        _ => {
+            state_holder.push(Inst::Store {
+                ty: ty_of(t),
+                addr,
+                value,
+                volatile: t.is_volatile(),
+            });
+            value
+        }
+    };
+    Ok(value)
+}
+
+fn lower_assign(left: &Exp, right: &Exp, state_holder: &mut StateHolder) -> LowerResult<Operand> {
+    let left_type = exp_type(left, state_holder)?;
+    if left_type.is_const() {
+        return Err(state_holder.error(Message::AssignToConst(left_type.to_string())));
+    }
+    let right_type = exp_type(right, state_holder)?;
+    check_pointer_conversion(
+        &left_type,
+        &right_type,
+        Conversion::Assignment,
+        state_holder,
+    );
+    lower_store(left, right, state_holder)
+}
+
+fn lower_store(left: &Exp, right: &Exp, state_holder: &mut StateHolder) -> LowerResult<Operand> {
+    let addr = lower_addr(left, state_holder)?;
+    let value = lower_exp(right, state_holder)?;
+    let left_type = exp_type(left, state_holder)?;
+    let value = convert(
+        &exp_type(right, state_holder)?,
+        &left_type,
+        value,
+        state_holder,
+    )?;
+    store(&left_type, addr, value, state_holder)
+}
+
+// 整数とポインタの演算。両辺を共通の型にそろえ、符号なしなら除算と比較も符号なしで行う。
+// ポインタを含む演算は64ビットで行う
+fn lower_int_infix(
+    exp: &Exp,
+    left: &Exp,
+    op: &Op,
+    right: &Exp,
+    state_holder: &mut StateHolder,
+) -> LowerResult<Operand> {
+    let left_type = exp_type(left, state_holder)?;
+    let right_type = exp_type(right, state_holder)?;
+    let t = if is_integer(&left_type) && is_integer(&right_type) {
+        arith_type(&left_type, &right_type)
+    } else {
+        TypeDec::ULong
+    };
+    let unsigned = t.is_unsigned();
+    let lhs = lower_exp(left, state_holder)?;
+    let lhs = convert(&left_type, &t, lhs, state_holder)?;
+    let rhs = lower_exp(right, state_holder)?;
+    let rhs = convert(&right_type, &t, rhs, state_holder)?;
+    let op = int_bin_op(op, unsigned);
+    let value = binary(op, ty_of(&t), lhs, rhs, state_holder);
+    if op.is_comparison() {
+        Ok(value)
+    } else {
+        // ポインタ同士の差はintになる
+        convert(&t, &exp_type(exp, state_holder)?, value, state_holder)
+    }
+}
+
+// 整数の二項演算子に対応するIRの演算。unsignedなら除算と比較を符号なしで行う
+pub(crate) fn int_bin_op(op: &Op, unsigned: bool) -> BinOp {
+    match op {
+        Plus => BinOp::Add,
+        Minus => BinOp::Sub,
+        Asterisk => BinOp::Mul,
+        Slash if unsigned => BinOp::UDiv,
+        Slash => BinOp::Div,
+        Eq => BinOp::Eq,
+        NotEq => BinOp::Ne,
+        Ls if unsigned => BinOp::ULt,
+        Ls => BinOp::Lt,
+        LsEq if unsigned => BinOp::ULe,
+        LsEq => BinOp::Le,
+        Gr if unsigned => BinOp::UGt,
+        Gr => BinOp::Gt,
+        GrEq if unsigned => BinOp::UGe,
+        GrEq => BinOp::Ge,
+        Assign => panic!("error"),
+    }
+}
+
+// 式を下ろす間は、エラーと警告をその式の位置で報告する
+fn lower_exp(exp: &Exp, state_holder: &mut StateHolder) -> LowerResult<Operand> {
+    let outer = state_holder.enter(exp.pos());
+    let value = lower_exp_at(exp, state_holder)?;
+    state_holder.pos.set(outer);
+    Ok(value)
+}
+
+fn lower_exp_at(exp: &Exp, state_holder: &mut StateHolder) -> LowerResult<Operand> {
+    let exp = &infix_pointer_exp_converter(exp.clone(), state_holder);
+    let value = match exp {
+        FuncCall { fun, args, .. } => lower_func_call(fun, args, state_holder)?,
+        InfixExp {
+            left,
+            op: Assign,
+            right,
+            ..
+        } => lower_assign(left, right, state_holder)?,
+        InfixExp {
+            left, op, right, ..
+        } => {
+            let left_type = exp_type(left, state_holder)?;
+            let right_type = exp_type(right, state_holder)?;
+            if left_type.is_floating() || right_type.is_floating() {
+                lower_float_infix(left, op, right, state_holder)?
+            } else {
+                lower_int_infix(exp, left, op, right, state_holder)?
+            }
+        }
+        Int(i, _) => Operand::Imm(*i),
+        Exp::Float { bits, t } => {
+            let dst = state_holder.new_vreg(ty_of(t));
+            state_holder.push(Inst::Const {
+                dst,
+                value: *bits as i64,
+            });
+            Operand::Reg(dst)
+        }
+        Var(..) | Exp::Member { .. } => {
+            let addr = lower_addr(exp, state_holder)?;
+            load(&exp_type(exp, state_holder)?, addr, state_holder)
+        }
+        UnaryExp { op, exp: inner, .. } => match op {
+            Address => lower_addr(inner, state_holder)?,
+            Deref => {
+                let addr = lower_exp(inner, state_holder)?;
+                load(&exp_type(exp, state_holder)?, addr, state_holder)
+            }
+        },
+    };
+    Ok(value)
+}
+
+struct Varinfo {
+    name: Symbol,
+    t: TypeDec,
+    // スタックではなくシンボルで参照する変数 (グローバル変数、静的ローカル変数、extern) のラベル
+    symbol: Option<String>,
+}
+
+// 構造体の大きさとアラインメント、各メンバの (名前, 型, 先頭からのオフセット)
+struct StructLayout {
+    size: i32,
+    align: i32,
+    members: Vec<(Symbol, TypeDec, i32)>,
+}
+
+struct StateHolder {
+    slot_map: HashMap<Symbol, Slot>,
+    label_counter: i32,
+    current_fun_name: String,
+    local_vars_env: Vec<Varinfo>,
+    global_vars_env: Vec<Varinfo>,
+    functions: HashMap<Symbol, TypeDec>,
+    structs: HashMap<Symbol, StructLayout>,
+    // 出した警告とその位置
+    warnings: Vec<(Message, Pos)>,
+    // 下ろしている文か式の位置。エラーと警告はここで報告する
+    pos: Cell<Pos>,
+    // できあがったデータと関数
+    module: Module,
+    // 組み立て中の関数のスロットの大きさ、仮想レジスタの型、ブロック。
+    // ブロックは終端がまだなければNone
+    slots: Vec<i32>,
+    vregs: Vec<Ty>,
+    blocks: Vec<(Vec<Inst>, Option<Terminator>)>,
+    current_block: BlockId,
+}
+
+fn new_state_holder() -> StateHolder {
+    StateHolder {
+        slot_map: HashMap::new(),
+        label_counter: 0,
+        current_fun_name: "".to_string(),
+        local_vars_env: vec![],
+        global_vars_env: vec![],
+        functions: HashMap::new(),
+        structs: HashMap::new(),
+        warnings: vec![],
+        pos: Cell::new(Pos::default()),
+        module: Module::default(),
+        slots: vec![],
+        vregs: vec![],
+        blocks: vec![],
+        current_block: BlockId(0),
+    }
+}
+
+impl StateHolder {
+    // 下ろしている位置での続行できないエラー
+    fn error(&self, message: Message) -> LowerError {
+        LowerError(message, self.pos.get())
+    }
+    fn warning(&mut self, message: Message) {
+        self.warnings.push((message, self.pos.get()));
+    }
+    // posがあれば、そこを下ろしている位置にする。戻すための元の位置を返す
+    fn enter(&self, pos: Option<Pos>) -> Pos {
+        let outer = self.pos.get();
+        if let Some(pos) = pos {
+            self.pos.set(pos);
+        }
+        outer
+    }
+    fn set_fun_name(&mut self, name: String) {
+        self.current_fun_name = name;
+    }
+    fn get_fun_name(&mut self) -> String {
+        self.current_fun_name.clone()
+    }
+    fn get_label(&mut self, prefix: String) -> String {
+        format!(".L.{}{}", prefix, self.get_label_counter())
+    }
+    fn get_label_counter(&mut self) -> i32 {
+        let value = self.label_counter;
+        self.label_counter += 1;
+        value
+    }
+    // 関数を組み立て始める。入口のブロックから書く
+    fn begin_function(&mut self) {
+        self.slot_map = HashMap::new();
+        self.slots = vec![];
+        self.vregs = vec![];
+        self.blocks = vec![];
+        self.current_block = self.new_block();
+    }
+    // 終端のないブロックは関数の終わりまで実行したことになる
+    fn end_function(
+        &mut self,
+        name: String,
+        global: bool,
+        params: Vec<(VReg, Abi)>,
+        ret: Abi,
+    ) -> Function {
+        let blocks = std::mem::take(&mut self.blocks)
+            .into_iter()
+            .map(|(insts, term)| Block {
+                insts,
+                term: term.unwrap_or(Terminator::Ret(None)),
+            })
+            .collect();
+        let mut f = Function {
+            name,
+            global,
+            inline: false,
+            params,
+            ret,
+            slots: std::mem::take(&mut self.slots),
+            vregs: std::mem::take(&mut self.vregs),
+            blocks,
+        };
+        // returnの後ろに作ったブロックのような、入口から辿れないブロックは出力しない
+        crate::ssa::remove_unreachable_blocks(&mut f);
+        f
+    }
+    fn new_vreg(&mut self, ty: Ty) -> VReg {
+        self.vregs.push(ty);
+        VReg(self.vregs.len() - 1)
+    }
+    // sizeバイトの名前のない領域をスタックに取る
+    fn new_slot(&mut self, size: i32) -> Slot {
+        self.slots.push(size);
+        Slot(self.slots.len() - 1)
+    }
+    fn new_block(&mut self) -> BlockId {
+        self.blocks.push((vec![], None));
+        BlockId(self.blocks.len() - 1)
+    }
+    fn switch_to(&mut self, block: BlockId) {
+        self.current_block = block;
+    }
+    fn push(&mut self, inst: Inst) {
+        self.blocks[self.current_block.0].0.push(inst);
+    }
+    fn terminate(&mut self, term: Terminator) {
+        self.blocks[self.current_block.0].1 = Some(term);
+    }
+    fn symbol_addr(&mut self, symbol: String) -> Operand {
+        let dst = self.new_vreg(Ty::I64);
+        self.push(Inst::SymbolAddr { dst, symbol });
+        Operand::Reg(dst)
+    }
+    fn local_var_addr(&mut self, var: &str) -> Operand {
+        let slot = self.get_local_var_slot(var);
+        let dst = self.new_vreg(Ty::I64);
+        self.push(Inst::SlotAddr { dst, slot });
+        Operand::Reg(dst)
+    }
+    // 同じ名前の変数は関数の中で一つのスロットを共有する
+    fn get_local_var_slot(&mut self, str: &str) -> Slot {
+        if let Some(slot) = self.slot_map.get(str) {
+            return *slot;
+        }
+        let slot = self.new_slot(LOCAL_VAR_SIZE);
+        self.slot_map.insert(str.into(), slot);
+        slot
+    }
+    fn set_local_var_env(&mut self, t: TypeDec, var: Symbol) -> LowerResult<()> {
+        if !self.slot_map.contains_key(&var) {
+            let slot = self.new_slot(self.size_of(&t)?);
+            self.slot_map.insert(var.clone(), slot);
+        }
+        self.local_vars_env.push(Varinfo {
+            name: var,
+            t,
+            symbol: None,
+        });
+        Ok(())
+    }
+    fn set_static_var_env(&mut self, t: TypeDec, var: Symbol, symbol: String) {
+        self.local_vars_env.push(Varinfo {
+            name: var,
+            t,
+            symbol: Some(symbol),
+        });
+    }
+    fn set_global_var_env(&mut self, t: TypeDec, var: Symbol, symbol: String) {
+        self.global_vars_env.push(Varinfo {
+            name: var,
+            t,
+            symbol: Some(symbol),
+        });
+    }
+    // ローカル変数を先に探し、なければグローバル変数を探す
+    fn find_var(&self, var: &str) -> Option<&Varinfo> {
+        self.local_vars_env
+            .iter()
+            .find(|v| &*v.name == var)
+            .or_else(|| self.global_vars_env.iter().find(|v| &*v.name == var))
+    }
+    fn find_var_type(&self, var: &str) -> Option<TypeDec> {
+        self.find_var(var).map(|v| v.t.clone())
+    }
+    fn reset_local_var_env(&mut self) {
+        self.local_vars_env = vec![];
+    }
+    fn check_var_from_env(&self, var: &str) -> bool {
+        self.find_var(var).is_some()
+    }
+    fn set_function(&mut self, name: Symbol, t: TypeDec) {
+        self.functions.insert(name, t);
+    }
+    fn find_function(&self, name: &str) -> Option<TypeDec> {
+        self.functions.get(name).cloned()
+    }
+    // メンバを宣言順に、それぞれのアラインメントに合わせて並べる
+    fn set_struct(&mut self, tag: Symbol, members: Vec<Member>) -> LowerResult<()> {
+        let mut offset = 0;
+        let mut align = 1;
+        let mut layout = vec![];
+        for (t, name) in members {
+            if layout.iter().any(|(n, _, _)| *n == name) {
+                return Err(self.error(Message::DuplicateMember {
+                    tag: tag.to_string(),
+                    member: name.to_string(),
+                }));
+            }
+            offset = align_to(offset, self.align_of(&t)?);
+            align = align.max(self.align_of(&t)?);
+            let size = self.size_of(&t)?;
+            layout.push((name, t, offset));
+            offset += size;
+        }
+        let layout = StructLayout {
+            size: align_to(offset, align),
+            align,
+            members: layout,
+        };
+        self.structs.insert(tag, layout);
+        Ok(())
+    }
+    fn find_struct(&self, t: &TypeDec) -> LowerResult<&StructLayout> {
+        match t.unqualified() {
+            TypeDec::Struct(tag) => match self.structs.get(tag) {
+                Some(layout) => Ok(layout),
+                None => Err(self.error(Message::IncompleteStruct(tag.to_string()))),
+            },
+            _ => Err(self.error(Message::NotAStruct(t.to_string()))),
+        }
+    }
+    // 構造体のメンバの型とオフセット。構造体の修飾子はメンバにも付く。
+    fn member(&self, t: &TypeDec, name: &str) -> LowerResult<(TypeDec, i32)> {
+        match self
+            .find_struct(t)?
+            .members
+            .iter()
+            .find(|(n, _, _)| **n == *name)
+        {
+            Some((_, member_t, offset)) => Ok((qualify(member_t.clone(), t.qualifiers()), *offset)),
+            None => Err(self.error(Message::NoSuchMember {
+                t: t.to_string(),
+                member: name.to_string(),
+            })),
+        }
+    }
+    // 構造体を平らにして、スカラーのメンバの (先頭からのオフセット, 浮動小数点数か) を集める
+    fn scalar_fields(&self, t: &TypeDec, base: i32, acm: &mut Vec<(i32, bool)>) -> LowerResult<()> {
+        match t.unqualified() {
+            TypeDec::Struct(_) => {
+                for (_, member_t, offset) in &self.find_struct(t)?.members {
+                    self.scalar_fields(member_t, base + offset, acm)?;
+                }
+            }
+            t => acm.push((base, t.is_floating())),
+        }
+        Ok(())
+    }
+    fn size_of(&self, t: &TypeDec) -> LowerResult<i32> {
+        match t.unqualified() {
+            TypeDec::Int | TypeDec::UInt | TypeDec::Float => Ok(4),
+            TypeDec::Struct(_) => Ok(self.find_struct(t)?.size),
+            _ => Ok(8),
+        }
+    }
+    fn align_of(&self, t: &TypeDec) -> LowerResult<i32> {
+        match t.unqualified() {
+            TypeDec::Int | TypeDec::UInt | TypeDec::Float => Ok(4),
+            TypeDec::Struct(_) => Ok(self.find_struct(t)?.align),
+            _ => Ok(8),
+        }
+    }
+}
+
+// テストで使う、構文エラーのないソースを下ろしたIR
+#[cfg(test)]
+pub(crate) fn module_of(src: &str) -> Result<Module, Vec<Diagnostic>> {
+    lower(crate::parser::program_of(src), &without_location).map(|(module, _)| module)
+}
+
+#[test]
+fn test_map() {
+    let mut state_holder = new_state_holder();
+    state_holder.begin_function();
+    let slot = state_holder.get_local_var_slot("a");
+    assert_eq!(slot, Slot(0));
+    let slot = state_holder.get_local_var_slot("a");
+    assert_eq!(slot, Slot(0));
+    let slot = state_holder.get_local_var_slot("b");
+    assert_eq!(slot, Slot(1));
+    let slot = state_holder.get_local_var_slot("c");
+    assert_eq!(slot, Slot(2));
+    let slot = state_holder.get_local_var_slot("d");
+    assert_eq!(slot, Slot(3));
+    let slot = state_holder.get_local_var_slot("d");
+    assert_eq!(slot, Slot(3));
+    state_holder.begin_function();
+    let slot = state_holder.get_local_var_slot("d");
+    assert_eq!(slot, Slot(0));
+    let slot = state_holder.get_local_var_slot("d");
+    assert_eq!(slot, Slot(0));
+    let slot = state_holder.get_local_var_slot("a");
+    assert_eq!(slot, Slot(1));
+    let slot = state_holder.get_local_var_slot("a");
+    assert_eq!(slot, Slot(1));
+    assert_eq!(state_holder.slots, vec![LOCAL_VAR_SIZE; 2]);
+
+    let label = state_holder.get_label("static.f.c.".to_string());
+    assert_eq!(label, ".L.static.f.c.0");
+}
+
+#[test]
+fn lower_test() {
+    let lower_str = |src: &str| module_of(src).map(|module| module.to_string());
+    assert_eq!(
+        lower_str("int g = 7; int f(int a) { if (a < 3) return a + g; return 0; }").unwrap(),
+        "data g: size 4, align 4 = 7
+function f(%0: i32) -> i32 {
+  slot $0: 4
+bb0:
+  %1 = addr $0  ; i64
+  store i32 %1, %0
+  %2 = addr $0  ; i64
+  %3 = load i32 %2  ; i32
+  %4 = lt i32 %3, 3  ; i32
+  br %4, bb1, bb2
+bb1:
+  %5 = addr $0  ; i64
+  %6 = load i32 %5  ; i32
+  %7 = addr @g  ; i64
+  %8 = load i32 %7  ; i32
+  %9 = add i32 %6, %8  ; i32
+  ret %9
+bb2:
+  ret 0
+}
+"
+    );
+    // 浮動小数点数への変換と構造体を返す呼び出し
+    assert_eq!(
+        lower_str(
+            "struct P { int x; int y; }; struct P mk(); \
+             double h(long n) { return mk().y + n; }"
+        )
+        .unwrap(),
+        "function h(%0: i64) -> f64 {
+  slot $0: 8
+  slot $1: 8
+bb0:
+  %1 = addr $0  ; i64
+  store i64 %1, %0
+  %2 = call struct(8) @mk() -> $1  ; i64
+  %3 = add i64 %2, 4  ; i64
+  %4 = load i32 %3  ; i32
+  %5 = sext i32 %4  ; i64
+  %6 = addr $0  ; i64
+  %7 = load i64 %6  ; i64
+  %8 = add i64 %5, %7  ; i64
+  %9 = itof i64 %8  ; f64
+  ret %9
+}
+"
+    );
+    let errors = lower_str("int h() { return y; }").unwrap_err();
+    assert_eq!(
+        errors,
+        vec![Diagnostic::new(Message::UndefinedVariable("y".to_string()))]
+    );
+}
@@ -1,25 +1,213 @@
-mod codegen;
-mod eval;
-mod lexer;
-mod parser;
-use crate::codegen::start;
-use crate::lexer::tokenize;
-use crate::parser::parse_program;
+use cygcc::diagnostic::{Diagnostic, Format, Lang, Message, Reporter};
+use cygcc::preprocess::{preprocess, to_text_with_markers, MacroOption, Options};
+use cygcc::{compile, compile_to_ir, CompileOptions};
 use std::env;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::process;
+
+#[derive(Default)]
+struct Args {
+    pp: Options,
+    // ファイル名か、ソースそのもの (ファイルとして存在しなければソースとみなす)
+    input: Option<String>,
+    // -E: プリプロセスの結果を出力して終わる
+    preprocess_only: bool,
+    // --emit-ir: アセンブリの代わりに中間表現を出力する
+    emit_ir: bool,
+    // -O0, -O1, -O2: 最適化のレベル。-Oだけなら-O1
+    opt_level: u8,
+    // -Wunreachable-code: 実行されない文を警告する。-Wno-unreachable-codeで戻す
+    warn_unreachable_code: bool,
+    // -fno-optimize-sibling-calls: 末尾の呼び出しもcallにする
+    no_sibling_calls: bool,
+    // --lang: 診断メッセージの言語。なければ環境変数LANGで決める
+    lang: Option<Lang>,
+    // --diagnostics-format: human (既定) かjson
+    format: Option<Format>,
+}
+
+fn parse_args(args: &[String], mut acm: Args) -> Result<Args, Message> {
+    match args {
+        [opt, value, rest @ ..] if opt == "-I" || opt == "-D" || opt == "-U" => {
+            parse_args(rest, parse_args(&[format!("{}{}", opt, value)], acm)?)
+        }
+        [opt] if opt == "-I" || opt == "-D" || opt == "-U" => {
+            Err(Message::MissingOptionArgument(opt.clone()))
+        }
+        [opt, value, rest @ ..] if opt == "--lang" => {
+            parse_args(rest, parse_args(&[format!("--lang={}", value)], acm)?)
+        }
+        [opt, rest @ ..] if opt.starts_with("--lang=") => {
+            match Lang::from_name(&opt["--lang=".len()..]) {
+                Some(lang) => acm.lang = Some(lang),
+                None => return Err(Message::UnknownLanguage(opt.clone())),
+            }
+            parse_args(rest, acm)
+        }
+        [opt, value, rest @ ..] if opt == "--diagnostics-format" => parse_args(
+            rest,
+            parse_args(&[format!("--diagnostics-format={}", value)], acm)?,
+        ),
+        [opt, rest @ ..] if opt.starts_with("--diagnostics-format=") => {
+            match Format::from_name(&opt["--diagnostics-format=".len()..]) {
+                Some(format) => acm.format = Some(format),
+                None => return Err(Message::UnknownDiagnosticsFormat(opt.clone())),
+            }
+            parse_args(rest, acm)
+        }
+        [opt, rest @ ..] if opt == "--emit-ir" => {
+            acm.emit_ir = true;
+            parse_args(rest, acm)
+        }
+        [opt, rest @ ..] if opt == "-O" => {
+            acm.opt_level = 1;
+            parse_args(rest, acm)
+        }
+        [opt, rest @ ..] if opt.starts_with("-O") => {
+            match opt[2..].parse::<u8>() {
+                Ok(level) => acm.opt_level = level.min(2),
+                Err(_) => return Err(Message::InvalidOptLevel(opt.clone())),
+            }
+            parse_args(rest, acm)
+        }
+        [opt, rest @ ..] if opt == "-Wunreachable-code" || opt == "-Wno-unreachable-code" => {
+            acm.warn_unreachable_code = opt == "-Wunreachable-code";
+            parse_args(rest, acm)
+        }
+        [opt, rest @ ..]
+            if opt == "-foptimize-sibling-calls" || opt == "-fno-optimize-sibling-calls" =>
+        {
+            acm.no_sibling_calls = opt == "-fno-optimize-sibling-calls";
+            parse_args(rest, acm)
+        }
+        [opt, rest @ ..] if opt == "-E" => {
+            acm.preprocess_only = true;
+            parse_args(rest, acm)
+        }
+        [opt, rest @ ..] if opt.starts_with("-I") => {
+            acm.pp.include_paths.push(PathBuf::from(&opt[2..]));
+            parse_args(rest, acm)
+        }
+        [opt, rest @ ..] if opt.starts_with("-D") => {
+            let define = match opt[2..].find('=') {
+                Some(i) => MacroOption::Define(opt[2..2 + i].to_string(), opt[3 + i..].to_string()),
+                None => MacroOption::Define(opt[2..].to_string(), "1".to_string()),
+            };
+            acm.pp.macros.push(define);
+            parse_args(rest, acm)
+        }
+        [opt, rest @ ..] if opt.starts_with("-U") => {
+            acm.pp.macros.push(MacroOption::Undef(opt[2..].to_string()));
+            parse_args(rest, acm)
+        }
+        [input, rest @ ..] => {
+            if acm.input.is_some() {
+                return Err(Message::MultipleInputs);
+            }
+            acm.input = Some(input.clone());
+            parse_args(rest, acm)
+        }
+        [] => Ok(acm),
+    }
+}
+
 fn main() {
     let args: Vec<String> = env::args().skip(1).collect();
-    let code = args[0].clone();
-
-    let tokenize_result = tokenize(&code);
-    let tokens = match tokenize_result {
-        Ok(result) => result,
-        Err(err) => panic!(err),
+    let args = match parse_args(&args, Args::default()) {
+        Ok(args) => args,
+        Err(message) => {
+            // --langや--diagnostics-formatを読めたとは限らないので、既定の出し方にする
+            let reporter = Reporter {
+                lang: Lang::from_env(),
+                format: Format::Human,
+            };
+            reporter.emit(&Diagnostic::new(message));
+            process::exit(1);
+        }
+    };
+    let reporter = Reporter {
+        lang: args.lang.unwrap_or_else(Lang::from_env),
+        format: args.format.unwrap_or(Format::Human),
+    };
+    let input = match args.input {
+        Some(input) => input,
+        None => {
+            reporter.emit(&Diagnostic::new(Message::NoInput));
+            process::exit(1);
+        }
+    };
+    let (file, code) = if Path::new(&input).is_file() {
+        match fs::read_to_string(&input) {
+            Ok(code) => (input.clone(), code),
+            Err(err) => {
+                reporter.emit(&Diagnostic::new(Message::ReadFailed(
+                    input.clone(),
+                    err.to_string(),
+                )));
+                process::exit(1);
+            }
+        }
+    } else {
+        ("<input>".to_string(), input)
     };
 
-    let parse_result = parse_program(tokens.as_slice());
-    let stmts = match parse_result {
-        Ok(stmts) => stmts,
-        Err(err) => panic!(err),
+    if args.preprocess_only {
+        let preprocessed = match preprocess(&code, &file, &args.pp) {
+            Ok(preprocessed) => preprocessed,
+            Err(err) => {
+                reporter.emit(&err.diagnostic());
+                process::exit(1);
+            }
+        };
+        for warning in preprocessed.warnings.iter() {
+            reporter.emit(&warning.diagnostic());
+        }
+        print!("{}", to_text_with_markers(&preprocessed));
+        return;
+    }
+
+    let options = CompileOptions {
+        file_name: file,
+        preprocess: args.pp,
+        opt_level: args.opt_level,
+        warn_unreachable_code: args.warn_unreachable_code,
+        optimize_sibling_calls: !args.no_sibling_calls,
     };
-    start(stmts);
+    if args.emit_ir {
+        match compile_to_ir(&code, &options) {
+            Ok(output) => {
+                for warning in &output.warnings {
+                    reporter.emit(warning);
+                }
+                print!("{}", output.module);
+            }
+            Err(diagnostics) => {
+                for diagnostic in &diagnostics {
+                    reporter.emit(diagnostic);
+                }
+                process::exit(1);
+            }
+        }
+        return;
+    }
+
+    match compile(&code, &options) {
+        Ok(output) => {
+            for warning in &output.warnings {
+                reporter.emit(warning);
+            }
+            if let Err(err) = io::stdout().lock().write_all(output.assembly.as_bytes()) {
+                reporter.emit(&Diagnostic::new(Message::WriteFailed(err.to_string())));
+                process::exit(1);
+            }
+        }
+        Err(diagnostics) => {
+            for diagnostic in &diagnostics {
+                reporter.emit(diagnostic);
+            }
+            process::exit(1);
+        }
+    }
 }
@@ -0,0 +1,687 @@
+// 中間表現の最適化。-O1以上でSSA形式にして各パスを変化がなくなるまで繰り返し、最後にSSA形式から戻す。
+// -O2ではその前に小さな関数をインライン展開する。
+// 各パスはSSA形式の関数を受け取り、ほかのパスとは別に使える
+use crate::inline::inline_functions;
+use crate::ir::{
+    BinOp, BlockId, ConvOp, Function, Inst, Module, Operand, Slot, Terminator, Ty, VReg,
+};
+use crate::ssa::{
+    dominator_tree, dominators, from_ssa, predecessors, remove_unreachable_blocks, retain_blocks,
+    reverse_postorder, successors, to_ssa,
+};
+use std::collections::HashMap;
+
+// パスを繰り返す回数の上限
+const MAX_ROUNDS: usize = 10;
+
+pub fn optimize(module: &mut Module, level: u8) {
+    if level == 0 {
+        return;
+    }
+    if level >= 2 {
+        inline_functions(module);
+    }
+    for f in module.functions.iter_mut() {
+        optimize_function(f);
+    }
+}
+
+fn optimize_function(f: &mut Function) {
+    to_ssa(f);
+    for _ in 0..MAX_ROUNDS {
+        let before = f.clone();
+        propagate_constants(f);
+        propagate_copies(f);
+        eliminate_common_subexpressions(f);
+        eliminate_dead_code(f);
+        simplify_cfg(f);
+        if *f == before {
+            break;
+        }
+    }
+    from_ssa(f);
+    remove_unused_slots(f);
+}
+
+// 仮想レジスタに昇格して使われなくなったスロットを消し、番号を詰める
+fn remove_unused_slots(f: &mut Function) {
+    let mut used = vec![false; f.slots.len()];
+    for inst in f.blocks.iter().flat_map(|block| block.insts.iter()) {
+        match inst {
+            Inst::SlotAddr { slot, .. }
+            | Inst::Call {
+                ret_slot: Some(slot),
+                ..
+            } => used[slot.0] = true,
+            _ => (),
+        }
+    }
+    let mut renumber = vec![0; f.slots.len()];
+    let mut slots = vec![];
+    for (i, size) in f.slots.iter().enumerate() {
+        if used[i] {
+            renumber[i] = slots.len();
+            slots.push(*size);
+        }
+    }
+    f.slots = slots;
+    for inst in f.blocks.iter_mut().flat_map(|block| block.insts.iter_mut()) {
+        match inst {
+            Inst::SlotAddr { slot, .. }
+            | Inst::Call {
+                ret_slot: Some(slot),
+                ..
+            } => *slot = Slot(renumber[slot.0]),
+            _ => (),
+        }
+    }
+}
+
+// オペランドを置き換え表に従って書き換える
+fn replace_operands(f: &mut Function, replace: &HashMap<VReg, Operand>) {
+    let lookup = |operand: &mut Operand| {
+        if let Operand::Reg(reg) = operand {
+            if let Some(value) = replace.get(reg) {
+                *operand = *value;
+            }
+        }
+    };
+    for block in f.blocks.iter_mut() {
+        for inst in block.insts.iter_mut() {
+            inst.operands_mut().into_iter().for_each(lookup);
+        }
+        block.term.operands_mut().into_iter().for_each(lookup);
+    }
+}
+
+// 定数の伝播と畳み込み。即値だけを引数とする演算と変換をconstにし、
+// constの値を使う側に即値として埋め込む。ゼロ除算のように実行時の結果が決まらないものは畳み込まない
+pub fn propagate_constants(f: &mut Function) {
+    let mut constants: HashMap<VReg, Operand> = HashMap::new();
+    loop {
+        let mut changed = false;
+        for block in reverse_postorder(f) {
+            for i in 0..f.blocks[block.0].insts.len() {
+                let inst = &mut f.blocks[block.0].insts[i];
+                for operand in inst.operands_mut() {
+                    if let Operand::Reg(reg) = operand {
+                        if let Some(value) = constants.get(reg) {
+                            *operand = *value;
+                        }
+                    }
+                }
+                let dst = match inst.dst() {
+                    Some(dst) if !constants.contains_key(&dst) => dst,
+                    _ => continue,
+                };
+                if let Some(value) = fold(inst, f.vregs[dst.0]) {
+                    constants.insert(dst, Operand::Imm(value));
+                    f.blocks[block.0].insts[i] = Inst::Const { dst, value };
+                    changed = true;
+                }
+            }
+        }
+        if !changed {
+            break;
+        }
+    }
+    replace_operands(f, &constants);
+}
+
+// 命令の結果が定数になればその値。tyは結果の型
+fn fold(inst: &Inst, ty: Ty) -> Option<i64> {
+    match inst {
+        Inst::Const { value, .. } => Some(*value),
+        Inst::Copy {
+            src: Operand::Imm(value),
+            ..
+        } => Some(*value),
+        Inst::Binary {
+            op,
+            ty: operand_ty,
+            lhs: Operand::Imm(lhs),
+            rhs: Operand::Imm(rhs),
+            ..
+        } => fold_binary(*op, *operand_ty, *lhs, *rhs),
+        Inst::Convert {
+            op,
+            from,
+            src: Operand::Imm(value),
+            ..
+        } => fold_convert(*op, *from, ty, *value),
+        Inst::Phi { args, .. } => match args.first() {
+            Some((_, Operand::Imm(value))) if args.iter().all(|(_, arg)| *arg == args[0].1) => {
+                Some(*value)
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+pub fn fold_binary(op: BinOp, ty: Ty, lhs: i64, rhs: i64) -> Option<i64> {
+    match ty {
+        Ty::I32 => fold_i32(op, lhs as i32, rhs as i32).map(|value| value as i64),
+        Ty::I64 => fold_i64(op, lhs, rhs),
+        Ty::F32 => fold_float(
+            op,
+            f32::from_bits(lhs as u32) as f64,
+            f32::from_bits(rhs as u32) as f64,
+        )
+        .map(|value| match value {
+            Float(value) => (value as f32).to_bits() as i64,
+            Bool(b) => b as i64,
+        }),
+        Ty::F64 => {
+            fold_float(op, f64::from_bits(lhs as u64), f64::from_bits(rhs as u64)).map(|value| {
+                match value {
+                    Float(value) => value.to_bits() as i64,
+                    Bool(b) => b as i64,
+                }
+            })
+        }
+    }
+}
+
+fn fold_i32(op: BinOp, lhs: i32, rhs: i32) -> Option<i32> {
+    let (ul, ur) = (lhs as u32, rhs as u32);
+    Some(match op {
+        BinOp::Add => lhs.wrapping_add(rhs),
+        BinOp::Sub => lhs.wrapping_sub(rhs),
+        BinOp::Mul => lhs.wrapping_mul(rhs),
+        // 0での除算とオーバーフローする除算は実行時に例外になる
+        BinOp::Div => lhs.checked_div(rhs)?,
+        BinOp::UDiv => ul.checked_div(ur)? as i32,
+        BinOp::Eq => (lhs == rhs) as i32,
+        BinOp::Ne => (lhs != rhs) as i32,
+        BinOp::Lt => (lhs < rhs) as i32,
+        BinOp::Le => (lhs <= rhs) as i32,
+        BinOp::Gt => (lhs > rhs) as i32,
+        BinOp::Ge => (lhs >= rhs) as i32,
+        BinOp::ULt => (ul < ur) as i32,
+        BinOp::ULe => (ul <= ur) as i32,
+        BinOp::UGt => (ul > ur) as i32,
+        BinOp::UGe => (ul >= ur) as i32,
+    })
+}
+
+fn fold_i64(op: BinOp, lhs: i64, rhs: i64) -> Option<i64> {
+    let (ul, ur) = (lhs as u64, rhs as u64);
+    Some(match op {
+        BinOp::Add => lhs.wrapping_add(rhs),
+        BinOp::Sub => lhs.wrapping_sub(rhs),
+        BinOp::Mul => lhs.wrapping_mul(rhs),
+        BinOp::Div => lhs.checked_div(rhs)?,
+        BinOp::UDiv => ul.checked_div(ur)? as i64,
+        BinOp::Eq => (lhs == rhs) as i64,
+        BinOp::Ne => (lhs != rhs) as i64,
+        BinOp::Lt => (lhs < rhs) as i64,
+        BinOp::Le => (lhs <= rhs) as i64,
+        BinOp::Gt => (lhs > rhs) as i64,
+        BinOp::Ge => (lhs >= rhs) as i64,
+        BinOp::ULt => (ul < ur) as i64,
+        BinOp::ULe => (ul <= ur) as i64,
+        BinOp::UGt => (ul > ur) as i64,
+        BinOp::UGe => (ul >= ur) as i64,
+    })
+}
+
+enum FloatValue {
+    Float(f64),
+    Bool(bool),
+}
+use FloatValue::*;
+
+// f32の演算もf64で計算してから丸める。四則演算ではこれで結果が同じになる
+fn fold_float(op: BinOp, lhs: f64, rhs: f64) -> Option<FloatValue> {
+    // NaNとの比較はcodegenの命令列に任せる
+    if op.is_comparison() && (lhs.is_nan() || rhs.is_nan()) {
+        return None;
+    }
+    Some(match op {
+        BinOp::Add => Float(lhs + rhs),
+        BinOp::Sub => Float(lhs - rhs),
+        BinOp::Mul => Float(lhs * rhs),
+        BinOp::Div => Float(lhs / rhs),
+        BinOp::Eq => Bool(lhs == rhs),
+        BinOp::Ne => Bool(lhs != rhs),
+        BinOp::Lt => Bool(lhs < rhs),
+        BinOp::Le => Bool(lhs <= rhs),
+        BinOp::Gt => Bool(lhs > rhs),
+        BinOp::Ge => Bool(lhs >= rhs),
+        _ => panic!("浮動小数点数に{}はない", op),
+    })
+}
+
+fn fold_convert(op: ConvOp, from: Ty, to: Ty, value: i64) -> Option<i64> {
+    let float = |from: Ty| match from {
+        Ty::F32 => f32::from_bits(value as u32) as f64,
+        _ => f64::from_bits(value as u64),
+    };
+    let to_float = |f: f64| match to {
+        Ty::F32 => (f as f32).to_bits() as i64,
+        _ => f.to_bits() as i64,
+    };
+    Some(match op {
+        ConvOp::SExt | ConvOp::Trunc => value as i32 as i64,
+        ConvOp::ZExt => value as u32 as i64,
+        // i64から直接f32へ丸める
+        ConvOp::IntToFloat if to == Ty::F32 => (value as f32).to_bits() as i64,
+        ConvOp::IntToFloat => to_float(value as f64),
+        ConvOp::ULongToFloat if to == Ty::F32 => (value as u64 as f32).to_bits() as i64,
+        ConvOp::ULongToFloat => to_float(value as u64 as f64),
+        // 範囲外の値は実行時の命令の結果に任せる
+        ConvOp::FloatToInt => {
+            let f = float(from);
+            if !(-9223372036854775808.0..9223372036854775808.0).contains(&f) {
+                return None;
+            }
+            f as i64
+        }
+        ConvOp::FloatExt => float(Ty::F32).to_bits() as i64,
+        ConvOp::FloatTrunc => (float(Ty::F64) as f32).to_bits() as i64,
+    })
+}
+
+// copyの結果を使う側にcopy元を直接書く。引数がすべて同じphiもcopyとみなす
+pub fn propagate_copies(f: &mut Function) {
+    let mut copies: HashMap<VReg, Operand> = HashMap::new();
+    for block in &f.blocks {
+        for inst in &block.insts {
+            match inst {
+                Inst::Copy { dst, src } => {
+                    copies.insert(*dst, *src);
+                }
+                Inst::Phi { dst, args } => {
+                    // 自分自身を引数とする辺は値を変えない
+                    let mut sources = args
+                        .iter()
+                        .map(|(_, arg)| *arg)
+                        .filter(|arg| *arg != Operand::Reg(*dst));
+                    if let Some(first) = sources.next() {
+                        if sources.all(|arg| arg == first) {
+                            copies.insert(*dst, first);
+                        }
+                    }
+                }
+                _ => (),
+            }
+        }
+    }
+    // copyの連鎖を辿り、最初の値にする
+    let resolved: HashMap<VReg, Operand> = copies
+        .keys()
+        .map(|reg| {
+            let mut value = Operand::Reg(*reg);
+            let mut steps = 0;
+            while let Operand::Reg(current) = value {
+                match copies.get(&current) {
+                    Some(next) if steps <= copies.len() => value = *next,
+                    _ => break,
+                }
+                steps += 1;
+            }
+            (*reg, value)
+        })
+        .collect();
+    replace_operands(f, &resolved);
+}
+
+// 使われない値を作る副作用のない命令を消す
+pub fn eliminate_dead_code(f: &mut Function) {
+    let mut defs: HashMap<VReg, &Inst> = HashMap::new();
+    let mut live: Vec<bool> = vec![false; f.vregs.len()];
+    let mut work: Vec<VReg> = vec![];
+    let mark = |operand: &Operand, live: &mut Vec<bool>, work: &mut Vec<VReg>| {
+        if let Operand::Reg(reg) = operand {
+            if !live[reg.0] {
+                live[reg.0] = true;
+                work.push(*reg);
+            }
+        }
+    };
+    for block in &f.blocks {
+        for inst in &block.insts {
+            if let Some(dst) = inst.dst() {
+                defs.insert(dst, inst);
+            }
+            if !inst.is_pure() {
+                for operand in inst.operands() {
+                    mark(operand, &mut live, &mut work);
+                }
+            }
+        }
+        for operand in block.term.operands() {
+            mark(operand, &mut live, &mut work);
+        }
+    }
+    while let Some(reg) = work.pop() {
+        if let Some(inst) = defs.get(&reg) {
+            for operand in inst.operands() {
+                mark(operand, &mut live, &mut work);
+            }
+        }
+    }
+    for block in f.blocks.iter_mut() {
+        block.insts.retain(|inst| match inst.dst() {
+            Some(dst) if inst.is_pure() => live[dst.0],
+            _ => true,
+        });
+    }
+}
+
+// 同じ値を計算する命令。オペランドと型が同じなら結果も同じになる
+#[derive(Clone, Eq, PartialEq, Hash)]
+enum Expression {
+    Binary(BinOp, Ty, Operand, Operand),
+    Convert(ConvOp, Ty, Ty, Operand),
+    SlotAddr(Slot),
+    SymbolAddr(String),
+}
+
+// 支配するブロックで同じ式を計算していれば、その結果をcopyする
+pub fn eliminate_common_subexpressions(f: &mut Function) {
+    let idom = dominators(f);
+    let children = dominator_tree(&idom);
+    let mut available: HashMap<Expression, VReg> = HashMap::new();
+    cse_block(f, BlockId(0), &children, &mut available);
+}
+
+fn cse_block(
+    f: &mut Function,
+    block: BlockId,
+    children: &[Vec<BlockId>],
+    available: &mut HashMap<Expression, VReg>,
+) {
+    let mut added = vec![];
+    for i in 0..f.blocks[block.0].insts.len() {
+        let inst = &f.blocks[block.0].insts[i];
+        let (dst, expression) = match inst {
+            Inst::Binary {
+                dst,
+                op,
+                ty,
+                lhs,
+                rhs,
+            } => (*dst, Expression::Binary(*op, *ty, *lhs, *rhs)),
+            Inst::Convert { dst, op, from, src } => {
+                (*dst, Expression::Convert(*op, *from, f.vregs[dst.0], *src))
+            }
+            Inst::SlotAddr { dst, slot } => (*dst, Expression::SlotAddr(*slot)),
+            Inst::SymbolAddr { dst, symbol } => (*dst, Expression::SymbolAddr(symbol.clone())),
+            _ => continue,
+        };
+        match available.get(&expression) {
+            Some(reg) => {
+                f.blocks[block.0].insts[i] = Inst::Copy {
+                    dst,
+                    src: Operand::Reg(*reg),
+                }
+            }
+            None => {
+                available.insert(expression.clone(), dst);
+                added.push(expression);
+            }
+        }
+    }
+    for child in &children[block.0] {
+        cse_block(f, *child, children, available);
+    }
+    for expression in added {
+        available.remove(&expression);
+    }
+}
+
+// 制御フローを簡単にする。定数や同じ行き先への分岐をjmpにし、辿れないブロックを消し、
+// 一本道でつながるブロックを一つにまとめる
+pub fn simplify_cfg(f: &mut Function) {
+    for i in 0..f.blocks.len() {
+        let (target, dropped) = match f.blocks[i].term {
+            Terminator::Branch {
+                cond: Operand::Imm(cond),
+                then,
+                els,
+            } if cond != 0 => (then, Some(els)),
+            Terminator::Branch {
+                cond: Operand::Imm(_),
+                then,
+                els,
+            } => (els, Some(then)),
+            Terminator::Branch { then, els, .. } if then == els => (then, None),
+            _ => continue,
+        };
+        f.blocks[i].term = Terminator::Jump(target);
+        match dropped {
+            Some(dropped) if dropped != target => remove_phi_args(f, dropped, BlockId(i)),
+            _ => (),
+        }
+    }
+    remove_unreachable_blocks(f);
+    while merge_blocks(f) {}
+}
+
+fn remove_phi_args(f: &mut Function, block: BlockId, pred: BlockId) {
+    for inst in f.blocks[block.0].insts.iter_mut() {
+        if let Inst::Phi { args, .. } = inst {
+            args.retain(|(from, _)| *from != pred);
+        }
+    }
+}
+
+// 直前のブロックがjmpでだけ来るブロックを、そのブロックの後ろにつなげる。まとめたらtrue
+fn merge_blocks(f: &mut Function) -> bool {
+    let preds = predecessors(f);
+    for i in 0..f.blocks.len() {
+        let next = match f.blocks[i].term {
+            Terminator::Jump(next) if next.0 != i && next.0 != 0 => next,
+            _ => continue,
+        };
+        if preds[next.0].len() != 1 {
+            continue;
+        }
+        let mut insts = std::mem::take(&mut f.blocks[next.0].insts);
+        // 直前のブロックが一つなのでphiは引数をそのままcopyする
+        for inst in insts.iter_mut() {
+            if let Inst::Phi { dst, args } = inst {
+                *inst = Inst::Copy {
+                    dst: *dst,
+                    src: args[0].1,
+                };
+            }
+        }
+        let term = f.blocks[next.0].term.clone();
+        for succ in successors(&term) {
+            for inst in f.blocks[succ.0].insts.iter_mut() {
+                if let Inst::Phi { args, .. } = inst {
+                    for (pred, _) in args.iter_mut() {
+                        if *pred == next {
+                            *pred = BlockId(i);
+                        }
+                    }
+                }
+            }
+        }
+        f.blocks[i].insts.append(&mut insts);
+        f.blocks[i].term = term;
+        let mut keep = vec![true; f.blocks.len()];
+        keep[next.0] = false;
+        retain_blocks(f, &keep);
+        return true;
+    }
+    false
+}
+
+#[cfg(test)]
+fn ssa_of(src: &str) -> Function {
+    let mut module = crate::lower::module_of(src).ok().unwrap();
+    let mut f = module.functions.remove(0);
+    to_ssa(&mut f);
+    f
+}
+
+#[test]
+fn to_ssa_test() {
+    // 変数はphiになり、アドレスを取る変数はスロットのまま残る
+    let f = ssa_of(
+        "int f(int n) { int i; int *p; i = 0; p = &n; while (i < 3) i = i + 1; return i + *p; }",
+    );
+    assert_eq!(
+        f.to_string(),
+        "function f(%0: i32) -> i32 {
+  slot $0: 4
+  slot $1: 4
+  slot $2: 8
+bb0:
+  %1 = addr $0  ; i64
+  store i32 %1, %0
+  %4 = addr $0  ; i64
+  jmp bb1
+bb1:
+  %18 = phi [bb0, 0], [bb2, %11]  ; i32
+  %6 = copy %18  ; i32
+  %7 = lt i32 %6, 3  ; i32
+  br %7, bb2, bb3
+bb2:
+  %10 = copy %18  ; i32
+  %11 = add i32 %10, 1  ; i32
+  jmp bb1
+bb3:
+  %13 = copy %18  ; i32
+  %15 = copy %4  ; i64
+  %16 = load i32 %15  ; i32
+  %17 = add i32 %13, %16  ; i32
+  ret %17
+}
+"
+    );
+}
+
+#[test]
+fn propagate_constants_test() {
+    let mut f = ssa_of("int f() { int a; a = 2 * 3; if (a > 5) return a - 10 / 0; return 1 / 2; }");
+    propagate_constants(&mut f);
+    assert_eq!(
+        f.to_string(),
+        "function f() -> i32 {
+  slot $0: 4
+bb0:
+  %1 = const 6  ; i32
+  %3 = const 6  ; i32
+  %4 = const 1  ; i32
+  br 1, bb1, bb2
+bb1:
+  %6 = const 6  ; i32
+  %7 = div i32 10, 0  ; i32
+  %8 = sub i32 6, %7  ; i32
+  ret %8
+bb2:
+  %9 = const 0  ; i32
+  ret 0
+}
+"
+    );
+    // i32の演算は32ビットで折り返す
+    assert_eq!(
+        fold_binary(BinOp::Add, Ty::I32, i32::MAX as i64, 1),
+        Some(i32::MIN as i64)
+    );
+    assert_eq!(fold_binary(BinOp::Div, Ty::I32, i32::MIN as i64, -1), None);
+    assert_eq!(fold_binary(BinOp::ULt, Ty::I32, -1, 1), Some(0));
+    assert_eq!(
+        fold_binary(
+            BinOp::Mul,
+            Ty::F64,
+            1.5f64.to_bits() as i64,
+            2.0f64.to_bits() as i64
+        ),
+        Some(3.0f64.to_bits() as i64)
+    );
+}
+
+#[test]
+fn propagate_copies_test() {
+    let mut f = ssa_of("int f(int a) { int b; int c; b = a; c = b; return c + b; }");
+    propagate_copies(&mut f);
+    eliminate_dead_code(&mut f);
+    assert_eq!(
+        f.to_string(),
+        "function f(%0: i32) -> i32 {
+  slot $0: 4
+  slot $1: 4
+  slot $2: 4
+bb0:
+  %12 = add i32 %0, %0  ; i32
+  ret %12
+}
+"
+    );
+}
+
+#[test]
+fn eliminate_dead_code_test() {
+    // volatileな読み出しと呼び出しは結果を使わなくても残す
+    let mut f =
+        ssa_of("int g(); int f(volatile int *p) { int a; a = *p + 1; a = g(); *p; return 0; }");
+    eliminate_dead_code(&mut f);
+    assert_eq!(
+        f.to_string(),
+        "function f(%0: i64) -> i32 {
+  slot $0: 8
+  slot $1: 4
+bb0:
+  %4 = copy %0  ; i64
+  %5 = load volatile i32 %4  ; i32
+  %8 = call i32 @g()  ; i32
+  %10 = copy %0  ; i64
+  %11 = load volatile i32 %10  ; i32
+  ret 0
+}
+"
+    );
+}
+
+#[test]
+fn eliminate_common_subexpressions_test() {
+    let mut f = ssa_of("int f(int a, int b) { if (a) return (a * b) + (a * b); return a * b; }");
+    propagate_copies(&mut f);
+    eliminate_common_subexpressions(&mut f);
+    propagate_copies(&mut f);
+    eliminate_dead_code(&mut f);
+    assert_eq!(
+        f.to_string(),
+        "function f(%0: i32, %2: i32) -> i32 {
+  slot $0: 4
+  slot $1: 4
+bb0:
+  br %0, bb1, bb2
+bb1:
+  %10 = mul i32 %0, %2  ; i32
+  %16 = add i32 %10, %10  ; i32
+  ret %16
+bb2:
+  %21 = mul i32 %0, %2  ; i32
+  ret %21
+}
+"
+    );
+}
+
+#[test]
+fn simplify_cfg_test() {
+    let mut f = ssa_of("int f(int a) { if (1) a = a + 1; else a = 0; while (0) a = 2; return a; }");
+    simplify_cfg(&mut f);
+    assert_eq!(
+        f.to_string(),
+        "function f(%0: i32) -> i32 {
+  slot $0: 4
+bb0:
+  %4 = copy %0  ; i32
+  %5 = add i32 %4, 1  ; i32
+  %11 = copy %5  ; i32
+  %10 = copy %11  ; i32
+  %9 = copy %10  ; i32
+  ret %9
+}
+"
+    );
+}
@@ -1,9 +1,111 @@
-use crate::lexer::{tokenize, Token, Type};
+#[cfg(test)]
+use crate::diagnostic::Lang;
+use crate::diagnostic::{Context, Message};
+#[cfg(test)]
+use crate::lexer::tokenize;
+use crate::lexer::{IntType, Symbol, Token, Type};
+use std::fmt;
+use std::hash::{Hash, Hasher};
+use std::ops::Range;
 
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum TypeDec {
     Int,
+    UInt,
+    Long,
+    ULong,
+    Float,
+    Double,
     Pointer(Box<TypeDec>),
+    // 構造体はタグ名で参照し、メンバの配置はStmt::StructDecから求める
+    Struct(Symbol),
+    Func {
+        ret: Box<TypeDec>,
+        params: Vec<TypeDec>,
+    },
+    // const/volatileの付いた型。入れ子にはせず、qualifyで一つにまとめる
+    Qualified {
+        t: Box<TypeDec>,
+        qualifiers: Qualifiers,
+    },
+}
+
+#[derive(Debug, Clone, Copy, Default, Eq, PartialEq, Hash)]
+pub struct Qualifiers {
+    pub is_const: bool,
+    pub is_volatile: bool,
+}
+
+impl TypeDec {
+    pub fn unqualified(&self) -> &TypeDec {
+        match self {
+            TypeDec::Qualified { t, .. } => t,
+            t => t,
+        }
+    }
+    pub fn qualifiers(&self) -> Qualifiers {
+        match self {
+            TypeDec::Qualified { qualifiers, .. } => *qualifiers,
+            _ => Qualifiers::default(),
+        }
+    }
+    pub fn is_const(&self) -> bool {
+        self.qualifiers().is_const
+    }
+    pub fn is_volatile(&self) -> bool {
+        self.qualifiers().is_volatile
+    }
+    pub fn is_floating(&self) -> bool {
+        matches!(self.unqualified(), TypeDec::Float | TypeDec::Double)
+    }
+    pub fn is_unsigned(&self) -> bool {
+        matches!(self.unqualified(), TypeDec::UInt | TypeDec::ULong)
+    }
+}
+
+pub fn qualify(t: TypeDec, qualifiers: Qualifiers) -> TypeDec {
+    let qualifiers = qualify_flags(t.qualifiers(), qualifiers);
+    let t = t.unqualified().clone();
+    if qualifiers == Qualifiers::default() {
+        return t;
+    }
+    TypeDec::Qualified {
+        t: boxing(t),
+        qualifiers,
+    }
+}
+
+// エラーメッセージ用にCの表記で型を表示する
+impl fmt::Display for TypeDec {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            TypeDec::Int => write!(f, "int"),
+            TypeDec::UInt => write!(f, "unsigned int"),
+            TypeDec::Long => write!(f, "long"),
+            TypeDec::ULong => write!(f, "unsigned long"),
+            TypeDec::Float => write!(f, "float"),
+            TypeDec::Double => write!(f, "double"),
+            TypeDec::Struct(tag) => write!(f, "struct {}", tag),
+            TypeDec::Pointer(t) => write!(f, "{} *", t),
+            TypeDec::Func { ret, params } => {
+                let params: Vec<String> = params.iter().map(|t| t.to_string()).collect();
+                write!(f, "{} ({})", ret, params.join(", "))
+            }
+            TypeDec::Qualified { t, qualifiers } => {
+                let mut names = vec![];
+                if qualifiers.is_const {
+                    names.push("const");
+                }
+                if qualifiers.is_volatile {
+                    names.push("volatile");
+                }
+                match **t {
+                    TypeDec::Pointer(_) => write!(f, "{} {}", t, names.join(" ")),
+                    _ => write!(f, "{} {}", names.join(" "), t),
+                }
+            }
+        }
+    }
 }
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum Op {
@@ -23,10 +125,48 @@ pub enum Op {
 fn map_type(t: Type) -> TypeDec {
     match t {
         Type::Int => TypeDec::Int,
-        _ => panic!("未対応 map_type"),
+        Type::Float => TypeDec::Float,
+        Type::Double => TypeDec::Double,
+        Type::Long => TypeDec::Long,
+        Type::Unsigned => TypeDec::UInt,
+    }
+}
+
+fn map_int_type(t: IntType) -> TypeDec {
+    match t {
+        IntType::Int => TypeDec::Int,
+        IntType::UInt => TypeDec::UInt,
+        IntType::Long => TypeDec::Long,
+        IntType::ULong => TypeDec::ULong,
     }
 }
 
+// int, long, unsignedは組み合わせて書ける (unsigned long int など)。long longはlongと同じ
+fn combine_type(t: Option<TypeDec>, ty: &Type) -> Result<TypeDec, Message> {
+    match (t, ty) {
+        (None, ty) => Ok(map_type(ty.clone())),
+        (Some(TypeDec::Int), Type::Long)
+        | (Some(TypeDec::Long), Type::Int)
+        | (Some(TypeDec::Long), Type::Long) => Ok(TypeDec::Long),
+        (Some(TypeDec::UInt), Type::Long)
+        | (Some(TypeDec::Long), Type::Unsigned)
+        | (Some(TypeDec::ULong), Type::Int)
+        | (Some(TypeDec::ULong), Type::Long) => Ok(TypeDec::ULong),
+        (Some(TypeDec::UInt), Type::Int) | (Some(TypeDec::Int), Type::Unsigned) => {
+            Ok(TypeDec::UInt)
+        }
+        (Some(t), ty) => Err(Message::InvalidTypeCombination(
+            t.to_string(),
+            Token::Type(ty.clone()).to_string(),
+        )),
+    }
+}
+
+// 整数定数。単項のマイナスやポインタの演算で作る定数はint
+pub fn int_exp(i: i64) -> Exp {
+    Exp::Int(i, TypeDec::Int)
+}
+
 fn boxing<T>(t: T) -> Box<T> {
     Box::new(t)
 }
@@ -36,70 +176,239 @@ pub enum UOp {
     Deref,
 }
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
+#[allow(clippy::enum_variant_names)]
 pub enum Exp {
-    Int(i32),
-    Var(String),
+    // 値と型。値は型に関わらず64ビットのビット列で持つ
+    Int(i64, TypeDec),
+    // tはFloatかDouble。bitsはその型での値のビット列
+    Float {
+        bits: u64,
+        t: TypeDec,
+    },
+    Var(Symbol, Pos),
     UnaryExp {
         op: UOp,
         exp: Box<Exp>,
+        pos: Pos,
     },
     InfixExp {
         left: Box<Exp>,
         op: Op,
         right: Box<Exp>,
+        pos: Pos,
     },
     FuncCall {
         fun: Box<Exp>,
         args: Vec<Exp>,
+        pos: Pos,
+    },
+    Member {
+        exp: Box<Exp>,
+        member: Symbol,
+        pos: Pos,
     },
 }
+
+impl Exp {
+    // 式の位置。定数は畳み込みで作られることもあるので位置を持たない
+    pub fn pos(&self) -> Option<Pos> {
+        match self {
+            Exp::Int(..) | Exp::Float { .. } => None,
+            Exp::Var(_, pos)
+            | Exp::UnaryExp { pos, .. }
+            | Exp::InfixExp { pos, .. }
+            | Exp::FuncCall { pos, .. }
+            | Exp::Member { pos, .. } => Some(*pos),
+        }
+    }
+}
+
 #[derive(Debug, Clone, Eq, PartialEq, Hash)]
 pub enum Stmt {
-    Exp(Exp),
-    Return(Exp),
-    Block(Vec<Stmt>),
+    Exp(Exp, Pos),
+    Return(Exp, Pos),
+    Block(Vec<Stmt>, Pos),
     If {
         cond: Box<Exp>,
         stmt1: Box<Stmt>,
         stmt2: Box<Option<Stmt>>,
+        pos: Pos,
     },
     While {
         cond: Box<Exp>,
         stmt: Box<Stmt>,
+        pos: Pos,
     },
     For {
         exp1: Box<Option<Exp>>,
         exp2: Box<Option<Exp>>,
         exp3: Box<Option<Exp>>,
         stmt: Box<Stmt>,
+        pos: Pos,
     },
     Func {
         t: TypeDec,
         fun: Box<Exp>,
         params: Vec<TypeAndExp>,
         body: Vec<Stmt>,
+        storage: StorageClass,
+        // inline指定。インライン展開の目安にする
+        inline: bool,
+        pos: Pos,
     },
     VarDec {
         t: TypeDec,
         var: Box<Exp>,
+        storage: StorageClass,
+        init: Box<Option<Exp>>,
+        pos: Pos,
+    },
+    StructDec {
+        tag: Symbol,
+        members: Vec<Member>,
+        pos: Pos,
     },
 }
 
+impl Stmt {
+    pub fn pos(&self) -> Pos {
+        match self {
+            Stmt::Exp(_, pos) | Stmt::Return(_, pos) | Stmt::Block(_, pos) => *pos,
+            Stmt::If { pos, .. }
+            | Stmt::While { pos, .. }
+            | Stmt::For { pos, .. }
+            | Stmt::Func { pos, .. }
+            | Stmt::VarDec { pos, .. }
+            | Stmt::StructDec { pos, .. } => *pos,
+        }
+    }
+}
+
+// 文や式のトークン列での範囲。ParseErrorと同じく、先頭のトークンと最後のトークンの次から
+// 後ろに残っているトークンの数で持つ。
+// 書いた場所が違っても同じ式は同じ式なので、比較とハッシュには含めない
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Pos {
+    start: usize,
+    end: usize,
+}
+
+impl Pos {
+    // tokensから読み始めてrestが残ったときの、読んだ部分の位置
+    fn new(tokens: &[Token], rest: &[Token]) -> Pos {
+        Pos {
+            start: tokens.len(),
+            end: rest.len(),
+        }
+    }
+    // tokensの中での添字の範囲
+    pub fn range(&self, tokens: &[Token]) -> Range<usize> {
+        tokens.len() - self.start..tokens.len() - self.end
+    }
+}
+
+impl PartialEq for Pos {
+    fn eq(&self, _: &Pos) -> bool {
+        true
+    }
+}
+
+impl Eq for Pos {}
+
+impl Hash for Pos {
+    fn hash<H: Hasher>(&self, _: &mut H) {}
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq, Hash)]
+pub enum StorageClass {
+    Default,
+    Static,
+    Extern,
+}
+
 pub type TypeAndExp = (TypeDec, Exp);
 
+pub type Member = (TypeDec, Symbol);
+
+// 宣言子の名前は省略できる (プロトタイプや関数ポインタの仮引数など)
+type Param = (TypeDec, Option<Symbol>);
+
 pub type Program = Vec<Stmt>;
 
-type ParseExpResult<'a> = Result<(Exp, &'a [Token]), String>;
+type ParseExpResult<'a> = Result<(Exp, &'a [Token]), ParseError>;
+
+// 構文エラー。位置はエラーになったトークンから後ろに残っているトークンの数で持つ。
+// 末尾からの数なので、どの部分スライスで作ったエラーでも元のトークン列の位置に戻せる
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct ParseError {
+    pub message: Message,
+    remaining: usize,
+    // 対応する開きかっこのような、関係する位置への注記
+    pub notes: Vec<ParseError>,
+}
+
+impl ParseError {
+    // エラーになったトークンのtokensの中の位置。入力の終わりならtokens.len()
+    pub fn index(&self, tokens: &[Token]) -> usize {
+        tokens.len() - self.remaining
+    }
+}
+
+fn error_at(tokens: &[Token], message: Message) -> ParseError {
+    ParseError {
+        message,
+        remaining: tokens.len(),
+        notes: vec![],
+    }
+}
+
+// エラーの位置にあったトークン。Noneは入力の終わり
+fn found(tokens: &[Token]) -> Option<String> {
+    tokens.first().map(|token| token.to_string())
+}
+
+// 次のトークンがexpectedなら読み進める。
+// 違えば「expected `;` after expression, found `}`」の形のエラーにする
+fn expect(tokens: &[Token], expected: Token, context: Context) -> Result<&[Token], ParseError> {
+    match tokens {
+        [first, rest @ ..] if *first == expected => Ok(rest),
+        _ => Err(error_at(
+            tokens,
+            Message::Expected {
+                expected: expected.to_string(),
+                context,
+                found: found(tokens),
+            },
+        )),
+    }
+}
+
+// 閉じかっこを読む。なければopenの先頭にある開きかっこを注記で指す
+fn expect_closing<'a>(
+    tokens: &'a [Token],
+    expected: Token,
+    context: Context,
+    open: &[Token],
+) -> Result<&'a [Token], ParseError> {
+    expect(tokens, expected, context).map_err(|mut err| {
+        err.notes.push(error_at(
+            open,
+            Message::ToMatch(found(open).unwrap_or_default()),
+        ));
+        err
+    })
+}
 
 fn box_exp(exp: Exp) -> Box<Exp> {
     Box::new(exp)
 }
 
-fn infix_exp(left: Exp, op: Op, right: Exp) -> Exp {
+fn infix_exp(left: Exp, op: Op, right: Exp, pos: Pos) -> Exp {
     Exp::InfixExp {
         left: box_exp(left),
-        op: op,
+        op,
         right: box_exp(right),
+        pos,
     }
 }
 
@@ -119,234 +428,525 @@ fn token_mapper(token: Token) -> Op {
     }
 }
 
-pub fn parse_program(tokens: &[Token]) -> Result<Program, String> {
-    fn parse_program_sub(tokens: &[Token], acm: Vec<Stmt>) -> Result<Program, String> {
-        let stmt_result = parse_stmt(tokens);
-        match stmt_result {
-            Ok((stmt, rest)) => {
-                let stmt_vec = vec![stmt];
-                let acm = [acm, stmt_vec].concat();
-                if rest.is_empty() {
-                    return Ok(acm);
+// トップレベルの宣言は数が多くなりうるので、再帰せずにループで読む。
+// 構文エラーがあってもそこを読み飛ばして最後まで読み、読めた部分のASTとすべてのエラーを返す
+pub fn parse_program(tokens: &[Token]) -> (Program, Vec<ParseError>) {
+    let mut program = vec![];
+    let mut errors = vec![];
+    let mut rest = tokens;
+    while !rest.is_empty() {
+        rest = match parse_stmt(rest, &mut errors) {
+            Ok((stmt, next)) => {
+                program.push(stmt);
+                next
+            }
+            Err(err) => {
+                let next = synchronize(&rest[err.index(rest)..]);
+                errors.push(err);
+                match next {
+                    // 対応する{のない}は捨てる
+                    [Token::RBrace, next @ ..] => next,
+                    next => next,
                 }
-                parse_program_sub(rest, acm)
             }
-            Err(err) => return Err(err),
+        };
+    }
+    (program, errors)
+}
+
+// エラーの後、次の文の先頭まで読み飛ばす (panic mode)。tokensはエラーになったトークンから始める。
+// ;の後ろか、{}で囲まれた部分の後ろまで進む。外側のブロックを閉じる}の手前では止まる
+fn synchronize(tokens: &[Token]) -> &[Token] {
+    let mut depth = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            Token::Semicolon if depth == 0 => return &tokens[i + 1..],
+            Token::LBrace => depth += 1,
+            Token::RBrace if depth == 0 => return &tokens[i..],
+            Token::RBrace => {
+                depth -= 1;
+                if depth == 0 {
+                    return &tokens[i + 1..];
+                }
+            }
+            _ => {}
         }
     }
-    parse_program_sub(tokens, vec![])
+    &[]
 }
 
-fn new_if(cond: Exp, stmt1: Stmt, stmt2: Option<Stmt>) -> Stmt {
+fn new_if(cond: Exp, stmt1: Stmt, stmt2: Option<Stmt>, pos: Pos) -> Stmt {
     Stmt::If {
         cond: Box::new(cond),
         stmt1: Box::new(stmt1),
         stmt2: Box::new(stmt2),
+        pos,
     }
 }
-pub fn parse_stmt(tokens: &[Token]) -> Result<(Stmt, &[Token]), String> {
+// ブロックの中の文の構文エラーはerrorsに集め、ブロックの残りを読み続ける
+pub fn parse_stmt<'a>(
+    tokens: &'a [Token],
+    errors: &mut Vec<ParseError>,
+) -> Result<(Stmt, &'a [Token]), ParseError> {
     match tokens {
         [Token::Return, rest @ ..] => {
             let (exp, rest) = parse_exp(rest)?;
-            match rest {
-                [Token::Semicolon, rest @ ..] => Ok((Stmt::Return(exp.clone()), rest)),
-                _ => Err(format!(
-                    "parse_stmt1: stmtがSemicolonで終了していない:\n{:?}",
-                    tokens
-                )),
-            }
+            let rest = expect(rest, Token::Semicolon, Context::AfterReturnValue)?;
+            Ok((Stmt::Return(exp, Pos::new(tokens, rest)), rest))
         }
-        [Token::LBrace, rest @ ..] => {
-            let (block, rest) = parse_block(rest, &mut vec![])?;
-            Ok((Stmt::Block(block), rest))
-        }
-        [Token::If, Token::LParen, rest @ ..] => parse_if(rest),
-        [Token::While, Token::LParen, rest @ ..] => parse_while(rest),
-        [Token::For, Token::LParen, rest @ ..] => parse_for(rest),
-        [Token::Type(t), Token::Var(fun), Token::LParen, rest @ ..] => {
-            parse_func(map_type(t.clone()), fun.clone(), rest)
-        }
-        [Token::Type(t), rest @ ..] => {
-            let (t, rest) = parse_type(rest, map_type(t.clone()));
-            let (var, rest) = parse_stmt(rest)?;
-            let var = match var {
-                Stmt::Exp(exp) => exp,
-                _ => {
-                    panic!("error");
-                }
-            };
-            Ok((
-                Stmt::VarDec {
-                    t,
-                    var: box_exp(var),
-                },
-                rest,
-            ))
+        [Token::LBrace, ..] => {
+            let (block, rest) = parse_block(tokens, errors)?;
+            Ok((Stmt::Block(block, Pos::new(tokens, rest)), rest))
         }
+        [Token::If, ..] => parse_if(tokens, errors),
+        [Token::While, ..] => parse_while(tokens, errors),
+        [Token::For, ..] => parse_for(tokens, errors),
+        [Token::Struct, Token::Var(tag), Token::LBrace, rest @ ..] => {
+            parse_struct_dec(tokens, tag.clone(), rest)
+        }
+        [Token::Static, ..]
+        | [Token::Extern, ..]
+        | [Token::Inline, ..]
+        | [Token::Struct, ..]
+        | [Token::Const, ..]
+        | [Token::Volatile, ..]
+        | [Token::Type(_), ..] => parse_decl(tokens, errors),
         _ => {
-            let result = parse_exp(tokens);
-            match result {
-                Ok((ref exp, [Token::Semicolon, rest @ ..])) => Ok((Stmt::Exp(exp.clone()), rest)),
-                Err(err) => Err(err),
-                _ => Err(format!(
-                    "parse_stmt2: stmtがSemicolonで終了していない:\n{:?}",
-                    tokens,
-                )),
-            }
+            let (exp, rest) = parse_exp(tokens)?;
+            let rest = expect(rest, Token::Semicolon, Context::AfterExpression)?;
+            Ok((Stmt::Exp(exp, Pos::new(tokens, rest)), rest))
         }
     }
 }
 
-fn parse_vars<'a>(
-    tokens: &'a [Token],
-    acm: &mut Vec<Exp>,
-) -> Result<(Vec<Exp>, &'a [Token]), String> {
-    let tokens = match tokens {
-        [Token::LParen, rest @ ..] => rest,
-        _ => tokens,
-    };
+fn parse_vars(tokens: &[Token], mut acm: Vec<Exp>) -> Result<(Vec<Exp>, &[Token]), ParseError> {
     match tokens {
-        [Token::Comma, rest @ ..] => parse_vars(rest, acm),
-        [Token::RParen, rest @ ..] => Ok((acm.clone(), rest)),
-        [_, _rest @ ..] => {
+        [Token::RParen, rest @ ..] if acm.is_empty() => Ok((acm, rest)),
+        _ => {
             let (exp, rest) = parse_exp(tokens)?;
             acm.push(exp);
-            parse_vars(rest, acm)
+            match rest {
+                [Token::Comma, rest @ ..] => parse_vars(rest, acm),
+                _ => Ok((acm, expect(rest, Token::RParen, Context::AfterArguments)?)),
+            }
         }
-        _ => Err(format!("varsの形式がおかしい: {:?}", tokens)),
     }
 }
 
+// * の後ろの修飾子はポインタ自身に、最初の * より前の修飾子は元の型に付く
 fn parse_type(tokens: &[Token], t: TypeDec) -> (TypeDec, &[Token]) {
     match tokens {
         [Token::Asterisk, rest @ ..] => parse_type(rest, TypeDec::Pointer(boxing(t))),
+        [Token::Const, rest @ ..] => parse_type(rest, qualify(t, CONST)),
+        [Token::Volatile, rest @ ..] => parse_type(rest, qualify(t, VOLATILE)),
         _ => (t, tokens),
     }
 }
 
-fn parse_type_vars<'a>(
+const CONST: Qualifiers = Qualifiers {
+    is_const: true,
+    is_volatile: false,
+};
+const VOLATILE: Qualifiers = Qualifiers {
+    is_const: false,
+    is_volatile: true,
+};
+
+// 記憶域クラス指定子、型修飾子、型指定子を順不同で読む
+fn parse_decl_specifiers(
+    tokens: &[Token],
+    storage: StorageClass,
+    t: Option<TypeDec>,
+    qualifiers: Qualifiers,
+) -> Result<(TypeDec, StorageClass, &[Token]), ParseError> {
+    match tokens {
+        [Token::Static, rest @ ..] => {
+            parse_decl_specifiers(rest, StorageClass::Static, t, qualifiers)
+        }
+        [Token::Extern, rest @ ..] => {
+            parse_decl_specifiers(rest, StorageClass::Extern, t, qualifiers)
+        }
+        // inlineは記憶域クラスではないので、付いているかは呼び出し側がhas_inlineで調べる
+        [Token::Inline, rest @ ..] => parse_decl_specifiers(rest, storage, t, qualifiers),
+        [Token::Const, rest @ ..] => {
+            parse_decl_specifiers(rest, storage, t, qualify_flags(qualifiers, CONST))
+        }
+        [Token::Volatile, rest @ ..] => {
+            parse_decl_specifiers(rest, storage, t, qualify_flags(qualifiers, VOLATILE))
+        }
+        [Token::Type(ty), rest @ ..] => {
+            let t = combine_type(t, ty).map_err(|message| error_at(tokens, message))?;
+            parse_decl_specifiers(rest, storage, Some(t), qualifiers)
+        }
+        [Token::Struct, Token::Var(tag), rest @ ..] if t.is_none() => parse_decl_specifiers(
+            rest,
+            storage,
+            Some(TypeDec::Struct(tag.clone())),
+            qualifiers,
+        ),
+        _ => match t {
+            Some(t) => Ok((qualify(t, qualifiers), storage, tokens)),
+            None => Err(error_at(
+                tokens,
+                Message::ExpectedTypeSpecifier(found(tokens)),
+            )),
+        },
+    }
+}
+
+fn qualify_flags(a: Qualifiers, b: Qualifiers) -> Qualifiers {
+    Qualifiers {
+        is_const: a.is_const || b.is_const,
+        is_volatile: a.is_volatile || b.is_volatile,
+    }
+}
+
+// struct tag { メンバ; ... };
+// 定義と同時に変数を宣言する書き方には対応していない
+// startは宣言の先頭、tokensは{の後ろ
+fn parse_struct_dec<'a>(
+    start: &[Token],
+    tag: Symbol,
     tokens: &'a [Token],
-    acm: &mut Vec<TypeAndExp>,
-) -> Result<(Vec<TypeAndExp>, &'a [Token]), String> {
-    let tokens = match tokens {
-        [Token::LParen, rest @ ..] => rest,
-        _ => tokens,
-    };
+) -> Result<(Stmt, &'a [Token]), ParseError> {
+    let (members, rest) = parse_members(tokens, vec![])?;
+    let rest = expect(rest, Token::Semicolon, Context::AfterStructDefinition)?;
+    let pos = Pos::new(start, rest);
+    Ok((Stmt::StructDec { tag, members, pos }, rest))
+}
+
+fn parse_members(
+    tokens: &[Token],
+    mut acm: Vec<Member>,
+) -> Result<(Vec<Member>, &[Token]), ParseError> {
     match tokens {
-        [Token::Comma, rest @ ..] => parse_type_vars(rest, acm),
-        [Token::RParen, rest @ ..] => Ok((acm.clone(), rest)),
-        [Token::Type(t), rest @ ..] => {
-            let (t, rest) = parse_type(rest, map_type(t.clone()));
-            let (exp, rest) = parse_exp(rest)?;
-            acm.push((t, exp));
-            parse_type_vars(rest, acm)
+        [Token::RBrace, rest @ ..] => Ok((acm, rest)),
+        _ => {
+            let (t, storage, rest) =
+                parse_decl_specifiers(tokens, StorageClass::Default, None, Qualifiers::default())?;
+            if storage != StorageClass::Default {
+                return Err(error_at(tokens, Message::StorageClassOnMember));
+            }
+            if has_inline(tokens, rest) {
+                return Err(error_at(tokens, Message::InlineOnNonFunction));
+            }
+            let (t, var, rest) = parse_declarator(rest, t)?;
+            match var {
+                Some(var) => {
+                    let rest = expect(rest, Token::Semicolon, Context::AfterStructMember)?;
+                    acm.push((t, var));
+                    parse_members(rest, acm)
+                }
+                None => Err(error_at(rest, Message::ExpectedMemberName(found(rest)))),
+            }
+        }
+    }
+}
+
+fn parse_decl<'a>(
+    tokens: &'a [Token],
+    errors: &mut Vec<ParseError>,
+) -> Result<(Stmt, &'a [Token]), ParseError> {
+    let (t, storage, rest) =
+        parse_decl_specifiers(tokens, StorageClass::Default, None, Qualifiers::default())?;
+    let inline = has_inline(tokens, rest);
+    let (t, rest) = parse_type(rest, t);
+    match rest {
+        [Token::Var(fun), Token::LParen, rest @ ..] => {
+            parse_func(tokens, t, fun.clone(), rest, storage, inline, errors)
         }
-        _ => Err(format!("varsの形式がおかしい: {:?}", tokens)),
+        _ if inline => Err(error_at(tokens, Message::InlineOnNonFunction)),
+        _ => parse_var_dec(tokens, t, rest, storage),
     }
 }
 
-fn parse_func(t: TypeDec, fun: String, tokens: &[Token]) -> Result<(Stmt, &[Token]), String> {
-    let (params, rest) = parse_type_vars(tokens, &mut vec![])?;
-    let (body, rest) = parse_block(rest, &mut vec![])?;
+// 宣言指定子 (tokensからrestの前まで) にinlineがあるか
+fn has_inline(tokens: &[Token], rest: &[Token]) -> bool {
+    tokens[..tokens.len() - rest.len()].contains(&Token::Inline)
+}
+
+// startは宣言の先頭、tokensは宣言子の先頭
+fn parse_var_dec<'a>(
+    start: &[Token],
+    t: TypeDec,
+    tokens: &'a [Token],
+    storage: StorageClass,
+) -> Result<(Stmt, &'a [Token]), ParseError> {
+    let (t, var, rest) = parse_declarator(tokens, t)?;
+    let var = match var {
+        Some(var) => var,
+        None => return Err(error_at(rest, Message::ExpectedVariableName(found(rest)))),
+    };
+    let (init, rest) = match rest {
+        [Token::Assign, rest @ ..] => {
+            let (init, rest) = parse_exp(rest)?;
+            (Some(init), rest)
+        }
+        _ => (None, rest),
+    };
+    let rest = expect(rest, Token::Semicolon, Context::AfterDeclaration)?;
+    // 宣言した名前の位置は宣言全体にする
+    let pos = Pos::new(start, rest);
     Ok((
-        Stmt::Func {
-            t: t,
-            fun: Box::new(Exp::Var(fun)),
-            params: params,
-            body: body,
+        Stmt::VarDec {
+            t,
+            var: box_exp(Exp::Var(var, pos)),
+            storage,
+            init: Box::new(init),
+            pos,
         },
         rest,
     ))
 }
 
-fn parse_fun_call(v: String, tokens: &[Token]) -> Result<(Exp, &[Token]), String> {
-    let (args, rest) = parse_vars(tokens, &mut vec![])?;
-    Ok((
-        Exp::FuncCall {
-            fun: Box::new(Exp::Var(v)),
-            args: args,
+// int (*fp)(int, int) のような宣言子を読む。
+// カッコの中の宣言子は後ろの引数リストを読むまで型が決まらないので、
+// 一旦Intを仮の型として読んでおき、後で差し替える。
+fn parse_declarator(
+    tokens: &[Token],
+    t: TypeDec,
+) -> Result<(TypeDec, Option<Symbol>, &[Token]), ParseError> {
+    let (t, tokens) = parse_type(tokens, t);
+    match tokens {
+        [Token::LParen, Token::Asterisk, ..] => {
+            let (inner, var, rest) = parse_declarator(&tokens[1..], TypeDec::Int)?;
+            let rest = expect_closing(rest, Token::RParen, Context::CloseDeclarator, tokens)?;
+            let (t, rest) = parse_type_suffix(rest, t)?;
+            Ok((replace_base_type(inner, t), var, rest))
+        }
+        [Token::Var(var), rest @ ..] => {
+            let (t, rest) = parse_type_suffix(rest, t)?;
+            Ok((t, Some(var.clone()), rest))
+        }
+        _ => {
+            let (t, rest) = parse_type_suffix(tokens, t)?;
+            Ok((t, None, rest))
+        }
+    }
+}
+
+fn replace_base_type(inner: TypeDec, t: TypeDec) -> TypeDec {
+    match inner {
+        TypeDec::Pointer(base) => TypeDec::Pointer(boxing(replace_base_type(*base, t))),
+        TypeDec::Func { ret, params } => TypeDec::Func {
+            ret: boxing(replace_base_type(*ret, t)),
+            params,
         },
-        rest,
-    ))
+        TypeDec::Qualified {
+            t: base,
+            qualifiers,
+        } => qualify(replace_base_type(*base, t), qualifiers),
+        // intは仮に置いた型なので、本当の型に置き換える
+        TypeDec::Int => t,
+        base => base,
+    }
 }
 
-fn parse_block<'a>(
-    tokens: &'a [Token],
-    acm: &mut Vec<Stmt>,
-) -> Result<(Vec<Stmt>, &'a [Token]), String> {
-    let tokens = match tokens {
-        [Token::LBrace, rest @ ..] => rest,
-        _ => tokens,
-    };
-    let (stmt, rest) = parse_stmt(tokens)?;
-    acm.push(stmt);
-    match rest {
-        [Token::RBrace, rest @ ..] => Ok((acm.clone(), rest)),
-        [] => Ok((acm.clone(), &[])),
-        _ => parse_block(rest, acm),
+fn parse_type_suffix(tokens: &[Token], t: TypeDec) -> Result<(TypeDec, &[Token]), ParseError> {
+    match tokens {
+        [Token::LParen, rest @ ..] => {
+            let (params, rest) = parse_params(rest, vec![])?;
+            Ok((func_type(t, &params), rest))
+        }
+        _ => Ok((t, tokens)),
     }
 }
 
-fn parse_if(tokens: &[Token]) -> Result<(Stmt, &[Token]), String> {
-    let (cond, rest) = parse_exp(tokens)?;
-    match rest {
-        [Token::RParen, rest @ ..] => {
-            let (stmt1, rest) = parse_stmt(rest)?;
+fn func_type(ret: TypeDec, params: &[Param]) -> TypeDec {
+    TypeDec::Func {
+        ret: boxing(ret),
+        params: params.iter().map(|(t, _)| t.clone()).collect(),
+    }
+}
+
+fn parse_params(
+    tokens: &[Token],
+    mut acm: Vec<Param>,
+) -> Result<(Vec<Param>, &[Token]), ParseError> {
+    match tokens {
+        [Token::RParen, rest @ ..] if acm.is_empty() => Ok((acm, rest)),
+        _ => {
+            let (t, storage, rest) =
+                parse_decl_specifiers(tokens, StorageClass::Default, None, Qualifiers::default())?;
+            if storage != StorageClass::Default {
+                return Err(error_at(tokens, Message::StorageClassOnParameter));
+            }
+            if has_inline(tokens, rest) {
+                return Err(error_at(tokens, Message::InlineOnNonFunction));
+            }
+            let (t, var, rest) = parse_declarator(rest, t)?;
+            // 関数型の仮引数は関数ポインタとして扱う
+            let t = match t {
+                TypeDec::Func { .. } => TypeDec::Pointer(boxing(t)),
+                t => t,
+            };
+            acm.push((t, var));
             match rest {
-                [Token::Else, rest @ ..] => {
-                    let (stmt2, rest) = parse_stmt(rest)?;
-                    Ok((new_if(cond, stmt1, Some(stmt2)), rest))
-                }
-                _ => Ok((new_if(cond, stmt1, None), rest)),
+                [Token::Comma, rest @ ..] => parse_params(rest, acm),
+                _ => Ok((acm, expect(rest, Token::RParen, Context::AfterParameters)?)),
             }
         }
-        _ => panic!("if: 条件式のかっこが閉じてない"),
     }
 }
 
-fn parse_while(tokens: &[Token]) -> Result<(Stmt, &[Token]), String> {
-    let (cond, rest) = parse_exp(tokens)?;
+// startは宣言の先頭、tokensは(の後ろ
+fn parse_func<'a>(
+    start: &[Token],
+    t: TypeDec,
+    fun: Symbol,
+    tokens: &'a [Token],
+    storage: StorageClass,
+    inline: bool,
+    errors: &mut Vec<ParseError>,
+) -> Result<(Stmt, &'a [Token]), ParseError> {
+    let (params, rest) = parse_params(tokens, vec![])?;
+    if let [Token::Semicolon, rest @ ..] = rest {
+        // プロトタイプ宣言
+        let pos = Pos::new(start, rest);
+        return Ok((
+            Stmt::VarDec {
+                t: func_type(t, &params),
+                var: box_exp(Exp::Var(fun, pos)),
+                storage,
+                init: Box::new(None),
+                pos,
+            },
+            rest,
+        ));
+    }
+    // 関数名と仮引数の位置は宣言の先頭から仮引数リストの終わりまでにする
+    let head = Pos::new(start, rest);
+    let mut named_params = vec![];
+    for (param_t, var) in params {
+        match var {
+            Some(var) => named_params.push((param_t, Exp::Var(var, head))),
+            None => {
+                return Err(error_at(
+                    tokens,
+                    Message::MissingParameterName(fun.to_string()),
+                ))
+            }
+        }
+    }
+    expect(rest, Token::LBrace, Context::AfterParameters)?;
+    let (body, rest) = parse_block(rest, errors)?;
+    Ok((
+        Stmt::Func {
+            t,
+            fun: Box::new(Exp::Var(fun, head)),
+            params: named_params,
+            body,
+            storage,
+            inline,
+            pos: Pos::new(start, rest),
+        },
+        rest,
+    ))
+}
+
+// openは{から始める。対応する}までを読む。文の数だけ再帰しないようにループで読む
+fn parse_block<'a>(
+    open: &'a [Token],
+    errors: &mut Vec<ParseError>,
+) -> Result<(Vec<Stmt>, &'a [Token]), ParseError> {
+    let mut acm = vec![];
+    let mut rest = &open[1..];
+    loop {
+        rest = match rest {
+            [Token::RBrace, rest @ ..] => return Ok((acm, rest)),
+            // }がないまま入力が終わった
+            [] => {
+                return expect_closing(rest, Token::RBrace, Context::CloseBlock, open)
+                    .map(|rest| (acm, rest))
+            }
+            _ => match parse_stmt(rest, errors) {
+                Ok((stmt, next)) => {
+                    acm.push(stmt);
+                    next
+                }
+                Err(err) => {
+                    let next = synchronize(&rest[err.index(rest)..]);
+                    errors.push(err);
+                    next
+                }
+            },
+        };
+    }
+}
+
+// tokensはifから始める
+fn parse_if<'a>(
+    tokens: &'a [Token],
+    errors: &mut Vec<ParseError>,
+) -> Result<(Stmt, &'a [Token]), ParseError> {
+    let open = &tokens[1..];
+    let rest = expect(open, Token::LParen, Context::AfterIf)?;
+    let (cond, rest) = parse_exp(rest)?;
+    let rest = expect_closing(rest, Token::RParen, Context::AfterIfCondition, open)?;
+    let (stmt1, rest) = parse_stmt(rest, errors)?;
     match rest {
-        [Token::RParen, rest @ ..] => {
-            let (stmt, rest) = parse_stmt(rest)?;
-            Ok((
-                Stmt::While {
-                    cond: Box::new(cond),
-                    stmt: Box::new(stmt),
-                },
-                rest,
-            ))
+        [Token::Else, rest @ ..] => {
+            let (stmt2, rest) = parse_stmt(rest, errors)?;
+            let pos = Pos::new(tokens, rest);
+            Ok((new_if(cond, stmt1, Some(stmt2), pos), rest))
         }
-        _ => panic!("if: 条件式のかっこが閉じてない"),
+        _ => Ok((new_if(cond, stmt1, None, Pos::new(tokens, rest)), rest)),
     }
 }
 
-fn parse_for_cond_exp(tokens: &[Token]) -> Result<(Option<Exp>, &[Token]), String> {
-    // exp1; exp2; exp3)
+// tokensはwhileから始める
+fn parse_while<'a>(
+    tokens: &'a [Token],
+    errors: &mut Vec<ParseError>,
+) -> Result<(Stmt, &'a [Token]), ParseError> {
+    let open = &tokens[1..];
+    let rest = expect(open, Token::LParen, Context::AfterWhile)?;
+    let (cond, rest) = parse_exp(rest)?;
+    let rest = expect_closing(rest, Token::RParen, Context::AfterWhileCondition, open)?;
+    let (stmt, rest) = parse_stmt(rest, errors)?;
+    Ok((
+        Stmt::While {
+            cond: Box::new(cond),
+            stmt: Box::new(stmt),
+            pos: Pos::new(tokens, rest),
+        },
+        rest,
+    ))
+}
+
+// exp1; exp2; exp3) の一つを読む。endはその後ろに来るトークンで、読まずに残す
+fn parse_for_cond_exp(tokens: &[Token], end: Token) -> Result<(Option<Exp>, &[Token]), ParseError> {
     match tokens {
-        [Token::Semicolon, rest @ ..] | [Token::RParen, rest @ ..] => Ok((None, rest)),
+        [first, ..] if *first == end => Ok((None, tokens)),
         _ => {
             let (exp, rest) = parse_exp(tokens)?;
-            match rest {
-                [Token::Semicolon, rest @ ..] | [Token::RParen, rest @ ..] => Ok((Some(exp), rest)),
-                _ => Err(format!("forの形式がおかしい。{:?}", tokens)),
-            }
+            Ok((Some(exp), rest))
         }
     }
 }
 
-fn parse_for(tokens: &[Token]) -> Result<(Stmt, &[Token]), String> {
+// tokensはforから始める
+fn parse_for<'a>(
+    tokens: &'a [Token],
+    errors: &mut Vec<ParseError>,
+) -> Result<(Stmt, &'a [Token]), ParseError> {
     // for(exp1; exp2; exp3) stmt
-    let (exp1, rest) = parse_for_cond_exp(tokens)?;
-    let (exp2, rest) = parse_for_cond_exp(rest)?;
-    let (exp3, rest) = parse_for_cond_exp(rest)?;
-    let (stmt, rest) = parse_stmt(rest)?;
+    let open = &tokens[1..];
+    let rest = expect(open, Token::LParen, Context::AfterFor)?;
+    let (exp1, rest) = parse_for_cond_exp(rest, Token::Semicolon)?;
+    let rest = expect(rest, Token::Semicolon, Context::AfterForInitializer)?;
+    let (exp2, rest) = parse_for_cond_exp(rest, Token::Semicolon)?;
+    let rest = expect(rest, Token::Semicolon, Context::AfterForCondition)?;
+    let (exp3, rest) = parse_for_cond_exp(rest, Token::RParen)?;
+    let rest = expect_closing(rest, Token::RParen, Context::AfterForClauses, open)?;
+    let (stmt, rest) = parse_stmt(rest, errors)?;
     Ok((
         Stmt::For {
             exp1: Box::new(exp1),
             exp2: Box::new(exp2),
             exp3: Box::new(exp3),
             stmt: Box::new(stmt),
+            pos: Pos::new(tokens, rest),
         },
         rest,
     ))
@@ -361,24 +961,31 @@ fn parse_assign<'a>(tokens: &'a [Token]) -> ParseExpResult<'a> {
     match rest {
         [Token::Assign, rest @ ..] => {
             let (assign, rest) = parse_assign(rest)?;
-            Ok((infix_exp(equality, Op::Assign, assign), rest))
+            let pos = Pos::new(tokens, rest);
+            Ok((infix_exp(equality, Op::Assign, assign, pos), rest))
         }
         _ => Ok((equality, rest)),
     }
 }
 
-const EQUALITY_TOKENS: &'static [Token] = &[Token::Eq, Token::NotEq];
+const EQUALITY_TOKENS: &[Token] = &[Token::Eq, Token::NotEq];
 fn parse_equality<'a>(tokens: &'a [Token]) -> ParseExpResult<'a> {
     let (relational, rest) = parse_relational(tokens)?;
     match rest {
         [first, rest @ ..] if EQUALITY_TOKENS.contains(first) => {
             let (right_relational, rest) = parse_relational(rest)?;
-            let relational = infix_exp(relational, token_mapper(first.clone()), right_relational);
+            let relational = infix_exp(
+                relational,
+                token_mapper(first.clone()),
+                right_relational,
+                Pos::new(tokens, rest),
+            );
             match rest {
                 [first, rest @ ..] if EQUALITY_TOKENS.contains(first) => {
                     let (equality, rest) = parse_equality(rest)?;
+                    let pos = Pos::new(tokens, rest);
                     Ok((
-                        infix_exp(relational, token_mapper(first.clone()), equality),
+                        infix_exp(relational, token_mapper(first.clone()), equality, pos),
                         rest,
                     ))
                 }
@@ -389,18 +996,24 @@ fn parse_equality<'a>(tokens: &'a [Token]) -> ParseExpResult<'a> {
     }
 }
 
-const RELATIONAL_TOKENS: &'static [Token] = &[Token::Ls, Token::LsEq, Token::Gr, Token::GrEq];
+const RELATIONAL_TOKENS: &[Token] = &[Token::Ls, Token::LsEq, Token::Gr, Token::GrEq];
 fn parse_relational<'a>(tokens: &'a [Token]) -> ParseExpResult<'a> {
     let (add, rest) = parse_add(tokens)?;
     match rest {
         [first, rest @ ..] if RELATIONAL_TOKENS.contains(first) => {
             let (right_add, rest) = parse_add(rest)?;
-            let add = infix_exp(add, token_mapper(first.clone()), right_add);
+            let add = infix_exp(
+                add,
+                token_mapper(first.clone()),
+                right_add,
+                Pos::new(tokens, rest),
+            );
             match rest {
                 [first, rest @ ..] if RELATIONAL_TOKENS.contains(first) => {
                     let (relational, rest) = parse_relational(rest)?;
+                    let pos = Pos::new(tokens, rest);
                     Ok((
-                        infix_exp(add, token_mapper(first.clone()), relational),
+                        infix_exp(add, token_mapper(first.clone()), relational, pos),
                         rest,
                     ))
                 }
@@ -411,17 +1024,23 @@ fn parse_relational<'a>(tokens: &'a [Token]) -> ParseExpResult<'a> {
     }
 }
 
-const ADD_TOKENS: &'static [Token] = &[Token::Plus, Token::Minus];
+const ADD_TOKENS: &[Token] = &[Token::Plus, Token::Minus];
 fn parse_add<'a>(tokens: &'a [Token]) -> ParseExpResult<'a> {
     let (mul, rest) = parse_mul(tokens)?;
     match rest {
         [first, rest @ ..] if ADD_TOKENS.contains(first) => {
             let (right_mul, rest) = parse_mul(rest)?;
-            let mul = infix_exp(mul, token_mapper(first.clone()), right_mul);
+            let mul = infix_exp(
+                mul,
+                token_mapper(first.clone()),
+                right_mul,
+                Pos::new(tokens, rest),
+            );
             match rest {
                 [first, rest @ ..] if ADD_TOKENS.contains(first) => {
                     let (add, rest) = parse_add(rest)?;
-                    Ok((infix_exp(mul, token_mapper(first.clone()), add), rest))
+                    let pos = Pos::new(tokens, rest);
+                    Ok((infix_exp(mul, token_mapper(first.clone()), add, pos), rest))
                 }
                 _ => Ok((mul, rest)),
             }
@@ -430,18 +1049,27 @@ fn parse_add<'a>(tokens: &'a [Token]) -> ParseExpResult<'a> {
     }
 }
 
-const MUL_TOKENS: &'static [Token] = &[Token::Asterisk, Token::Slash];
+const MUL_TOKENS: &[Token] = &[Token::Asterisk, Token::Slash];
 
 fn parse_mul<'a>(tokens: &'a [Token]) -> ParseExpResult<'a> {
     let (primary, rest) = parse_unary(tokens)?;
     match rest {
         [first, rest @ ..] if MUL_TOKENS.contains(first) => {
             let (right_primary, rest) = parse_unary(rest)?;
-            let primary = infix_exp(primary, token_mapper(first.clone()), right_primary);
+            let primary = infix_exp(
+                primary,
+                token_mapper(first.clone()),
+                right_primary,
+                Pos::new(tokens, rest),
+            );
             match rest {
                 [first, rest @ ..] if MUL_TOKENS.contains(first) => {
                     let (mul, rest) = parse_mul(rest)?;
-                    Ok((infix_exp(primary, token_mapper(first.clone()), mul), rest))
+                    let pos = Pos::new(tokens, rest);
+                    Ok((
+                        infix_exp(primary, token_mapper(first.clone()), mul, pos),
+                        rest,
+                    ))
                 }
                 _ => Ok((primary, rest)),
             }
@@ -450,10 +1078,11 @@ fn parse_mul<'a>(tokens: &'a [Token]) -> ParseExpResult<'a> {
     }
 }
 
-fn unary_exp(op: UOp, exp: Exp) -> Exp {
+fn unary_exp(op: UOp, exp: Exp, pos: Pos) -> Exp {
     Exp::UnaryExp {
-        op: op,
+        op,
         exp: Box::new(exp),
+        pos,
     }
 }
 
@@ -462,17 +1091,60 @@ fn parse_unary<'a>(tokens: &'a [Token]) -> ParseExpResult<'a> {
         [Token::Plus, rest @ ..] => parse_unary(rest),
         [Token::Minus, rest @ ..] => {
             let (p, rest) = parse_unary(rest)?;
-            Ok((infix_exp(Exp::Int(0), Op::Minus, p), rest))
+            let pos = Pos::new(tokens, rest);
+            Ok((infix_exp(int_exp(0), Op::Minus, p, pos), rest))
         }
         [Token::Asterisk, rest @ ..] => {
             let (e, rest) = parse_unary(rest)?;
-            Ok((unary_exp(UOp::Deref, e), rest))
+            Ok((unary_exp(UOp::Deref, e, Pos::new(tokens, rest)), rest))
         }
         [Token::Address, rest @ ..] => {
             let (e, rest) = parse_unary(rest)?;
-            Ok((unary_exp(UOp::Address, e), rest))
+            Ok((unary_exp(UOp::Address, e, Pos::new(tokens, rest)), rest))
+        }
+        _ => parse_postfix(tokens),
+    }
+}
+
+fn parse_postfix<'a>(tokens: &'a [Token]) -> ParseExpResult<'a> {
+    let (primary, rest) = parse_primary(tokens)?;
+    parse_postfix_sub(tokens, primary, rest)
+}
+
+// startはexpの先頭
+fn parse_postfix_sub<'a>(start: &[Token], exp: Exp, tokens: &'a [Token]) -> ParseExpResult<'a> {
+    match tokens {
+        [Token::LParen, rest @ ..] => {
+            let (args, rest) = parse_vars(rest, vec![])?;
+            let call = Exp::FuncCall {
+                fun: box_exp(exp),
+                args,
+                pos: Pos::new(start, rest),
+            };
+            parse_postfix_sub(start, call, rest)
         }
-        _ => parse_primary(tokens),
+        [Token::Dot, Token::Var(member), rest @ ..] => {
+            let exp = Exp::Member {
+                exp: box_exp(exp),
+                member: member.clone(),
+                pos: Pos::new(start, rest),
+            };
+            parse_postfix_sub(start, exp, rest)
+        }
+        // a->b は (*a).b として扱う
+        [Token::Arrow, Token::Var(member), rest @ ..] => {
+            let pos = Pos::new(start, rest);
+            let exp = Exp::Member {
+                exp: box_exp(unary_exp(UOp::Deref, exp, pos)),
+                member: member.clone(),
+                pos,
+            };
+            parse_postfix_sub(start, exp, rest)
+        }
+        [Token::Dot, rest @ ..] | [Token::Arrow, rest @ ..] => {
+            Err(error_at(rest, Message::ExpectedMemberName(found(rest))))
+        }
+        _ => Ok((exp, tokens)),
     }
 }
 
@@ -480,30 +1152,43 @@ fn parse_primary<'a>(tokens: &'a [Token]) -> ParseExpResult<'a> {
     match tokens {
         [Token::LParen, rest @ ..] => {
             let (add, rest) = parse_add(rest)?;
-            match rest {
-                [Token::RParen, rest @ ..] => Ok((add, rest)),
-                _ => Err(format!("カッコが閉じていない: {:?}", tokens)),
-            }
+            Ok((
+                add,
+                expect_closing(rest, Token::RParen, Context::CloseParen, tokens)?,
+            ))
         }
-        [Token::Var(v), Token::LParen, rest @ ..] => parse_fun_call(v.clone(), rest),
-        [Token::Int(i), rest @ ..] => Ok((Exp::Int(*i), rest)),
-        [Token::Var(v), rest @ ..] => Ok((Exp::Var(v.clone()), rest)),
-        _ => Err(format!("unexpected token: {:?}", tokens)),
+        [Token::Int(i, t), rest @ ..] => Ok((Exp::Int(*i, map_int_type(*t)), rest)),
+        [Token::Float { bits, t }, rest @ ..] => Ok((
+            Exp::Float {
+                bits: *bits,
+                t: map_type(t.clone()),
+            },
+            rest,
+        )),
+        [Token::Var(v), rest @ ..] => Ok((Exp::Var(v.clone(), Pos::new(tokens, rest)), rest)),
+        _ => Err(error_at(tokens, Message::ExpectedExpression(found(tokens)))),
     }
 }
 
+// テストで使う、構文エラーのないソースの構文木
+#[cfg(test)]
+pub(crate) fn program_of(src: &str) -> Program {
+    let (program, errors) = parse_program(&tokenize(src).ok().unwrap());
+    assert!(errors.is_empty(), "{:?}", errors);
+    program
+}
+
+#[cfg(test)]
 fn parse_test(str: &str) {
     let tokens = tokenize(str);
     let tokens = match tokens {
         Ok(result) => result,
-        Err(err) => panic!(err),
-    };
-    let stmts = parse_program(tokens.as_slice());
-    let exp = match stmts {
-        Ok(result) => result,
-        Err(err) => panic!(err),
+        Err(err) => panic!("{}", err.message.text(Lang::En)),
     };
-    println!("{:?}", exp);
+    let (_, errors) = parse_program(tokens.as_slice());
+    if let Some(err) = errors.first() {
+        panic!("{}", err.message.text(Lang::En));
+    }
 }
 
 #[test]
@@ -568,4 +1253,180 @@ fn parse_exp_test() {
     parse_test("1 + **a; &*a * 2;");
     parse_test("int *a; ");
     parse_test("int ***a; ");
+
+    parse_test("int (*fp)(int, int); fp = &add; (*fp)(1, 2); fp(3, 4);");
+    parse_test("int apply(int (*f)(int), int x) { return f(x); }");
+    parse_test("int add(int, int); int *ptr(int *p) { return p; }");
+
+    parse_test("static int f() { static int n; return n; }");
+    parse_test("extern int g; extern int h(int); int x; static int *y;");
+
+    parse_test("const int x = 3; int const y = 4; volatile int *const p = &x;");
+    parse_test("int f(const int *p, int *volatile q) { static const int z = 1; return *p; }");
+
+    parse_test("struct P { int x; struct P *next; }; struct P p; p.x = 1; p.next->next = 0;");
+    parse_test("struct P make(struct P a, const struct P *b) { return a; }");
+}
+
+#[test]
+fn parse_declarator_test() {
+    let tokens = tokenize("(**fp)(int, int *);").ok().unwrap();
+    let (t, var, rest) = parse_declarator(&tokens, TypeDec::Int).ok().unwrap();
+    assert_eq!(
+        t,
+        TypeDec::Pointer(boxing(TypeDec::Pointer(boxing(TypeDec::Func {
+            ret: boxing(TypeDec::Int),
+            params: vec![TypeDec::Int, TypeDec::Pointer(boxing(TypeDec::Int))],
+        }))))
+    );
+    assert_eq!(var, Some("fp".into()));
+    assert_eq!(rest, &[Token::Semicolon]);
+}
+
+#[test]
+fn parse_qualifier_test() {
+    let tokens = tokenize("const int *const volatile p;").ok().unwrap();
+    let (stmt, _) = parse_stmt(&tokens, &mut vec![]).ok().unwrap();
+    let const_int = qualify(TypeDec::Int, CONST);
+    let expected = qualify(
+        TypeDec::Pointer(boxing(const_int)),
+        qualify_flags(CONST, VOLATILE),
+    );
+    match stmt {
+        Stmt::VarDec { t, .. } => {
+            assert_eq!(t, expected);
+            assert_eq!(t.to_string(), "const int * const volatile");
+        }
+        _ => panic!("VarDecでない: {:?}", stmt),
+    }
+    // 修飾子は入れ子にならない
+    assert_eq!(
+        qualify(qualify(TypeDec::Int, CONST), CONST),
+        qualify(TypeDec::Int, CONST)
+    );
+}
+
+#[test]
+fn parse_many_functions_test() {
+    // トップレベルの宣言の数だけ再帰しないので、関数が多くてもスタックが溢れない
+    let src: String = (0..10000)
+        .map(|i| format!("int f{}(int a) {{ int x; x = a + {}; return x; }}\n", i, i))
+        .collect();
+    let tokens = tokenize(&src).ok().unwrap();
+    let (program, errors) = parse_program(&tokens);
+    assert!(errors.is_empty());
+    assert_eq!(program.len(), 10000);
+
+    // ブロックの中の文の数だけでも再帰しない
+    let body: String = (0..20000).map(|i| format!("x = {};\n", i)).collect();
+    let src = format!("int main() {{ int x; {} return x; }}", body);
+    let tokens = tokenize(&src).ok().unwrap();
+    let (program, errors) = parse_program(&tokens);
+    assert!(errors.is_empty());
+    match &program[0] {
+        Stmt::Func { body, .. } => assert_eq!(body.len(), 20002),
+        stmt => panic!("関数ではない: {:?}", stmt),
+    }
+}
+
+#[test]
+fn parse_recovery_test() {
+    let src = "int f() { int x; x = ; if (x) { x = 1 +; } return x; }
+               int g( { return 0; }
+               int h() { while (1 { } return 2 }
+               int main() { return 0; }";
+    let tokens = tokenize(src).ok().unwrap();
+    let (program, errors) = parse_program(&tokens);
+    // x = ; と 1 +; と g の宣言と while と return 2 }
+    assert_eq!(errors.len(), 5, "{:?}", errors);
+    // 中の文にエラーがあってもfとhは残る。本体を読めなかったgはない
+    let funcs: Vec<&Exp> = program
+        .iter()
+        .filter_map(|stmt| match stmt {
+            Stmt::Func { fun, .. } => Some(fun.as_ref()),
+            _ => None,
+        })
+        .collect();
+    assert_eq!(
+        funcs,
+        vec![
+            &Exp::Var("f".into(), Pos::default()),
+            &Exp::Var("h".into(), Pos::default()),
+            &Exp::Var("main".into(), Pos::default())
+        ]
+    );
+}
+
+#[test]
+fn parse_recovery_position_test() {
+    // 文の先頭からではなくエラーの位置から読み飛ばすので、forの中の;で止まって余分なエラーを出さない
+    for (src, column) in [
+        (
+            "int main() { int i; for (i = 0; i < 3; i = i + 1) return 1 }",
+            "int main() { int i; for (i = 0; i < 3; i = i + 1) return 1 ".len(),
+        ),
+        (
+            "int main() { int i; for (i = 0; i < 3; i = i + 1) i = 2 return 0; }",
+            "int main() { int i; for (i = 0; i < 3; i = i + 1) i = 2 ".len(),
+        ),
+    ] {
+        let (tokens, spans) = crate::lexer::tokenize_with_spans(src).ok().unwrap();
+        let (_, errors) = parse_program(&tokens);
+        assert_eq!(errors.len(), 1, "{}: {:?}", src, errors);
+        assert_eq!(spans[errors[0].index(&tokens)].start, column);
+    }
+}
+
+#[test]
+fn parse_error_message_test() {
+    let tokens = tokenize("int main() { int x; x = 1 } int f(int a { return a; }")
+        .ok()
+        .unwrap();
+    let (_, errors) = parse_program(&tokens);
+    let messages: Vec<String> = errors
+        .iter()
+        .map(|err| err.message.text(Lang::En))
+        .collect();
+    assert_eq!(
+        messages,
+        vec![
+            "expected `;` after expression, found `}`",
+            "expected `)` after parameters, found `{`",
+        ]
+    );
+    // エラーの位置は問題のトークンを指す
+    assert_eq!(tokens[errors[0].index(&tokens)], Token::RBrace);
+
+    let tokens = tokenize("int main() { return (1 + 2; }").ok().unwrap();
+    let (_, errors) = parse_program(&tokens);
+    assert_eq!(
+        errors[0].message.text(Lang::En),
+        "expected `)` to close parenthesized expression, found `;`"
+    );
+    let tokens = tokenize("int main() { return 1").ok().unwrap();
+    let (_, errors) = parse_program(&tokens);
+    assert_eq!(
+        errors[0].message.text(Lang::En),
+        "expected `;` after return value, found end of input"
+    );
+    assert_eq!(errors[0].index(&tokens), tokens.len());
+    // 関数の本体は { から始める
+    let tokens = tokenize("int main() return 3; }").ok().unwrap();
+    let (program, errors) = parse_program(&tokens);
+    assert_eq!(
+        errors[0].message.text(Lang::En),
+        "expected `{` after parameters, found `return`"
+    );
+    assert_eq!(tokens[errors[0].index(&tokens)], Token::Return);
+    assert!(!program.iter().any(|stmt| matches!(stmt, Stmt::Func { .. })));
+    // }がないまま入力が終われば、対応する{を注記で指す
+    let tokens = tokenize("int main() { return 3;").ok().unwrap();
+    let (_, errors) = parse_program(&tokens);
+    assert_eq!(errors.len(), 1, "{:?}", errors);
+    assert_eq!(
+        errors[0].message.text(Lang::En),
+        "expected `}` to close block, found end of input"
+    );
+    assert_eq!(errors[0].index(&tokens), tokens.len());
+    assert_eq!(tokens[errors[0].notes[0].index(&tokens)], Token::LBrace);
 }
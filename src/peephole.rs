@@ -0,0 +1,741 @@
+// 出力するアセンブリの覗き穴最適化。-O1以上で使う。
+// codegenは一行ずつを命令とオペランドに分けた値で作り、ここで決まった並びを短い並びに書き換えてから文字列にする。
+// rax, rdi, rdx, r10, r11はcodegenが命令の中だけで使う作業用のレジスタで、
+// ブロックの先頭では生きていない。書き換えてよいかはこれを頼りに決める
+use std::fmt;
+
+// 書き換えを繰り返す回数の上限
+const MAX_ROUNDS: usize = 10;
+
+// 作業用のレジスタ (64ビットの名前)
+static SCRATCH: [&str; 5] = ["rax", "rdi", "rdx", "r10", "r11"];
+
+// 汎用レジスタの64ビット、32ビット、8ビットの名前
+static GP_REGS: [[&str; 3]; 16] = [
+    ["rax", "eax", "al"],
+    ["rbx", "ebx", "bl"],
+    ["rcx", "ecx", "cl"],
+    ["rdx", "edx", "dl"],
+    ["rsi", "esi", "sil"],
+    ["rdi", "edi", "dil"],
+    ["rbp", "ebp", "bpl"],
+    ["rsp", "esp", "spl"],
+    ["r8", "r8d", "r8b"],
+    ["r9", "r9d", "r9b"],
+    ["r10", "r10d", "r10b"],
+    ["r11", "r11d", "r11b"],
+    ["r12", "r12d", "r12b"],
+    ["r13", "r13d", "r13b"],
+    ["r14", "r14d", "r14b"],
+    ["r15", "r15d", "r15b"],
+];
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Line {
+    Label(String),
+    // .globalや.dataなど。書き換えない
+    Directive(String),
+    Inst(Inst),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Inst {
+    pub op: String,
+    pub args: Vec<Arg>,
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Arg {
+    Reg(String),
+    Imm(i64),
+    Mem(Mem),
+    // ラベルや関数の名前
+    Sym(String),
+}
+
+// dword ptr [rbp - 8] のようなメモリのオペランド。
+// sizeがなければ大きさはもう一方のオペランドで決まる
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Mem {
+    pub size: Option<Size>,
+    pub base: String,
+    pub disp: Disp,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum Size {
+    Dword,
+    Qword,
+}
+
+// baseからの距離
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Disp {
+    Imm(i64),
+    // [rip + g] のように、ripからシンボルまで
+    Sym(String),
+}
+
+pub fn inst(op: &str, args: Vec<Arg>) -> Line {
+    Line::Inst(Inst {
+        op: op.to_string(),
+        args,
+    })
+}
+
+pub fn reg(name: &str) -> Arg {
+    Arg::Reg(name.to_string())
+}
+
+pub fn sym(name: &str) -> Arg {
+    Arg::Sym(name.to_string())
+}
+
+impl Mem {
+    // [base + disp]
+    pub fn new(base: &str, disp: i64) -> Mem {
+        Mem {
+            size: None,
+            base: base.to_string(),
+            disp: Disp::Imm(disp),
+        }
+    }
+    // [rip + symbol]
+    pub fn symbol(symbol: &str) -> Mem {
+        Mem {
+            size: None,
+            base: "rip".to_string(),
+            disp: Disp::Sym(symbol.to_string()),
+        }
+    }
+    pub fn sized(self, size: Size) -> Mem {
+        Mem {
+            size: Some(size),
+            ..self
+        }
+    }
+}
+
+impl fmt::Display for Line {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Line::Label(label) => write!(f, "{}:", label),
+            Line::Directive(text) => write!(f, "{}", text),
+            Line::Inst(inst) => {
+                write!(f, "  {}", inst.op)?;
+                for (i, arg) in inst.args.iter().enumerate() {
+                    write!(f, "{}{}", if i == 0 { " " } else { ", " }, arg)?;
+                }
+                Ok(())
+            }
+        }
+    }
+}
+
+impl fmt::Display for Arg {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Arg::Reg(text) | Arg::Sym(text) => write!(f, "{}", text),
+            Arg::Imm(i) => write!(f, "{}", i),
+            Arg::Mem(mem) => write!(f, "{}", mem),
+        }
+    }
+}
+
+impl fmt::Display for Mem {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.size {
+            Some(Size::Dword) => write!(f, "dword ptr ")?,
+            Some(Size::Qword) => write!(f, "qword ptr ")?,
+            None => (),
+        }
+        match &self.disp {
+            Disp::Imm(0) => write!(f, "[{}]", self.base),
+            Disp::Imm(i) if *i < 0 => write!(f, "[{} - {}]", self.base, -i),
+            Disp::Imm(i) => write!(f, "[{} + {}]", self.base, i),
+            Disp::Sym(symbol) => write!(f, "[{} + {}]", self.base, symbol),
+        }
+    }
+}
+
+// 汎用レジスタの64ビットの名前。汎用レジスタでなければNone
+fn family(reg: &str) -> Option<&'static str> {
+    GP_REGS
+        .iter()
+        .find(|names| names.contains(&reg))
+        .map(|names| names[0])
+}
+
+fn reg_family(arg: &Arg) -> Option<&'static str> {
+    match arg {
+        Arg::Reg(reg) => family(reg),
+        _ => None,
+    }
+}
+
+// 64ビットか32ビットの名前で、レジスタ全体を書き換えるオペランド
+fn is_full_reg(arg: &Arg) -> bool {
+    match arg {
+        Arg::Reg(reg) => GP_REGS
+            .iter()
+            .any(|names| names[0] == reg || names[1] == reg),
+        _ => false,
+    }
+}
+
+fn is_reg64(arg: &Arg) -> bool {
+    matches!(arg, Arg::Reg(reg) if family(reg) == Some(reg.as_str()))
+}
+
+// メモリのオペランドのアドレスにレジスタfamが使われているか
+fn mentions(arg: &Arg, fam: &str) -> bool {
+    match arg {
+        Arg::Mem(mem) => family(&mem.base) == Some(fam),
+        _ => false,
+    }
+}
+
+fn jump_target(inst: &Inst) -> Option<&str> {
+    match inst.args.as_slice() {
+        [Arg::Sym(target)] if inst.op.starts_with('j') => Some(target),
+        _ => None,
+    }
+}
+
+fn is_cond_jump(inst: &Inst) -> bool {
+    inst.op.starts_with('j') && inst.op != "jmp"
+}
+
+// 条件を逆にした条件コード
+fn invert(cc: &str) -> Option<&'static str> {
+    Some(match cc {
+        "e" => "ne",
+        "ne" => "e",
+        "l" => "ge",
+        "ge" => "l",
+        "le" => "g",
+        "g" => "le",
+        "b" => "ae",
+        "ae" => "b",
+        "be" => "a",
+        "a" => "be",
+        "p" => "np",
+        "np" => "p",
+        _ => return None,
+    })
+}
+
+// 基本ブロックのラベル (.L.関数名.bb番号)。関数の出口のラベルや数字だけのラベルは含まない
+fn is_block_label(label: &str) -> bool {
+    label.starts_with(".L.")
+        && label
+            .rsplit('.')
+            .next()
+            .and_then(|last| last.strip_prefix("bb"))
+            .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+}
+
+// オペランドを読まずに最初のオペランドのレジスタ全体を書き換える命令
+fn is_pure_write(op: &str) -> bool {
+    matches!(
+        op,
+        "mov" | "movabs" | "movzx" | "movsx" | "movsxd" | "lea" | "pop"
+    ) || op.starts_with("cvtt")
+}
+
+// 命令がレジスタfamを読むか。暗黙に読むものは読むとみなす
+fn reads(inst: &Inst, fam: &str) -> bool {
+    if matches!(
+        inst.op.as_str(),
+        "cdq" | "cqo" | "idiv" | "div" | "call" | "ret" | "push"
+    ) {
+        return true;
+    }
+    // 関数ポインタへの末尾呼び出し (jmp r10) は引数のレジスタを読む
+    if inst.op == "jmp" && jump_target(inst).is_none() {
+        return true;
+    }
+    inst.args.iter().enumerate().any(|(i, arg)| {
+        mentions(arg, fam)
+            || (reg_family(arg) == Some(fam)
+                && !(i == 0 && (is_pure_write(&inst.op) || inst.op.starts_with("set"))))
+    })
+}
+
+// 作業用のレジスタfamが、from番目の行の前で生きていないか
+fn is_dead(lines: &[Line], from: usize, fam: &str) -> bool {
+    if !SCRATCH.contains(&fam) {
+        return false;
+    }
+    for line in &lines[from..] {
+        let inst = match line {
+            Line::Label(label) => return is_block_label(label),
+            Line::Directive(_) => return false,
+            Line::Inst(inst) => inst,
+        };
+        if reads(inst, fam) {
+            return false;
+        }
+        if is_pure_write(&inst.op) && reg_family(&inst.args[0]) == Some(fam) {
+            return is_full_reg(&inst.args[0]);
+        }
+        match jump_target(inst) {
+            Some(target) if !is_block_label(target) => return false,
+            Some(target) if inst.op == "jmp" => return is_block_label(target),
+            _ => (),
+        }
+    }
+    false
+}
+
+pub fn optimize(lines: &mut Vec<Line>) {
+    for _ in 0..MAX_ROUNDS {
+        let before = lines.clone();
+        thread_jumps(lines);
+        invert_branches(lines);
+        remove_jumps_to_next(lines);
+        fuse_compare_branches(lines);
+        forward_moves(lines);
+        remove_redundant_moves(lines);
+        remove_unreachable(lines);
+        if *lines == before {
+            break;
+        }
+    }
+    use_test_for_zero(lines);
+}
+
+// ラベルの直後がjmpなら、そのラベルへのジャンプはjmpの先へ直接飛ぶ
+pub fn thread_jumps(lines: &mut [Line]) {
+    let mut forward = std::collections::HashMap::new();
+    for pair in lines.windows(2) {
+        if let [Line::Label(label), Line::Inst(next)] = pair {
+            if let (Some(target), "jmp") = (jump_target(next), next.op.as_str()) {
+                if target != label {
+                    forward.insert(label.clone(), target.to_string());
+                }
+            }
+        }
+    }
+    for line in lines.iter_mut() {
+        if let Line::Inst(inst) = line {
+            if let Some(mut target) = jump_target(inst).map(|t| t.to_string()) {
+                // 循環していても止まるように、ラベルの数までしかたどらない
+                for _ in 0..forward.len() {
+                    match forward.get(&target) {
+                        Some(next) => target = next.clone(),
+                        None => break,
+                    }
+                }
+                inst.args = vec![Arg::Sym(target)];
+            }
+        }
+    }
+}
+
+// jcc L1; jmp L2; L1: をj!cc L2; L1: にする
+pub fn invert_branches(lines: &mut [Line]) {
+    for i in 0..lines.len().saturating_sub(2) {
+        if let [Line::Inst(cond), Line::Inst(jmp), Line::Label(label)] = &lines[i..i + 3] {
+            if !is_cond_jump(cond) || jmp.op != "jmp" || jump_target(cond) != Some(label) {
+                continue;
+            }
+            if let (Some(cc), Some(target)) = (invert(&cond.op[1..]), jump_target(jmp)) {
+                let jump = inst(&format!("j{}", cc), vec![Arg::Sym(target.to_string())]);
+                lines[i + 1] = inst("jmp", vec![Arg::Sym(label.clone())]);
+                lines[i] = jump;
+            }
+        }
+    }
+}
+
+// 直後のラベルへのジャンプを消す
+pub fn remove_jumps_to_next(lines: &mut Vec<Line>) {
+    let mut i = 0;
+    while i < lines.len() {
+        let target = match &lines[i] {
+            Line::Inst(inst) => jump_target(inst).map(|t| t.to_string()),
+            _ => None,
+        };
+        let falls_into = target.is_some_and(|target| {
+            lines[i + 1..]
+                .iter()
+                .take_while(|line| matches!(line, Line::Label(_)))
+                .any(|line| *line == Line::Label(target.clone()))
+        });
+        if falls_into {
+            lines.remove(i);
+        } else {
+            i += 1;
+        }
+    }
+}
+
+// set<cc> al; movzx R, al; cmp R, 0; je L を j!cc L にする。
+// setとcmpの間にはフラグを変えないmovがあってもよい。Rが作業用のレジスタで後ろで使われなければ
+// setとmovzxも消し、そうでなければ値を残す
+pub fn fuse_compare_branches(lines: &mut Vec<Line>) {
+    let mut i = 0;
+    while i + 1 < lines.len() {
+        if let Some((set, cc)) = compare_branch(lines, i) {
+            let (target, to_block) = match &lines[i + 1] {
+                Line::Inst(jump) => (
+                    jump.args.clone(),
+                    jump_target(jump).is_some_and(is_block_label),
+                ),
+                _ => unreachable!(),
+            };
+            lines[i] = inst(&format!("j{}", cc), target);
+            lines.remove(i + 1);
+            let fam = match &lines[set + 1] {
+                Line::Inst(movzx) => reg_family(&movzx.args[0]).unwrap_or(""),
+                _ => unreachable!(),
+            };
+            let between_reads = lines[set + 2..i].iter().any(|line| match line {
+                Line::Inst(inst) => reads(inst, fam) || reads(inst, "rax"),
+                _ => true,
+            });
+            if to_block
+                && !between_reads
+                && is_dead(lines, i + 1, fam)
+                && is_dead(lines, i + 1, "rax")
+            {
+                lines.drain(set..set + 2);
+                i -= 2;
+            }
+        }
+        i += 1;
+    }
+}
+
+// i番目からcmp R, 0; je/jneで、Rがset<cc> al; movzx R, alで作られていれば、
+// setの位置と、ジャンプに使う条件コード
+fn compare_branch(lines: &[Line], i: usize) -> Option<(usize, &'static str)> {
+    let (cmp, jump) = match &lines[i..i + 2] {
+        [Line::Inst(cmp), Line::Inst(jump)] => (cmp, jump),
+        _ => return None,
+    };
+    let reg = match cmp.args.as_slice() {
+        [reg @ Arg::Reg(_), Arg::Imm(0)] if cmp.op == "cmp" && is_full_reg(reg) => reg,
+        _ => return None,
+    };
+    if (jump.op != "je" && jump.op != "jne") || jump_target(jump).is_none() {
+        return None;
+    }
+    let fam = reg_family(reg)?;
+    let mut j = i;
+    while j > 0 {
+        j -= 1;
+        let prev = match &lines[j] {
+            Line::Inst(prev) => prev,
+            _ => return None,
+        };
+        if prev.op == "movzx" {
+            match (&prev.args[..], j.checked_sub(1).map(|k| &lines[k])) {
+                ([dst, Arg::Reg(al)], Some(Line::Inst(set)))
+                    if dst == reg && al == "al" && set.op.starts_with("set") =>
+                {
+                    let cc = invert(&set.op[3..])?;
+                    let cc = if jump.op == "je" { cc } else { invert(cc)? };
+                    return Some((j - 1, cc));
+                }
+                _ => return None,
+            }
+        }
+        // 間に置けるのは、Rにもalにも書かないmovだけ
+        let dst = prev.args.first().and_then(reg_family);
+        if prev.op != "mov" || dst == Some(fam) || dst == Some("rax") {
+            return None;
+        }
+    }
+    None
+}
+
+// mov S, X; mov D, S で作業用のレジスタSが後ろで使われなければ mov D, X にする。
+// 後ろで使われない作業用のレジスタへの書き込みは消す
+pub fn forward_moves(lines: &mut Vec<Line>) {
+    let mut i = 0;
+    while i < lines.len() {
+        if is_dead_write(lines, i) {
+            lines.remove(i);
+        } else if let Some(line) = forwarded_move(lines, i) {
+            lines[i] = line;
+            lines.remove(i + 1);
+        } else {
+            i += 1;
+        }
+    }
+}
+
+fn is_dead_write(lines: &[Line], i: usize) -> bool {
+    match &lines[i] {
+        Line::Inst(inst) if is_pure_write(&inst.op) && inst.op != "pop" => {
+            is_full_reg(&inst.args[0])
+                && reg_family(&inst.args[0]).is_some_and(|fam| is_dead(lines, i + 1, fam))
+        }
+        _ => false,
+    }
+}
+
+fn forwarded_move(lines: &[Line], i: usize) -> Option<Line> {
+    let (first, second) = match lines.get(i..i + 2)? {
+        [Line::Inst(first), Line::Inst(second)] if first.op == "mov" && second.op == "mov" => {
+            (first, second)
+        }
+        _ => return None,
+    };
+    let (s, x, d) = match (first.args.as_slice(), second.args.as_slice()) {
+        ([s, x], [d, s2]) if s == s2 => (s, x, d),
+        _ => return None,
+    };
+    let fam = reg_family(s)?;
+    let memory_to_memory = matches!(x, Arg::Mem(_)) && matches!(d, Arg::Mem(_));
+    if memory_to_memory
+        || !is_full_reg(s)
+        || reg_family(d) == Some(fam)
+        || mentions(d, fam)
+        || !is_dead(lines, i + 2, fam)
+    {
+        return None;
+    }
+    Some(inst("mov", vec![d.clone(), x.clone()]))
+}
+
+// mov A, B; mov B, A の二つめを消す。32ビットのmovは上位をゼロにするので64ビットに限る
+pub fn remove_redundant_moves(lines: &mut Vec<Line>) {
+    let mut i = 1;
+    while i < lines.len() {
+        let redundant = match &lines[i - 1..=i] {
+            [Line::Inst(first), Line::Inst(second)] if first.op == "mov" && second.op == "mov" => {
+                match (first.args.as_slice(), second.args.as_slice()) {
+                    ([a, b], [b2, a2]) => a == a2 && b == b2 && is_reg64(a) && is_reg64(b),
+                    _ => false,
+                }
+            }
+            _ => false,
+        };
+        if redundant {
+            lines.remove(i);
+        } else {
+            i += 1;
+        }
+    }
+}
+
+// 無条件のジャンプの後ろから次のラベルまでの命令は実行されないので消す
+pub fn remove_unreachable(lines: &mut Vec<Line>) {
+    let mut reachable = true;
+    lines.retain(|line| match line {
+        Line::Label(_) | Line::Directive(_) => {
+            reachable = true;
+            true
+        }
+        Line::Inst(inst) => {
+            let keep = reachable;
+            if inst.op == "jmp" || inst.op == "ret" {
+                reachable = false;
+            }
+            keep
+        }
+    });
+}
+
+// cmp R, 0 をフラグが同じになるtest R, Rにする
+pub fn use_test_for_zero(lines: &mut [Line]) {
+    for line in lines.iter_mut() {
+        if let Line::Inst(cmp) = line {
+            if let [reg @ Arg::Reg(_), Arg::Imm(0)] = cmp.args.as_slice() {
+                if cmp.op == "cmp" && reg_family(reg).is_some() {
+                    *line = inst("test", vec![reg.clone(), reg.clone()]);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+fn optimize_lines(mut lines: Vec<Line>) -> String {
+    optimize(&mut lines);
+    lines.iter().map(|line| format!("{}\n", line)).collect()
+}
+
+#[cfg(test)]
+fn label(name: &str) -> Line {
+    Line::Label(name.to_string())
+}
+
+#[test]
+fn render_line_test() {
+    let lines = [
+        label(".L.f.bb0"),
+        Line::Directive("  .align 4".to_string()),
+        Line::Directive(".global f".to_string()),
+        inst(
+            "mov",
+            vec![
+                Arg::Mem(Mem::new("rbp", -8).sized(Size::Dword)),
+                Arg::Imm(3),
+            ],
+        ),
+        inst(
+            "mov",
+            vec![Arg::Mem(Mem::new("rbp", 16).sized(Size::Qword)), reg("rax")],
+        ),
+        inst("movabs", vec![reg("rax"), Arg::Imm(i64::MIN)]),
+        inst("lea", vec![reg("rax"), Arg::Mem(Mem::symbol("g"))]),
+        inst("mov", vec![reg("eax"), Arg::Mem(Mem::new("rdi", 0))]),
+        inst("js", vec![sym("1f")]),
+        inst("cqo", vec![]),
+        inst("call", vec![sym("f")]),
+    ];
+    assert_eq!(
+        lines
+            .iter()
+            .map(|line| format!("{}\n", line))
+            .collect::<String>(),
+        ".L.f.bb0:
+  .align 4
+.global f
+  mov dword ptr [rbp - 8], 3
+  mov qword ptr [rbp + 16], rax
+  movabs rax, -9223372036854775808
+  lea rax, [rip + g]
+  mov eax, [rdi]
+  js 1f
+  cqo
+  call f
+"
+    );
+}
+
+#[test]
+fn peephole_test() {
+    let frame = |offset| Arg::Mem(Mem::new("rbp", offset).sized(Size::Dword));
+    // 比較の結果で分岐する。作業用のレジスタに作った値は消す
+    assert_eq!(
+        optimize_lines(vec![
+            inst("cmp", vec![reg("esi"), Arg::Imm(3)]),
+            inst("sete", vec![reg("al")]),
+            inst("movzx", vec![reg("eax"), reg("al")]),
+            inst("cmp", vec![reg("eax"), Arg::Imm(0)]),
+            inst("je", vec![sym(".L.f.bb2")]),
+            inst("jmp", vec![sym(".L.f.bb1")]),
+            label(".L.f.bb1"),
+            inst("mov", vec![reg("eax"), Arg::Imm(1)]),
+            inst("jmp", vec![sym(".L.return.f")]),
+            label(".L.f.bb2"),
+            inst("mov", vec![reg("eax"), Arg::Imm(0)]),
+            inst("jmp", vec![sym(".L.return.f")]),
+            label(".L.f.bb3"),
+            inst("jmp", vec![sym(".L.f.bb2")]),
+            label(".L.return.f"),
+            inst("ret", vec![]),
+        ]),
+        "  cmp esi, 3
+  jne .L.f.bb2
+.L.f.bb1:
+  mov eax, 1
+  jmp .L.return.f
+.L.f.bb2:
+  mov eax, 0
+  jmp .L.return.f
+.L.f.bb3:
+  jmp .L.f.bb2
+.L.return.f:
+  ret
+"
+    );
+    // 割り当てられたレジスタの値は後で使うかもしれないので残す。間のmovはフラグを変えない
+    assert_eq!(
+        optimize_lines(vec![
+            inst("cmp", vec![reg("r8d"), reg("r12d")]),
+            inst("setg", vec![reg("al")]),
+            inst("movzx", vec![reg("r13d"), reg("al")]),
+            inst("mov", vec![reg("r14d"), reg("r9d")]),
+            inst("cmp", vec![reg("r13d"), Arg::Imm(0)]),
+            inst("je", vec![sym(".L.f.bb5")]),
+            inst("jmp", vec![sym(".L.f.bb4")]),
+            label(".L.f.bb4"),
+            inst("add", vec![reg("r14d"), Arg::Imm(1)]),
+            label(".L.f.bb5"),
+            inst("mov", vec![reg("eax"), reg("r14d")]),
+        ]),
+        "  cmp r8d, r12d
+  setg al
+  movzx r13d, al
+  mov r14d, r9d
+  jle .L.f.bb5
+.L.f.bb4:
+  add r14d, 1
+.L.f.bb5:
+  mov eax, r14d
+"
+    );
+    // 分岐の先がジャンプならその先へ直接飛ぶ
+    assert_eq!(
+        optimize_lines(vec![
+            inst("cmp", vec![reg("ecx"), Arg::Imm(0)]),
+            inst("je", vec![sym(".L.f.bb3")]),
+            inst("jmp", vec![sym(".L.f.bb4")]),
+            label(".L.f.bb3"),
+            inst("jmp", vec![sym(".L.f.bb6")]),
+            label(".L.f.bb4"),
+            inst("mov", vec![reg("ecx"), Arg::Imm(1)]),
+            label(".L.f.bb6"),
+            inst("mov", vec![reg("eax"), reg("ecx")]),
+        ]),
+        "  test ecx, ecx
+  je .L.f.bb6
+  jmp .L.f.bb4
+.L.f.bb3:
+  jmp .L.f.bb6
+.L.f.bb4:
+  mov ecx, 1
+.L.f.bb6:
+  mov eax, ecx
+"
+    );
+    // 作業用のレジスタを通すだけのmovをまとめる
+    assert_eq!(
+        optimize_lines(vec![
+            inst("mov", vec![reg("eax"), Arg::Imm(5)]),
+            inst("mov", vec![frame(-8), reg("eax")]),
+            inst("mov", vec![reg("rax"), reg("rbx")]),
+            inst("mov", vec![reg("rdi"), reg("rax")]),
+            inst("mov", vec![reg("rax"), reg("rdi")]),
+            inst("mov", vec![reg("eax"), frame(-8)]),
+            inst("mov", vec![frame(-12), reg("eax")]),
+            inst("mov", vec![reg("eax"), Arg::Imm(0)]),
+            inst("mov", vec![reg("esi"), reg("eax")]),
+            inst("call", vec![sym("f")]),
+        ]),
+        "  mov dword ptr [rbp - 8], 5
+  mov rdi, rbx
+  mov eax, dword ptr [rbp - 8]
+  mov dword ptr [rbp - 12], eax
+  mov eax, 0
+  mov esi, eax
+  call f
+"
+    );
+    // 生きている値や、ブロックの外で使われうる値はそのまま
+    assert_eq!(
+        optimize_lines(vec![
+            inst("mov", vec![reg("eax"), reg("esi")]),
+            inst("mov", vec![reg("ecx"), reg("eax")]),
+            inst("jmp", vec![sym(".L.return.f")]),
+            label(".L.f.bb1"),
+            inst("mov", vec![reg("eax"), Arg::Imm(0)]),
+            inst("mov", vec![reg("edi"), reg("eax")]),
+            inst("call", vec![sym("g")]),
+        ]),
+        "  mov eax, esi
+  mov ecx, eax
+  jmp .L.return.f
+.L.f.bb1:
+  mov eax, 0
+  mov edi, eax
+  call g
+"
+    );
+}
@@ -0,0 +1,1558 @@
+// プリプロセッサ。ソースをpp-tokenに分け、ディレクティブの処理とマクロ展開をして、
+// 字句解析に渡すためのトークン列を作る。
+// マクロ展開はhide set (展開中のマクロの名前の集合) を使うProsserのアルゴリズムによる。
+use crate::diagnostic::{Diagnostic, Location, Message};
+use crate::eval;
+use crate::parser::{Op, TypeDec};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::rc::Rc;
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum PpKind {
+    Ident,
+    Number,
+    Str,
+    Char,
+    Punct,
+    // どの種類にも当てはまらない文字。そのまま後ろに渡す
+    Other,
+}
+
+#[derive(Debug, Clone)]
+pub struct PpToken {
+    pub kind: PpKind,
+    pub text: String,
+    pub file: Rc<str>,
+    pub line: usize,
+    // 行の中の文字の位置 (1始まり)
+    pub column: usize,
+    // 直前に空白があったか
+    pub space: bool,
+    hideset: Vec<String>,
+}
+
+impl PpToken {
+    fn is(&self, text: &str) -> bool {
+        self.kind != PpKind::Str && self.kind != PpKind::Char && self.text == text
+    }
+}
+
+// -Eの出力で行マーカーを出すための、ファイルの切り替わりの記録。
+// atはそのファイルの最初のトークンの出力中の位置
+#[derive(Debug, Clone)]
+pub struct FileChange {
+    pub at: usize,
+    pub file: Rc<str>,
+    pub line: usize,
+    pub kind: FileChangeKind,
+}
+
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum FileChangeKind {
+    Start,
+    // systemはシステムのヘッダか
+    Enter { system: bool },
+    Return { system: bool },
+}
+
+#[derive(Debug)]
+pub struct Preprocessed {
+    pub tokens: Vec<PpToken>,
+    pub file_changes: Vec<FileChange>,
+    // #warningの警告。出すのは呼び出し側
+    pub warnings: Vec<PpError>,
+}
+
+// プリプロセッサのエラーと#warning。位置はエラーになったトークンの元のファイルでの位置で、
+// end_columnはトークンの後ろの列
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct PpError {
+    pub message: Message,
+    pub location: Location,
+    pub end_column: Option<usize>,
+}
+
+impl PpError {
+    pub fn diagnostic(&self) -> Diagnostic {
+        let mut diagnostic = Diagnostic::new(self.message.clone());
+        diagnostic.location = Some(self.location.clone());
+        diagnostic.end = self.end_column.map(|column| Location {
+            column,
+            ..self.location.clone()
+        });
+        diagnostic
+    }
+}
+
+// -Dと-Uはコマンドラインに書かれた順に処理する
+#[derive(Debug, Clone)]
+pub enum MacroOption {
+    Define(String, String),
+    Undef(String),
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Options {
+    pub include_paths: Vec<PathBuf>,
+    pub macros: Vec<MacroOption>,
+}
+
+struct Macro {
+    // 関数形式マクロなら仮引数の名前。可変長引数は__VA_ARGS__という名前の最後の仮引数になる
+    params: Option<Vec<String>>,
+    variadic: bool,
+    body: Vec<PpToken>,
+}
+
+// #if などの入れ子の状態
+struct Cond {
+    // 外側のグループが有効か
+    parent_active: bool,
+    // このグループが有効か
+    active: bool,
+    // すでにどれかの分岐を選んだか
+    taken: bool,
+    in_else: bool,
+    // #ifのディレクティブ名。対応する#endifがないときのエラーの位置
+    directive: PpToken,
+}
+
+// 同梱のヘッダはバイナリに埋め込み、このディレクトリにあるものとして扱う
+const BUNDLED_INCLUDE_DIR: &str = "<cygcc>/include";
+const BUNDLED_HEADERS: &[(&str, &str)] = &[
+    ("stdarg.h", include_str!("../include/stdarg.h")),
+    ("stdbool.h", include_str!("../include/stdbool.h")),
+    ("stddef.h", include_str!("../include/stddef.h")),
+    ("stdint.h", include_str!("../include/stdint.h")),
+    ("stdio.h", include_str!("../include/stdio.h")),
+    ("stdlib.h", include_str!("../include/stdlib.h")),
+    ("string.h", include_str!("../include/string.h")),
+];
+
+const SYSTEM_INCLUDE_PATHS: &[&str] = &[
+    "/usr/local/include",
+    "/usr/include/x86_64-linux-gnu",
+    "/usr/include",
+];
+const MAX_INCLUDE_DEPTH: usize = 200;
+
+const PREDEFINED_MACROS: &str = "#define __cygcc__ 1
+#define __STDC__ 1
+#define __x86_64__ 1
+#define __LP64__ 1
+";
+
+const PUNCTUATORS: &[&str] = &[
+    "...", "<<=", ">>=", "->", "++", "--", "<<", ">>", "<=", ">=", "==", "!=", "&&", "||", "*=",
+    "/=", "%=", "+=", "-=", "&=", "^=", "|=", "##", "[", "]", "(", ")", "{", "}", ".", "&", "*",
+    "+", "-", "~", "!", "/", "%", "<", ">", "^", "|", "?", ":", ";", "=", ",", "#",
+];
+
+pub fn preprocess(src: &str, file: &str, options: &Options) -> Result<Preprocessed, PpError> {
+    let mut pp = Preprocessor {
+        macros: HashMap::new(),
+        options,
+        once: HashSet::new(),
+        output: vec![],
+        file_changes: vec![],
+        warnings: vec![],
+        depth: 0,
+    };
+    pp.process(PREDEFINED_MACROS, "<built-in>", None)?;
+    let command_line: String = options
+        .macros
+        .iter()
+        .map(|m| match m {
+            MacroOption::Define(name, value) => format!("#define {} {}\n", name, value),
+            MacroOption::Undef(name) => format!("#undef {}\n", name),
+        })
+        .collect();
+    pp.process(&command_line, "<command-line>", None)?;
+    let dir = Path::new(file).parent().map(|dir| dir.to_path_buf());
+    pp.file_changes.push(FileChange {
+        at: 0,
+        file: Rc::from(file),
+        line: 1,
+        kind: FileChangeKind::Start,
+    });
+    pp.process(src, file, dir)?;
+    Ok(Preprocessed {
+        tokens: pp.output,
+        file_changes: pp.file_changes,
+        warnings: pp.warnings,
+    })
+}
+
+// 字句解析に渡すソースに戻す。元の行の区切りを保ち、くっつくと別のトークンになる所には空白を入れる
+pub fn to_source(tokens: &[PpToken]) -> String {
+    to_source_with_map(tokens).0
+}
+
+// to_sourceの出力と、その中の位置を元のファイルの位置に戻すための表
+pub fn to_source_with_map(tokens: &[PpToken]) -> (String, SourceMap) {
+    let mut out = String::new();
+    let mut map = SourceMap { entries: vec![] };
+    let mut prev: Option<&PpToken> = None;
+    for t in tokens {
+        match prev {
+            Some(p) if starts_new_line(p, t) => out.push('\n'),
+            Some(p) if t.space || would_paste(p, t) => out.push(' '),
+            _ => {}
+        }
+        map.entries
+            .push((out.len(), t.file.clone(), t.line, t.column));
+        out.push_str(&t.text);
+        prev = Some(t);
+    }
+    out.push('\n');
+    (out, map)
+}
+
+// 出力の中のトークンの先頭の位置と、そのトークンの元のファイル、行、列。位置の順に並ぶ
+#[derive(Debug)]
+pub struct SourceMap {
+    entries: Vec<(usize, Rc<str>, usize, usize)>,
+}
+
+impl SourceMap {
+    // 出力の中の位置posを含むトークンの、元のファイルでの位置。
+    // トークンの途中なら列をその分ずらす。マクロから来たトークンは呼び出しの位置になる
+    pub fn lookup(&self, pos: usize) -> Option<(Rc<str>, usize, usize)> {
+        let i = self.entries.partition_point(|(start, ..)| *start <= pos);
+        let (start, file, line, column) = self.entries.get(i.checked_sub(1)?)?;
+        Some((file.clone(), *line, column + (pos - start)))
+    }
+}
+
+fn starts_new_line(prev: &PpToken, t: &PpToken) -> bool {
+    prev.file != t.file || prev.line != t.line
+}
+
+// これより行が離れていれば、空行を並べる代わりに行マーカーを出す (gccと同じ)
+const MAX_BLANK_LINES: usize = 8;
+
+// -Eの出力。gccのcppと同じ形式の行マーカー (# 行 "ファイル" フラグ) を付ける。
+// フラグの1はファイルに入ったとき、2は戻ったとき、3 4はシステムのヘッダであることを表す
+pub fn to_text_with_markers(pp: &Preprocessed) -> String {
+    let mut out = String::new();
+    let mut changes = pp.file_changes.iter().peekable();
+    let mut file: Rc<str> = Rc::from("");
+    let mut line = 0;
+    let mut prev: Option<&PpToken> = None;
+    for i in 0..=pp.tokens.len() {
+        while let Some(change) = changes.next_if(|change| change.at == i) {
+            let flags = match change.kind {
+                FileChangeKind::Start => "",
+                FileChangeKind::Enter { system: false } => " 1",
+                FileChangeKind::Enter { system: true } => " 1 3 4",
+                FileChangeKind::Return { system: false } => " 2",
+                FileChangeKind::Return { system: true } => " 2 3 4",
+            };
+            push_marker(&mut out, change.line, &change.file, flags);
+            file = change.file.clone();
+            line = change.line;
+            prev = None;
+        }
+        let t = match pp.tokens.get(i) {
+            Some(t) => t,
+            None => break,
+        };
+        if t.file != file || t.line < line || t.line - line > MAX_BLANK_LINES {
+            push_marker(&mut out, t.line, &t.file, "");
+            file = t.file.clone();
+        } else if t.line > line {
+            out.push_str(&"\n".repeat(t.line - line));
+        } else if let Some(p) = prev {
+            if t.space || would_paste(p, t) {
+                out.push(' ');
+            }
+        }
+        line = t.line;
+        out.push_str(&t.text);
+        prev = Some(t);
+    }
+    if !out.is_empty() && !out.ends_with('\n') {
+        out.push('\n');
+    }
+    out
+}
+
+fn push_marker(out: &mut String, line: usize, file: &str, flags: &str) {
+    if !out.is_empty() && !out.ends_with('\n') {
+        out.push('\n');
+    }
+    out.push_str(&format!("# {} {}{}\n", line, quote(file), flags));
+}
+
+// 二つのトークンを空白なしで並べると、字句解析で一つのトークンとして読まれてしまうか
+fn would_paste(prev: &PpToken, t: &PpToken) -> bool {
+    let word = |c: char| c.is_alphanumeric() || c == '_';
+    match (prev.text.chars().last(), t.text.chars().next()) {
+        (Some(a), Some(b)) if word(a) && (word(b) || b == '.') => true,
+        (Some(_), Some(b)) if prev.kind == PpKind::Punct => {
+            let joined = format!("{}{}", prev.text, b);
+            PUNCTUATORS.iter().any(|p| p.starts_with(&joined))
+                || (prev.text == "." && b.is_ascii_digit())
+        }
+        (Some('.'), Some(b)) if prev.kind == PpKind::Number => b == '.' || b.is_ascii_digit(),
+        _ => false,
+    }
+}
+
+struct Preprocessor<'a> {
+    macros: HashMap<String, Macro>,
+    options: &'a Options,
+    // #pragma onceのあったファイル
+    once: HashSet<PathBuf>,
+    output: Vec<PpToken>,
+    file_changes: Vec<FileChange>,
+    warnings: Vec<PpError>,
+    depth: usize,
+}
+
+fn error_at(t: &PpToken, message: Message) -> PpError {
+    let mut err = error_at_position(&t.file, t.line, t.column, message);
+    err.end_column = Some(t.column + t.text.chars().count());
+    err
+}
+
+fn error_at_position(file: &Rc<str>, line: usize, column: usize, message: Message) -> PpError {
+    PpError {
+        message,
+        location: Location {
+            file: file.clone(),
+            line,
+            column,
+        },
+        end_column: None,
+    }
+}
+
+impl<'a> Preprocessor<'a> {
+    // dirは#include "..."で最初に探すディレクトリ
+    fn process(&mut self, src: &str, file: &str, dir: Option<PathBuf>) -> Result<(), PpError> {
+        let file: Rc<str> = Rc::from(file);
+        let lines = tokenize_lines(src, &file)?;
+        let mut conds: Vec<Cond> = vec![];
+        // ディレクティブの間のテキスト行はまとめて展開する (関数形式マクロの引数は行をまたげる)
+        let mut text: Vec<PpToken> = vec![];
+        for line in lines {
+            let active = conds.last().is_none_or(|c| c.active);
+            match line.first() {
+                Some(hash) if hash.is("#") => {
+                    if !text.is_empty() {
+                        let expanded = self.expand(std::mem::take(&mut text))?;
+                        self.output.extend(expanded);
+                    }
+                    self.directive(hash, &line[1..], &mut conds, active, &dir)?;
+                }
+                _ if active => text.extend(line),
+                _ => {}
+            }
+        }
+        if !text.is_empty() {
+            let expanded = self.expand(text)?;
+            self.output.extend(expanded);
+        }
+        if let Some(cond) = conds.last() {
+            return Err(error_at(&cond.directive, Message::UnterminatedConditional));
+        }
+        Ok(())
+    }
+
+    fn directive(
+        &mut self,
+        hash: &PpToken,
+        line: &[PpToken],
+        conds: &mut Vec<Cond>,
+        active: bool,
+        dir: &Option<PathBuf>,
+    ) -> Result<(), PpError> {
+        let (name, args) = match line {
+            [] => return Ok(()),
+            [name, args @ ..] => (name, args),
+        };
+        match name.text.as_str() {
+            "if" | "ifdef" | "ifndef" => {
+                let value = active && self.condition(name, args)?;
+                conds.push(Cond {
+                    parent_active: active,
+                    active: value,
+                    taken: value,
+                    in_else: false,
+                    directive: name.clone(),
+                });
+            }
+            "elif" => {
+                let cond = match conds.last() {
+                    Some(cond) if !cond.in_else => cond,
+                    _ => {
+                        return Err(error_at(
+                            name,
+                            Message::UnmatchedDirective(name.text.clone()),
+                        ))
+                    }
+                };
+                let value = cond.parent_active && !cond.taken && self.condition(name, args)?;
+                let cond = conds.last_mut().unwrap();
+                cond.active = value;
+                cond.taken |= value;
+            }
+            "else" => match conds.last_mut() {
+                Some(cond) if !cond.in_else => {
+                    cond.active = cond.parent_active && !cond.taken;
+                    cond.taken = true;
+                    cond.in_else = true;
+                }
+                _ => {
+                    return Err(error_at(
+                        name,
+                        Message::UnmatchedDirective(name.text.clone()),
+                    ))
+                }
+            },
+            "endif" => {
+                if conds.pop().is_none() {
+                    return Err(error_at(
+                        name,
+                        Message::UnmatchedDirective(name.text.clone()),
+                    ));
+                }
+            }
+            // 無効なグループの中では条件以外のディレクティブを読み飛ばす
+            _ if !active => {}
+            "define" => self.define(name, args)?,
+            "undef" => match args {
+                [macro_name] if macro_name.kind == PpKind::Ident => {
+                    self.macros.remove(&macro_name.text);
+                }
+                _ => {
+                    return Err(error_at(
+                        name,
+                        Message::MalformedDirective(name.text.clone()),
+                    ))
+                }
+            },
+            "include" => self.include(name, args, dir)?,
+            "error" => return Err(error_at(name, Message::ErrorDirective(join_tokens(args)))),
+            "warning" => self
+                .warnings
+                .push(error_at(name, Message::WarningDirective(join_tokens(args)))),
+            "pragma" => match args {
+                [once] if once.is("once") => {
+                    self.once.insert(once_key(Path::new(&*hash.file)));
+                }
+                // その他の#pragmaは無視する
+                _ => {}
+            },
+            _ => return Err(error_at(name, Message::UnknownDirective(name.text.clone()))),
+        }
+        Ok(())
+    }
+
+    fn define(&mut self, directive: &PpToken, args: &[PpToken]) -> Result<(), PpError> {
+        let (name, rest) = match args {
+            [name, rest @ ..] if name.kind == PpKind::Ident => (name, rest),
+            _ => return Err(error_at(directive, Message::MissingMacroName)),
+        };
+        if name.text == "defined" {
+            return Err(error_at(name, Message::DefinedAsMacroName));
+        }
+        // 名前の直後に空白なしで(が続けば関数形式マクロ
+        let (params, variadic, body) = match rest {
+            [paren, rest @ ..] if paren.is("(") && !paren.space => {
+                let (params, variadic, body) = parse_macro_params(name, rest, vec![])?;
+                (Some(params), variadic, body)
+            }
+            _ => (None, false, rest),
+        };
+        let mut body = body.to_vec();
+        if let Some(first) = body.first_mut() {
+            first.space = false;
+        }
+        if let (Some(first), Some(last)) = (body.first(), body.last()) {
+            if first.is("##") || last.is("##") {
+                return Err(error_at(first, Message::PasteAtEdge));
+            }
+        }
+        self.macros.insert(
+            name.text.clone(),
+            Macro {
+                params,
+                variadic,
+                body,
+            },
+        );
+        Ok(())
+    }
+
+    fn include(
+        &mut self,
+        directive: &PpToken,
+        args: &[PpToken],
+        dir: &Option<PathBuf>,
+    ) -> Result<(), PpError> {
+        let (name, quoted) = match include_name(args) {
+            Some(name) => name,
+            // マクロで書かれたファイル名は展開してから読む
+            None => match include_name(&self.expand(args.to_vec())?) {
+                Some(name) => name,
+                None => {
+                    return Err(error_at(
+                        directive,
+                        Message::MalformedDirective(directive.text.clone()),
+                    ))
+                }
+            },
+        };
+        let (path, system) = match self.find_include(&name, quoted, dir) {
+            Some(found) => found,
+            None => {
+                // ファイル名のトークンを指す
+                let at = args.first().unwrap_or(directive);
+                return Err(error_at(at, Message::IncludeNotFound(name)));
+            }
+        };
+        if self.once.contains(&once_key(&path)) {
+            return Ok(());
+        }
+        if self.depth >= MAX_INCLUDE_DEPTH {
+            return Err(error_at(directive, Message::IncludeTooDeep));
+        }
+        let src = match bundled_header(&path) {
+            Some(src) => src.to_string(),
+            None => match fs::read_to_string(&path) {
+                Ok(src) => src,
+                Err(err) => {
+                    return Err(error_at(
+                        directive,
+                        Message::IncludeReadFailed(path.display().to_string(), err.to_string()),
+                    ))
+                }
+            },
+        };
+        let file = path.display().to_string();
+        self.file_changes.push(FileChange {
+            at: self.output.len(),
+            file: Rc::from(file.as_str()),
+            line: 1,
+            kind: FileChangeKind::Enter { system },
+        });
+        self.depth += 1;
+        let dir = path.parent().map(|dir| dir.to_path_buf());
+        let result = self.process(&src, &file, dir);
+        self.depth -= 1;
+        self.file_changes.push(FileChange {
+            at: self.output.len(),
+            file: directive.file.clone(),
+            line: directive.line + 1,
+            kind: FileChangeKind::Return {
+                system: self.is_system_file(&directive.file),
+            },
+        });
+        result
+    }
+
+    fn is_system_file(&self, file: &str) -> bool {
+        SYSTEM_INCLUDE_PATHS
+            .iter()
+            .chain(std::iter::once(&BUNDLED_INCLUDE_DIR))
+            .any(|dir| Path::new(file).starts_with(dir))
+    }
+
+    // "..."ならインクルードしたファイルのディレクトリ、同梱のヘッダ、-Iのディレクトリ、
+    // システムのディレクトリの順に探す。<...>ならインクルードしたファイルのディレクトリは見ない。
+    // 見つかったパスと、それがシステムのヘッダかを返す
+    fn find_include(
+        &self,
+        name: &str,
+        quoted: bool,
+        dir: &Option<PathBuf>,
+    ) -> Option<(PathBuf, bool)> {
+        let current = match (quoted, dir) {
+            (true, Some(dir)) => Some(dir.clone()),
+            (true, None) => Some(PathBuf::from(".")),
+            _ => None,
+        };
+        let system = SYSTEM_INCLUDE_PATHS.iter().map(PathBuf::from);
+        current
+            .into_iter()
+            .chain(std::iter::once(PathBuf::from(BUNDLED_INCLUDE_DIR)))
+            .chain(self.options.include_paths.iter().cloned())
+            .chain(system)
+            .map(|dir| {
+                if dir == Path::new(".") {
+                    PathBuf::from(name)
+                } else {
+                    dir.join(name)
+                }
+            })
+            .find(|path| bundled_header(path).is_some() || path.is_file())
+            .map(|path| {
+                let system = self.is_system_file(&path.display().to_string());
+                (path, system)
+            })
+    }
+
+    fn condition(&self, directive: &PpToken, args: &[PpToken]) -> Result<bool, PpError> {
+        match (directive.text.as_str(), args) {
+            ("ifdef", [name]) if name.kind == PpKind::Ident => Ok(self.is_defined(&name.text)),
+            ("ifndef", [name]) if name.kind == PpKind::Ident => Ok(!self.is_defined(&name.text)),
+            ("ifdef", _) | ("ifndef", _) => Err(error_at(
+                directive,
+                Message::MalformedDirective(directive.text.clone()),
+            )),
+            _ => self.eval_if(directive, args),
+        }
+    }
+
+    fn is_defined(&self, name: &str) -> bool {
+        self.macros.contains_key(name) || name == "__FILE__" || name == "__LINE__"
+    }
+
+    // #ifの式。definedを先に置き換え、マクロを展開してから、残った識別子を0として計算する
+    fn eval_if(&self, directive: &PpToken, args: &[PpToken]) -> Result<bool, PpError> {
+        let mut replaced = vec![];
+        let mut rest = args;
+        while let Some(t) = rest.first() {
+            if !t.is("defined") {
+                replaced.push(t.clone());
+                rest = &rest[1..];
+                continue;
+            }
+            let (name, next) = match rest {
+                [_, name, next @ ..] if name.kind == PpKind::Ident => (name, next),
+                [_, l, name, r, next @ ..]
+                    if l.is("(") && name.kind == PpKind::Ident && r.is(")") =>
+                {
+                    (name, next)
+                }
+                _ => return Err(error_at(t, Message::MalformedDefined)),
+            };
+            let value = if self.is_defined(&name.text) {
+                "1"
+            } else {
+                "0"
+            };
+            replaced.push(new_token(PpKind::Number, value, t));
+            rest = next;
+        }
+        let expanded: Vec<PpToken> = self
+            .expand(replaced)?
+            .into_iter()
+            .map(|t| match t.kind {
+                PpKind::Ident => new_token(PpKind::Number, "0", &t),
+                _ => t,
+            })
+            .collect();
+        match eval_cond(&expanded, true) {
+            Ok(((value, _), [])) => Ok(value != 0),
+            Ok((_, [t, ..])) => Err(error_at(t, Message::ExtraTokenInIf(t.text.clone()))),
+            Err(err) => Err(error_at(directive, err)),
+        }
+    }
+
+    fn expand(&self, tokens: Vec<PpToken>) -> Result<Vec<PpToken>, PpError> {
+        let mut input: VecDeque<PpToken> = tokens.into();
+        let mut out = vec![];
+        while let Some(t) = input.pop_front() {
+            if t.kind != PpKind::Ident || t.hideset.contains(&t.text) {
+                out.push(t);
+                continue;
+            }
+            match t.text.as_str() {
+                "__FILE__" => {
+                    out.push(new_token(PpKind::Str, &quote(&t.file), &t));
+                    continue;
+                }
+                "__LINE__" => {
+                    out.push(new_token(PpKind::Number, &t.line.to_string(), &t));
+                    continue;
+                }
+                _ => {}
+            }
+            let m = match self.macros.get(&t.text) {
+                Some(m) => m,
+                None => {
+                    out.push(t);
+                    continue;
+                }
+            };
+            let body = match &m.params {
+                None => {
+                    let hideset = with(&t.hideset, &t.text);
+                    self.subst(m, &[], &[], hideset, &t)?
+                }
+                // 後ろに(が続かない関数形式マクロの名前は展開しない
+                Some(params) => match input.front() {
+                    Some(paren) if paren.is("(") => {
+                        input.pop_front();
+                        let (args, rparen) = collect_args(&t, &mut input)?;
+                        let args = match_args(&t, m, params, args)?;
+                        let hideset: Vec<String> = t
+                            .hideset
+                            .iter()
+                            .filter(|name| rparen.hideset.contains(name))
+                            .cloned()
+                            .collect();
+                        self.subst(m, params, &args, with(&hideset, &t.text), &t)?
+                    }
+                    _ => {
+                        out.push(t);
+                        continue;
+                    }
+                },
+            };
+            for token in body.into_iter().rev() {
+                input.push_front(token);
+            }
+        }
+        Ok(out)
+    }
+
+    // マクロの本体の仮引数を実引数で置き換える。
+    // #と##の被演算子になる実引数は展開せず、それ以外は展開してから置き換える。
+    fn subst(
+        &self,
+        m: &Macro,
+        params: &[String],
+        args: &[Vec<PpToken>],
+        hideset: Vec<String>,
+        origin: &PpToken,
+    ) -> Result<Vec<PpToken>, PpError> {
+        let param_index = |t: &PpToken| match t.kind {
+            PpKind::Ident => params.iter().position(|p| *p == t.text),
+            _ => None,
+        };
+        let mut out: Vec<PpToken> = vec![];
+        // 直前に置いたのが空の実引数だったか (##の左辺が空のときに使う)
+        let mut last_empty = false;
+        let mut i = 0;
+        while i < m.body.len() {
+            let t = &m.body[i];
+            let next = m.body.get(i + 1);
+            if t.is("#") && m.params.is_some() {
+                match next.and_then(&param_index) {
+                    Some(p) => {
+                        let mut s = new_token(PpKind::Str, &stringify(&args[p]), t);
+                        s.space = t.space;
+                        out.push(s);
+                        last_empty = false;
+                        i += 2;
+                        continue;
+                    }
+                    None => return Err(error_at(t, Message::StringifyNonParameter)),
+                }
+            }
+            if t.is("##") {
+                let rhs: Vec<PpToken> = match next.and_then(&param_index) {
+                    Some(p) => args[p].clone(),
+                    None => next.into_iter().cloned().collect(),
+                };
+                if !rhs.is_empty() {
+                    match out.pop() {
+                        Some(lhs) if !last_empty => {
+                            out.push(paste(&lhs, &rhs[0])?);
+                            out.extend(rhs[1..].iter().cloned());
+                        }
+                        lhs => {
+                            out.extend(lhs);
+                            out.extend(rhs);
+                        }
+                    }
+                }
+                last_empty = false;
+                i += 2;
+                continue;
+            }
+            match param_index(t) {
+                Some(p) => {
+                    let arg = if next.is_some_and(|next| next.is("##")) {
+                        args[p].clone()
+                    } else {
+                        self.expand(args[p].clone())?
+                    };
+                    last_empty = arg.is_empty();
+                    let mut arg = arg;
+                    if let Some(first) = arg.first_mut() {
+                        first.space = t.space;
+                    }
+                    out.extend(arg);
+                }
+                None => {
+                    out.push(t.clone());
+                    last_empty = false;
+                }
+            }
+            i += 1;
+        }
+        // 展開結果は呼び出した位置にあるものとして扱う
+        for (i, t) in out.iter_mut().enumerate() {
+            for name in &hideset {
+                if !t.hideset.contains(name) {
+                    t.hideset.push(name.clone());
+                }
+            }
+            t.file = origin.file.clone();
+            t.line = origin.line;
+            t.column = origin.column;
+            if i == 0 {
+                t.space = origin.space;
+            }
+        }
+        Ok(out)
+    }
+}
+
+fn bundled_header(path: &Path) -> Option<&'static str> {
+    let name = path.strip_prefix(BUNDLED_INCLUDE_DIR).ok()?;
+    BUNDLED_HEADERS
+        .iter()
+        .find(|(header, _)| Path::new(header) == name)
+        .map(|(_, src)| *src)
+}
+
+// #pragma onceで同じファイルかを調べるためのパス。同梱のヘッダはそのままのパスを使う
+fn once_key(path: &Path) -> PathBuf {
+    fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())
+}
+
+fn with(hideset: &[String], name: &str) -> Vec<String> {
+    let mut hideset = hideset.to_vec();
+    if !hideset.iter().any(|n| n == name) {
+        hideset.push(name.to_string());
+    }
+    hideset
+}
+
+fn new_token(kind: PpKind, text: &str, origin: &PpToken) -> PpToken {
+    PpToken {
+        kind,
+        text: text.to_string(),
+        file: origin.file.clone(),
+        line: origin.line,
+        column: origin.column,
+        space: origin.space,
+        hideset: origin.hideset.clone(),
+    }
+}
+
+fn join_tokens(tokens: &[PpToken]) -> String {
+    let mut s = String::new();
+    for (i, t) in tokens.iter().enumerate() {
+        if i > 0 && t.space {
+            s.push(' ');
+        }
+        s.push_str(&t.text);
+    }
+    s
+}
+
+fn quote(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+// #による文字列化。文字列と文字定数の中の\と"はエスケープする
+fn stringify(arg: &[PpToken]) -> String {
+    let mut s = String::new();
+    for (i, t) in arg.iter().enumerate() {
+        if i > 0 && t.space {
+            s.push(' ');
+        }
+        match t.kind {
+            PpKind::Str | PpKind::Char => {
+                s.push_str(&t.text.replace('\\', "\\\\").replace('"', "\\\""))
+            }
+            _ => s.push_str(&t.text),
+        }
+    }
+    format!("\"{}\"", s)
+}
+
+// ##による連結。つないだ文字列がちょうど一つのトークンにならなければエラー
+fn paste(lhs: &PpToken, rhs: &PpToken) -> Result<PpToken, PpError> {
+    let text = format!("{}{}", lhs.text, rhs.text);
+    let lines = tokenize_lines(&text, &lhs.file)?;
+    match lines.as_slice() {
+        [line] if line.len() == 1 => {
+            let mut t = new_token(line[0].kind, &text, lhs);
+            t.space = lhs.space;
+            Ok(t)
+        }
+        _ => Err(error_at(
+            lhs,
+            Message::InvalidPaste(lhs.text.clone(), rhs.text.clone()),
+        )),
+    }
+}
+
+fn parse_macro_params<'t>(
+    name: &PpToken,
+    tokens: &'t [PpToken],
+    mut acm: Vec<String>,
+) -> Result<(Vec<String>, bool, &'t [PpToken]), PpError> {
+    match tokens {
+        [r, rest @ ..] if r.is(")") && acm.is_empty() => Ok((acm, false, rest)),
+        [dots, r, rest @ ..] if dots.is("...") && r.is(")") => {
+            acm.push("__VA_ARGS__".to_string());
+            Ok((acm, true, rest))
+        }
+        [param, sep, rest @ ..] if param.kind == PpKind::Ident => {
+            if acm.contains(&param.text) {
+                return Err(error_at(
+                    param,
+                    Message::DuplicateMacroParameter(param.text.clone()),
+                ));
+            }
+            acm.push(param.text.clone());
+            match sep.text.as_str() {
+                ")" => Ok((acm, false, rest)),
+                "," => parse_macro_params(name, rest, acm),
+                _ => Err(error_at(sep, Message::MalformedMacroParameters)),
+            }
+        }
+        _ => Err(error_at(name, Message::MalformedMacroParameters)),
+    }
+}
+
+// 関数形式マクロの実引数を読む。(は読んだ後で、対応する)までを読む
+fn collect_args(
+    name: &PpToken,
+    input: &mut VecDeque<PpToken>,
+) -> Result<(Vec<Vec<PpToken>>, PpToken), PpError> {
+    let mut args = vec![vec![]];
+    let mut depth = 0;
+    while let Some(t) = input.pop_front() {
+        if t.is(")") && depth == 0 {
+            return Ok((args, t));
+        }
+        if t.is(",") && depth == 0 {
+            args.push(vec![]);
+            continue;
+        }
+        if t.is("(") {
+            depth += 1;
+        } else if t.is(")") {
+            depth -= 1;
+        }
+        args.last_mut().unwrap().push(t);
+    }
+    Err(error_at(
+        name,
+        Message::UnterminatedMacroCall(name.text.clone()),
+    ))
+}
+
+fn match_args(
+    name: &PpToken,
+    m: &Macro,
+    params: &[String],
+    mut args: Vec<Vec<PpToken>>,
+) -> Result<Vec<Vec<PpToken>>, PpError> {
+    // F() は仮引数がなければ実引数0個、あれば空の実引数1個として扱う
+    if params.is_empty() && args.len() == 1 && args[0].is_empty() {
+        args.clear();
+    }
+    if m.variadic {
+        let fixed = params.len() - 1;
+        if args.len() == fixed {
+            args.push(vec![]);
+        }
+        // 可変長の部分はカンマも含めて一つの実引数にまとめる
+        if args.len() > params.len() {
+            let rest = args.split_off(fixed);
+            let mut joined = vec![];
+            for (i, arg) in rest.into_iter().enumerate() {
+                if i > 0 {
+                    joined.push(new_token(PpKind::Punct, ",", name));
+                }
+                joined.extend(arg);
+            }
+            args.push(joined);
+        }
+    }
+    if args.len() != params.len() {
+        return Err(error_at(
+            name,
+            Message::MacroArgumentCount {
+                name: name.text.clone(),
+                expected: params.len(),
+                found: args.len(),
+            },
+        ));
+    }
+    Ok(args)
+}
+
+fn include_name(args: &[PpToken]) -> Option<(String, bool)> {
+    match args {
+        [s] if s.kind == PpKind::Str => Some((s.text[1..s.text.len() - 1].to_string(), true)),
+        [l, rest @ ..] if l.is("<") => match rest.iter().position(|t| t.is(">")) {
+            Some(end) if end + 1 == rest.len() => Some((join_tokens(&rest[..end]), false)),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+// #ifの式の値。intmax_t (long) かuintmax_t (unsigned long) のどちらかの型で計算する
+type PpValue = (i64, TypeDec);
+
+fn pp_int(value: i64) -> PpValue {
+    (value, TypeDec::Long)
+}
+
+// 二項演算の両辺をそろえる型。どちらかが符号なしなら符号なしで計算する
+fn pp_type(left: &TypeDec, right: &TypeDec) -> TypeDec {
+    if left.is_unsigned() || right.is_unsigned() {
+        TypeDec::ULong
+    } else {
+        TypeDec::Long
+    }
+}
+
+// #ifの式を計算する。liveがfalseなら結果を使わない部分 (&&, ||, ?:の読み飛ばす側) なので、
+// 0での除算をエラーにしない
+fn eval_cond(tokens: &[PpToken], live: bool) -> Result<(PpValue, &[PpToken]), Message> {
+    let ((cond, t), rest) = eval_binary(tokens, 1, live)?;
+    match rest {
+        [q, rest @ ..] if q.is("?") => {
+            let ((a, a_type), rest) = eval_cond(rest, live && cond != 0)?;
+            match rest {
+                [c, rest @ ..] if c.is(":") => {
+                    let ((b, b_type), rest) = eval_cond(rest, live && cond == 0)?;
+                    let t = pp_type(&a_type, &b_type);
+                    Ok(((if cond != 0 { a } else { b }, t), rest))
+                }
+                _ => Err(Message::MissingColonInIf),
+            }
+        }
+        _ => Ok(((cond, t), rest)),
+    }
+}
+
+fn binary_precedence(t: &PpToken) -> Option<u8> {
+    if t.kind != PpKind::Punct {
+        return None;
+    }
+    match t.text.as_str() {
+        "||" => Some(1),
+        "&&" => Some(2),
+        "|" => Some(3),
+        "^" => Some(4),
+        "&" => Some(5),
+        "==" | "!=" => Some(6),
+        "<" | "<=" | ">" | ">=" => Some(7),
+        "<<" | ">>" => Some(8),
+        "+" | "-" => Some(9),
+        "*" | "/" | "%" => Some(10),
+        _ => None,
+    }
+}
+
+fn eval_binary(tokens: &[PpToken], min: u8, live: bool) -> Result<(PpValue, &[PpToken]), Message> {
+    let ((mut left, mut left_type), mut rest) = eval_unary(tokens, live)?;
+    while let Some((op, prec)) = rest
+        .first()
+        .and_then(|op| binary_precedence(op).map(|prec| (op, prec)))
+    {
+        if prec < min {
+            break;
+        }
+        let right_live = match op.text.as_str() {
+            "&&" => live && left != 0,
+            "||" => live && left == 0,
+            _ => live,
+        };
+        let ((right, right_type), next) = eval_binary(&rest[1..], prec + 1, right_live)?;
+        let t = pp_type(&left_type, &right_type);
+        (left, left_type) = match op.text.as_str() {
+            "||" => pp_int((left != 0 || right != 0) as i64),
+            "&&" => pp_int((left != 0 && right != 0) as i64),
+            "|" => (left | right, t),
+            "^" => (left ^ right, t),
+            "&" => (left & right, t),
+            // シフトの結果は左辺の型になる。符号なしの右シフトは論理シフト
+            "<<" => (left.wrapping_shl(right as u32), left_type),
+            ">>" if left_type.is_unsigned() => {
+                ((left as u64).wrapping_shr(right as u32) as i64, left_type)
+            }
+            ">>" => (left.wrapping_shr(right as u32), left_type),
+            "/" | "%" if right == 0 => {
+                if live {
+                    return Err(Message::DivisionByZeroInIf);
+                }
+                (0, t)
+            }
+            "%" if t.is_unsigned() => (((left as u64) % (right as u64)) as i64, t),
+            "%" => (left.wrapping_rem(right), t),
+            // Cの式と共通の演算は構文木の定数畳み込みと同じ計算をする
+            text => {
+                let op = c_op(text);
+                let t = match op {
+                    Op::Plus | Op::Minus | Op::Asterisk | Op::Slash => t,
+                    _ => TypeDec::Long,
+                };
+                match eval::eval_binary(&op, &pp_type(&left_type, &right_type), left, right) {
+                    Some(value) => (value, t),
+                    None if live => return Err(Message::DivisionOverflowInIf),
+                    None => (0, t),
+                }
+            }
+        };
+        rest = next;
+    }
+    Ok(((left, left_type), rest))
+}
+
+// #ifの式の演算子のうち、Cの式にもある二項演算子
+fn c_op(text: &str) -> Op {
+    match text {
+        "==" => Op::Eq,
+        "!=" => Op::NotEq,
+        "<" => Op::Ls,
+        "<=" => Op::LsEq,
+        ">" => Op::Gr,
+        ">=" => Op::GrEq,
+        "+" => Op::Plus,
+        "-" => Op::Minus,
+        "*" => Op::Asterisk,
+        _ => Op::Slash,
+    }
+}
+
+fn eval_unary(tokens: &[PpToken], live: bool) -> Result<(PpValue, &[PpToken]), Message> {
+    match tokens {
+        [op, rest @ ..] if op.is("+") => eval_unary(rest, live),
+        [op, rest @ ..] if op.is("-") => {
+            eval_unary(rest, live).map(|((v, t), rest)| ((v.wrapping_neg(), t), rest))
+        }
+        [op, rest @ ..] if op.is("!") => {
+            eval_unary(rest, live).map(|((v, _), rest)| (pp_int((v == 0) as i64), rest))
+        }
+        [op, rest @ ..] if op.is("~") => {
+            eval_unary(rest, live).map(|((v, t), rest)| ((!v, t), rest))
+        }
+        [l, rest @ ..] if l.is("(") => match eval_cond(rest, live)? {
+            (v, [r, rest @ ..]) if r.is(")") => Ok((v, rest)),
+            _ => Err(Message::UnclosedParenInIf),
+        },
+        [n, rest @ ..] if n.kind == PpKind::Number => Ok((parse_pp_number(&n.text)?, rest)),
+        [c, rest @ ..] if c.kind == PpKind::Char => Ok((pp_int(char_value(&c.text)?), rest)),
+        [t, ..] => Err(Message::InvalidTokenInIf(t.text.clone())),
+        [] => Err(Message::MissingIfExpression),
+    }
+}
+
+// uかUの接尾辞があるか、intmax_tに収まらない数は符号なしになる。
+// #ifではlongもlong longも同じ幅なので、lとLは型を変えない
+fn parse_pp_number(text: &str) -> Result<PpValue, Message> {
+    let digits = text.trim_end_matches(['u', 'U', 'l', 'L']);
+    let suffix = &text[digits.len()..];
+    let suffix_ok = matches!(
+        suffix.to_ascii_lowercase().as_str(),
+        "" | "u" | "l" | "ul" | "lu" | "ll" | "ull" | "llu"
+    ) && !suffix.contains("lL")
+        && !suffix.contains("Ll");
+    let (digits, radix) = if let Some(hex) = digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        (hex, 16)
+    } else if let Some(bin) = digits
+        .strip_prefix("0b")
+        .or_else(|| digits.strip_prefix("0B"))
+    {
+        (bin, 2)
+    } else if digits.len() > 1 && digits.starts_with('0') {
+        (&digits[1..], 8)
+    } else {
+        (digits, 10)
+    };
+    match u64::from_str_radix(digits, radix) {
+        Ok(value) if suffix_ok => {
+            let unsigned = suffix.contains(['u', 'U']) || value > i64::MAX as u64;
+            let t = if unsigned {
+                TypeDec::ULong
+            } else {
+                TypeDec::Long
+            };
+            Ok((value as i64, t))
+        }
+        _ => Err(Message::InvalidNumberInIf(text.to_string())),
+    }
+}
+
+fn char_value(text: &str) -> Result<i64, Message> {
+    let inner: Vec<char> = text[1..text.len() - 1].chars().collect();
+    match inner.as_slice() {
+        [c] => Ok(*c as i64),
+        ['\\', 'n'] => Ok('\n' as i64),
+        ['\\', 't'] => Ok('\t' as i64),
+        ['\\', 'r'] => Ok('\r' as i64),
+        ['\\', '0'] => Ok(0),
+        ['\\', c] => Ok(*c as i64),
+        _ => Err(Message::InvalidCharInIf(text.to_string())),
+    }
+}
+
+// ソースを論理行ごとのpp-tokenに分ける。
+// バックスラッシュと改行はつなぎ、コメントは空白として扱う。
+fn tokenize_lines(src: &str, file: &Rc<str>) -> Result<Vec<Vec<PpToken>>, PpError> {
+    // 各文字に物理行の番号を付け、行の継続を取り除く
+    // 列はcharsと同じ並びでcolumnsに持つ
+    let mut chars: Vec<(char, usize)> = vec![];
+    let mut columns: Vec<usize> = vec![];
+    let mut line = 1;
+    let mut column = 1;
+    let mut iter = src.chars().peekable();
+    while let Some(c) = iter.next() {
+        match c {
+            '\\' if iter.peek() == Some(&'\n') => {
+                iter.next();
+                line += 1;
+                column = 1;
+                continue;
+            }
+            '\n' => {
+                chars.push((c, line));
+                columns.push(column);
+                line += 1;
+                column = 1;
+                continue;
+            }
+            '\r' if iter.peek() == Some(&'\n') => {}
+            _ => {
+                chars.push((c, line));
+                columns.push(column);
+            }
+        }
+        column += 1;
+    }
+
+    let mut lines = vec![];
+    let mut current: Vec<PpToken> = vec![];
+    let mut space = false;
+    let mut i = 0;
+    let text =
+        |from: usize, to: usize| -> String { chars[from..to].iter().map(|(c, _)| c).collect() };
+    while i < chars.len() {
+        let (c, line) = chars[i];
+        let next = chars.get(i + 1).map(|(c, _)| *c);
+        let start = i;
+        let kind = match (c, next) {
+            ('\n', _) => {
+                lines.push(std::mem::take(&mut current));
+                space = false;
+                i += 1;
+                continue;
+            }
+            (c, _) if c.is_whitespace() => {
+                space = true;
+                i += 1;
+                continue;
+            }
+            ('/', Some('/')) => {
+                while i < chars.len() && chars[i].0 != '\n' {
+                    i += 1;
+                }
+                space = true;
+                continue;
+            }
+            ('/', Some('*')) => {
+                i += 2;
+                while i < chars.len()
+                    && !(chars[i].0 == '*' && chars.get(i + 1).map(|(c, _)| *c) == Some('/'))
+                {
+                    i += 1;
+                }
+                if i >= chars.len() {
+                    return Err(error_at_position(
+                        file,
+                        line,
+                        columns[start],
+                        Message::UnterminatedComment,
+                    ));
+                }
+                i += 2;
+                space = true;
+                continue;
+            }
+            (c, _) if c.is_alphabetic() || c == '_' => {
+                while i < chars.len() && (chars[i].0.is_alphanumeric() || chars[i].0 == '_') {
+                    i += 1;
+                }
+                PpKind::Ident
+            }
+            // pp-number: 数字か.数字で始まり、英数字、_、.、指数の符号が続く
+            (c, _) if c.is_ascii_digit() => {
+                i = pp_number_end(&chars, i);
+                PpKind::Number
+            }
+            ('.', Some(d)) if d.is_ascii_digit() => {
+                i = pp_number_end(&chars, i);
+                PpKind::Number
+            }
+            ('"', _) | ('\'', _) => {
+                i += 1;
+                while i < chars.len() && chars[i].0 != c && chars[i].0 != '\n' {
+                    if chars[i].0 == '\\' {
+                        i += 1;
+                    }
+                    i += 1;
+                }
+                if i >= chars.len() || chars[i].0 != c {
+                    return Err(error_at_position(
+                        file,
+                        line,
+                        columns[start],
+                        Message::UnterminatedQuote,
+                    ));
+                }
+                i += 1;
+                if c == '"' {
+                    PpKind::Str
+                } else {
+                    PpKind::Char
+                }
+            }
+            _ => {
+                let rest = text(i, (i + 3).min(chars.len()));
+                match PUNCTUATORS.iter().find(|p| rest.starts_with(**p)) {
+                    Some(p) => {
+                        i += p.chars().count();
+                        PpKind::Punct
+                    }
+                    None => {
+                        i += 1;
+                        PpKind::Other
+                    }
+                }
+            }
+        };
+        current.push(PpToken {
+            kind,
+            text: text(start, i),
+            file: file.clone(),
+            line,
+            column: columns[start],
+            space,
+            hideset: vec![],
+        });
+        space = false;
+    }
+    lines.push(current);
+    Ok(lines)
+}
+
+fn pp_number_end(chars: &[(char, usize)], start: usize) -> usize {
+    let mut i = start + 1;
+    while i < chars.len() {
+        let c = chars[i].0;
+        let prev = chars[i - 1].0;
+        let exponent_sign = (c == '+' || c == '-') && matches!(prev, 'e' | 'E' | 'p' | 'P');
+        if !(c.is_alphanumeric() || c == '_' || c == '.' || exponent_sign) {
+            break;
+        }
+        i += 1;
+    }
+    i
+}
+
+#[cfg(test)]
+fn preprocess_test_source(src: &str) -> String {
+    match preprocess(src, "test.c", &Options::default()) {
+        Ok(pp) => to_source(&pp.tokens),
+        Err(err) => panic!("{:?}", err),
+    }
+}
+
+#[test]
+fn preprocess_test() {
+    assert_eq!(
+        preprocess_test_source("#define N 3\nint x = N;"),
+        "int x = 3;\n"
+    );
+    assert_eq!(
+        preprocess_test_source("#define ADD(a, b) ((a) + (b))\nADD(1, ADD(2, 3))"),
+        "((1) + (((2) + (3))))\n"
+    );
+    // 自分自身を含むマクロは一度しか展開しない
+    assert_eq!(
+        preprocess_test_source("#define foo foo + 1\nfoo"),
+        "foo + 1\n"
+    );
+    // 規格では2 * 9 * gと2 * f(9)のどちらでもよい。hide setによる展開では後者になる
+    assert_eq!(
+        preprocess_test_source("#define f(a) a * g\n#define g f\nf(2)(9)"),
+        "2 * f(9)\n"
+    );
+    assert_eq!(
+        preprocess_test_source("#define str(x) #x\nstr(a  + \"b\\n\")"),
+        "\"a + \\\"b\\\\n\\\"\"\n"
+    );
+    assert_eq!(
+        preprocess_test_source("#define cat(a, b) a ## b\ncat(x, 1) cat(, y) cat(1, 2)"),
+        "x1 y 12\n"
+    );
+    assert_eq!(
+        preprocess_test_source("#define f(fmt, ...) g(fmt, __VA_ARGS__)\nf(1, 2, 3)"),
+        "g(1, 2, 3)\n"
+    );
+    // 関数形式マクロの名前だけなら展開しない
+    assert_eq!(preprocess_test_source("#define f(x) x\nf + 1"), "f + 1\n");
+    assert_eq!(
+        preprocess_test_source("#define A -\nint x = -A 1;"),
+        "int x = - - 1;\n"
+    );
+    assert_eq!(
+        preprocess_test_source("a\n__LINE__ __FILE__"),
+        "a\n2 \"test.c\"\n"
+    );
+    assert_eq!(
+        preprocess_test_source("int a; /* c\nc */ int b; // d\nint \\\nc;"),
+        "int a;\nint b;\nint\nc;\n"
+    );
+}
+
+#[test]
+fn preprocess_conditional_test() {
+    let src = "#define X 2
+#if X == 1
+one
+#elif X == 2 && defined(X) && !defined Y
+two
+#else
+other
+#endif
+#ifdef Y
+y
+#endif
+#ifndef Y
+not_y
+#endif
+#if 0
+#error unreachable
+#if 1
+nested
+#endif
+#else
+zero
+#endif
+#undef X
+#if X
+x
+#endif
+#if (1 ? 2 : 0 / 0) * 3 == 6 && 0x10 == 16 && 'a' == 97 && (1 << 4) > 15
+expr
+#endif
+#if (0u - 1) > 0 && (-1 < 0u) == 0 && 18446744073709551615UL > 0
+unsigned
+#endif
+#if -1 >> 1 == -1 && (-1u >> 63) == 1 && -7 / 2 == -3 && -7u / 2 == 9223372036854775804
+shift
+#endif";
+    assert_eq!(
+        preprocess_test_source(src),
+        "two\nnot_y\nzero\nexpr\nunsigned\nshift\n"
+    );
+
+    let options = Options {
+        include_paths: vec![],
+        macros: vec![
+            MacroOption::Define("A".to_string(), "1".to_string()),
+            MacroOption::Define("B".to_string(), "1".to_string()),
+            MacroOption::Undef("B".to_string()),
+        ],
+    };
+    let pp = preprocess("#if A && !defined B\nok\n#endif", "test.c", &options).unwrap();
+    assert_eq!(to_source(&pp.tokens), "ok\n");
+
+    let error = |src: &str| preprocess(src, "test.c", &Options::default()).unwrap_err();
+    assert_eq!(
+        error("#error stop").message,
+        Message::ErrorDirective("stop".to_string())
+    );
+    // 閉じていない#ifは#ifの位置を指す
+    let err = error("\n  #if 1\nx");
+    assert_eq!(err.message, Message::UnterminatedConditional);
+    assert_eq!(err.location.to_string(), "test.c:2:4");
+    assert_eq!(
+        error("#if (-9223372036854775807 - 1) / -1\n#endif").message,
+        Message::DivisionOverflowInIf
+    );
+    assert_eq!(
+        error("#if 1 / 0\n#endif").message,
+        Message::DivisionByZeroInIf
+    );
+    assert_eq!(
+        error("#endif").message,
+        Message::UnmatchedDirective("endif".to_string())
+    );
+    assert_eq!(
+        error("#define f(x) x\nf(1, 2)").message,
+        Message::MacroArgumentCount {
+            name: "f".to_string(),
+            expected: 1,
+            found: 2
+        }
+    );
+    assert_eq!(
+        error("#include \"nope.h\"").message,
+        Message::IncludeNotFound("nope.h".to_string())
+    );
+}
+
+#[test]
+fn line_marker_test() {
+    let dir = std::env::temp_dir().join(format!("cygcc_line_marker_{}", std::process::id()));
+    fs::create_dir_all(&dir).unwrap();
+    fs::write(dir.join("x.h"), "#define Y y\nx Y\n").unwrap();
+    let main = dir.join("test.c").display().to_string();
+    let src = "a\n#include \"x.h\"\nb\n\n\nc\n\n\n\n\n\n\n\n\n\nd";
+    let pp = preprocess(src, &main, &Options::default()).unwrap();
+    let header = dir.join("x.h").display().to_string();
+    assert_eq!(
+        to_text_with_markers(&pp),
+        format!(
+            "# 1 \"{0}\"\na\n# 1 \"{1}\" 1\n\nx y\n# 3 \"{0}\" 2\nb\n\n\nc\n# 16 \"{0}\"\nd\n",
+            main, header
+        )
+    );
+    fs::remove_dir_all(&dir).unwrap();
+}
+
+#[test]
+fn bundled_header_test() {
+    let pp = preprocess(
+        "#include <stdbool.h>\n#include <stdbool.h>\nbool b = true;",
+        "test.c",
+        &Options::default(),
+    )
+    .unwrap();
+    assert_eq!(to_source(&pp.tokens), "int b = 1;\n");
+    assert!(to_text_with_markers(&pp).contains("# 1 \"<cygcc>/include/stdbool.h\" 1 3 4\n"));
+    for (name, _) in BUNDLED_HEADERS {
+        let src = format!("#include <{}>", name);
+        assert!(preprocess(&src, "test.c", &Options::default()).is_ok());
+    }
+}
+
+#[test]
+fn source_map_test() {
+    let src = "#define N 10\nint  x =\\\n  N;\n\tint y;";
+    let pp = preprocess(src, "test.c", &Options::default()).unwrap();
+    let (out, map) = to_source_with_map(&pp.tokens);
+    assert_eq!(out, "int x =\n10;\nint y;\n");
+    let at = |text: &str| {
+        let (file, line, column) = map.lookup(out.find(text).unwrap()).unwrap();
+        (file.to_string(), line, column)
+    };
+    assert_eq!(at("x"), ("test.c".to_string(), 2, 6));
+    // マクロから来たトークンは呼び出しの位置
+    assert_eq!(at("10"), ("test.c".to_string(), 3, 3));
+    assert_eq!(at(";\nint y"), ("test.c".to_string(), 3, 4));
+    assert_eq!(at("y"), ("test.c".to_string(), 4, 6));
+}
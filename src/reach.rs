@@ -0,0 +1,191 @@
+// 到達しない文を取り除く。returnの後ろや終わらないループ (条件が定数で真のwhileやfor) の後ろの文は
+// 実行されないので捨てる。条件が定数のifは、実行されない側の文を捨てる。
+// 宣言と構造体の定義は実行される文ではないので残す
+use crate::diagnostic::Message;
+use crate::eval::eval_const;
+use crate::parser::{Exp, Pos, Program, Stmt};
+
+// 実行される文を捨てた関数ごとに、-Wunreachable-codeの警告を一つ返す。
+// 警告の位置は最初に捨てた文にする
+pub fn remove_unreachable(program: Program) -> (Program, Vec<(Message, Pos)>) {
+    let mut warnings = vec![];
+    let program = program
+        .into_iter()
+        .map(|stmt| match stmt {
+            Stmt::Func {
+                t,
+                fun,
+                params,
+                body,
+                storage,
+                inline,
+                pos,
+            } => {
+                let mut removed = None;
+                let (body, _) = reach_stmts(body, &mut removed);
+                if let (Some(pos), Exp::Var(name, _)) = (removed, &*fun) {
+                    warnings.push((Message::UnreachableCode(name.to_string()), pos));
+                }
+                Stmt::Func {
+                    t,
+                    fun,
+                    params,
+                    body,
+                    storage,
+                    inline,
+                    pos,
+                }
+            }
+            stmt => stmt,
+        })
+        .collect();
+    (program, warnings)
+}
+
+// 文の並びのうち到達するものを残す。並びの後ろまで実行が進みうるかも返す。
+// removedには最初に捨てた文の位置を入れる
+fn reach_stmts(stmts: Vec<Stmt>, removed: &mut Option<Pos>) -> (Vec<Stmt>, bool) {
+    let mut acm = vec![];
+    let mut completes = true;
+    for stmt in stmts {
+        if completes {
+            let (stmt, next) = reach_stmt(stmt, removed);
+            acm.push(stmt);
+            completes = next;
+        } else if is_declaration(&stmt) {
+            acm.push(stmt);
+        } else {
+            removed.get_or_insert(stmt.pos());
+        }
+    }
+    (acm, completes)
+}
+
+fn reach_stmt(stmt: Stmt, removed: &mut Option<Pos>) -> (Stmt, bool) {
+    match stmt {
+        Stmt::Return(..) => (stmt, false),
+        Stmt::Block(stmts, pos) => {
+            let (stmts, completes) = reach_stmts(stmts, removed);
+            (Stmt::Block(stmts, pos), completes)
+        }
+        Stmt::If {
+            cond,
+            stmt1,
+            stmt2,
+            pos,
+        } => match (is_true(&cond), *stmt2) {
+            (Some(true), _) => reach_stmt(*stmt1, removed),
+            (Some(false), Some(stmt2)) => reach_stmt(stmt2, removed),
+            (Some(false), None) => (Stmt::Block(vec![], pos), true),
+            (None, stmt2) => {
+                let (stmt1, completes1) = reach_stmt(*stmt1, removed);
+                let (stmt2, completes2) = match stmt2 {
+                    Some(stmt2) => {
+                        let (stmt2, completes) = reach_stmt(stmt2, removed);
+                        (Some(stmt2), completes)
+                    }
+                    None => (None, true),
+                };
+                let stmt = Stmt::If {
+                    cond,
+                    stmt1: Box::new(stmt1),
+                    stmt2: Box::new(stmt2),
+                    pos,
+                };
+                (stmt, completes1 || completes2)
+            }
+        },
+        // breakはないので、条件が定数で真のループからは出られない
+        Stmt::While { cond, stmt, pos } => {
+            let (stmt, _) = reach_stmt(*stmt, removed);
+            let completes = is_true(&cond) != Some(true);
+            (
+                Stmt::While {
+                    cond,
+                    stmt: Box::new(stmt),
+                    pos,
+                },
+                completes,
+            )
+        }
+        Stmt::For {
+            exp1,
+            exp2,
+            exp3,
+            stmt,
+            pos,
+        } => {
+            let (stmt, _) = reach_stmt(*stmt, removed);
+            let completes = match &*exp2 {
+                Some(cond) => is_true(cond) != Some(true),
+                None => false,
+            };
+            (
+                Stmt::For {
+                    exp1,
+                    exp2,
+                    exp3,
+                    stmt: Box::new(stmt),
+                    pos,
+                },
+                completes,
+            )
+        }
+        stmt => (stmt, true),
+    }
+}
+
+// 条件が整数定数なら、その真偽
+fn is_true(cond: &Exp) -> Option<bool> {
+    eval_const(cond).map(|(value, _)| value != 0)
+}
+
+fn is_declaration(stmt: &Stmt) -> bool {
+    match stmt {
+        Stmt::VarDec { init, .. } => init.is_none(),
+        Stmt::StructDec { .. } => true,
+        _ => false,
+    }
+}
+
+#[test]
+fn remove_unreachable_test() {
+    use crate::lexer::tokenize;
+    use crate::parser::{parse_program, program_of};
+    let reach = |src: &str| remove_unreachable(program_of(src));
+    let expected = |src: &str| reach(src).0;
+    let unreachable = |name: &str| (Message::UnreachableCode(name.to_string()), Pos::default());
+
+    let (program, warnings) = reach("int f() { return 1; 2; 3; }");
+    assert_eq!(program, expected("int f() { return 1; }"));
+    assert_eq!(warnings, vec![unreachable("f")]);
+
+    // ifの両方の枝がreturnなら後ろには進まない。宣言は残す
+    let (program, warnings) =
+        reach("int g(int i) { if (i) { return i; return 100; } else return 0; int x; x = 1; }");
+    assert_eq!(
+        program,
+        expected("int g(int i) { if (i) { return i; } else return 0; int x; }")
+    );
+    assert_eq!(warnings, vec![unreachable("g")]);
+
+    // 片方の枝だけのreturnや、終わりうるループの後ろは残す
+    let src = "int h(int i) { if (i) return 1; while (i) i = 0; for (;i;) i = 0; return 2; }";
+    assert_eq!(reach(src), (expected(src), vec![]));
+
+    // 終わらないループの後ろは捨てる。条件が定数のifは警告せずに片方を捨てる
+    let (program, warnings) = reach(
+        "int k() { if (0) return 1; else { while (1) { return 2; } return 3; } for (;;) {} }",
+    );
+    assert_eq!(program, expected("int k() { { while (1) { return 2; } } }"));
+    assert_eq!(warnings, vec![unreachable("k")]);
+
+    // 警告の位置は最初に捨てた文
+    let tokens = tokenize("int f() { while (1) {} x = 1; return 2; }")
+        .ok()
+        .unwrap();
+    let (program, _) = parse_program(&tokens);
+    let (_, warnings) = remove_unreachable(program);
+    let range = warnings[0].1.range(&tokens);
+    assert_eq!(tokens[range], tokenize("x = 1;").ok().unwrap()[..]);
+}
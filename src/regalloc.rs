@@ -0,0 +1,327 @@
+// 整数の仮想レジスタをx86-64の汎用レジスタに割り当てる線形走査のレジスタ割り当て。
+// 命令に通し番号を振り、生存区間 (最初と最後に生きている番号の間) の重ならない仮想レジスタで
+// 同じレジスタを使い回す。足りなければ終わりの遠い区間をスタックに追い出す (スピル)。
+// スロットやシンボルのアドレスはレジスタに置かず、使う場所でアドレスとして書く
+use crate::ir::{Function, Inst, Operand, Slot, Ty, VReg};
+use crate::ssa::successors;
+
+// codegenが作業用に使わず、呼び出しの間だけ引数に使うレジスタ。呼び出しをまたがない区間に使う
+pub static CALLER_SAVED: [&str; 4] = ["rsi", "rcx", "r8", "r9"];
+// 呼び出しで値が保たれるレジスタ。使ったものはプロローグで保存し、エピローグで戻す
+pub static CALLEE_SAVED: [&str; 5] = ["rbx", "r12", "r13", "r14", "r15"];
+
+// 使う場所でそのまま書けるアドレス
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub enum Address {
+    Slot(Slot),
+    Symbol(String),
+}
+
+#[derive(Debug, Clone, Eq, PartialEq)]
+pub struct Allocation {
+    // 仮想レジスタごとの汎用レジスタ (64ビットの名前)。Noneならスタックに置く
+    pub regs: Vec<Option<&'static str>>,
+    // 値がアドレスで、レジスタにもスタックにも置かない仮想レジスタ
+    pub addresses: Vec<Option<Address>>,
+    // 使ったcallee-savedレジスタ
+    pub callee_saved: Vec<&'static str>,
+}
+
+// 生存区間。start, endは命令の番号で、両端を含む
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub struct Interval {
+    pub vreg: VReg,
+    pub start: usize,
+    pub end: usize,
+    // 関数呼び出しをまたぐか、引数としてプロローグでレジスタから受け取るならtrue
+    pub crosses_call: bool,
+}
+
+// 一度だけaddr命令で定義される仮想レジスタのアドレス
+pub fn addresses(f: &Function) -> Vec<Option<Address>> {
+    let mut defs = vec![0; f.vregs.len()];
+    let mut addresses = vec![None; f.vregs.len()];
+    for inst in f.blocks.iter().flat_map(|block| block.insts.iter()) {
+        if let Some(dst) = inst.dst() {
+            defs[dst.0] += 1;
+            addresses[dst.0] = match inst {
+                Inst::SlotAddr { slot, .. } => Some(Address::Slot(*slot)),
+                Inst::SymbolAddr { symbol, .. } => Some(Address::Symbol(symbol.clone())),
+                _ => None,
+            };
+        }
+    }
+    for (address, defs) in addresses.iter_mut().zip(defs) {
+        if defs != 1 {
+            *address = None;
+        }
+    }
+    addresses
+}
+
+fn uses(inst: &Inst) -> impl Iterator<Item = VReg> + '_ {
+    inst.operands()
+        .into_iter()
+        .filter_map(|operand| match operand {
+            Operand::Reg(reg) => Some(*reg),
+            Operand::Imm(_) => None,
+        })
+}
+
+// ブロックの入口で生きている仮想レジスタを後ろ向きの反復で求める
+fn live_in(f: &Function) -> Vec<Vec<bool>> {
+    let n = f.vregs.len();
+    let mut gen = vec![vec![false; n]; f.blocks.len()];
+    let mut kill = vec![vec![false; n]; f.blocks.len()];
+    for (i, block) in f.blocks.iter().enumerate() {
+        for operand in block.term.operands() {
+            if let Operand::Reg(reg) = operand {
+                gen[i][reg.0] = true;
+            }
+        }
+        for inst in block.insts.iter().rev() {
+            if let Some(dst) = inst.dst() {
+                kill[i][dst.0] = true;
+                gen[i][dst.0] = false;
+            }
+            for reg in uses(inst) {
+                gen[i][reg.0] = true;
+            }
+        }
+    }
+    let mut live_in = gen.clone();
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for i in (0..f.blocks.len()).rev() {
+            for succ in successors(&f.blocks[i].term) {
+                for reg in 0..n {
+                    if live_in[succ.0][reg] && !kill[i][reg] && !live_in[i][reg] {
+                        live_in[i][reg] = true;
+                        changed = true;
+                    }
+                }
+            }
+        }
+    }
+    live_in
+}
+
+// 整数の仮想レジスタの生存区間を開始の順に返す。番号0は仮引数を受け取るプロローグで、
+// ブロックは並び順に、ブロックの中では命令、終端命令の順に番号を振る
+pub fn intervals(f: &Function) -> Vec<Interval> {
+    let live_in = live_in(f);
+    let addresses = addresses(f);
+    let mut ranges: Vec<Option<(usize, usize)>> = vec![None; f.vregs.len()];
+    let mut extend = |reg: VReg, pos: usize| {
+        ranges[reg.0] = Some(match ranges[reg.0] {
+            Some((start, end)) => (start.min(pos), end.max(pos)),
+            None => (pos, pos),
+        });
+    };
+    for (param, _) in &f.params {
+        extend(*param, 0);
+    }
+    let mut calls = vec![];
+    let mut pos = 1;
+    for (i, block) in f.blocks.iter().enumerate() {
+        let block_start = pos;
+        for (reg, live) in live_in[i].iter().enumerate() {
+            if *live {
+                extend(VReg(reg), block_start);
+            }
+        }
+        for inst in &block.insts {
+            for reg in uses(inst) {
+                extend(reg, pos);
+            }
+            if let Some(dst) = inst.dst() {
+                extend(dst, pos);
+            }
+            if let Inst::Call { .. } = inst {
+                calls.push(pos);
+            }
+            pos += 1;
+        }
+        for operand in block.term.operands() {
+            if let Operand::Reg(reg) = operand {
+                extend(*reg, pos);
+            }
+        }
+        // 出口で生きているものは後続の入口で生きているもの
+        for succ in successors(&block.term) {
+            for (reg, live) in live_in[succ.0].iter().enumerate() {
+                if *live {
+                    extend(VReg(reg), pos);
+                }
+            }
+        }
+        pos += 1;
+    }
+    let mut intervals: Vec<Interval> = ranges
+        .iter()
+        .enumerate()
+        .filter(|(reg, _)| matches!(f.vregs[*reg], Ty::I32 | Ty::I64) && addresses[*reg].is_none())
+        .filter_map(|(reg, range)| {
+            range.map(|(start, end)| Interval {
+                vreg: VReg(reg),
+                start,
+                end,
+                // 呼び出しの結果を受け取るだけなら呼び出しの後なのでまたがない
+                crosses_call: start == 0 || calls.iter().any(|call| start < *call && *call <= end),
+            })
+        })
+        .collect();
+    intervals.sort_by_key(|interval| (interval.start, interval.vreg));
+    intervals
+}
+
+pub fn allocate(f: &Function) -> Allocation {
+    let mut regs: Vec<Option<&'static str>> = vec![None; f.vregs.len()];
+    let mut callee_saved: Vec<&'static str> = vec![];
+    // 割り当て中の区間。同じ番号で生きている区間どうしは別のレジスタを使う
+    let mut active: Vec<Interval> = vec![];
+    for interval in intervals(f) {
+        active.retain(|other| other.end >= interval.start);
+        let in_use: Vec<&'static str> = active.iter().filter_map(|a| regs[a.vreg.0]).collect();
+        let candidates: Vec<&'static str> = if interval.crosses_call {
+            CALLEE_SAVED.to_vec()
+        } else {
+            CALLER_SAVED
+                .iter()
+                .chain(CALLEE_SAVED.iter())
+                .copied()
+                .collect()
+        };
+        match candidates.iter().find(|reg| !in_use.contains(reg)) {
+            Some(reg) => {
+                regs[interval.vreg.0] = Some(reg);
+                active.push(interval);
+            }
+            None => {
+                // 同じレジスタを使える区間のうち最も遠くまで生きるものと比べ、遠い方をスピルする
+                let victim = active
+                    .iter()
+                    .enumerate()
+                    .filter(
+                        |(_, a)| matches!(regs[a.vreg.0], Some(reg) if candidates.contains(&reg)),
+                    )
+                    .max_by_key(|(_, a)| a.end)
+                    .map(|(i, a)| (i, *a));
+                match victim {
+                    Some((i, victim)) if victim.end > interval.end => {
+                        regs[interval.vreg.0] = regs[victim.vreg.0].take();
+                        active.remove(i);
+                        active.push(interval);
+                    }
+                    _ => (),
+                }
+            }
+        }
+    }
+    for reg in regs.iter().flatten() {
+        if CALLEE_SAVED.contains(reg) && !callee_saved.contains(reg) {
+            callee_saved.push(reg);
+        }
+    }
+    callee_saved.sort_by_key(|reg| CALLEE_SAVED.iter().position(|r| r == reg));
+    Allocation {
+        regs,
+        addresses: addresses(f),
+        callee_saved,
+    }
+}
+
+#[cfg(test)]
+fn function_of(src: &str) -> Function {
+    let mut module = crate::lower::module_of(src).ok().unwrap();
+    crate::opt::optimize(&mut module, 1);
+    module.functions.pop().unwrap()
+}
+
+#[test]
+fn intervals_test() {
+    let f = function_of(
+        "int f(int a) { int s; s = 0; while (a > 0) { s = s + a; a = a - 1; } return s; }",
+    );
+    let ranges: Vec<(usize, usize, usize, bool)> = intervals(&f)
+        .iter()
+        .map(|i| (i.vreg.0, i.start, i.end, i.crosses_call))
+        .collect();
+    // ループの中で使う値はループ全体で生きている
+    assert_eq!(
+        f.to_string(),
+        "function f(%0: i32) -> i32 {
+bb0:
+  %20 = copy %0  ; i32
+  %21 = copy 0  ; i32
+  jmp bb1
+bb1:
+  %18 = copy %20  ; i32
+  %19 = copy %21  ; i32
+  %5 = gt i32 %18, 0  ; i32
+  br %5, bb2, bb3
+bb2:
+  %11 = add i32 %19, %18  ; i32
+  %15 = sub i32 %18, 1  ; i32
+  %20 = copy %15  ; i32
+  %21 = copy %11  ; i32
+  jmp bb1
+bb3:
+  ret %19
+}
+"
+    );
+    assert_eq!(
+        ranges,
+        vec![
+            (0, 0, 1, true),
+            (20, 1, 12, false),
+            (21, 2, 12, false),
+            (18, 4, 9, false),
+            (19, 5, 13, false),
+            (5, 6, 7, false),
+            (11, 8, 11, false),
+            (15, 9, 10, false),
+        ]
+    );
+}
+
+#[test]
+fn allocate_test() {
+    // 呼び出しをまたぐ値はcallee-savedレジスタに置く
+    let f = function_of("int g(); int f(int a) { int b; b = g(); return a + b; }");
+    let allocation = allocate(&f);
+    assert_eq!(allocation.regs[0], Some("rbx"));
+    assert_eq!(allocation.callee_saved, vec!["rbx"]);
+    let b = f.blocks[0]
+        .insts
+        .iter()
+        .find_map(|inst| match inst {
+            Inst::Call { dst, .. } => Some(*dst),
+            _ => None,
+        })
+        .unwrap();
+    assert_eq!(allocation.regs[b.0], Some("rsi"));
+
+    // レジスタが足りなければ遠くまで生きる値をスピルする
+    let src = "int f(int a, int b, int c, int d, int e, int g) { return a + b + c + d + e + g; }";
+    let f = function_of(src);
+    let allocation = allocate(&f);
+    let params: Vec<Option<&str>> = f
+        .params
+        .iter()
+        .map(|(param, _)| allocation.regs[param.0])
+        .collect();
+    assert_eq!(
+        params,
+        vec![
+            Some("rbx"),
+            Some("r12"),
+            Some("r13"),
+            Some("r14"),
+            Some("r15"),
+            None
+        ]
+    );
+}
@@ -0,0 +1,430 @@
+// 中間表現をSSA形式にする変換と、SSA形式から戻す変換。
+// to_ssaはアドレスを取られないスロットを仮想レジスタに昇格させ、合流点にphiを置く。
+// from_ssaはphiを直前のブロックでのcopyに置き換え、codegenが扱える形に戻す
+use crate::ir::{BlockId, Function, Inst, Operand, Slot, Terminator, Ty, VReg};
+use std::collections::{HashMap, HashSet};
+
+// ブロックごとの直前のブロック。同じ辺は一度だけ数える
+pub fn predecessors(f: &Function) -> Vec<Vec<BlockId>> {
+    let mut preds = vec![vec![]; f.blocks.len()];
+    for (i, block) in f.blocks.iter().enumerate() {
+        for succ in successors(&block.term) {
+            preds[succ.0].push(BlockId(i));
+        }
+    }
+    preds
+}
+
+// thenとelseが同じ分岐も一つの辺として扱う
+pub fn successors(term: &Terminator) -> Vec<BlockId> {
+    let mut succs = term.successors();
+    succs.dedup();
+    succs
+}
+
+// 入口から辿れるブロックの逆後順
+pub fn reverse_postorder(f: &Function) -> Vec<BlockId> {
+    let mut visited = vec![false; f.blocks.len()];
+    let mut order = vec![];
+    // (ブロック, 次に見る後続の番号)
+    let mut stack = vec![(BlockId(0), 0)];
+    visited[0] = true;
+    while let Some((block, i)) = stack.pop() {
+        let succs = successors(&f.blocks[block.0].term);
+        if i < succs.len() {
+            stack.push((block, i + 1));
+            let succ = succs[i];
+            if !visited[succ.0] {
+                visited[succ.0] = true;
+                stack.push((succ, 0));
+            }
+        } else {
+            order.push(block);
+        }
+    }
+    order.reverse();
+    order
+}
+
+// 各ブロックの直接の支配者。入口は自分自身、辿れないブロックはNone。
+// Cooper, Harvey, Kennedyの反復法で求める
+pub fn dominators(f: &Function) -> Vec<Option<BlockId>> {
+    let rpo = reverse_postorder(f);
+    let preds = predecessors(f);
+    let mut order = vec![usize::MAX; f.blocks.len()];
+    for (i, block) in rpo.iter().enumerate() {
+        order[block.0] = i;
+    }
+    let mut idom: Vec<Option<BlockId>> = vec![None; f.blocks.len()];
+    idom[0] = Some(BlockId(0));
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for block in rpo.iter().skip(1) {
+            let mut new_idom: Option<BlockId> = None;
+            for pred in &preds[block.0] {
+                if idom[pred.0].is_none() {
+                    continue;
+                }
+                new_idom = Some(match new_idom {
+                    None => *pred,
+                    Some(other) => intersect(*pred, other, &idom, &order),
+                });
+            }
+            if new_idom != idom[block.0] {
+                idom[block.0] = new_idom;
+                changed = true;
+            }
+        }
+    }
+    idom
+}
+
+fn intersect(mut a: BlockId, mut b: BlockId, idom: &[Option<BlockId>], order: &[usize]) -> BlockId {
+    while a != b {
+        while order[a.0] > order[b.0] {
+            a = idom[a.0].unwrap();
+        }
+        while order[b.0] > order[a.0] {
+            b = idom[b.0].unwrap();
+        }
+    }
+    a
+}
+
+// 支配木での子
+pub fn dominator_tree(idom: &[Option<BlockId>]) -> Vec<Vec<BlockId>> {
+    let mut children = vec![vec![]; idom.len()];
+    for (i, parent) in idom.iter().enumerate() {
+        match parent {
+            Some(parent) if parent.0 != i => children[parent.0].push(BlockId(i)),
+            _ => (),
+        }
+    }
+    children
+}
+
+pub fn dominance_frontiers(f: &Function, idom: &[Option<BlockId>]) -> Vec<HashSet<BlockId>> {
+    let preds = predecessors(f);
+    let mut frontiers = vec![HashSet::new(); f.blocks.len()];
+    for (i, preds) in preds.iter().enumerate() {
+        if preds.len() < 2 || idom[i].is_none() {
+            continue;
+        }
+        for pred in preds {
+            let mut runner = *pred;
+            if idom[runner.0].is_none() {
+                continue;
+            }
+            while Some(runner) != idom[i] {
+                frontiers[runner.0].insert(BlockId(i));
+                runner = idom[runner.0].unwrap();
+            }
+        }
+    }
+    frontiers
+}
+
+// keepがfalseのブロックを消し、番号を詰める。消したブロックから来るphiの引数も消す
+pub fn retain_blocks(f: &mut Function, keep: &[bool]) {
+    let mut renumber = vec![None; f.blocks.len()];
+    let mut next = 0;
+    for (i, keep) in keep.iter().enumerate() {
+        if *keep {
+            renumber[i] = Some(BlockId(next));
+            next += 1;
+        }
+    }
+    let blocks = std::mem::take(&mut f.blocks);
+    for (i, mut block) in blocks.into_iter().enumerate() {
+        if !keep[i] {
+            continue;
+        }
+        for inst in block.insts.iter_mut() {
+            if let Inst::Phi { args, .. } = inst {
+                args.retain(|(pred, _)| renumber[pred.0].is_some());
+                for (pred, _) in args.iter_mut() {
+                    *pred = renumber[pred.0].unwrap();
+                }
+            }
+        }
+        match &mut block.term {
+            Terminator::Jump(target) => *target = renumber[target.0].unwrap(),
+            Terminator::Branch { then, els, .. } => {
+                *then = renumber[then.0].unwrap();
+                *els = renumber[els.0].unwrap();
+            }
+            Terminator::Ret(_) => (),
+        }
+        f.blocks.push(block);
+    }
+}
+
+// 入口から辿れないブロックを消す。消したらtrue
+pub fn remove_unreachable_blocks(f: &mut Function) -> bool {
+    let mut keep = vec![false; f.blocks.len()];
+    for block in reverse_postorder(f) {
+        keep[block.0] = true;
+    }
+    if keep.iter().all(|keep| *keep) {
+        return false;
+    }
+    retain_blocks(f, &keep);
+    true
+}
+
+pub fn new_vreg(f: &mut Function, ty: Ty) -> VReg {
+    f.vregs.push(ty);
+    VReg(f.vregs.len() - 1)
+}
+
+// 仮想レジスタに置けるスロットとその値の型を返す。
+// アドレスがvolatileでないload/storeのアドレスとしてだけ使われ、読み書きの型が一つでスロットの大きさと合うもの
+fn promotable_slots(f: &Function) -> HashMap<Slot, Ty> {
+    let mut addrs: HashMap<VReg, Slot> = HashMap::new();
+    for block in &f.blocks {
+        for inst in &block.insts {
+            if let Inst::SlotAddr { dst, slot } = inst {
+                addrs.insert(*dst, *slot);
+            }
+        }
+    }
+    let mut types: HashMap<Slot, Option<Ty>> = HashMap::new();
+    let slot_of = |operand: &Operand| match operand {
+        Operand::Reg(reg) => addrs.get(reg).copied(),
+        Operand::Imm(_) => None,
+    };
+    for block in &f.blocks {
+        for inst in &block.insts {
+            let (access, others) = match inst {
+                Inst::Load {
+                    ty,
+                    addr,
+                    volatile: false,
+                    ..
+                } => (Some((addr, *ty)), vec![]),
+                Inst::Store {
+                    ty,
+                    addr,
+                    value,
+                    volatile: false,
+                } => (Some((addr, *ty)), vec![value]),
+                Inst::Call {
+                    ret_slot: Some(slot),
+                    ..
+                } => {
+                    types.insert(*slot, None);
+                    (None, inst.operands())
+                }
+                _ => (None, inst.operands()),
+            };
+            if let Some((addr, ty)) = access {
+                if let Some(slot) = slot_of(addr) {
+                    let fits = f.slots[slot.0] == ty_size(ty);
+                    let entry = types.entry(slot).or_insert(Some(ty));
+                    if *entry != Some(ty) || !fits {
+                        *entry = None;
+                    }
+                }
+            }
+            for operand in others {
+                if let Some(slot) = slot_of(operand) {
+                    types.insert(slot, None);
+                }
+            }
+        }
+        for operand in block.term.operands() {
+            if let Some(slot) = slot_of(operand) {
+                types.insert(slot, None);
+            }
+        }
+    }
+    types
+        .into_iter()
+        .filter_map(|(slot, ty)| ty.map(|ty| (slot, ty)))
+        .collect()
+}
+
+pub(crate) fn ty_size(ty: Ty) -> i32 {
+    match ty {
+        Ty::I32 | Ty::F32 => 4,
+        Ty::I64 | Ty::F64 => 8,
+    }
+}
+
+// スロットを仮想レジスタに昇格させてSSA形式にする。
+// 辿れないブロックは先に消す。書く前に読んだ値は0とする
+pub fn to_ssa(f: &mut Function) {
+    remove_unreachable_blocks(f);
+    let promoted = promotable_slots(f);
+    if promoted.is_empty() {
+        return;
+    }
+    let idom = dominators(f);
+    let frontiers = dominance_frontiers(f, &idom);
+    let addrs: HashMap<VReg, Slot> = f
+        .blocks
+        .iter()
+        .flat_map(|block| block.insts.iter())
+        .filter_map(|inst| match inst {
+            Inst::SlotAddr { dst, slot } if promoted.contains_key(slot) => Some((*dst, *slot)),
+            _ => None,
+        })
+        .collect();
+
+    // storeのあるブロックの支配辺境に、繰り返しphiを置く
+    let mut phis: HashMap<(BlockId, VReg), Slot> = HashMap::new();
+    let mut slots: Vec<&Slot> = promoted.keys().collect();
+    slots.sort_by_key(|slot| slot.0);
+    let mut inserted: Vec<Vec<Inst>> = vec![vec![]; f.blocks.len()];
+    for slot in slots {
+        let mut work: Vec<BlockId> = vec![];
+        for (i, block) in f.blocks.iter().enumerate() {
+            let stores = block.insts.iter().any(|inst| match inst {
+                Inst::Store {
+                    addr: Operand::Reg(reg),
+                    ..
+                } => addrs.get(reg) == Some(slot),
+                _ => false,
+            });
+            if stores {
+                work.push(BlockId(i));
+            }
+        }
+        let mut has_phi = HashSet::new();
+        while let Some(block) = work.pop() {
+            let mut frontier: Vec<&BlockId> = frontiers[block.0].iter().collect();
+            frontier.sort();
+            for target in frontier {
+                if has_phi.insert(*target) {
+                    let dst = new_vreg(f, promoted[slot]);
+                    inserted[target.0].push(Inst::Phi { dst, args: vec![] });
+                    phis.insert((*target, dst), *slot);
+                    work.push(*target);
+                }
+            }
+        }
+    }
+    for (block, mut insts) in f.blocks.iter_mut().zip(inserted) {
+        insts.append(&mut block.insts);
+        block.insts = insts;
+    }
+
+    let children = dominator_tree(&idom);
+    let mut renamer = Renamer {
+        addrs,
+        phis,
+        current: HashMap::new(),
+    };
+    renamer.rename(f, BlockId(0), &children);
+}
+
+struct Renamer {
+    // 昇格させるスロットのアドレスを持つ仮想レジスタ
+    addrs: HashMap<VReg, Slot>,
+    // 置いたphiとそのスロット
+    phis: HashMap<(BlockId, VReg), Slot>,
+    // 支配木を辿る途中での各スロットの値
+    current: HashMap<Slot, Operand>,
+}
+
+impl Renamer {
+    fn promoted(&self, addr: &Operand) -> Option<Slot> {
+        match addr {
+            Operand::Reg(reg) => self.addrs.get(reg).copied(),
+            Operand::Imm(_) => None,
+        }
+    }
+
+    fn value(&self, slot: Slot) -> Operand {
+        self.current.get(&slot).copied().unwrap_or(Operand::Imm(0))
+    }
+
+    fn rename(&mut self, f: &mut Function, block: BlockId, children: &[Vec<BlockId>]) {
+        let saved = self.current.clone();
+        let insts = std::mem::take(&mut f.blocks[block.0].insts);
+        let mut renamed = vec![];
+        for inst in insts {
+            match inst {
+                Inst::Phi { dst, .. } if self.phis.contains_key(&(block, dst)) => {
+                    let slot = self.phis[&(block, dst)];
+                    self.current.insert(slot, Operand::Reg(dst));
+                    renamed.push(inst);
+                }
+                Inst::Load { dst, ref addr, .. } if self.promoted(addr).is_some() => {
+                    let slot = self.promoted(addr).unwrap();
+                    renamed.push(Inst::Copy {
+                        dst,
+                        src: self.value(slot),
+                    });
+                }
+                Inst::Store {
+                    ref addr, value, ..
+                } if self.promoted(addr).is_some() => {
+                    let slot = self.promoted(addr).unwrap();
+                    self.current.insert(slot, value);
+                }
+                Inst::SlotAddr { dst, .. } if self.addrs.contains_key(&dst) => (),
+                inst => renamed.push(inst),
+            }
+        }
+        f.blocks[block.0].insts = renamed;
+
+        for succ in successors(&f.blocks[block.0].term) {
+            let mut args = vec![];
+            for inst in &f.blocks[succ.0].insts {
+                if let Inst::Phi { dst, .. } = inst {
+                    if let Some(slot) = self.phis.get(&(succ, *dst)) {
+                        args.push(self.value(*slot));
+                    }
+                }
+            }
+            let mut args = args.into_iter();
+            for inst in f.blocks[succ.0].insts.iter_mut() {
+                if let Inst::Phi {
+                    dst,
+                    args: phi_args,
+                } = inst
+                {
+                    if self.phis.contains_key(&(succ, *dst)) {
+                        phi_args.push((block, args.next().unwrap()));
+                    }
+                }
+            }
+        }
+        for child in &children[block.0] {
+            self.rename(f, *child, children);
+        }
+        self.current = saved;
+    }
+}
+
+// phiを消してSSA形式から戻す。phiごとに一時レジスタを作り、
+// 直前の各ブロックの終わりでそこに値をcopyし、phiの場所で一時レジスタから受け取る
+pub fn from_ssa(f: &mut Function) {
+    for i in 0..f.blocks.len() {
+        let insts = std::mem::take(&mut f.blocks[i].insts);
+        let mut rest = vec![];
+        for inst in insts {
+            match inst {
+                Inst::Phi { dst, args } => {
+                    let temp = new_vreg(f, f.vregs[dst.0]);
+                    for (pred, arg) in args {
+                        f.blocks[pred.0].insts.push(Inst::Copy {
+                            dst: temp,
+                            src: arg,
+                        });
+                    }
+                    rest.push(Inst::Copy {
+                        dst,
+                        src: Operand::Reg(temp),
+                    });
+                }
+                inst => rest.push(inst),
+            }
+        }
+        // 自分自身が直前のブロックならcopyがすでに入っている
+        rest.append(&mut f.blocks[i].insts);
+        f.blocks[i].insts = rest;
+    }
+}
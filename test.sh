@@ -1,9 +1,20 @@
 #!/bin/bash
+# assertでコンパイルしたアセンブリの命令数の合計。コード生成の改善を測るために最後に表示する
+insts=0
+
 assert() {
   expected="$1"
   input="$2"
-  ./target/debug/cygcc "$input" > tmp.s
-  cc -o tmp tmp.s
+  lib="$3"
+  ./target/debug/cygcc $flags "$input" > tmp.s
+  insts=$((insts + $(grep -c '^  [a-z]' tmp.s)))
+  if [ -n "$lib" ]; then
+    # 3番目の引数があれば、gccでコンパイルしたCのコードと一緒にリンクする
+    echo "$lib" | cc -xc -c -o tmp2.o -
+    cc -o tmp tmp.s tmp2.o
+  else
+    cc -o tmp tmp.s
+  fi
   ./tmp
   actual="$?"
 
@@ -15,6 +26,98 @@ assert() {
   fi
 }
 
+# コンパイラへのオプションを付けてassertする
+assert_flags() {
+  flags="$1"
+  shift
+  assert "$@"
+  flags=
+}
+
+# -Eの出力を確かめる
+assert_preprocess() {
+  expected="$1"
+  input="$2"
+  actual=$(./target/debug/cygcc -E $3 "$input")
+  if [ "$actual" = "$expected" ]; then
+    echo "-E $input => ok"
+  else
+    echo "-E $input => $expected expected, but got $actual"
+    exit 1
+  fi
+}
+
+# --emit-irの出力に期待する行があることを確かめる。3番目の引数はコンパイラへのオプション
+assert_ir() {
+  expected="$1"
+  input="$2"
+  if ! ./target/debug/cygcc --emit-ir $3 "$input" | grep -xF -- "$expected" > /dev/null; then
+    echo "$input => IR line '$expected' expected"
+    exit 1
+  fi
+  echo "$input => $expected"
+}
+
+# コンパイルエラーになることを確かめる
+assert_error() {
+  input="$1"
+  if ./target/debug/cygcc "$input" > tmp.s 2> /dev/null; then
+    echo "$input => compile error expected"
+    exit 1
+  fi
+  echo "$input => compile error"
+}
+
+# 構文エラーがすべて報告されることを確かめる
+assert_error_count() {
+  expected="$1"
+  input="$2"
+  actual=$(./target/debug/cygcc "$input" 2>&1 > /dev/null | grep -c "error\[")
+  if [ "$actual" = "$expected" ]; then
+    echo "$input => $actual errors"
+  else
+    echo "$input => $expected errors expected, but got $actual"
+    exit 1
+  fi
+}
+
+# 診断メッセージがそのまま出ることを確かめる
+assert_error_message() {
+  expected="$1"
+  input="$2"
+  flags="$3"
+  actual=$(./target/debug/cygcc $flags "$input" 2>&1 > tmp.s)
+  if [ "$actual" != "$expected" ]; then
+    echo "$input => $expected expected, but got $actual"
+    exit 1
+  fi
+  echo "$input => $actual"
+}
+
+# コマンドラインの誤りが診断になり、ステータス1で終わることを確かめる (panicなら101になる)
+assert_usage_error() {
+  expected="$1"
+  flags="$2"
+  actual=$(./target/debug/cygcc $flags 2>&1 > /dev/null)
+  status=$?
+  if [ "$status" != 1 ] || [ "$actual" != "$expected" ]; then
+    echo "$flags => $expected (status 1) expected, but got $actual (status $status)"
+    exit 1
+  fi
+  echo "$flags => $actual"
+}
+
+# 警告が出ることを確かめる
+assert_warning() {
+  input="$1"
+  warning=$(./target/debug/cygcc "$input" 2>&1 > tmp.s | grep warning)
+  if [ -z "$warning" ]; then
+    echo "$input => warning expected"
+    exit 1
+  fi
+  echo "$input => $warning"
+}
+
 assert 7 'int main() { int x; int y; int *z; x=3; y=5; z=&y; *(z+1)=7; return x; }'
 
 assert 32 'int main() { return ret32(); } int ret32() { return 32; }'
@@ -91,10 +194,10 @@ assert 3 'int main(){ if (1-1){ return 2;} return 3; }'
 assert 2 'int main(){ if (1) return 2; return 3; }'
 assert 2 'int main(){ if (2-1) return 2; return 3; }'
 
-assert 3 'int main(){ if (0) {return 2;} else {return 3;} '
+assert 3 'int main(){ if (0) {return 2;} else {return 3;} }'
 assert 3 'int main(){ if (1-1) return 2; else return 3; }'
 assert 2 'int main(){ if (1) return 2; else return 3; }'
-assert 2 'int main(){ if (2-1) {return 2;} else {return 3;} '
+assert 2 'int main(){ if (2-1) {return 2;} else {return 3;} }'
 
 assert 10 'int main(){ int i; i=0; while(i<10) {i=i+1;} return i; }'
 assert 35 'int main(){ int i; int j; i=0; j = 7; while(i<35) {i= i + j;} return i;}'
@@ -111,13 +214,13 @@ assert 11 'int main(){ int i; int j;  i=0; j=0; for (i=0; i<=10; i=i+1) { j=2; }
 assert 55 'int main(){  int i; int j; i=0; j=0; for (i=0; i<=10; i=i+1) {j=i+j;} return j; }'
 
 assert 9 'int main(){  int i; int j; j = 0; for (i=0; i<10; i=i+1) {j = i;} return j; }'
-assert 10 'int main() int i; int j; { j = 0; for (i=0; i<10; i=i+1) {j = i; }return i;}'
+assert 10 'int main() { int i; int j; j = 0; for (i=0; i<10; i=i+1) {j = i; }return i;}'
 
 assert 3 'int main(){  for (;;) {return 3;} return 5; }'
 
 assert 90 'int main(){ int i; int j; j = 0; for (i = 0; i < 10; i = i + 1) {j = j + i ; j = j + i;} return j; }'
 
-assert 3 'int main(){ if (0) {return 2;} else {int i; i = 3; return i; return 100;} '
+assert 3 'int main(){ if (0) {return 2;} else {int i; i = 3; return i; return 100;} }'
 
 assert 32 'int main() { return ret32(); } int ret32() { return 32; }'
 assert 7 'int main() { return add2(3,4); } int add2(int x, int y) { return x+y; }'
@@ -139,4 +242,301 @@ assert 5 'int main() { int x; x=3; return (&x+2)-&x+3; }'
 assert 3 'int main() { int x; int *y; x = 0; y=&x; *y = 3; return x; }'
 
 assert 3 'int main() { int x; int *y; int **z;  x = 0; y=&x; z = &y; **z = 3; return x; }'
+assert 7 'int main() { int (*fp)(int, int); fp = add2; return fp(3,4); } int add2(int x, int y) { return x+y; }'
+assert 7 'int main() { int (*fp)(int, int); fp = &add2; return (*fp)(3,4); } int add2(int x, int y) { return x+y; }'
+assert 5 'int main() { return apply(sub2, 8, 3); } int apply(int (*f)(int, int), int a, int b) { return f(a, b); } int sub2(int x, int y) { return x-y; }'
+assert 9 'int sq(int x) { return x*x; } int main() { int (*fp)(int); int (**pp)(int); fp = sq; pp = &fp; return (**pp)(3); }'
+assert 3 'int add2(int, int); int main() { return add2(1, 2); } int add2(int x, int y) { return x+y; }'
+assert 8 'int twice(int (*f)(int), int x) { return f(f(x)); } int inc(int x) { return x+1; } int main() { int a; int b; int c; a = 1; return twice(inc, 6) + (a - 1); }'
+
+assert 3 'int main() { int x; x = 0 - 1; return x + 4; }'
+assert 3 'int g; int main() { g = 3; return g; }'
+assert 12 'int *p; int g; int main() { int x; g = 5; p = &g; *p = *p + 7; return g; }'
+assert 3 'int counter() { static int c; c = c + 1; return c; } int main() { counter(); counter(); return counter(); }'
+assert 23 'int a() { static int n; n = n + 1; return n; } int b() { static int n; n = n + 10; return n; } int main() { a(); b(); a(); return a() + b(); }'
+assert 3 'static int add(int x, int y) { return x+y; } int main() { return add(1, 2); }'
+assert 11 'static int add(int x, int y) { return x+y; } int call_lib_add(); int main() { return add(2, 3) + call_lib_add(); }' 'int add(int x, int y) { return x * y; } int call_lib_add() { return add(2, 3); }'
+assert 42 'extern int g; int main() { return g; }' 'int g = 42;'
+assert 5 'extern int g; int get_g(); int main() { g = 5; return get_g(); }' 'int g = 1; int get_g() { return g; }'
+assert 7 'int shared; int read_shared(); int main() { shared = 7; return read_shared(); }' 'extern int shared; int read_shared() { return shared; }'
+assert 9 'int main() { extern int g; return g + 1; }' 'int g = 8;'
+
+assert 3 'int main() { const int x = 3; return x; }'
+assert 5 'int main() { int x = 2; int *const p = &x; *p = 5; return x; }'
+assert 4 'int main() { int x; const int *p; x = 4; p = &x; return *p; }'
+assert 7 'int g = 7; int main() { return g; }'
+assert 12 'int f() { static int c = 10; c = c + 1; return c; } int main() { f(); return f(); }'
+assert 4 'int main() { volatile int v = 3; v = v + 1; return v; }'
+assert 6 'int get(const int *p) { return *p; } int main() { int const x = 6; return get(&x); }'
+assert_error 'int main() { const int x = 3; x = 4; return x; }'
+assert_error 'int main() { int x; int *const p = &x; p = &x; return 0; }'
+assert_error 'int main() { int x; const int *p = &x; *p = 1; return 0; }'
+assert_error 'int set(const int *p) { *p = 1; return 0; } int main() { return 0; }'
+assert_warning 'int main() { const int x = 1; int *p; p = &x; return *p; }'
+assert_warning 'int main() { const int x = 1; int *p = &x; return *p; }'
+assert_warning 'int f(int *p) { return *p; } int main() { const int x = 1; return f(&x); }'
+
+assert 7 'struct P { int x; int y; }; int main() { struct P p; p.x = 3; p.y = 4; return p.x + p.y; }'
+assert 5 'struct P { int x; int y; }; int main() { struct P p; struct P *q; q = &p; q->y = 5; return p.y; }'
+assert 9 'struct P { int x; int y; }; int main() { struct P a; struct P b; a.x = 2; a.y = 7; b = a; return b.x + b.y; }'
+assert 10 'struct P { int x; int y; }; int sum(struct P p) { return p.x + p.y; } int main() { struct P p; p.x = 4; p.y = 6; return sum(p); }'
+assert 4 'struct P { int x; int y; }; int set(struct P p) { p.x = 100; return p.y; } int main() { struct P p; p.x = 1; p.y = 4; set(p); return p.x + 3; }'
+assert 6 'struct T { int a; int *p; int b; }; int f(struct T t) { return t.a + *t.p + t.b; } int main() { struct T t; int x; x = 2; t.a = 1; t.p = &x; t.b = 3; return f(t); }'
+assert 15 'struct B { int a; int b; int c; int d; int e; }; int f(struct B s) { return s.a + s.b + s.c + s.d + s.e; } int main() { struct B s; s.a = 1; s.b = 2; s.c = 3; s.d = 4; s.e = 5; return f(s); }'
+assert 11 'struct P { int x; int y; }; struct P mk(int x, int y) { struct P p; p.x = x; p.y = y; return p; } int main() { struct P p; p = mk(5, 6); return p.x + p.y; }'
+assert 5 'struct P { int x; int y; }; struct P mk(int x, int y) { struct P p; p.x = x; p.y = y; return p; } int main() { return mk(3, 5).y; }'
+assert 20 'struct B { int a; int b; int c; int d; int e; }; struct B mk(int n) { struct B s; s.a = n; s.b = n; s.c = n; s.d = n; s.e = n; return s; } int main() { struct B s = mk(4); return s.a + s.b + s.c + s.d + s.e; }'
+assert 28 'struct P { int x; int y; }; int f(int a, int b, int c, int d, int e, struct P p, int g) { return a + b + c + d + e + p.x + p.y + g; } int main() { struct P p; p.x = 6; p.y = 7; return f(1, 2, 3, 4, 5, p, 0); }'
+assert 36 'int f(int a, int b, int c, int d, int e, int g, int h, int i) { return a + b + c + d + e + g + h + i; } int main() { return f(1, 2, 3, 4, 5, 6, 7, 8); }'
+assert 10 'struct P { int x; int y; }; int sum(struct P p); int main() { struct P p; p.x = 3; p.y = 7; return sum(p); }' 'struct P { int x; int y; }; int sum(struct P p) { return p.x + p.y; }'
+assert 15 'struct B { int a; int b; int c; int d; int e; }; int sum(struct B s); int main() { struct B s; s.a = 1; s.b = 2; s.c = 3; s.d = 4; s.e = 5; return sum(s); }' 'struct B { int a; int b; int c; int d; int e; }; int sum(struct B s) { return s.a + s.b + s.c + s.d + s.e; }'
+assert 17 'struct Q { int a; int b; int c; }; struct Q mk(int n); int main() { struct Q q; q = mk(4); return q.a + q.b + q.c; }' 'struct Q { int a; int b; int c; }; struct Q mk(int n) { struct Q q = { n, n + 1, n + 4 }; return q; }'
+assert 30 'struct B { int a; int b; int c; int d; int e; }; struct B mk(int n); int main() { struct B s; s = mk(2); return s.a + s.b + s.c + s.d + s.e; }' 'struct B { int a; int b; int c; int d; int e; }; struct B mk(int n) { struct B s = { n, n * 2, n * 3, n * 4, n * 5 }; return s; }'
+assert 17 'struct Q { int a; int b; int c; }; struct Q mk(int a, int b, int c) { struct Q q; q.a = a; q.b = b; q.c = c; return q; } int check(); int main() { return check(); }' 'struct Q { int a; int b; int c; }; struct Q mk(int a, int b, int c); int check() { struct Q q = mk(2, 5, 10); return q.a + q.b + q.c; }'
+assert 27 'struct B { int a; int b; int c; int d; int e; }; int sum(struct B s) { return s.a + s.b + s.c + s.d + s.e; } int check(); int main() { return check(); }' 'struct B { int a; int b; int c; int d; int e; }; int sum(struct B s); int check() { struct B s = { 1, 2, 3, 4, 17 }; return sum(s); }'
+assert_error 'struct P { int x; }; int main() { struct P p; return p.y; }'
+assert_error 'int main() { struct U u; return 0; }'
+
+assert 3 'int main() { double x = 1.5; return x * 2; }'
+assert 7 'int main() { float f = 3.5f; return f + f; }'
+assert 2 'int main() { double x = 7; return x / 3; }'
+assert 1 'int main() { return 0.1 + 0.2 > 0.3; }'
+assert 1 'int main() { double x = 1e3; return x == 1000; }'
+assert 25 'int main() { double x = .25; return x * 100; }'
+assert 5 'int main() { double a = 2.5; float b = 2.5f; return a + b; }'
+assert 1 'int main() { double a = 1.5; double b = 2.5; return a < b; }'
+assert 0 'int main() { double a = 1.5; double b = 2.5; return a > b; }'
+assert 0 'int main() { double a = 1.5; double b = 2.5; return a >= b; }'
+assert 1 'int main() { double a = 2.0; return a <= 2.0; }'
+assert 1 'int main() { float a = 2.0f; return a != 3; }'
+assert 0 'int main() { float a = 2.0f; return a == 2.5; }'
+assert 3 'int main() { double x = 0.5; int n = 0; while (x) { n = n + 1; if (n == 3) x = 0; } return n; }'
+assert 4 'int main() { int i = 10; double d = i; d = d / 4; i = d + 1.5; return i; }'
+assert 6 'double half(double x) { return x / 2; } int main() { return half(12.0); }'
+assert 9 'float sum(float a, int b, double c) { return a + b + c; } int main() { return sum(1.5f, 3, 4.5); }'
+assert 36 'double f(double a, double b, double c, double d, double e, double g, double h, double i, double j, double k) { return a + b + c + d + e + g + h + i + j + k; } int main() { return f(1, 2, 3, 4, 5, 6, 7, 8, 0.5, -0.5); }'
+assert 10 'double g = 2.5; float h = 1.5f; int main() { return g * 4 + h - h; }'
+assert 6 'struct V { double x; double y; }; double len2(struct V v) { return v.x * v.x + v.y * v.y; } int main() { struct V v; v.x = 1; v.y = 2.2360679; return len2(v) + 0.5; }'
+assert 7 'struct M { double d; int i; }; struct M mk(double d, int i) { struct M m; m.d = d; m.i = i; return m; } int main() { struct M m = mk(2.5, 4); return m.d + m.i + 0.5; }'
+assert 12 'struct F { float a; float b; double c; }; struct F mk(); int main() { struct F f = mk(); return f.a + f.b + f.c; }' 'struct F { float a; float b; double c; }; struct F mk() { struct F f = { 1.5f, 2.5f, 8.0 }; return f; }'
+assert 8 'double scale(double x, int n); int main() { return scale(2.0, 4); }' 'double scale(double x, int n) { return x * n; }'
+assert 5 'float addf(float a, float b); int main() { return addf(2.25f, 2.75f); }' 'float addf(float a, float b) { return a + b; }'
+assert 11 'double call_twice(); double twice(double x) { return x * 2; } int main() { return call_twice(); }' 'double twice(double x); double call_twice() { return twice(5.5); }'
+assert 7 'double vsum(); int main() { float f = 3.0f; return vsum(3, 1.5, 2.5, f); }' '#include <stdarg.h>
+double vsum(int n, ...) { va_list ap; double s = 0; va_start(ap, n); for (int i = 0; i < n; i++) s += va_arg(ap, double); va_end(ap); return s; }'
+assert 3 'double g = -2.5; int main() { return 0 - g + 0.5; }'
+assert 9 'struct D { double x; int n; }; int use(struct D d); int main() { struct D d; d.x = 4.5; d.n = 2; return use(d); }' 'struct D { double x; int n; }; int use(struct D d) { return d.x * d.n; }'
+assert_error 'int main() { int x; int *p = &x; double d = p; return 0; }'
+
+assert 127 'int main() { return 0x7f; }'
+assert 255 'int main() { return 0XFF; }'
+assert 237 'int main() { return 0755 - 256; }'
+assert 5 'int main() { return 0b101; }'
+assert 10 'int main() { return 10UL; }'
+assert 1 'int main() { long x = 4294967296; return x / 4294967296; }'
+assert 1 'int main() { long x = 0x100000000; return x == 4294967296; }'
+assert 3 'int main() { long x = 9223372036854775807; return x / 3074457345618258602; }'
+assert 0 'int main() { return 0xffffffff < 0; }'
+assert 0 'int main() { return 0 - 1 < 1u; }'
+assert 1 'int main() { return 0 - 1 < 1; }'
+assert 0 'int main() { unsigned int u = 0xffffffff; u = u + 1; return u; }'
+assert 1 'int main() { unsigned int u = 0xffffffff; long l = u; return l == 4294967295; }'
+assert 1 'int main() { int i = 0 - 1; unsigned long u = i; return u == 0xffffffffffffffff; }'
+assert 2 'int main() { unsigned long u = 0xffffffffffffffffUL; return u / 9223372036854775807; }'
+assert 1 'int main() { long a = 3000000000; int b = a; return b < 0; }'
+assert 8 'int main() { long long x = 8LL; unsigned long long y = 8ull; return x * y / 8; }'
+assert 1 'int main() { unsigned long u = 0x8000000000000000; double d = u; return d > 9e18; }'
+assert 6 'long mul(long a, long b) { return a * b; } int main() { return mul(0x200000000, 3) / 0x100000000; }'
+assert 7 'long g = 0x700000000; int main() { return g / 0x100000000; }'
+assert 9 'unsigned add(unsigned a, unsigned b); int main() { return add(4294967290u, 15u); }' 'unsigned add(unsigned a, unsigned b) { return a + b; }'
+assert_error 'int main() { return 10x; }'
+assert_error 'int main() { return 089; }'
+assert_error 'int main() { int int x; return 0; }'
+
+mkdir -p tmp_include/sub
+cat > tmp_include/sub/three.h <<'END'
+#pragma once
+#define THREE 3
+int three() { return THREE; }
+END
+cat > tmp_include/once.h <<'END'
+#include "sub/three.h"
+#include "sub/three.h"
+END
+cat > tmp_include/main.c <<'END'
+#include "once.h"
+#include <sub/three.h>
+int main() { return three() + __LINE__; }
+END
+assert 3 '#define N 3
+int main() { return N; }'
+assert 9 '#define SQ(x) ((x) * (x))
+int main() { return SQ(1 + 2); }'
+assert 12 '#define CAT(a, b) a ## b
+int main() { int xy = 12; return CAT(x, y); }'
+assert 6 '#define SUM(...) sum(__VA_ARGS__)
+int sum(int a, int b, int c) { return a + b + c; }
+int main() { return SUM(1, 2, 3); }'
+assert 2 '#define X 2
+#if X == 1
+int main() { return 1; }
+#elif defined(X) && X * 2 == 4
+int main() { return 2; }
+#else
+int main() { return 3; }
+#endif'
+assert 3 '#if (0u - 1) > 0 && (-1 < 0u) == 0 && 18446744073709551615UL > 0
+int main() { return 3; }
+#else
+int main() { return 4; }
+#endif'
+assert 7 '#ifdef UNDEFINED
+#error unreachable
+#endif
+#define F 1
+#undef F
+#ifndef F
+int main() { return __LINE__; }
+#endif'
+assert_flags '-DN=5 -DM -UM' 5 '#ifdef M
+#error M is undefined
+#endif
+int main() { return N; }'
+assert_flags '-I tmp_include' 6 'tmp_include/main.c'
+assert_flags '-Itmp_include' 3 '#include <sub/three.h>
+int main() { return three(); }'
+assert_preprocess '# 1 "tmp_include/main.c"
+# 1 "tmp_include/once.h" 1
+# 1 "tmp_include/sub/three.h" 1
+
+
+int three() { return 3; }
+# 2 "tmp_include/once.h" 2
+# 2 "tmp_include/main.c" 2
+
+int main() { return three() + 3; }' 'tmp_include/main.c' '-I tmp_include'
+assert_error '#error stop'
+assert_error '#if 1
+int main() { return 0; }'
+assert_error '#include "no_such_file.h"'
+assert_error_message '<input>:1:10: error[E0315]: `nope.h` file not found' '#include "nope.h"' --lang=en
+assert_error_message '<input>:1:2: error[E0321]: #if の式で0による除算' '#if 1 / 0
+#endif' --lang=ja
+assert_error_message '<input>:2:1: error[E0314]: macro `F` takes 1 arguments, but 2 given' '#define F(a) a
+F(1, 2)' --lang=en
+assert_error_message '<input>:1:2: warning[W0301]: #warning careful' '#warning careful
+int main() { return 0; }' --lang=en
+rm -rf tmp_include
+
+assert 7 '#include <stdio.h>
+#include <stdlib.h>
+#include <string.h>
+#include <stdint.h>
+#include <stdbool.h>
+#include <stddef.h>
+int main() {
+  int64_t *p = malloc(16);
+  memset(p, 0, 16);
+  bool ok = *(p + 1) == 0;
+  *p = INT64_MAX;
+  fflush(stdout);
+  free(p);
+  return ok + abs(0 - 4) + (INT32_MIN + 2147483647 + 2) + EXIT_FAILURE;
+}'
+assert 3 '#include <stdarg.h>
+#include "stdbool.h"
+int main() { va_list ap = 0; return true + 2; }'
+
+assert_ir '  %3 = mul i32 %2, 7  ; i32' 'int main() { int x; x = 6; return x * 7; }'
+assert_ir '  ret 42' 'int main() { return 6 * 7; }'
+assert_ir '  %1 = add i32 42, %0  ; i32' 'int main() { return 6 * 7 + 100 / 0; }'
+assert_ir 'data g: size 4, align 4 = -4' 'int g = 2 * 3 - 10; int main() { return g; }'
+assert 1 'int main() { return 2147483647 + 1 < 0; }'
+assert 1 'int main() { return 0 - 1u > 0; }'
+assert_ir '  br %3, bb2, bb3' 'int main() { int i; i = 0; while (i < 3) i = i + 1; return i; }'
+assert_ir '  %1 = call f64 @half(f64 %0)  ; f64' 'double half(double x); int main() { return half(3.0); }'
+
+assert_ir '  ret 42' 'int main() { int x; x = 6; return x * 7; }' -O1
+assert_ir '  %10 = add i32 %18, %17  ; i32' 'int main() { int i; int s; s = 0; for (i = 0; i < 10; i = i + 1) s = s + i; return s; }' -O1
+assert_ir '  %3 = div i32 1, 0  ; i32' 'int main() { int x; x = 1; return x / 0; }' -O1
+assert_ir '  %2 = load volatile i32 %0  ; i32' 'int main() { volatile int x; x = 1; return x; }' -O1
+assert_flags '-O1' 45 'int main() { int i; int s; s = 0; for (i = 0; i < 10; i = i + 1) s = s + i; return s; }'
+assert_flags '-O1' 8 'int fib(int n) { if (n < 2) return n; return fib(n - 1) + fib(n - 2); } int main() { return fib(6); }'
+assert_flags '-O1' 21 'int main() { int a; int b; int t; int i; a = 1; b = 2; for (i = 0; i < 3; i = i + 1) { t = a; a = b; b = t; } return a * 10 + b; }'
+assert_flags '-O1' 3 'int main() { int x; int *p; x = 1; p = &x; *p = 3; return x; }'
+assert_flags '-O1' 2 'int main() { double d; d = 1.5; if (d > 1.0) return d + 0.5; return 0; }'
+assert_flags '-O1' 28 'int g(int x) { return x; } int main() { int a; int b; int c; int d; int e; int f; int h; a = g(1); b = g(2); c = g(3); d = g(4); e = g(5); f = g(6); h = g(7); return a + b + c + d + e + f + h; }'
+assert_flags '-O1' 20 'int sum(int a, int b, int c, int d, int e, int f, int g, int h) { return a + b + c + d + e + f + g + h; } int main() { int i; int s; s = 0; for (i = 0; i < 2; i = i + 1) s = s + sum(i, i + 1, i + 2, i + 3, 1, 1, 1, 1) - i * 4; return s; }'
+assert_flags '-O1' 28 'int t(int a) { int b; int c; int d; int e; int f; int g; int h; int i; int j; b = a + 1; c = b + 1; d = c + 1; e = d + 1; f = e + 1; g = f + 1; h = g + 1; i = h + 1; j = i + 1; return a * j + b * i + c - d + e - f + g - h + (j - i) * 3; } int main() { return t(1); }'
+assert_flags '-O1' 3 'int main() { int i; int n; n = 0; for (i = 0; i < 10; i = i + 1) { if (i == 2) n = n + 1; if (i > 7) n = n + 1; } return n; }'
+assert_flags '-O1' 1 'int main() { double d; unsigned long u; u = 0 - 1ul; d = u; if (d > 1.0) return 1; return 0; }'
+assert_flags '-O1' 4 'int less(int a, int b) { return a < b; } int main() { int c; c = less(3, 4) == 1; return less(1, 2) + less(2, 1) + c * 3; }'
+assert_flags '-O2' 14 'int add2(int a, int b) { return a + b; } static int twice(int x) { if (x > 3) return x * 2; return x; } int main() { int i; int s; s = 0; for (i = 0; i < 5; i = i + 1) s = add2(s, twice(i)); return s; }'
+assert_flags '-O2' 120 'int fact(int n) { if (n < 2) return 1; return n * fact(n - 1); } int main() { return fact(5); }'
+assert_flags '-O2' 21 'static inline int big(int x) { int y; y = x * 2; y = y + x; y = y - 1; y = y * 2; y = y + 3; y = y - x; return y; } int main() { return big(3) + big(1) - big(0); }'
+assert_flags '-O2' 9 'double sq(double x) { return x * x; } int main() { double d; d = sq(3.0); return d; }'
+assert_ir 'function inline one() -> i32 {' 'inline int one() { return 1; } int main() { return one() + one(); }'
+assert_ir '  ret 2' 'inline int one() { return 1; } int main() { return one() + one(); }' -O2
+assert_error 'inline int x; int main() { return 0; }'
+assert 42 'int count(int n, int acc) { if (n == 0) return acc; return count(n - 1, acc + 1); } int main() { return count(10000000, 0) - 9999958; }'
+assert 1 'int odd(int n); int even(int n) { if (n == 0) return 1; return odd(n - 1); } int odd(int n) { if (n == 0) return 0; return even(n - 1); } int main() { return even(10000000); }'
+assert 3 'double halve(double x, int n) { if (n == 0) return x; return halve(x / 2.0, n - 1); } int main() { return halve(3.0 * 1024.0, 10); }'
+assert 7 'int id(int x) { return x; } int apply(int (*f)(int), int x) { return f(x); } int main() { return apply(id, 7); }'
+assert 5 'int get(int *p) { return *p; } int f() { int x; x = 5; return get(&x); } int main() { return f(); }'
+assert_flags '-O2' 42 'int count(int n, int acc) { if (n == 0) return acc; return count(n - 1, acc + 1); } int main() { return count(10000000, 0) - 9999958; }'
+assert_flags '-fno-optimize-sibling-calls' 42 'int count(int n, int acc) { if (n == 0) return acc; return count(n - 1, acc + 1); } int main() { return count(1000, 0) - 958; }'
+assert 1 'int main() { return 1; 2; 3; }'
+assert 7 'int f(int i) { if (i) { return i; return 100; } else return 0; i = 5; } int main() { return f(7); }'
+assert 9 'int main() { int i; i = 0; while (1) { i = i + 1; if (i == 9) return i; } return 100; }'
+assert_flags '-O1' 4 'int main() { if (0) return 3; for (;;) { return 4; } return 5; }'
+assert_error_message '<input>:1:24: warning[W0202]: code in function `main` will never be executed' 'int main() { return 1; 2; }' '-Wunreachable-code --lang=en'
+assert_error_message '{"code":"W0202","severity":"warning","message":"code in function `f` will never be executed","file":"<input>","line":1,"column":35,"end_line":1,"end_column":46,"notes":[]}' 'int f(int i) { if (i) { return i; return 100; } else return 0; i = 5; } int main() { return f(7); }' '-Wunreachable-code --lang=en --diagnostics-format=json'
+assert_error_message '' 'int main() { return 1; 2; }' '-Wunreachable-code -Wno-unreachable-code'
+assert 55 'int keep(int x) { return x; } int main() { int i; int s; s = 0; for (i = 1; i <= 10; i = i + 1) s = keep(s) + keep(i); return s; }'
+assert_error 'int main() { if (1 return 0; }'
+assert_error_count 3 'int f() { int x; x = ; return x; } int g() { return 1 +; } int main() { while (1 { } return 0; }'
+assert_error_message '<input>:1:27: error[E0101]: expected `;` after expression, found `}`' 'int main() { int x; x = 1 }' --lang=en
+assert_error_message '<input>:1:60: error[E0101]: expected `;` after return value, found `}`' 'int main() { int i; for (i = 0; i < 3; i = i + 1) return 1 }' --lang=en
+assert_error_message '<input>:1:57: error[E0101]: expected `;` after expression, found `return`' 'int main() { int i; for (i = 0; i < 3; i = i + 1) i = 2 return 0; }' --lang=en
+assert_error_message '<input>:1:27: error[E0101]: 式の後ろに `;` が必要だが、`}` がある' 'int main() { int x; x = 1 }' --lang=ja
+assert_error_message '<input>:1:12: error[E0101]: expected `{` after parameters, found `return`' 'int main() return 3; }' --lang=en
+assert_error_message '<input>:1:21: error[E0004]: integer constant `99999999999999999999` is too large' 'int main() { return 99999999999999999999; }' '--lang en'
+assert_error_message '<input>:1:21: error[E0204]: 未定義変数 `y`' 'int main() { return y; }' '--lang ja'
+assert_error_message '<input>:3:7: error[E0204]: undefined variable `zz`' 'int main() {
+  int x;
+  x = zz + 1;
+  return x;
+}' '--lang en'
+assert_error_message '<input>:1:31: error[E0205]: cannot assign to const lvalue of type `const int`' 'int main() { const int x = 1; x = 2; return x; }' '--lang en'
+assert_error_message '<input>:1:50: error[E0209]: `struct S` has no member `b`' 'struct S { int a; }; int f(struct S *s) { return s->b; } int main() { return 0; }' '--lang en'
+assert_error_message '<input>:1:1: error[E0202]: extern variable `g` cannot be initialized' 'extern int g = 1; int main() { return 0; }' '--lang en'
+assert_error_message '<input>:4:16: error[E0101]: expected `)` after `while` condition, found `{`
+<input>:4:9: note: to match this `(`' '#include <stdbool.h>
+int main() {
+  int i = 0;
+  while (i < 3 {
+    i = i + 1;
+  }
+  return i;
+}' --lang=en
+LANG=ja_JP.UTF-8 assert_error_message '<input>:1:25: error[E0102]: 式が必要だが、`;` がある' 'int main() { return 1 + ; }'
+LANG=C assert_error_message '<input>:1:25: error[E0102]: expected expression, found `;`' 'int main() { return 1 + ; }'
+assert_error_message '<input>:2:16: error[E0101]: expected `)` to close parenthesized expression, found `;`
+<input>:2:10: note: to match this `(`' 'int main() {
+  return (1 + 2;
+}' --lang=en
+assert_error_message '<input>:1:22: error[E0101]: expected `}` to close block, found end of input
+<input>:1:12: note: to match this `{`' 'int main() { return 3;' --lang=en
+assert_error_message '{"code":"E0101","severity":"error","message":"expected `)` after `if` condition, found `return`","file":"<input>","line":1,"column":20,"end_line":1,"end_column":26,"notes":[{"severity":"note","message":"to match this `(`","file":"<input>","line":1,"column":17,"end_line":1,"end_column":18}]}
+{"code":"E0101","severity":"error","message":"expected `;` after return value, found `}`","file":"<input>","line":1,"column":39,"end_line":1,"end_column":40,"notes":[]}' 'int main() { if (1 return 0; return 1 }' '--lang=en --diagnostics-format=json'
+assert_error_message '{"code":"E0204","severity":"error","message":"undefined variable `y`","file":"<input>","line":1,"column":21,"end_line":1,"end_column":22,"notes":[]}' 'int main() { return y; }' '--lang=en --diagnostics-format json'
+assert_error_message '{"code":"W0201","severity":"warning","message":"initialization from `const int *` to `int *` discards qualifiers of the pointed-to type","file":"<input>","line":1,"column":40,"end_line":1,"end_column":51,"notes":[]}' 'int main() { int x; const int *p = &x; int *q = p; return 0; }' '--lang=en --diagnostics-format json'
+assert_error_message '{"code":"E0205","severity":"error","message":"cannot assign to const lvalue of type `const int`","file":"<input>","line":1,"column":31,"end_line":1,"end_column":36,"notes":[]}' 'int main() { const int x = 1; x = 2; return x; }' '--lang=en --diagnostics-format json'
+assert_usage_error 'error[E0506]: no input' '--lang=en'
+LANG=ja_JP.UTF-8 assert_usage_error 'error[E0501]: `-I` の後ろに引数がない' '-I'
+LANG=C assert_usage_error 'error[E0502]: unknown language in `--lang=fr`' '--lang=fr'
+LANG=C assert_usage_error 'error[E0504]: invalid optimization level `-Ox`' '-Ox'
+LANG=C assert_usage_error 'error[E0505]: more than one input given' 'a.c b.c'
+
+echo "instructions: $insts"
 echo OK